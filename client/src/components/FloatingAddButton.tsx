@@ -63,7 +63,7 @@ export function FloatingAddButton({ onTrackAdded }: FloatingAddButtonProps) {
       const data = await response.json();
 
       if (!response.ok) {
-        throw new Error(data.error || "Failed to download and convert track");
+        throw new Error(data.message || "Failed to download and convert track");
       }
 
       // Create a track object from the download response
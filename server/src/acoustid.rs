@@ -0,0 +1,159 @@
+//! Identifying untitled tracks by audio fingerprint: Chromaprint's `fpcalc` plus an
+//! AcoustID lookup.
+
+use crate::process::ProcessLimits;
+use crate::storage::{Identification, IdentificationStatus};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command;
+
+const LOOKUP_URL: &str = "https://api.acoustid.org/v2/lookup";
+/// Seconds of audio fingerprinted; AcoustID matches on the start of the recording.
+const FINGERPRINT_SECONDS: &str = "120";
+
+pub struct AcoustId {
+    pub api_key: String,
+    /// Matches scoring below this (0-1) are ignored
+    pub min_score: f64,
+    pub client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct Fingerprint {
+    duration: f64,
+    fingerprint: String,
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    status: String,
+    #[serde(default)]
+    results: Vec<LookupResult>,
+    #[serde(default)]
+    error: Option<LookupError>,
+}
+
+#[derive(Deserialize)]
+struct LookupError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct LookupResult {
+    score: f64,
+    #[serde(default)]
+    recordings: Vec<Recording>,
+}
+
+#[derive(Deserialize)]
+struct Recording {
+    id: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    artists: Vec<Named>,
+    #[serde(default)]
+    releasegroups: Vec<ReleaseGroup>,
+}
+
+#[derive(Deserialize)]
+struct Named {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ReleaseGroup {
+    title: String,
+    #[serde(default, rename = "type")]
+    kind: Option<String>,
+}
+
+impl AcoustId {
+    /// Best match for the audio in `file_path`, if any scores at least `min_score`.
+    /// The returned identification is pending; its `previous_*` fields are left empty
+    /// for the caller to fill in.
+    pub async fn identify(
+        &self,
+        file_path: &Path,
+        limits: &ProcessLimits,
+    ) -> Result<Option<Identification>, Box<dyn std::error::Error + Send + Sync>> {
+        let output = limits
+            .output(
+                Command::new("fpcalc")
+                    .args(["-json", "-length", FINGERPRINT_SECONDS])
+                    .arg(file_path),
+            )
+            .await?;
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("fpcalc error: {}", error.trim()).into());
+        }
+        let fingerprint: Fingerprint = serde_json::from_slice(&output.stdout)?;
+
+        // Fingerprints are too long for a query string
+        let duration = (fingerprint.duration.round() as u64).to_string();
+        let response: LookupResponse = self
+            .client
+            .post(LOOKUP_URL)
+            .timeout(Duration::from_secs(15))
+            .form(&[
+                ("client", self.api_key.as_str()),
+                ("meta", "recordings releasegroups"),
+                ("duration", duration.as_str()),
+                ("fingerprint", fingerprint.fingerprint.as_str()),
+            ])
+            .send()
+            .await?
+            .json()
+            .await?;
+        if response.status != "ok" {
+            let message = response.error.map(|e| e.message).unwrap_or_default();
+            return Err(format!("AcoustID lookup failed: {}", message).into());
+        }
+
+        let best = response
+            .results
+            .into_iter()
+            .filter(|result| result.score >= self.min_score)
+            .flat_map(|result| {
+                let score = result.score;
+                result
+                    .recordings
+                    .into_iter()
+                    .map(move |recording| (score, recording))
+            })
+            .filter(|(_, recording)| recording.title.is_some())
+            .max_by(|a, b| a.0.total_cmp(&b.0));
+
+        Ok(best.map(|(score, recording)| {
+            let artist = (!recording.artists.is_empty()).then(|| {
+                recording
+                    .artists
+                    .iter()
+                    .map(|artist| artist.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            });
+            // Prefer the album a recording appeared on over singles and compilations
+            let album = recording
+                .releasegroups
+                .iter()
+                .find(|group| group.kind.as_deref() == Some("Album"))
+                .or(recording.releasegroups.first())
+                .map(|group| group.title.clone());
+
+            Identification {
+                recording_id: recording.id,
+                score,
+                title: recording.title.unwrap_or_default(),
+                artist,
+                album,
+                status: IdentificationStatus::Pending,
+                previous_title: String::new(),
+                previous_artist: None,
+                previous_album: None,
+            }
+        }))
+    }
+}
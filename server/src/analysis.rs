@@ -0,0 +1,295 @@
+//! Tempo and musical key detection on audio decoded by ffmpeg.
+
+use crate::process::within;
+use crate::storage::TempoKey;
+use crate::transcode::TranscodeOptions;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::AsyncReadExt;
+
+/// Audio is decoded to mono at this rate; enough for beats and pitches up to B6.
+const SAMPLE_RATE: usize = 11025;
+/// Only this much of the track, taken from the middle, is analyzed.
+const ANALYSIS_SECONDS: f64 = 120.0;
+
+/// Samples per onset envelope value (~12 ms).
+const ONSET_HOP: usize = 128;
+/// Hops whose energy is summed for each envelope value (~46 ms), long enough to
+/// smooth out beating between sustained notes.
+const ONSET_WINDOW_HOPS: usize = 4;
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+
+/// Samples per chroma frame (~190 ms).
+const CHROMA_FRAME: usize = 2048;
+/// MIDI notes C3 to B6 feed the pitch class profile.
+const CHROMA_NOTES: std::ops::RangeInclusive<u32> = 48..=95;
+
+/// Krumhansl-Kessler key profiles, starting at the tonic.
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+const PITCH_CLASSES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Estimates BPM and key of `file_path`. `duration` (of the clip, when `options` has
+/// one) centers the analysis window; without it the start of the track is used.
+pub async fn analyze_tempo_key(
+    file_path: &Path,
+    options: &TranscodeOptions,
+    duration: Option<f64>,
+) -> Result<TempoKey, Box<dyn std::error::Error + Send + Sync>> {
+    let (clip_start, clip_end) = options.clip.unwrap_or((0.0, f64::INFINITY));
+    let offset = duration.map_or(0.0, |d| ((d - ANALYSIS_SECONDS) / 2.0).max(0.0));
+    let start = clip_start + offset;
+    let window = TranscodeOptions {
+        clip: Some((start, (start + ANALYSIS_SECONDS).min(clip_end))),
+        ..options.clone()
+    };
+
+    let mut child = options.limits.spawn(
+        window
+            .ffmpeg(file_path)
+            .args(["-vn", "-ac", "1", "-ar"])
+            .arg(SAMPLE_RATE.to_string())
+            .args(["-f", "f32le", "pipe:1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+    )?;
+
+    let mut stdout = child.stdout.take().ok_or("ffmpeg stdout unavailable")?;
+    let mut analyzer = Analyzer::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut pending: Vec<u8> = Vec::with_capacity(4);
+    let timeout = child.timeout();
+    let status = within(timeout, async {
+        loop {
+            let n = stdout.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            pending.extend_from_slice(&buf[..n]);
+            let whole = pending.len() / 4 * 4;
+            for bytes in pending[..whole].chunks_exact(4) {
+                analyzer.push(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64);
+            }
+            pending.drain(..whole);
+        }
+        child.wait().await
+    })
+    .await??;
+
+    if !status.success() {
+        return Err("FFmpeg failed to decode audio for analysis".into());
+    }
+
+    let bpm = analyzer
+        .bpm()
+        .ok_or("Not enough audio to detect the tempo")?;
+    let (tonic, minor) = analyzer.key().ok_or("Not enough audio to detect the key")?;
+    Ok(TempoKey {
+        bpm: (bpm * 10.0).round() / 10.0,
+        key: format!(
+            "{} {}",
+            PITCH_CLASSES[tonic],
+            if minor { "minor" } else { "major" }
+        ),
+        camelot: camelot(tonic, minor),
+    })
+}
+
+/// Streaming state: an onset envelope for the tempo and pitch class energies for the key.
+struct Analyzer {
+    hop_energy: f64,
+    hop_len: usize,
+    recent_hops: VecDeque<f64>,
+    previous_log_energy: Option<f64>,
+    onsets: Vec<f64>,
+    frame: Vec<f64>,
+    window: Vec<f64>,
+    chroma: [f64; 12],
+}
+
+impl Analyzer {
+    fn new() -> Self {
+        let window = (0..CHROMA_FRAME)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / (CHROMA_FRAME - 1) as f64).cos())
+            .collect();
+        Analyzer {
+            hop_energy: 0.0,
+            hop_len: 0,
+            recent_hops: VecDeque::with_capacity(ONSET_WINDOW_HOPS),
+            previous_log_energy: None,
+            onsets: Vec::new(),
+            frame: Vec::with_capacity(CHROMA_FRAME),
+            window,
+            chroma: [0.0; 12],
+        }
+    }
+
+    fn push(&mut self, sample: f64) {
+        self.hop_energy += sample * sample;
+        self.hop_len += 1;
+        if self.hop_len == ONSET_HOP {
+            if self.recent_hops.len() == ONSET_WINDOW_HOPS {
+                self.recent_hops.pop_front();
+            }
+            self.recent_hops.push_back(self.hop_energy);
+            self.hop_energy = 0.0;
+            self.hop_len = 0;
+
+            // Rises in log energy mark note and drum onsets
+            if self.recent_hops.len() == ONSET_WINDOW_HOPS {
+                let energy =
+                    self.recent_hops.iter().sum::<f64>() / (ONSET_HOP * ONSET_WINDOW_HOPS) as f64;
+                let log_energy = (energy + 1e-10).ln();
+                if let Some(previous) = self.previous_log_energy {
+                    self.onsets.push((log_energy - previous).max(0.0));
+                }
+                self.previous_log_energy = Some(log_energy);
+            }
+        }
+
+        self.frame.push(sample);
+        if self.frame.len() == CHROMA_FRAME {
+            self.add_chroma_frame();
+            self.frame.clear();
+        }
+    }
+
+    fn add_chroma_frame(&mut self) {
+        for note in CHROMA_NOTES {
+            let frequency = 440.0 * 2f64.powf((note as f64 - 69.0) / 12.0);
+            let coefficient = 2.0 * (2.0 * PI * frequency / SAMPLE_RATE as f64).cos();
+            let (mut s1, mut s2) = (0.0, 0.0);
+            for (sample, weight) in self.frame.iter().zip(&self.window) {
+                let s0 = sample * weight + coefficient * s1 - s2;
+                s2 = s1;
+                s1 = s0;
+            }
+            let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+            self.chroma[(note % 12) as usize] += power.max(0.0).sqrt();
+        }
+    }
+
+    /// Strongest beat period of the onset envelope, favouring tempos near 120 BPM
+    /// so half- and double-time readings lose out.
+    fn bpm(&self) -> Option<f64> {
+        let envelopes_per_second = SAMPLE_RATE as f64 / ONSET_HOP as f64;
+        let min_lag = (60.0 * envelopes_per_second / MAX_BPM).floor() as usize;
+        let max_lag = (60.0 * envelopes_per_second / MIN_BPM).ceil() as usize;
+        if self.onsets.len() < max_lag * 4 {
+            return None;
+        }
+
+        // Beat periods rarely span a whole number of envelope values, so onsets are
+        // widened a little to keep them correlating across many beats
+        const KERNEL: [f64; 5] = [1.0, 2.0, 3.0, 2.0, 1.0];
+        let smoothed: Vec<f64> = (0..self.onsets.len())
+            .map(|i| {
+                KERNEL
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(k, weight)| {
+                        (i + k)
+                            .checked_sub(2)
+                            .and_then(|j| self.onsets.get(j))
+                            .map(|o| o * weight)
+                    })
+                    .sum::<f64>()
+            })
+            .collect();
+
+        let mean = smoothed.iter().sum::<f64>() / smoothed.len() as f64;
+        let centered: Vec<f64> = smoothed.iter().map(|o| o - mean).collect();
+        let autocorrelation = |lag: usize| -> f64 {
+            centered
+                .iter()
+                .zip(&centered[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f64>()
+                / (centered.len() - lag) as f64
+        };
+
+        let scores: Vec<(usize, f64)> = (min_lag.saturating_sub(1)..=max_lag + 1)
+            .map(|lag| (lag, autocorrelation(lag)))
+            .collect();
+        let (best, _) = scores[1..scores.len() - 1]
+            .iter()
+            .enumerate()
+            .map(|(i, &(lag, score))| {
+                let bpm = 60.0 * envelopes_per_second / lag as f64;
+                let prior = (-0.5 * (bpm / 120.0).log2().powi(2)).exp();
+                (i + 1, score * prior)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        // Parabolic interpolation between neighbouring lags for sub-frame precision
+        let (lag, score) = scores[best];
+        let (before, after) = (scores[best - 1].1, scores[best + 1].1);
+        let denominator = before - 2.0 * score + after;
+        let shift = if denominator.abs() > f64::EPSILON {
+            (0.5 * (before - after) / denominator).clamp(-0.5, 0.5)
+        } else {
+            0.0
+        };
+        Some(60.0 * envelopes_per_second / (lag as f64 + shift))
+    }
+
+    /// Tonic pitch class and mode whose profile best correlates with the chroma.
+    fn key(&self) -> Option<(usize, bool)> {
+        if self.chroma.iter().all(|energy| *energy <= 0.0) {
+            return None;
+        }
+
+        (0..12)
+            .flat_map(|tonic| {
+                [
+                    (
+                        tonic,
+                        false,
+                        correlation(&self.chroma, &MAJOR_PROFILE, tonic),
+                    ),
+                    (
+                        tonic,
+                        true,
+                        correlation(&self.chroma, &MINOR_PROFILE, tonic),
+                    ),
+                ]
+            })
+            .max_by(|a, b| a.2.total_cmp(&b.2))
+            .map(|(tonic, minor, _)| (tonic, minor))
+    }
+}
+
+/// Pearson correlation of the chroma with a key profile rotated to `tonic`.
+fn correlation(chroma: &[f64; 12], profile: &[f64; 12], tonic: usize) -> f64 {
+    let chroma_mean = chroma.iter().sum::<f64>() / 12.0;
+    let profile_mean = profile.iter().sum::<f64>() / 12.0;
+    let (mut covariance, mut chroma_var, mut profile_var) = (0.0, 0.0, 0.0);
+    for (pitch, energy) in chroma.iter().enumerate() {
+        let c = energy - chroma_mean;
+        let p = profile[(pitch + 12 - tonic) % 12] - profile_mean;
+        covariance += c * p;
+        chroma_var += c * c;
+        profile_var += p * p;
+    }
+    covariance / (chroma_var * profile_var).sqrt().max(f64::EPSILON)
+}
+
+/// Camelot wheel code, e.g. "8B" for C major and "8A" for A minor.
+fn camelot(tonic: usize, minor: bool) -> String {
+    // Minor keys share the number of their relative major, three semitones up
+    let major_tonic = if minor { (tonic + 3) % 12 } else { tonic };
+    let number = match (7 * major_tonic + 8) % 12 {
+        0 => 12,
+        n => n,
+    };
+    format!("{}{}", number, if minor { "A" } else { "B" })
+}
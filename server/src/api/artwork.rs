@@ -0,0 +1,158 @@
+//! Artwork uploaded for a track, replacing the thumbnails made from its source.
+
+use super::{json_error, read_only_track, AppState};
+use crate::library::track_info;
+use crate::transcode::{thumbnail_file, THUMBNAIL_SIZES};
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+pub(super) const MAX_ARTWORK_SIZE: usize = 10 * 1024 * 1024;
+
+/// Where uploads are scaled before their thumbnails replace the track's, so a
+/// failed upload leaves the old ones in place.
+const UPLOAD_DIR: &str = "artwork.upload";
+
+/// File extension of the image formats accepted, recognized by their magic bytes.
+fn image_extension(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("jpg")
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+/// Replaces the track's thumbnails with ones scaled from the uploaded JPEG, PNG or
+/// WebP image. Refreshes keep it, where they would replace the source's artwork.
+pub(super) async fn set_artwork(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+    body: Bytes,
+) -> Response {
+    if body.len() > MAX_ARTWORK_SIZE {
+        return json_error(
+            &format!(
+                "Artwork is larger than {} MiB",
+                MAX_ARTWORK_SIZE / 1024 / 1024
+            ),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        );
+    }
+    let Some(extension) = image_extension(&body) else {
+        return json_error(
+            "Artwork must be a JPEG, PNG or WebP image",
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        );
+    };
+
+    let segments_dir = {
+        let cache = state.hls_cache.read().await;
+        let Some(session) = cache.get(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        session.segments_dir.clone()
+    };
+
+    let upload_dir = segments_dir.join(UPLOAD_DIR);
+    let _ = tokio::fs::remove_dir_all(&upload_dir).await;
+    let image = upload_dir.join(format!("source.{}", extension));
+    let written = match tokio::fs::create_dir_all(&upload_dir).await {
+        Ok(()) => tokio::fs::write(&image, &body).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_dir_all(&upload_dir).await;
+        return json_error(
+            &format!("Failed to save artwork: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        );
+    }
+    let options = &state.ingest_options;
+    let artwork_hash = match options
+        .tools
+        .thumbnails(&image, &upload_dir, &options.transcode)
+        .await
+    {
+        Ok(artwork_hash) => artwork_hash,
+        Err(e) => {
+            let _ = tokio::fs::remove_dir_all(&upload_dir).await;
+            eprintln!("Warning: Uploaded artwork conversion failed: {}", e);
+            return json_error("Artwork could not be decoded", StatusCode::BAD_REQUEST);
+        }
+    };
+
+    // Swapped in under the lock, so a refresh or delete meanwhile doesn't get
+    // thumbnails it didn't ask for
+    let track = {
+        let mut cache = state.hls_cache.write().await;
+        let Some(session) = cache
+            .get_mut(&track_id)
+            .filter(|s| s.segments_dir == segments_dir)
+        else {
+            let _ = tokio::fs::remove_dir_all(&upload_dir).await;
+            return json_error(
+                "Track was changed or deleted during the upload",
+                StatusCode::CONFLICT,
+            );
+        };
+        for (size, _) in THUMBNAIL_SIZES {
+            let file = thumbnail_file(size);
+            if let Err(e) =
+                tokio::fs::rename(upload_dir.join(&file), segments_dir.join(&file)).await
+            {
+                let _ = tokio::fs::remove_dir_all(&upload_dir).await;
+                return json_error(
+                    &format!("Failed to save artwork: {}", e),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                );
+            }
+        }
+        session.has_thumbnail = true;
+        session.artwork_hash = Some(artwork_hash);
+        session.custom_artwork = true;
+        track_info(&track_id, session)
+    };
+    let _ = tokio::fs::remove_dir_all(&upload_dir).await;
+
+    state.hls_cache.changed();
+    Json(track).into_response()
+}
+
+/// Removes the track's thumbnails, uploaded or not. The next refresh fetches the
+/// source's artwork again.
+pub(super) async fn delete_artwork(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+) -> Response {
+    let track = {
+        let mut cache = state.hls_cache.write().await;
+        let Some(session) = cache.get_mut(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        if !session.has_thumbnail {
+            return json_error("Track has no artwork", StatusCode::NOT_FOUND);
+        }
+        for (size, _) in THUMBNAIL_SIZES {
+            let _ = tokio::fs::remove_file(session.segments_dir.join(thumbnail_file(size))).await;
+        }
+        session.has_thumbnail = false;
+        session.artwork_hash = None;
+        session.custom_artwork = false;
+        track_info(&track_id, session)
+    };
+
+    state.hls_cache.changed();
+    Json(track).into_response()
+}
@@ -0,0 +1,49 @@
+//! Listing the metadata backups, and taking one outside the schedule.
+
+use super::{json_error, AppState};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+const NOT_CONFIGURED: &str =
+    "Backups are not configured; start the server with --backup-dir or --backup-s3";
+
+/// The backups at the target, newest first, with the schedule and the last failure.
+pub(super) async fn list_backups(State(state): State<AppState>) -> Response {
+    let Some(backups) = &state.backups else {
+        return json_error(NOT_CONFIGURED, StatusCode::NOT_FOUND);
+    };
+    match backups.list().await {
+        Ok(list) => Json(serde_json::json!({
+            "location": backups.location(),
+            "interval_hours": backups.interval.as_secs() / 3600,
+            "keep": backups.keep,
+            "last_error": backups.last_error(),
+            "backups": list,
+        }))
+        .into_response(),
+        Err(e) => json_error(
+            &format!("Failed to list backups: {}", e),
+            StatusCode::BAD_GATEWAY,
+        ),
+    }
+}
+
+/// Takes a backup now, e.g. before an upgrade; older ones beyond the retention are
+/// deleted as after scheduled backups.
+pub(super) async fn create_backup(State(state): State<AppState>) -> Response {
+    let Some(backups) = &state.backups else {
+        return json_error(NOT_CONFIGURED, StatusCode::NOT_FOUND);
+    };
+    // The snapshot is taken from the files, so changes still waiting for
+    // --save-delay go in first
+    state.hls_cache.flush().await;
+    match backups.backup().await {
+        Ok(backup) => (StatusCode::CREATED, Json(backup)).into_response(),
+        Err(e) => json_error(
+            &format!("Backup failed: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    }
+}
@@ -0,0 +1,240 @@
+//! Batch download handlers.
+
+use super::{finish_download, json_error, queue_download, run_download, AppState};
+use crate::downloader::{
+    DownloadError, DownloadFailure, DownloadRequest, DownloadResponse, DownloadStatus,
+};
+use crate::storage::unix_timestamp;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+/// Most URLs accepted in one batch.
+pub(super) const MAX_BATCH_SIZE: usize = 100;
+
+pub(super) type DownloadBatches = Arc<RwLock<HashMap<String, DownloadBatch>>>;
+
+#[derive(Debug, Clone)]
+pub(super) struct DownloadBatch {
+    created_at: u64,
+    /// Download job ids and their URLs, in request order
+    jobs: Vec<(String, String)>,
+    /// Set once every job has finished and `batch_finished` was emitted
+    finished: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchJob {
+    download_id: String,
+    url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchCreated {
+    id: String,
+    downloads: Vec<BatchJob>,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct BatchStatus {
+    id: String,
+    created_at: u64,
+    /// queued, running, ready, error (every job failed) or partial
+    status: &'static str,
+    total: usize,
+    ready: usize,
+    failed: usize,
+    items: Vec<DownloadStatus>,
+}
+
+/// Queues one download job per URL and runs them one after another in the background
+pub(super) async fn create_batch(
+    State(state): State<AppState>,
+    Json(requests): Json<Vec<DownloadRequest>>,
+) -> Response {
+    if requests.is_empty() {
+        return json_error(
+            "Batch must contain at least one URL",
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    if requests.len() > MAX_BATCH_SIZE {
+        return json_error(
+            &format!("Batch must not contain more than {} URLs", MAX_BATCH_SIZE),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    let (batch_id, jobs, queued) = queue_batch(&state, requests).await;
+    let worker_state = state.clone();
+    tokio::spawn(async move {
+        for (download_id, request) in queued {
+            // Failures are recorded in the job's status; the batch moves on
+            let _ = run_batch_job(&worker_state, &download_id, request).await;
+        }
+    });
+
+    let downloads = jobs
+        .into_iter()
+        .map(|(download_id, url)| BatchJob { download_id, url })
+        .collect();
+    (
+        StatusCode::ACCEPTED,
+        Json(BatchCreated {
+            id: batch_id,
+            downloads,
+        }),
+    )
+        .into_response()
+}
+
+/// Queues one download job per request under a new batch. Returns the batch id, every
+/// job with its URL in request order, and the newly queued jobs, which the caller runs.
+pub(super) async fn queue_batch(
+    state: &AppState,
+    requests: Vec<DownloadRequest>,
+) -> (
+    String,
+    Vec<(String, String)>,
+    Vec<(String, DownloadRequest)>,
+) {
+    let batch_id = Uuid::new_v4().to_string();
+    let mut jobs = Vec::with_capacity(requests.len());
+    let mut queued = Vec::with_capacity(requests.len());
+    for request in requests {
+        let download_id = Uuid::new_v4().to_string();
+        match queue_download(state, &download_id, Some(&batch_id), None, &request).await {
+            Ok(()) => {
+                jobs.push((download_id.clone(), request.url.clone()));
+                queued.push((download_id, request));
+            }
+            // Already being downloaded, for another request or earlier in this batch;
+            // the batch follows that job
+            Err(existing) => jobs.push((existing, request.url)),
+        }
+    }
+
+    let mut batches = state.download_batches.write().await;
+    batches.insert(
+        batch_id.clone(),
+        DownloadBatch {
+            created_at: unix_timestamp(),
+            jobs: jobs.clone(),
+            finished: false,
+        },
+    );
+    (batch_id, jobs, queued)
+}
+
+/// Runs one queued job of a batch, failing it right away if the URL policy or the
+/// extractor argument allowlist refuses it.
+pub(super) async fn run_batch_job(
+    state: &AppState,
+    download_id: &str,
+    request: DownloadRequest,
+) -> Result<DownloadResponse, DownloadError> {
+    let options = &state.ingest_options;
+    let refused = match options.url_policy.check(&request.url) {
+        Err(reason) => Some(DownloadError::new(DownloadFailure::UrlNotAllowed, reason)),
+        Ok(()) => options
+            .extractor
+            .check(&request)
+            .err()
+            .map(|reason| DownloadError::new(DownloadFailure::InvalidRequest, reason)),
+    };
+    if let Some(error) = refused {
+        finish_download(state, download_id, Some(&error)).await;
+        return Err(error);
+    }
+    run_download(state, download_id, request).await
+}
+
+/// Status of every job in a batch, plus an overall status
+pub(super) async fn batch_status(
+    State(state): State<AppState>,
+    Path(batch_id): Path<String>,
+) -> Result<Json<BatchStatus>, StatusCode> {
+    let batch = {
+        let batches = state.download_batches.read().await;
+        batches
+            .get(&batch_id)
+            .cloned()
+            .ok_or(StatusCode::NOT_FOUND)?
+    };
+
+    let items: Vec<DownloadStatus> = {
+        let queue = state.download_queue.read().await;
+        batch
+            .jobs
+            .iter()
+            .filter_map(|(download_id, _)| queue.get(download_id).cloned())
+            .collect()
+    };
+
+    let (status, total, ready, failed) = summarize(&items);
+    Ok(Json(BatchStatus {
+        id: batch_id,
+        created_at: batch.created_at,
+        status,
+        total,
+        ready,
+        failed,
+        items,
+    }))
+}
+
+/// Overall status of a batch's jobs, with how many there are, are ready and failed.
+fn summarize(items: &[DownloadStatus]) -> (&'static str, usize, usize, usize) {
+    let count = |status: &str| items.iter().filter(|item| item.status == status).count();
+    let (total, ready, failed, queued) =
+        (items.len(), count("ready"), count("error"), count("queued"));
+    let status = if queued == total {
+        "queued"
+    } else if ready + failed < total {
+        "running"
+    } else if failed == 0 {
+        "ready"
+    } else if ready == 0 {
+        "error"
+    } else {
+        "partial"
+    };
+    (status, total, ready, failed)
+}
+
+/// Emits `batch_finished` for every batch whose last running job was `download_id`.
+pub(super) async fn finish_batches(state: &AppState, download_id: &str) {
+    let mut batches = state.download_batches.write().await;
+    let queue = state.download_queue.read().await;
+    for (batch_id, batch) in batches.iter_mut() {
+        if batch.finished || !batch.jobs.iter().any(|(id, _)| id == download_id) {
+            continue;
+        }
+        let items: Vec<DownloadStatus> = batch
+            .jobs
+            .iter()
+            .filter_map(|(id, _)| queue.get(id).cloned())
+            .collect();
+        let (status, total, ready, failed) = summarize(&items);
+        if status == "queued" || status == "running" {
+            continue;
+        }
+        batch.finished = true;
+        state.webhooks.emit(
+            "batch_finished",
+            serde_json::json!({
+                "batch_id": batch_id,
+                "status": status,
+                "total": total,
+                "ready": ready,
+                "failed": failed,
+            }),
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! Telegram bot: links pasted into a chat with the bot are downloaded into the
+//! library, and the bot answers with the download's status and a playback link.
+
+use super::{queue_download, run_download, AppState};
+use crate::downloader::{DownloadRequest, Priority};
+use serde::Deserialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long a getUpdates call waits for new messages before returning empty.
+const POLL_TIMEOUT: u64 = 50;
+
+/// How long to wait before polling again after Telegram couldn't be reached.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub(super) struct TelegramBot {
+    /// Bot API base, with the token, e.g. https://api.telegram.org/bot<token>
+    endpoint: String,
+    /// Chats allowed to add tracks
+    chats: Vec<i64>,
+    /// Server address put in front of playback links
+    public_url: String,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Deserialize)]
+struct Reply<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<Message>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    message_id: i64,
+    chat: Chat,
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+impl TelegramBot {
+    pub(super) fn new(api_url: &str, token: &str, chats: Vec<i64>, public_url: &str) -> Self {
+        TelegramBot {
+            endpoint: format!("{}/bot{}", api_url.trim_end_matches('/'), token),
+            chats,
+            public_url: public_url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Calls a Bot API method. Errors leave out the request URL, which holds the token.
+    async fn call<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        body: serde_json::Value,
+        timeout: Duration,
+    ) -> Result<T, String> {
+        let reply: Reply<T> = self
+            .client
+            .post(format!("{}/{}", self.endpoint, method))
+            .json(&body)
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(|e| e.without_url().to_string())?
+            .json()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+        match reply.result {
+            Some(result) if reply.ok => Ok(result),
+            _ => Err(reply
+                .description
+                .unwrap_or_else(|| format!("{} failed", method))),
+        }
+    }
+
+    /// Sends `text` as a reply to `message_id`, returning the id of the sent message.
+    async fn reply(&self, chat_id: i64, message_id: i64, text: &str) -> Option<i64> {
+        let sent = self
+            .call::<Message>(
+                "sendMessage",
+                serde_json::json!({
+                    "chat_id": chat_id,
+                    "text": text,
+                    "reply_parameters": { "message_id": message_id },
+                    "link_preview_options": { "is_disabled": true },
+                }),
+                Duration::from_secs(30),
+            )
+            .await;
+        match sent {
+            Ok(message) => Some(message.message_id),
+            Err(e) => {
+                eprintln!("Warning: Failed to send Telegram message: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Replaces the text of a message the bot sent, or sends `text` as a new reply
+    /// when there is none.
+    async fn edit(&self, chat_id: i64, message_id: i64, status_id: Option<i64>, text: &str) {
+        let Some(status_id) = status_id else {
+            self.reply(chat_id, message_id, text).await;
+            return;
+        };
+        let edited = self
+            .call::<serde_json::Value>(
+                "editMessageText",
+                serde_json::json!({
+                    "chat_id": chat_id,
+                    "message_id": status_id,
+                    "text": text,
+                    "link_preview_options": { "is_disabled": true },
+                }),
+                Duration::from_secs(30),
+            )
+            .await;
+        if let Err(e) = edited {
+            eprintln!("Warning: Failed to update Telegram message: {}", e);
+        }
+    }
+}
+
+/// Polls Telegram for new messages for as long as the server runs.
+pub(super) async fn run_telegram_bot(state: AppState, bot: TelegramBot) {
+    let mut offset = 0;
+    loop {
+        let updates = bot
+            .call::<Vec<Update>>(
+                "getUpdates",
+                serde_json::json!({
+                    "offset": offset,
+                    "timeout": POLL_TIMEOUT,
+                    "allowed_updates": ["message"],
+                }),
+                Duration::from_secs(POLL_TIMEOUT + 10),
+            )
+            .await;
+        let updates = match updates {
+            Ok(updates) => updates,
+            Err(e) => {
+                eprintln!("Warning: Telegram polling failed: {}", e);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        for update in updates {
+            offset = offset.max(update.update_id + 1);
+            if let Some(message) = update.message {
+                handle_message(&state, &bot, message).await;
+            }
+        }
+    }
+}
+
+async fn handle_message(state: &AppState, bot: &TelegramBot, message: Message) {
+    let chat_id = message.chat.id;
+    let Some(text) = message.text else {
+        return;
+    };
+    if !bot.chats.contains(&chat_id) {
+        bot.reply(
+            chat_id,
+            message.message_id,
+            &format!(
+                "This chat may not add tracks. To allow it, start the server with --telegram-chat {}",
+                chat_id
+            ),
+        )
+        .await;
+        return;
+    }
+
+    let urls: Vec<&str> = text
+        .split_whitespace()
+        .filter(|word| word.starts_with("http://") || word.starts_with("https://"))
+        .collect();
+    if urls.is_empty() {
+        bot.reply(
+            chat_id,
+            message.message_id,
+            "Send me a link and I'll add it to the library.",
+        )
+        .await;
+        return;
+    }
+
+    for url in urls {
+        if let Err(reason) = state.ingest_options.url_policy.check(url) {
+            bot.reply(chat_id, message.message_id, &format!("🚫 {}", reason))
+                .await;
+            continue;
+        }
+        let request = DownloadRequest {
+            url: url.to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            split_chapters: false,
+            video: false,
+            priority: Priority::Normal,
+            limit_rate: None,
+            extractor_args: Vec::new(),
+            format: None,
+            refresh: None,
+        };
+        let download_id = Uuid::new_v4().to_string();
+        if queue_download(state, &download_id, None, None, &request)
+            .await
+            .is_err()
+        {
+            bot.reply(
+                chat_id,
+                message.message_id,
+                &format!("⏳ {} is already being downloaded", url),
+            )
+            .await;
+            continue;
+        }
+
+        let status_id = bot
+            .reply(
+                chat_id,
+                message.message_id,
+                &format!("⏳ Downloading {}", url),
+            )
+            .await;
+        let state = state.clone();
+        let bot = bot.clone();
+        let message_id = message.message_id;
+        tokio::spawn(async move {
+            let text = match run_download(&state, &download_id, request).await {
+                Ok(response) => format!(
+                    "✅ {}\n{}{}",
+                    response.title, bot.public_url, response.playlist_url
+                ),
+                Err(e) => format!("❌ Download failed: {}", e),
+            };
+            bot.edit(chat_id, message_id, status_id, &text).await;
+        });
+    }
+}
@@ -0,0 +1,311 @@
+//! Offline bundles: a track, or every track of a collection, as one zip of the
+//! playlists, segments, artwork and a manifest, so a client can download it and
+//! play without a connection.
+//!
+//! The archive is written while it is sent, so it isn't kept in memory or on disk,
+//! and has no `Content-Length`.
+
+use super::keys::{has_key_access, unauthorized, TokenQuery};
+use super::visibility::{is_visible, Owner};
+use super::AppState;
+use crate::library::{track_info, TrackInfo};
+use crate::lyrics::LYRICS_FILE;
+use crate::playlist_files::{write_m3u, PlaylistEntry};
+use crate::storage::{playlist_segments, Collection, HlsSession};
+use crate::transcode::{thumbnail_file, KEY_FILE, THUMBNAIL_SIZES};
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Name of each track's media playlist in a bundle, and of a collection's playlist
+/// of tracks.
+const PLAYLIST_FILE: &str = "playlist.m3u8";
+
+/// Bytes handed to the response at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks written ahead of a slow client before writing waits for it.
+const BUFFERED_CHUNKS: usize = 4;
+
+/// A track in a bundle's manifest. Paths are relative to the bundle's root.
+#[derive(Debug, Serialize)]
+struct BundledTrack {
+    #[serde(flatten)]
+    info: TrackInfo,
+    playlist: String,
+    /// Decryption key of an encrypted track, which its playlist refers to
+    key_file: Option<String>,
+    /// By size, like `thumbnails`
+    thumbnail_files: Option<BTreeMap<&'static str, String>>,
+    /// Synced lyrics in LRC format
+    lyrics_file: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CollectionManifest<'a> {
+    collection: &'a Collection,
+    /// Playlist of the tracks' playlists, in collection order
+    playlist: &'static str,
+    tracks: Vec<BundledTrack>,
+}
+
+/// A file of the library and its path in the bundle.
+struct BundleFile {
+    name: String,
+    path: PathBuf,
+}
+
+/// The files of a track, under `prefix` in the bundle, and its manifest entry.
+async fn track_files(
+    track_id: &str,
+    session: &HlsSession,
+    prefix: &str,
+) -> io::Result<(BundledTrack, Vec<BundleFile>)> {
+    let dir = &session.segments_dir;
+    let playlist = tokio::fs::read_to_string(&session.playlist_path).await?;
+    let mut files = vec![BundleFile {
+        name: format!("{}{}", prefix, PLAYLIST_FILE),
+        path: session.playlist_path.clone(),
+    }];
+    // Single-file tracks list the same file once per segment
+    let mut seen = HashSet::new();
+    for segment in playlist_segments(&playlist) {
+        if seen.insert(segment.uri) {
+            files.push(BundleFile {
+                name: format!("{}{}", prefix, segment.uri),
+                path: dir.join(segment.uri),
+            });
+        }
+    }
+
+    let key_file = session.encrypted.then(|| {
+        let name = format!("{}{}", prefix, KEY_FILE);
+        files.push(BundleFile {
+            name: name.clone(),
+            path: dir.join(KEY_FILE),
+        });
+        name
+    });
+    let thumbnail_files = session.has_thumbnail.then(|| {
+        THUMBNAIL_SIZES
+            .iter()
+            .map(|(size, _)| {
+                let name = format!("{}{}", prefix, thumbnail_file(size));
+                files.push(BundleFile {
+                    name: name.clone(),
+                    path: dir.join(thumbnail_file(size)),
+                });
+                (*size, name)
+            })
+            .collect()
+    });
+    let lyrics_file = match tokio::fs::try_exists(dir.join(LYRICS_FILE)).await? {
+        true => {
+            let name = format!("{}{}", prefix, LYRICS_FILE);
+            files.push(BundleFile {
+                name: name.clone(),
+                path: dir.join(LYRICS_FILE),
+            });
+            Some(name)
+        }
+        false => None,
+    };
+
+    let track = BundledTrack {
+        info: track_info(track_id, session),
+        playlist: format!("{}{}", prefix, PLAYLIST_FILE),
+        key_file,
+        thumbnail_files,
+        lyrics_file,
+    };
+    Ok((track, files))
+}
+
+/// Sends what the zip writer writes to the response, a chunk at a time.
+struct ChunkSender(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes `files` and then the `generated` ones, by name, into a zip. Segments are
+/// compressed audio already, so every entry is stored as is.
+fn write_bundle(
+    output: impl Write,
+    files: &[BundleFile],
+    generated: &[(&str, Vec<u8>)],
+) -> zip::result::ZipResult<()> {
+    let mut zip = ZipWriter::new_stream(output);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+    for file in files {
+        zip.start_file(file.name.as_str(), options)?;
+        io::copy(&mut std::fs::File::open(&file.path)?, &mut zip)?;
+    }
+    for (name, content) in generated {
+        zip.start_file(*name, options)?;
+        zip.write_all(content)?;
+    }
+    zip.finish()?.into_inner().flush()?;
+    Ok(())
+}
+
+/// Streams a zip of `files` and the `generated` ones as a download named after `name`.
+fn bundle_response(
+    name: &str,
+    files: Vec<BundleFile>,
+    generated: Vec<(&'static str, Vec<u8>)>,
+) -> Response {
+    let (sender, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+    tokio::task::spawn_blocking(move || {
+        let output = BufWriter::with_capacity(CHUNK_SIZE, ChunkSender(sender.clone()));
+        if let Err(e) = write_bundle(output, &files, &generated) {
+            // Ends the response early, so the client sees the archive is incomplete
+            let _ = sender.blocking_send(Err(io::Error::other(e)));
+        }
+    });
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    let disposition = format!(
+        "attachment; filename=\"{}.zip\"",
+        name.replace(['"', '/', '\\'], "_")
+    );
+    (
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+/// A track as a zip for offline playback
+pub(super) async fn track_bundle(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(track_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    // Share links to a private track let it be played, not kept
+    let Some(session) = state
+        .hls_cache
+        .read()
+        .await
+        .get(&track_id)
+        .filter(|session| is_visible(session, owner))
+        .cloned()
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // The bundle holds the key, so it takes what fetching the key takes
+    if session.encrypted && !has_key_access(&state, &headers, &query).await {
+        return unauthorized();
+    }
+    let (track, files) = match track_files(&track_id, &session, "").await {
+        Ok(bundled) => bundled,
+        Err(e) => {
+            eprintln!("Warning: Failed to bundle track {}: {}", track_id, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let manifest = serde_json::to_vec_pretty(&track).unwrap_or_default();
+    bundle_response(&session.title, files, vec![(MANIFEST_FILE, manifest)])
+}
+
+/// A collection's tracks as a zip for offline playback, each in a directory named
+/// after its id
+pub(super) async fn collection_bundle(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(collection_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(collection) = state.collections.read().await.get(&collection_id).cloned() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let key_access = has_key_access(&state, &headers, &query).await;
+    // Tracks deleted since they were added are left out, as are private tracks for
+    // guests and encrypted ones for requests that may not fetch keys
+    let sessions: Vec<(String, HlsSession)> = {
+        let cache = state.hls_cache.read().await;
+        let mut seen = HashSet::new();
+        collection
+            .track_ids
+            .iter()
+            .filter(|track_id| seen.insert(track_id.as_str()))
+            .filter_map(|track_id| Some((track_id.clone(), cache.get(track_id)?.clone())))
+            .filter(|(_, session)| is_visible(session, owner))
+            .filter(|(_, session)| key_access || !session.encrypted)
+            .collect()
+    };
+
+    let mut files = Vec::new();
+    let mut tracks = Vec::with_capacity(sessions.len());
+    for (track_id, session) in &sessions {
+        match track_files(track_id, session, &format!("{}/", track_id)).await {
+            Ok((track, track_files)) => {
+                tracks.push(track);
+                files.extend(track_files);
+            }
+            Err(e) => {
+                eprintln!("Warning: Failed to bundle track {}: {}", track_id, e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    }
+
+    let entries: Vec<PlaylistEntry> = sessions
+        .iter()
+        .zip(&tracks)
+        .map(|((_, session), track)| PlaylistEntry {
+            location: track.playlist.clone(),
+            title: Some(session.title.clone()),
+            duration: Some(session.duration).filter(|duration| *duration > 0.0),
+            creator: session.artist.clone(),
+            album: session.album.clone(),
+            image: None,
+        })
+        .collect();
+    let name = collection
+        .path
+        .rsplit('/')
+        .next()
+        .unwrap_or(&collection.path)
+        .to_string();
+    let manifest = serde_json::to_vec_pretty(&CollectionManifest {
+        collection: &collection,
+        playlist: PLAYLIST_FILE,
+        tracks,
+    })
+    .unwrap_or_default();
+    let generated = vec![
+        (PLAYLIST_FILE, write_m3u(&entries).into_bytes()),
+        (MANIFEST_FILE, manifest),
+    ];
+    bundle_response(&name, files, generated)
+}
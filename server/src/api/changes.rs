@@ -0,0 +1,323 @@
+//! The change feed: tracks and playlists (collections) created, updated or deleted
+//! since a cursor, so clients keep an offline copy in sync without fetching and
+//! comparing the whole library.
+//!
+//! Changes aren't recorded where they're made. When a client asks, the library is
+//! compared with what the feed saw last time, so several changes to one item in
+//! between are a single change.
+
+use super::visibility::{is_visible, visible_collections, Owner};
+use super::{ApiError, AppState, ErrorCode};
+use crate::library::track_info;
+use crate::storage::{Collection, HlsSession};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+/// Changes kept; clients further behind start over from the full library.
+const MAX_CHANGES: usize = 10_000;
+
+/// Changes returned per request unless the request asks for fewer.
+const MAX_PAGE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ChangeKind {
+    Track,
+    Playlist,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ChangeAction {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(Debug, Clone)]
+struct Change {
+    cursor: u64,
+    kind: ChangeKind,
+    id: String,
+    action: ChangeAction,
+}
+
+/// Everything the feed has seen, behind one lock so cursors are handed out in order.
+struct FeedState {
+    /// Cursor of the newest change. Starts at the startup time in milliseconds, so
+    /// cursors from before a restart are older than every change kept.
+    cursor: u64,
+    /// Cursor the kept changes are complete from
+    oldest: u64,
+    changes: VecDeque<Change>,
+    /// Library revision the track fingerprints were taken at
+    revision: u64,
+    tracks: HashMap<String, u64>,
+    playlists: HashMap<String, u64>,
+}
+
+pub(super) struct ChangeFeed {
+    state: Mutex<FeedState>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ChangesQuery {
+    /// Cursor of the last change the client has; without it only the current cursor
+    /// is returned
+    since: Option<u64>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChangeEntry {
+    cursor: u64,
+    #[serde(rename = "type")]
+    kind: ChangeKind,
+    action: ChangeAction,
+    id: String,
+    /// The item as it is now; `None` for deletions
+    data: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChangesResponse {
+    /// Where the next request continues from
+    cursor: u64,
+    /// More changes follow; ask again right away with `cursor`
+    has_more: bool,
+    changes: Vec<ChangeEntry>,
+}
+
+/// Tells apart two versions of an item, by its JSON.
+fn fingerprint(value: &impl Serialize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_vec(value)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+fn track_fingerprints(tracks: &HashMap<String, HlsSession>) -> HashMap<String, u64> {
+    tracks
+        .iter()
+        .map(|(id, session)| (id.clone(), fingerprint(&track_info(id, session))))
+        .collect()
+}
+
+fn playlist_fingerprints(collections: &HashMap<String, Collection>) -> HashMap<String, u64> {
+    collections
+        .iter()
+        .map(|(id, collection)| (id.clone(), fingerprint(collection)))
+        .collect()
+}
+
+impl ChangeFeed {
+    /// A feed starting from the library as it is, `revision` being its revision.
+    pub(super) fn new(
+        tracks: &HashMap<String, HlsSession>,
+        revision: u64,
+        collections: &HashMap<String, Collection>,
+    ) -> Self {
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        ChangeFeed {
+            state: Mutex::new(FeedState {
+                cursor: start,
+                oldest: start,
+                changes: VecDeque::new(),
+                revision,
+                tracks: track_fingerprints(tracks),
+                playlists: playlist_fingerprints(collections),
+            }),
+        }
+    }
+}
+
+impl FeedState {
+    /// Records how `current` differs from what was seen of `kind` last time.
+    fn record(&mut self, kind: ChangeKind, current: HashMap<String, u64>) {
+        let previous = match kind {
+            ChangeKind::Track => std::mem::replace(&mut self.tracks, current),
+            ChangeKind::Playlist => std::mem::replace(&mut self.playlists, current),
+        };
+        let current = match kind {
+            ChangeKind::Track => &self.tracks,
+            ChangeKind::Playlist => &self.playlists,
+        };
+
+        let mut found: Vec<(String, ChangeAction)> = current
+            .iter()
+            .filter_map(|(id, fingerprint)| match previous.get(id) {
+                None => Some((id.clone(), ChangeAction::Created)),
+                Some(seen) if seen != fingerprint => Some((id.clone(), ChangeAction::Updated)),
+                Some(_) => None,
+            })
+            .chain(
+                previous
+                    .keys()
+                    .filter(|id| !current.contains_key(*id))
+                    .map(|id| (id.clone(), ChangeAction::Deleted)),
+            )
+            .collect();
+        found.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        for (id, action) in found {
+            self.cursor += 1;
+            self.changes.push_back(Change {
+                cursor: self.cursor,
+                kind,
+                id,
+                action,
+            });
+        }
+        while self.changes.len() > MAX_CHANGES {
+            if let Some(dropped) = self.changes.pop_front() {
+                self.oldest = dropped.cursor;
+            }
+        }
+    }
+}
+
+/// One change per item since `since`, in the order of their last change: created if
+/// the item is new since then, left out if it was also deleted since.
+fn collapse<'a>(changes: impl Iterator<Item = &'a Change>) -> Vec<Change> {
+    let mut by_item: HashMap<(ChangeKind, &str), (ChangeAction, Change)> = HashMap::new();
+    for change in changes {
+        by_item
+            .entry((change.kind, change.id.as_str()))
+            .and_modify(|(_, last)| *last = change.clone())
+            .or_insert_with(|| (change.action, change.clone()));
+    }
+    let mut collapsed: Vec<Change> = by_item
+        .into_values()
+        .filter_map(|(first, mut last)| {
+            match (first, last.action) {
+                (ChangeAction::Created, ChangeAction::Deleted) => return None,
+                (ChangeAction::Created, _) => last.action = ChangeAction::Created,
+                (_, ChangeAction::Deleted) => {}
+                _ => last.action = ChangeAction::Updated,
+            }
+            Some(last)
+        })
+        .collect();
+    collapsed.sort_by_key(|change| change.cursor);
+    collapsed
+}
+
+/// Tracks and playlists changed since a cursor
+pub(super) async fn list_changes(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Query(query): Query<ChangesQuery>,
+) -> Response {
+    let mut feed = state.changes.state.lock().await;
+    {
+        let cache = state.hls_cache.read().await;
+        let revision = state.hls_cache.revision();
+        if revision != feed.revision {
+            feed.record(ChangeKind::Track, track_fingerprints(&cache));
+            feed.revision = revision;
+        }
+    }
+    {
+        let collections = state.collections.read().await;
+        feed.record(ChangeKind::Playlist, playlist_fingerprints(&collections));
+    }
+
+    let Some(since) = query.since else {
+        return Json(ChangesResponse {
+            cursor: feed.cursor,
+            has_more: false,
+            changes: Vec::new(),
+        })
+        .into_response();
+    };
+    if since < feed.oldest || since > feed.cursor {
+        return ApiError::new(
+            StatusCode::GONE,
+            "The cursor is too old or from before a restart; fetch the library again",
+        )
+        .with_code(ErrorCode::CursorExpired)
+        .into_response();
+    }
+
+    let limit = query.limit.unwrap_or(MAX_PAGE).clamp(1, MAX_PAGE);
+    let mut changes = collapse(feed.changes.iter().filter(|change| change.cursor > since));
+    let has_more = changes.len() > limit;
+    changes.truncate(limit);
+    let cursor = match has_more {
+        true => changes.last().map_or(since, |change| change.cursor),
+        false => feed.cursor,
+    };
+    drop(feed);
+
+    // One lock at a time: handlers changing collections look tracks up meanwhile
+    let mut data: Vec<Option<serde_json::Value>> = vec![None; changes.len()];
+    {
+        let cache = state.hls_cache.read().await;
+        for (change, data) in changes.iter().zip(data.iter_mut()) {
+            if change.kind == ChangeKind::Track && change.action != ChangeAction::Deleted {
+                *data = cache
+                    .get(&change.id)
+                    .filter(|session| is_visible(session, owner))
+                    .and_then(|session| serde_json::to_value(track_info(&change.id, session)).ok());
+            }
+        }
+    }
+    let playlists: Vec<Collection> = {
+        let collections = state.collections.read().await;
+        changes
+            .iter()
+            .filter(|change| {
+                change.kind == ChangeKind::Playlist && change.action != ChangeAction::Deleted
+            })
+            .filter_map(|change| collections.get(&change.id).cloned())
+            .collect()
+    };
+    let playlists: HashMap<String, Collection> = visible_collections(&state, playlists, owner)
+        .await
+        .into_iter()
+        .map(|collection| (collection.id.clone(), collection))
+        .collect();
+    for (change, data) in changes.iter().zip(data.iter_mut()) {
+        if change.kind == ChangeKind::Playlist && change.action != ChangeAction::Deleted {
+            *data = playlists
+                .get(&change.id)
+                .and_then(|collection| serde_json::to_value(collection).ok());
+        }
+    }
+
+    let mut entries = Vec::with_capacity(changes.len());
+    for (change, data) in changes.into_iter().zip(data) {
+        let action = match (change.action, &data) {
+            (ChangeAction::Deleted, _) => ChangeAction::Deleted,
+            // Gone again since, or private: to the client as good as deleted, unless
+            // it never had the item
+            (ChangeAction::Created, None) => continue,
+            (_, None) => ChangeAction::Deleted,
+            (action, Some(_)) => action,
+        };
+        entries.push(ChangeEntry {
+            cursor: change.cursor,
+            kind: change.kind,
+            action,
+            id: change.id,
+            data,
+        });
+    }
+
+    Json(ChangesResponse {
+        cursor,
+        has_more,
+        changes: entries,
+    })
+    .into_response()
+}
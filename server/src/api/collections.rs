@@ -0,0 +1,510 @@
+//! Collection handlers.
+
+use super::batch::{queue_batch, run_batch_job, MAX_BATCH_SIZE};
+use super::visibility::{is_visible, visible_collections, Owner};
+use super::{json_error, AppState};
+use crate::downloader::{DownloadRequest, Priority};
+use crate::library::{build_collection_tree, normalize_collection_path, CollectionNode};
+use crate::playlist_files::{parse_playlist, PlaylistEntry};
+use crate::storage::{
+    generate_url_hash, save_collections, track_with_origin, Collection, HlsSession,
+};
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CollectionRequest {
+    pub(super) path: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct CollectionTrackRequest {
+    pub(super) track_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ImportQuery {
+    /// Path of the collection to create
+    path: String,
+    /// Directory relative local entries are resolved against, usually the one the
+    /// playlist file was in
+    base: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportedCollection {
+    collection: Collection,
+    /// Entries found in the library
+    matched: usize,
+    /// Set when remote entries are being downloaded; they join the collection as
+    /// they become ready
+    batch_id: Option<String>,
+    downloads: usize,
+    /// Entries that are neither in the library nor downloadable
+    unmatched: Vec<String>,
+}
+
+/// What a playlist entry turned out to be.
+enum Resolved {
+    Track(String),
+    Download(Box<DownloadRequest>),
+    Unmatched,
+}
+
+/// How often an import checks on a download that was started by another request.
+const DOWNLOAD_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+pub(super) async fn list_collections(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+) -> Json<Vec<CollectionNode>> {
+    let collections: Vec<Collection> = state.collections.read().await.values().cloned().collect();
+    let collections = visible_collections(&state, collections, owner)
+        .await
+        .into_iter()
+        .map(|collection| (collection.id.clone(), collection))
+        .collect();
+    Json(build_collection_tree(&collections))
+}
+
+/// `collection` as the request may see it.
+async fn visible_collection(state: &AppState, collection: Collection, owner: bool) -> Collection {
+    let mut visible = visible_collections(state, vec![collection], owner).await;
+    visible.remove(0)
+}
+
+pub(super) async fn create_collection(
+    State(state): State<AppState>,
+    Json(request): Json<CollectionRequest>,
+) -> Response {
+    let Some(path) = normalize_collection_path(&request.path) else {
+        return json_error("Collection path must not be empty", StatusCode::BAD_REQUEST);
+    };
+
+    let mut collections = state.collections.write().await;
+    if collections.values().any(|c| c.path == path) {
+        return json_error(
+            &format!("Collection \"{}\" already exists", path),
+            StatusCode::CONFLICT,
+        );
+    }
+
+    let collection = Collection {
+        id: Uuid::new_v4().to_string(),
+        path,
+        track_ids: Vec::new(),
+    };
+    collections.insert(collection.id.clone(), collection.clone());
+
+    if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+        eprintln!("Warning: Failed to save collections: {}", e);
+    }
+
+    (StatusCode::CREATED, Json(collection)).into_response()
+}
+
+pub(super) async fn rename_collection(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(collection_id): Path<String>,
+    Json(request): Json<CollectionRequest>,
+) -> Result<Response, StatusCode> {
+    let Some(path) = normalize_collection_path(&request.path) else {
+        return Ok(json_error(
+            "Collection path must not be empty",
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+
+    let mut collections = state.collections.write().await;
+    if collections
+        .values()
+        .any(|c| c.path == path && c.id != collection_id)
+    {
+        return Ok(json_error(
+            &format!("Collection \"{}\" already exists", path),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    let collection = match collections.get_mut(&collection_id) {
+        Some(collection) => {
+            collection.path = path;
+            collection.clone()
+        }
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+
+    if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+        eprintln!("Warning: Failed to save collections: {}", e);
+    }
+    drop(collections);
+
+    Ok(Json(visible_collection(&state, collection, owner).await).into_response())
+}
+
+pub(super) async fn delete_collection(
+    State(state): State<AppState>,
+    Path(collection_id): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let mut collections = state.collections.write().await;
+    let Some(collection) = collections.remove(&collection_id) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+        eprintln!("Warning: Failed to save collections: {}", e);
+    }
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "message": format!("Collection '{}' deleted", collection.path)
+    })))
+}
+
+pub(super) async fn add_track_to_collection(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(collection_id): Path<String>,
+    Json(request): Json<CollectionTrackRequest>,
+) -> Result<Json<Collection>, StatusCode> {
+    let track_exists = {
+        let cache = state.hls_cache.read().await;
+        cache
+            .get(&request.track_id)
+            .is_some_and(|session| is_visible(session, owner))
+    };
+    if !track_exists {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let mut collections = state.collections.write().await;
+    let collection = match collections.get_mut(&collection_id) {
+        Some(collection) => {
+            if !collection.track_ids.contains(&request.track_id) {
+                collection.track_ids.push(request.track_id);
+            }
+            collection.clone()
+        }
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+
+    if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+        eprintln!("Warning: Failed to save collections: {}", e);
+    }
+    drop(collections);
+
+    Ok(Json(visible_collection(&state, collection, owner).await))
+}
+
+pub(super) async fn remove_track_from_collection(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path((collection_id, track_id)): Path<(String, String)>,
+) -> Result<Json<Collection>, StatusCode> {
+    let mut collections = state.collections.write().await;
+    let collection = match collections.get_mut(&collection_id) {
+        Some(collection) => {
+            collection.track_ids.retain(|id| *id != track_id);
+            collection.clone()
+        }
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+
+    if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+        eprintln!("Warning: Failed to save collections: {}", e);
+    }
+    drop(collections);
+
+    Ok(Json(visible_collection(&state, collection, owner).await))
+}
+
+/// Creates a collection from an uploaded M3U or PLS playlist. Local entries are matched
+/// against imported tracks, and remote URLs are taken from the library or downloaded
+/// in a batch, joining the collection in playlist order as they become ready.
+pub(super) async fn import_collection(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Query(query): Query<ImportQuery>,
+    body: Bytes,
+) -> Response {
+    let Some(path) = normalize_collection_path(&query.path) else {
+        return json_error("Collection path must not be empty", StatusCode::BAD_REQUEST);
+    };
+    // Older players write playlists in Latin-1, which only garbles some titles
+    let entries = parse_playlist(&String::from_utf8_lossy(&body));
+    if entries.is_empty() {
+        return json_error("The playlist has no entries", StatusCode::BAD_REQUEST);
+    }
+
+    let resolved: Vec<(String, Resolved)> = {
+        let cache = state.hls_cache.read().await;
+        let names = import_names(&cache);
+        entries
+            .into_iter()
+            .map(|entry| {
+                let resolved = resolve_entry(&entry, query.base.as_deref(), &cache, &names);
+                (entry.location, resolved)
+            })
+            .collect()
+    };
+    let downloads = resolved
+        .iter()
+        .filter(|(_, resolved)| matches!(resolved, Resolved::Download(_)))
+        .count();
+    if downloads > MAX_BATCH_SIZE {
+        return json_error(
+            &format!(
+                "Playlist must not contain more than {} URLs that still need downloading",
+                MAX_BATCH_SIZE
+            ),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    // The order every track should end up in, including those still to be downloaded
+    let mut planned: Vec<String> = Vec::new();
+    let mut track_ids: Vec<String> = Vec::new();
+    let mut requests = Vec::new();
+    let mut unmatched = Vec::new();
+    for (location, resolved) in resolved {
+        match resolved {
+            Resolved::Track(track_id) => {
+                if !track_ids.contains(&track_id) {
+                    track_ids.push(track_id.clone());
+                    planned.push(track_id);
+                }
+            }
+            Resolved::Download(request) => {
+                planned.push(generate_url_hash(&request.url));
+                requests.push(*request);
+            }
+            Resolved::Unmatched => unmatched.push(location),
+        }
+    }
+    let matched = track_ids.len();
+
+    let collection = {
+        let mut collections = state.collections.write().await;
+        if collections.values().any(|c| c.path == path) {
+            return json_error(
+                &format!("Collection \"{}\" already exists", path),
+                StatusCode::CONFLICT,
+            );
+        }
+        let collection = Collection {
+            id: Uuid::new_v4().to_string(),
+            path,
+            track_ids,
+        };
+        collections.insert(collection.id.clone(), collection.clone());
+        if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+            eprintln!("Warning: Failed to save collections: {}", e);
+        }
+        collection
+    };
+
+    let batch_id = if requests.is_empty() {
+        None
+    } else {
+        Some(download_into_collection(&state, collection.id.clone(), planned, requests).await)
+    };
+    let collection = visible_collection(&state, collection, owner).await;
+
+    (
+        StatusCode::CREATED,
+        Json(ImportedCollection {
+            collection,
+            matched,
+            batch_id,
+            downloads,
+            unmatched,
+        }),
+    )
+        .into_response()
+}
+
+/// Imported tracks by lowercase title and "artist - title", as players name entries;
+/// `None` where the name is ambiguous.
+fn import_names(cache: &HashMap<String, HlsSession>) -> HashMap<String, Option<String>> {
+    let mut names: HashMap<String, Option<String>> = HashMap::new();
+    // Imports are the tracks without an origin URL
+    for (track_id, session) in cache.iter().filter(|(_, s)| s.origin_url.is_empty()) {
+        let mut keys = vec![session.title.to_lowercase()];
+        if let Some(artist) = &session.artist {
+            keys.push(format!("{} - {}", artist, session.title).to_lowercase());
+        }
+        for key in keys {
+            names
+                .entry(key)
+                .and_modify(|found| {
+                    if found.as_ref() != Some(track_id) {
+                        *found = None;
+                    }
+                })
+                .or_insert_with(|| Some(track_id.clone()));
+        }
+    }
+    names
+}
+
+fn resolve_entry(
+    entry: &PlaylistEntry,
+    base: Option<&std::path::Path>,
+    cache: &HashMap<String, HlsSession>,
+    names: &HashMap<String, Option<String>>,
+) -> Resolved {
+    let location = entry.location.as_str();
+    if location.starts_with("http://") || location.starts_with("https://") {
+        if let Some((track_id, _)) = cache.iter().find(|(_, s)| s.origin_url == location) {
+            return Resolved::Track(track_id.clone());
+        }
+        // "Artist - Title" is how most players label an entry
+        let (artist, title) = match entry.title.as_deref().map(|t| t.split_once(" - ")) {
+            Some(Some((artist, title))) => (Some(artist.to_string()), Some(title.to_string())),
+            Some(None) => (None, entry.title.clone()),
+            None => (None, None),
+        };
+        return Resolved::Download(Box::new(DownloadRequest {
+            url: location.to_string(),
+            title,
+            artist,
+            album: None,
+            split_chapters: false,
+            video: false,
+            // A playlist backfill shouldn't hold up tracks someone wants to play now
+            priority: Priority::Low,
+            limit_rate: None,
+            extractor_args: Vec::new(),
+            format: None,
+            refresh: None,
+        }));
+    }
+
+    // Imports are recognised by their file's location, so a path that still points
+    // at the imported file finds it directly
+    let path = match location.strip_prefix("file://") {
+        Some(_) => reqwest::Url::parse(location)
+            .ok()
+            .and_then(|url| url.to_file_path().ok()),
+        None => Some(PathBuf::from(location)),
+    };
+    if let Some(path) = path.map(|path| match base {
+        Some(base) if path.is_relative() => base.join(path),
+        _ => path,
+    }) {
+        let path = std::fs::canonicalize(&path).unwrap_or(path);
+        let origin_hash = generate_url_hash(&format!("file://{}", path.display()));
+        if let Some(track_id) = track_with_origin(cache, &origin_hash) {
+            return Resolved::Track(track_id);
+        }
+    }
+
+    // Otherwise by name: imports are titled after their file unless tagged
+    let stem = location
+        .rsplit(['/', '\\'])
+        .next()
+        .map(|name| name.rsplit_once('.').map_or(name, |(stem, _)| stem));
+    entry
+        .title
+        .iter()
+        .map(String::as_str)
+        .chain(stem)
+        .find_map(|name| names.get(&name.to_lowercase()).cloned().flatten())
+        .map_or(Resolved::Unmatched, Resolved::Track)
+}
+
+/// Downloads `requests` in a batch and adds each track to the collection once it is
+/// ready, in its place among the `planned` track ids. Returns the batch id.
+pub(super) async fn download_into_collection(
+    state: &AppState,
+    collection_id: String,
+    planned: Vec<String>,
+    requests: Vec<DownloadRequest>,
+) -> String {
+    let (batch_id, jobs, queued) = queue_batch(state, requests).await;
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut queued: HashMap<String, DownloadRequest> = queued.into_iter().collect();
+        for (download_id, url) in jobs {
+            match queued.remove(&download_id) {
+                Some(request) => {
+                    if run_batch_job(&state, &download_id, request).await.is_err() {
+                        continue;
+                    }
+                }
+                None => wait_for_download(&state, &download_id).await,
+            }
+            add_planned_track(&state, &collection_id, &planned, &url).await;
+        }
+    });
+    batch_id
+}
+
+/// Waits until a download job has finished, or is gone.
+async fn wait_for_download(state: &AppState, download_id: &str) {
+    loop {
+        let running = state
+            .download_queue
+            .read()
+            .await
+            .get(download_id)
+            .is_some_and(|job| job.status != "ready" && job.status != "error");
+        if !running {
+            return;
+        }
+        tokio::time::sleep(DOWNLOAD_POLL_INTERVAL).await;
+    }
+}
+
+/// Adds the track downloaded from `url` to the collection, after the planned tracks
+/// before it that are already there. Tracks still to be downloaded are planned by
+/// the hash of their URL, as their id isn't known yet.
+async fn add_planned_track(state: &AppState, collection_id: &str, planned: &[String], url: &str) {
+    let origin_hash = generate_url_hash(url);
+    let (track_id, planned_ids) = {
+        let cache = state.hls_cache.read().await;
+        let Some(track_id) = track_with_origin(&cache, &origin_hash) else {
+            return;
+        };
+        let planned_ids: Vec<Option<String>> = planned
+            .iter()
+            .map(|id| match cache.contains_key(id) {
+                true => Some(id.clone()),
+                false => track_with_origin(&cache, id),
+            })
+            .collect();
+        (track_id, planned_ids)
+    };
+
+    let mut collections = state.collections.write().await;
+    let Some(collection) = collections.get_mut(collection_id) else {
+        return;
+    };
+    if collection.track_ids.contains(&track_id) {
+        return;
+    }
+    let index = planned
+        .iter()
+        .position(|id| *id == origin_hash)
+        .unwrap_or(planned.len());
+    let position = planned_ids[..index]
+        .iter()
+        .rev()
+        .flatten()
+        .find_map(|id| collection.track_ids.iter().position(|t| t == id))
+        .map_or(0, |position| position + 1);
+    collection.track_ids.insert(position, track_id);
+
+    if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+        eprintln!("Warning: Failed to save collections: {}", e);
+    }
+}
@@ -0,0 +1,119 @@
+//! Accept-Encoding negotiation and response compression.
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, response::Builder, HeaderValue, Response};
+use std::io::Write;
+
+/// Bodies smaller than this aren't worth the compression overhead.
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Picks the preferred encoding the client accepts, favouring brotli over gzip.
+pub(super) fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let accepted: Vec<(&str, f32)> = accept_encoding?
+        .split(',')
+        .map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (name, quality)
+        })
+        .collect();
+
+    let quality = |name: &str| {
+        accepted
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .or_else(|| accepted.iter().find(|(n, _)| *n == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    };
+
+    [Encoding::Brotli, Encoding::Gzip]
+        .into_iter()
+        .map(|encoding| (encoding, quality(encoding.name())))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(
+            None,
+            |best: Option<(Encoding, f32)>, (encoding, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((encoding, q)),
+            },
+        )
+        .map(|(encoding, _)| encoding)
+}
+
+/// Whether a body of `len` bytes is large enough to be worth compressing.
+pub(super) fn worth_compressing(len: usize) -> bool {
+    len >= MIN_COMPRESS_SIZE
+}
+
+fn encode(data: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                writer.write_all(data)?;
+            }
+            Ok(out)
+        }
+        Encoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Finishes `builder` with `data`, compressed with `encoding` when that is worthwhile.
+/// Always sets `Vary: Accept-Encoding` since the representation depends on it.
+pub(super) fn compressed_body(
+    builder: Builder,
+    data: Bytes,
+    encoding: Option<Encoding>,
+) -> Response<Body> {
+    let builder = builder.header(header::VARY, "accept-encoding");
+    let compressed = encoding
+        .filter(|_| worth_compressing(data.len()))
+        .and_then(|encoding| Some((encoding, encode(&data, encoding).ok()?)));
+
+    let response = match compressed {
+        Some((encoding, body)) => builder
+            .header(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.name()),
+            )
+            .body(Body::from(body)),
+        None => builder.body(Body::from(data)),
+    };
+    response.expect("valid response headers")
+}
+
+/// Serializes `value` as a JSON response, compressed according to `accept_encoding`.
+pub(super) fn compressed_json<T: serde::Serialize>(
+    value: &T,
+    accept_encoding: Option<&str>,
+) -> Response<Body> {
+    let data = Bytes::from(serde_json::to_vec(value).unwrap_or_default());
+    let builder = Response::builder().header(header::CONTENT_TYPE, "application/json");
+    compressed_body(builder, data, negotiate(accept_encoding))
+}
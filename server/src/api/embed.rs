@@ -0,0 +1,232 @@
+//! Embeddable mini-player pages for single tracks, and the oEmbed endpoint that
+//! turns links to them into a player in Discord, Mastodon and blogs.
+
+use super::export::request_origin;
+use super::hls::encode_query_value;
+use super::{json_error, AppState};
+use crate::library::thumbnail_url;
+use crate::playlist_files::escape_xml;
+use crate::storage::{HlsSession, Visibility};
+use crate::transcode::thumbnail_file;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Player page template; `{{name}}` placeholders are filled in per track.
+const EMBED_PAGE: &str = include_str!("../../assets/embed.html");
+
+const PROVIDER_NAME: &str = "Music Library";
+
+/// Size the player is embedded at unless the consumer asks for less.
+const EMBED_WIDTH: u32 = 480;
+const EMBED_HEIGHT: u32 = 152;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct OEmbedQuery {
+    url: String,
+    format: Option<String>,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+}
+
+/// The track a key names, by id or slug. Private tracks are never embedded, as the
+/// page is meant to be seen by anyone.
+fn find_track<'a>(
+    state: &AppState,
+    cache: &'a HashMap<String, HlsSession>,
+    key: &str,
+) -> Option<(&'a String, &'a HlsSession)> {
+    cache
+        .get_key_value(key)
+        .or_else(|| cache.iter().find(|(_, s)| s.slug == key))
+        .filter(|(_, s)| state.owner_token.is_none() || s.visibility == Visibility::Public)
+}
+
+/// "Artist – Title", or the title alone.
+fn heading(session: &HlsSession) -> String {
+    match &session.artist {
+        Some(artist) => format!("{} – {}", artist, session.title),
+        None => session.title.clone(),
+    }
+}
+
+/// Replaces every `{{name}}` in `template` with its value, in one pass so values
+/// that look like placeholders stay as they are.
+fn fill_template(template: &str, values: &[(&str, String)]) -> String {
+    let mut page = String::with_capacity(template.len() + 1024);
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        page.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        match values.iter().find(|(name, _)| *name == &after[..end]) {
+            Some((_, value)) => page.push_str(value),
+            None => page.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    page.push_str(rest);
+    page
+}
+
+/// Width and height of a JPEG, from its first start-of-frame segment.
+fn jpeg_size(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut i = 2;
+    while i + 9 < data.len() {
+        if data[i] != 0xFF {
+            return None;
+        }
+        let marker = data[i + 1];
+        // SOF0 to SOF15, except DHT, JPG and DAC, which share the range
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let height = u16::from_be_bytes([data[i + 5], data[i + 6]]);
+            let width = u16::from_be_bytes([data[i + 7], data[i + 8]]);
+            return Some((width as u32, height as u32));
+        }
+        let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        i += 2 + len;
+    }
+    None
+}
+
+/// A track's mini-player: artwork, title and an HLS player, with the Open Graph and
+/// oEmbed tags link previews look for.
+pub(super) async fn embed_page(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let origin = request_origin(&headers);
+    let cache = state.hls_cache.read().await;
+    let Some((track_id, session)) = find_track(&state, &cache, &key) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let embed_url = format!("{}/embed/{}", origin, track_id);
+    let oembed_url = format!(
+        "{}/api/oembed?url={}&format=json",
+        origin,
+        encode_query_value(&embed_url)
+    );
+    let (artwork, image_meta) = match session.has_thumbnail {
+        true => (
+            format!(
+                "<img src=\"{}\" alt=\"\">",
+                escape_xml(&format!("{}{}", origin, thumbnail_url(session, "medium")))
+            ),
+            format!(
+                "<meta property=\"og:image\" content=\"{}\">",
+                escape_xml(&format!("{}{}", origin, thumbnail_url(session, "large")))
+            ),
+        ),
+        false => (String::new(), String::new()),
+    };
+    let page = fill_template(
+        EMBED_PAGE,
+        &[
+            ("heading", escape_xml(&heading(session))),
+            ("title", escape_xml(&session.title)),
+            (
+                "artist",
+                escape_xml(session.artist.as_deref().unwrap_or_default()),
+            ),
+            ("embed_url", escape_xml(&embed_url)),
+            ("oembed_url", escape_xml(&oembed_url)),
+            (
+                "playlist_url",
+                escape_xml(&format!("{}/api/hls/{}/master.m3u8", origin, session.id)),
+            ),
+            ("artwork", artwork),
+            ("image_meta", image_meta),
+            ("width", EMBED_WIDTH.to_string()),
+            ("height", EMBED_HEIGHT.to_string()),
+        ],
+    );
+    Html(page).into_response()
+}
+
+/// oEmbed for links to a track's embed page or short link (`/embed/{id}`,
+/// `/t/{slug}`): a `rich` response with the player in an iframe.
+pub(super) async fn oembed(
+    State(state): State<AppState>,
+    Query(query): Query<OEmbedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if query
+        .format
+        .as_deref()
+        .is_some_and(|format| format != "json")
+    {
+        return json_error(
+            "Only the json format is supported",
+            StatusCode::NOT_IMPLEMENTED,
+        );
+    }
+    let Ok(url) = reqwest::Url::parse(&query.url) else {
+        return json_error("url is not a valid URL", StatusCode::BAD_REQUEST);
+    };
+    let segments: Option<Vec<&str>> = url.path_segments().map(Iterator::collect);
+    let key = match segments.as_deref() {
+        // Under `/libraries/{name}` when the server hosts several libraries
+        Some([.., "embed" | "t", key]) => key.to_string(),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let origin = request_origin(&headers);
+    let (track_id, session) = {
+        let cache = state.hls_cache.read().await;
+        match find_track(&state, &cache, &key) {
+            Some((track_id, session)) => (track_id.clone(), session.clone()),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        }
+    };
+
+    let width = query
+        .maxwidth
+        .map_or(EMBED_WIDTH, |max| max.min(EMBED_WIDTH));
+    let height = query
+        .maxheight
+        .map_or(EMBED_HEIGHT, |max| max.min(EMBED_HEIGHT));
+    let html = format!(
+        "<iframe src=\"{}\" width=\"{}\" height=\"{}\" frameborder=\"0\" allow=\"autoplay; encrypted-media\" title=\"{}\"></iframe>",
+        escape_xml(&format!("{}/embed/{}", origin, track_id)),
+        width,
+        height,
+        escape_xml(&heading(&session))
+    );
+    let mut body = serde_json::json!({
+        "version": "1.0",
+        "type": "rich",
+        "provider_name": PROVIDER_NAME,
+        "provider_url": origin,
+        "title": session.title,
+        "html": html,
+        "width": width,
+        "height": height,
+    });
+    if let Some(artist) = &session.artist {
+        body["author_name"] = artist.clone().into();
+    }
+    // Thumbnails keep the source's aspect ratio, which oEmbed wants spelled out
+    if session.has_thumbnail {
+        let thumbnail = tokio::fs::read(session.segments_dir.join(thumbnail_file("large"))).await;
+        if let Some((thumbnail_width, thumbnail_height)) =
+            thumbnail.ok().and_then(|data| jpeg_size(&data))
+        {
+            body["thumbnail_url"] =
+                format!("{}{}", origin, thumbnail_url(&session, "large")).into();
+            body["thumbnail_width"] = thumbnail_width.into();
+            body["thumbnail_height"] = thumbnail_height.into();
+        }
+    }
+    Json(body).into_response()
+}
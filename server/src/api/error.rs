@@ -0,0 +1,172 @@
+//! Structured `{code, message, details}` error bodies shared by every route.
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, HeaderValue, Response, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Serialize;
+
+/// Rejection bodies from axum extractors are short; anything longer is not worth relaying.
+const MAX_REJECTION_BODY: usize = 16 * 1024;
+
+/// What went wrong, for clients to branch on instead of the message. Errors without a
+/// code of their own get the generic one of their status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    Gone,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    UnprocessableEntity,
+    TooManyRequests,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    InsufficientStorage,
+    /// A status without a generic code of its own
+    Error,
+    /// The server runs with `--readonly`
+    ReadonlyMode,
+    /// The track belongs to a read-only overlay library
+    ReadOnlyTrack,
+    /// `--allow-domain` or `--block-domain` refuses the URL
+    UrlNotAllowed,
+    /// A download of the URL is running already
+    AlreadyDownloading,
+    /// The URL is in the library already
+    DuplicateTrack,
+    /// The library is at `--max-tracks`
+    QuotaExceeded,
+    /// The source is over `--max-filesize` or `--max-duration`
+    SourceTooLarge,
+    /// The download ran past `--download-timeout`
+    DownloadTimedOut,
+    /// The source is gone, private or blocked
+    SourceUnavailable,
+    /// The download failed for another reason, possibly a passing one
+    DownloadFailed,
+    /// ffmpeg couldn't convert the audio
+    TranscodeFailed,
+    /// The `Idempotency-Key` was sent before with a different request
+    IdempotencyKeyReused,
+    /// The change feed cursor is older than the changes kept, or from before a restart
+    CursorExpired,
+}
+
+impl ErrorCode {
+    /// The generic code of an error status.
+    fn of_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => ErrorCode::BadRequest,
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::GONE => ErrorCode::Gone,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::UnsupportedMediaType,
+            StatusCode::RANGE_NOT_SATISFIABLE => ErrorCode::RangeNotSatisfiable,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorCode::UnprocessableEntity,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::TooManyRequests,
+            StatusCode::INTERNAL_SERVER_ERROR => ErrorCode::InternalServerError,
+            StatusCode::NOT_IMPLEMENTED => ErrorCode::NotImplemented,
+            StatusCode::BAD_GATEWAY => ErrorCode::BadGateway,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::ServiceUnavailable,
+            StatusCode::GATEWAY_TIMEOUT => ErrorCode::GatewayTimeout,
+            StatusCode::INSUFFICIENT_STORAGE => ErrorCode::InsufficientStorage,
+            _ => ErrorCode::Error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct ApiError {
+    code: ErrorCode,
+    message: String,
+    details: Option<serde_json::Value>,
+    #[serde(skip)]
+    status: StatusCode,
+}
+
+impl ApiError {
+    pub(super) fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code: ErrorCode::of_status(status),
+            message: message.into(),
+            details: None,
+            status,
+        }
+    }
+
+    pub(super) fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Replaces the status's generic code with a more specific one.
+    pub(super) fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = code;
+        self
+    }
+
+    pub(super) fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response<Body> {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+pub(super) fn json_error(message: &str, status: StatusCode) -> Response<Body> {
+    ApiError::new(status, message).into_response()
+}
+
+/// Rewrites error responses that don't already carry JSON, such as bare status
+/// codes returned by handlers, unmatched routes and extractor rejections.
+pub(super) async fn structured_errors(response: Response<Body>) -> Response<Body> {
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !(status.is_client_error() || status.is_server_error()) || is_json {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let text = to_bytes(body, MAX_REJECTION_BODY)
+        .await
+        .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string())
+        .unwrap_or_default();
+    let message = if text.is_empty() {
+        status.canonical_reason().unwrap_or("Error").to_string()
+    } else {
+        text
+    };
+
+    let mut error = ApiError::new(status, message).into_response();
+    // Keep headers such as Allow on 405 responses
+    for (name, value) in parts.headers.iter() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            error.headers_mut().insert(name, value.clone());
+        }
+    }
+    error.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    error
+}
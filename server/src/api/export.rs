@@ -0,0 +1,203 @@
+//! Collections and the whole library as playlist files for other players.
+
+use super::hls::encode_query_value;
+use super::keys::{uri_token, TokenQuery};
+use super::libraries::PREFIX_HEADER;
+use super::visibility::{is_visible, private_uri_token, Owner};
+use super::{header_str, AppState};
+use crate::library::thumbnail_url;
+use crate::playlist_files::{write_m3u, write_xspf, PlaylistEntry};
+use crate::storage::HlsSession;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    M3u,
+    Xspf,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::M3u => "m3u8",
+            Format::Xspf => "xspf",
+        }
+    }
+
+    /// `.m3u8` playlists are M3U in UTF-8.
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::M3u => "audio/x-mpegurl; charset=utf-8",
+            Format::Xspf => "application/xspf+xml",
+        }
+    }
+
+    fn response(self, name: &str, entries: &[PlaylistEntry]) -> Response {
+        let body = match self {
+            Format::M3u => write_m3u(entries),
+            Format::Xspf => write_xspf(name, entries),
+        };
+        let disposition = format!(
+            "inline; filename=\"{}.{}\"",
+            name.replace(['"', '/', '\\'], "_"),
+            self.extension()
+        );
+        (
+            [
+                (header::CONTENT_TYPE, self.content_type().to_string()),
+                (header::CONTENT_DISPOSITION, disposition),
+            ],
+            body,
+        )
+            .into_response()
+    }
+}
+
+/// Where the client reached the server; external players need absolute URLs.
+/// A reverse proxy's `X-Forwarded-Proto` and `X-Forwarded-Host` take precedence, and
+/// `X-Forwarded-Prefix` names the path the library is served under.
+pub(super) fn request_origin(headers: &HeaderMap) -> String {
+    let host = header_str(headers, "x-forwarded-host")
+        .or_else(|| header_str(headers, header::HOST.as_str()))
+        .unwrap_or("localhost");
+    let scheme = header_str(headers, "x-forwarded-proto").unwrap_or("http");
+    let prefix = header_str(headers, PREFIX_HEADER).unwrap_or_default();
+    format!("{}://{}{}", scheme, host, prefix.trim_end_matches('/'))
+}
+
+/// A track as a playlist entry pointing at its HLS playlist.
+fn track_entry(session: &HlsSession, origin: &str, token: Option<&str>) -> PlaylistEntry {
+    let mut location = format!("{}/api/hls/{}/playlist.m3u8", origin, session.id);
+    if let Some(token) = token {
+        location.push_str(&format!("?token={}", encode_query_value(token)));
+    }
+    PlaylistEntry {
+        location,
+        title: Some(session.title.clone()),
+        duration: Some(session.duration).filter(|duration| *duration > 0.0),
+        creator: session.artist.clone(),
+        album: session.album.clone(),
+        image: session
+            .has_thumbnail
+            .then(|| format!("{}{}", origin, thumbnail_url(session, "large"))),
+    }
+}
+
+/// The whole library, by artist, album and title.
+async fn export_library(
+    state: &AppState,
+    owner: bool,
+    query: &TokenQuery,
+    headers: &HeaderMap,
+    format: Format,
+) -> Response {
+    let origin = request_origin(headers);
+    let token = uri_token(state, headers, query).await;
+    let cache = state.hls_cache.read().await;
+    let mut sessions: Vec<&HlsSession> = cache
+        .values()
+        .filter(|session| is_visible(session, owner))
+        .collect();
+    sessions.sort_by_cached_key(|session| {
+        (
+            session.artist.as_deref().unwrap_or_default().to_lowercase(),
+            session.album.as_deref().unwrap_or_default().to_lowercase(),
+            session.title.to_lowercase(),
+        )
+    });
+    let entries: Vec<PlaylistEntry> = sessions
+        .into_iter()
+        .map(|session| {
+            let token = token.or(private_uri_token(state, session, query));
+            track_entry(session, &origin, token)
+        })
+        .collect();
+    format.response("Library", &entries)
+}
+
+/// A collection's tracks, in collection order.
+async fn export_collection(
+    state: &AppState,
+    owner: bool,
+    collection_id: &str,
+    query: &TokenQuery,
+    headers: &HeaderMap,
+    format: Format,
+) -> Result<Response, StatusCode> {
+    let collection = {
+        let collections = state.collections.read().await;
+        collections
+            .get(collection_id)
+            .cloned()
+            .ok_or(StatusCode::NOT_FOUND)?
+    };
+    let origin = request_origin(headers);
+    let token = uri_token(state, headers, query).await;
+    let cache = state.hls_cache.read().await;
+    // Tracks deleted since they were added are left out, as are private tracks for
+    // guests
+    let entries: Vec<PlaylistEntry> = collection
+        .track_ids
+        .iter()
+        .filter_map(|track_id| cache.get(track_id))
+        .filter(|session| is_visible(session, owner))
+        .map(|session| {
+            let token = token.or(private_uri_token(state, session, query));
+            track_entry(session, &origin, token)
+        })
+        .collect();
+    let name = collection
+        .path
+        .rsplit('/')
+        .next()
+        .unwrap_or(&collection.path);
+    Ok(format.response(name, &entries))
+}
+
+pub(super) async fn export_library_m3u(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    export_library(&state, owner, &query, &headers, Format::M3u).await
+}
+
+pub(super) async fn export_library_xspf(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    export_library(&state, owner, &query, &headers, Format::Xspf).await
+}
+
+pub(super) async fn export_collection_m3u(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(collection_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    export_collection(&state, owner, &collection_id, &query, &headers, Format::M3u).await
+}
+
+pub(super) async fn export_collection_xspf(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(collection_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    export_collection(
+        &state,
+        owner,
+        &collection_id,
+        &query,
+        &headers,
+        Format::Xspf,
+    )
+    .await
+}
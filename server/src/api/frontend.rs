@@ -0,0 +1,30 @@
+//! Frontend serving: the bundled web UI or a user supplied single page app.
+
+use super::AppState;
+use axum::http::StatusCode;
+use axum::response::Html;
+use axum::routing::{any, get};
+use axum::Router;
+use std::path::PathBuf;
+use tower_http::services::{ServeDir, ServeFile};
+
+/// Minimal player/library page bundled into the binary and served at `/`.
+const WEB_UI: &str = include_str!("../../assets/index.html");
+
+/// Adds the frontend to `router`. With `static_dir` set, files are served from it and any
+/// other non-API `GET` falls back to its `index.html` so client-side routing works;
+/// otherwise the bundled UI is served at `/`.
+pub(super) fn with_frontend(
+    router: Router<AppState>,
+    static_dir: Option<PathBuf>,
+) -> Router<AppState> {
+    let Some(dir) = static_dir else {
+        return router.route("/", get(|| async { Html(WEB_UI) }));
+    };
+
+    let index = dir.join("index.html");
+    router
+        .route("/api", any(|| async { StatusCode::NOT_FOUND }))
+        .route("/api/{*rest}", any(|| async { StatusCode::NOT_FOUND }))
+        .fallback_service(ServeDir::new(dir).fallback(ServeFile::new(index)))
+}
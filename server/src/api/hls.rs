@@ -0,0 +1,1264 @@
+//! HLS playlist and segment handlers.
+
+use super::compression::{compressed_body, negotiate, worth_compressing};
+use super::keys::{has_key_access, presented_token, unauthorized, uri_token, TokenQuery};
+use super::shares::{share_access, share_key_token};
+use super::visibility::{private_uri_token, reaches_track};
+use super::{header_str, json_error, AppState, ClientIp, DeviceId};
+use crate::config::HlsProfile;
+use crate::content_hash::{hashed_name, split_hashed_name};
+use crate::federation::{fetch_upstream_file, Upstream, SYNC_HEADER};
+use crate::id3::first_pts;
+use crate::library::track_duration;
+use crate::lyrics::{parse_lrc, to_webvtt, vtt_time, LYRICS_FILE};
+use crate::storage::{
+    append_history, is_safe_path_component, playlist_segments, segment_durations, unix_timestamp,
+    AudioCodec, Chapter, PlayEvent, SECONDS_PER_DAY,
+};
+use crate::transcode::{thumbnail_file, KEY_FILE, THUMBNAIL_SIZES, VIDEO_PLAYLIST};
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, HeaderMap, Response, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+/// Optional anonymous client identifier used to count unique listeners.
+const CLIENT_ID_HEADER: &str = "x-client-id";
+
+const PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+const SEGMENT_CONTENT_TYPE: &str = "video/mp2t";
+const THUMBNAIL_CONTENT_TYPE: &str = "image/jpeg";
+const KEY_CONTENT_TYPE: &str = "application/octet-stream";
+const LYRICS_CONTENT_TYPE: &str = "text/vtt; charset=utf-8";
+const CHAPTERS_JSON_CONTENT_TYPE: &str = "application/json";
+
+/// `DATA-ID` under which AVPlayer looks for chapters in a master playlist.
+const APPLE_CHAPTERS_DATA_ID: &str = "com.apple.hls.chapters";
+
+/// Where ffmpeg's MPEG-TS muxer starts a track's timestamps (1.4 s), assumed for
+/// lyrics when the first segment can't be read.
+const FFMPEG_START_PTS: u64 = 126_000;
+
+/// Answer for playlists of a track deleted since the player loaded it.
+fn track_removed() -> Response<Body> {
+    json_error("Track was removed", StatusCode::GONE)
+}
+
+/// Playlists may be rewritten, so clients must revalidate them on every use.
+const PLAYLIST_CACHE_CONTROL: &str = "public, no-cache";
+/// Segment files never change once written; a re-ingested track gets a new session id.
+const SEGMENT_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+/// Keys must not end up in shared caches next to the segments they unlock.
+const KEY_CACHE_CONTROL: &str = "private, no-store";
+
+/// Percent-encodes everything but RFC 3986 unreserved characters.
+pub(super) fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Applies the HLS profile to a media playlist and passes a valid `?token=` of the
+/// playlist request on to the key URI, so native players, which can't add headers,
+/// can fetch the key.
+fn rewrite_playlist(state: &AppState, content: String, token: Option<&str>) -> String {
+    let content = profile_playlist(content, state.hls_profile);
+    match token {
+        Some(token) => content.replace(
+            &format!("URI=\"{}\"", KEY_FILE),
+            &format!("URI=\"{}?token={}\"", KEY_FILE, encode_query_value(token)),
+        ),
+        None => content,
+    }
+}
+
+/// Adds `?token=` to every segment URI of a media playlist.
+fn token_segment_uris(content: String, token: &str) -> String {
+    let query = format!("?token={}", encode_query_value(token));
+    let mut tokenized = String::with_capacity(content.len());
+    for line in content.lines() {
+        tokenized.push_str(line);
+        if !line.trim().is_empty() && !line.starts_with('#') {
+            tokenized.push_str(&query);
+        }
+        tokenized.push('\n');
+    }
+    tokenized
+}
+
+/// Names the segments of a local playlist by their content hash, so their URLs can
+/// be cached forever and change when a segment is rewritten.
+async fn hash_segment_uris(
+    state: &AppState,
+    segments_dir: &std::path::Path,
+    content: String,
+) -> String {
+    let mut hashed = String::with_capacity(content.len() + content.len() / 2);
+    // Single-file tracks list the same file once per byte range
+    let mut last: Option<(&str, String)> = None;
+    for line in content.lines() {
+        let uri = line.trim();
+        if uri.is_empty() || uri.starts_with('#') || !is_safe_path_component(uri) {
+            hashed.push_str(line);
+        } else {
+            let hash = match &last {
+                Some((last_uri, hash)) if *last_uri == uri => Some(hash.clone()),
+                _ => state.file_hashes.hash(&segments_dir.join(uri)).await,
+            };
+            match hash {
+                Some(hash) => {
+                    hashed.push_str(&hashed_name(uri, &hash));
+                    last = Some((uri, hash));
+                }
+                None => hashed.push_str(line),
+            }
+        }
+        hashed.push('\n');
+    }
+    hashed
+}
+
+/// Checks an `If-None-Match` header value against an entity tag.
+pub(super) fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    if_none_match.is_some_and(|value| {
+        value
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag)
+    })
+}
+
+/// `304 Not Modified` for a client whose copy matches `etag`.
+pub(super) fn not_modified(cache_control: &str, etag: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::ETAG, etag)
+        .body(Body::empty())
+        .expect("valid response headers")
+}
+
+/// Playlist response; compressed bodies get a per-encoding entity tag.
+fn playlist_response(
+    content: String,
+    if_none_match: Option<&str>,
+    accept_encoding: Option<&str>,
+) -> Response<Body> {
+    text_response(
+        content,
+        PLAYLIST_CONTENT_TYPE,
+        if_none_match,
+        accept_encoding,
+    )
+}
+
+/// A generated text file that may change, such as a playlist or the lyrics.
+fn text_response(
+    content: String,
+    content_type: &str,
+    if_none_match: Option<&str>,
+    accept_encoding: Option<&str>,
+) -> Response<Body> {
+    let hash = hex::encode(Sha256::digest(content.as_bytes()));
+    let encoding = negotiate(accept_encoding).filter(|_| worth_compressing(content.len()));
+    let etag = match encoding {
+        Some(encoding) => format!("\"{}-{}\"", &hash[..16], encoding.name()),
+        None => format!("\"{}\"", &hash[..16]),
+    };
+
+    if etag_matches(if_none_match, &etag) {
+        return not_modified(PLAYLIST_CACHE_CONTROL, &etag);
+    }
+
+    let builder = Response::builder()
+        .header(header::CACHE_CONTROL, PLAYLIST_CACHE_CONTROL)
+        .header(header::ETAG, &etag)
+        .header(header::CONTENT_TYPE, content_type);
+    compressed_body(builder, Bytes::from(content), encoding)
+}
+
+/// Rewrites a media playlist for the configured profile.
+///
+/// The legacy profile pins the playlist to version 3 (4 for single-file tracks, which
+/// need byte ranges), marks it as VOD and rounds TARGETDURATION up from the longest
+/// segment: players predating RFC 8216 reject segments longer than the target, where
+/// ffmpeg may round to the nearest second.
+fn profile_playlist(content: String, profile: HlsProfile) -> String {
+    if profile == HlsProfile::Standard {
+        return content;
+    }
+
+    let version = if content.contains("#EXT-X-BYTERANGE") {
+        4
+    } else {
+        3
+    };
+    let target = segment_durations(&content).fold(1.0_f64, f64::max).ceil();
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:{}\n#EXT-X-TARGETDURATION:{}\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+        version, target
+    );
+    for line in content.lines().map(str::trim_end) {
+        let replaced = [
+            "#EXTM3U",
+            "#EXT-X-VERSION:",
+            "#EXT-X-TARGETDURATION:",
+            "#EXT-X-PLAYLIST-TYPE:",
+        ]
+        .iter()
+        .any(|tag| line.starts_with(tag));
+        if !replaced && !line.is_empty() {
+            playlist.push_str(line);
+            playlist.push('\n');
+        }
+    }
+    if !content.contains("#EXT-X-ENDLIST") {
+        playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+    playlist
+}
+
+/// Master playlist listing a track's audio rendition with its bandwidth and codecs,
+/// which players like Smart TVs and `mediastreamvalidator` expect up front, its
+/// lyrics as a subtitle rendition when it has any, and its chapters as session data.
+fn master_playlist(
+    peak_bandwidth: u64,
+    average_bandwidth: u64,
+    codec: AudioCodec,
+    profile: HlsProfile,
+    token: Option<&str>,
+    lyrics: bool,
+    chapters: bool,
+) -> String {
+    let mut attributes = format!("BANDWIDTH={}", peak_bandwidth);
+    // AVERAGE-BANDWIDTH postdates version 3
+    if profile == HlsProfile::Standard {
+        attributes.push_str(&format!(",AVERAGE-BANDWIDTH={}", average_bandwidth));
+    }
+    let query = token
+        .map(|token| format!("?token={}", encode_query_value(token)))
+        .unwrap_or_default();
+    let mut media = String::new();
+    if lyrics {
+        media.push_str(&format!(
+            "#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"lyrics\",NAME=\"Lyrics\",DEFAULT=YES,AUTOSELECT=YES,URI=\"lyrics.m3u8{}\"\n",
+            query
+        ));
+        attributes.push_str(",SUBTITLES=\"lyrics\"");
+    }
+    if chapters {
+        media.push_str(&format!(
+            "#EXT-X-SESSION-DATA:DATA-ID=\"{}\",URI=\"chapters.json{}\"\n",
+            APPLE_CHAPTERS_DATA_ID, query
+        ));
+    }
+    format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n{}#EXT-X-STREAM-INF:{},CODECS=\"{}\"\nplaylist.m3u8{}\n",
+        media,
+        attributes,
+        codec.codecs(),
+        query
+    )
+}
+
+/// Subtitle playlist of a track's lyrics: the whole WebVTT file as one segment.
+fn lyrics_playlist(duration: f64, token: Option<&str>) -> String {
+    let query = token
+        .map(|token| format!("?token={}", encode_query_value(token)))
+        .unwrap_or_default();
+    format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXTINF:{:.3},\nlyrics.vtt{}\n#EXT-X-ENDLIST\n",
+        duration.ceil().max(1.0),
+        duration,
+        query
+    )
+}
+
+/// A track's chapters as a WebVTT chapters track, one cue per chapter, for
+/// `<track kind="chapters">` next to hls.js and similar players.
+fn chapters_webvtt(chapters: &[Chapter]) -> String {
+    let mut vtt = "WEBVTT\n".to_string();
+    for (index, chapter) in chapters.iter().enumerate() {
+        if chapter.end <= chapter.start {
+            continue;
+        }
+        vtt.push_str(&format!(
+            "\n{}\n{} --> {}\n{}\n",
+            index + 1,
+            vtt_time(chapter.start),
+            vtt_time(chapter.end),
+            chapter.title.replace("-->", "->")
+        ));
+    }
+    vtt
+}
+
+/// A track's chapters in the JSON format AVPlayer reads from the
+/// `com.apple.hls.chapters` session data.
+fn chapters_json(chapters: &[Chapter]) -> String {
+    let chapters: Vec<serde_json::Value> = chapters
+        .iter()
+        .enumerate()
+        .map(|(index, chapter)| {
+            serde_json::json!({
+                "chapter": index + 1,
+                "start-time": chapter.start,
+                "duration": (chapter.end - chapter.start).max(0.0),
+                "titles": [{ "language": "und", "title": chapter.title }],
+            })
+        })
+        .collect();
+    serde_json::Value::Array(chapters).to_string()
+}
+
+/// Segments are immutable, so their entity tag is derived from the name alone.
+fn segment_etag(session_id: &str, segment_name: &str) -> String {
+    format!("\"{}-{}\"", session_id, segment_name)
+}
+
+/// Segment response; MPEG-TS audio is already compressed, so bodies are sent as-is.
+fn segment_response(body: Body, len: u64, etag: &str) -> Response<Body> {
+    Response::builder()
+        .header(header::CACHE_CONTROL, SEGMENT_CACHE_CONTROL)
+        .header(header::ETAG, etag)
+        .header(header::CONTENT_TYPE, SEGMENT_CONTENT_TYPE)
+        .header(header::CONTENT_LENGTH, len)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .body(body)
+        .expect("valid response headers")
+}
+
+/// `206 Partial Content` with bytes `start..=end` of a `total` byte segment file.
+fn partial_segment_response(
+    body: Body,
+    (start, end): (u64, u64),
+    total: u64,
+    etag: &str,
+) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::CACHE_CONTROL, SEGMENT_CACHE_CONTROL)
+        .header(header::ETAG, etag)
+        .header(header::CONTENT_TYPE, SEGMENT_CONTENT_TYPE)
+        .header(header::CONTENT_LENGTH, end - start + 1)
+        .header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, total),
+        )
+        .header(header::ACCEPT_RANGES, "bytes")
+        .body(body)
+        .expect("valid response headers")
+}
+
+fn range_not_satisfiable(total: u64) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+        .body(Body::empty())
+        .expect("valid response headers")
+}
+
+/// The inclusive byte range a `Range` header asks for out of `total` bytes, or `Err`
+/// if it lies outside the file. Headers that aren't a single byte range are ignored,
+/// so those requests get the whole file.
+fn parse_range(value: &str, total: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let last = total.checked_sub(1);
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            last.filter(|_| suffix > 0)
+                .map(|last| (total.saturating_sub(suffix), last))
+        }
+        (start, "") => {
+            let start: u64 = start.parse().ok()?;
+            last.filter(|&last| start <= last).map(|last| (start, last))
+        }
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            last.filter(|&last| start <= last)
+                .map(|last| (start, end.min(last)))
+        }
+    };
+    Some(range.ok_or(()))
+}
+
+/// Anonymous listener identity: a hash of `X-Client-Id`, `X-Device-Id` or, failing
+/// both, the client's IP.
+fn listener_id(headers: &HeaderMap, client: Option<IpAddr>) -> Option<String> {
+    let id = header_str(headers, CLIENT_ID_HEADER)
+        .or_else(|| header_str(headers, "x-device-id"))
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .or_else(|| client.map(|ip| ip.to_string()))?;
+    Some(hex::encode(&Sha256::digest(id.as_bytes())[..8]))
+}
+
+pub(super) async fn serve_hls_playlist(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    ClientIp(client): ClientIp,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let hls_cache = &state.hls_cache;
+    let if_none_match = header_str(&headers, header::IF_NONE_MATCH.as_str());
+    let accept_encoding = header_str(&headers, header::ACCEPT_ENCODING.as_str());
+    let token = uri_token(&state, &headers, &query).await;
+
+    // Find the file_hash for this session and increment listen count; mirrors
+    // fetching with the sync header don't count as listens
+    let file_hash_to_update = if headers.contains_key(SYNC_HEADER) {
+        None
+    } else {
+        let cache = hls_cache.read().await;
+        cache
+            .iter()
+            .find(|(_, s)| s.id == session_id)
+            .map(|(hash, _)| hash.clone())
+    };
+
+    if let Some(hash) = file_hash_to_update {
+        // Listeners count once per track and day
+        let listener = if state.private_stats {
+            None
+        } else {
+            listener_id(&headers, client)
+        };
+        let day_start = unix_timestamp() / SECONDS_PER_DAY * SECONDS_PER_DAY;
+        let new_listener = match &listener {
+            Some(listener) => {
+                let history = state.history.read().await;
+                !history
+                    .iter()
+                    .rev()
+                    .take_while(|play| play.played_at >= day_start)
+                    .any(|play| play.track_id == hash && play.listener.as_ref() == Some(listener))
+            }
+            None => false,
+        };
+
+        let play = {
+            let mut cache = hls_cache.write().await;
+            cache.get_mut(&hash).and_then(|session| {
+                let now = Instant::now();
+                let should_increment = match session.last_listen {
+                    Some(last) => now.duration_since(last) > Duration::from_secs(2),
+                    None => true,
+                };
+
+                should_increment.then(|| {
+                    session.listen_count += 1;
+                    if new_listener {
+                        session.unique_listeners += 1;
+                    }
+                    session.last_listen = Some(now);
+                    PlayEvent {
+                        track_id: hash.clone(),
+                        played_at: unix_timestamp(),
+                        duration: track_duration(session),
+                        listener,
+                    }
+                })
+            })
+        };
+
+        if let Some(play) = play {
+            hls_cache.changed();
+            if let Err(e) = append_history(&state.cache_dir, &play).await {
+                eprintln!("Warning: Failed to append to listening history: {}", e);
+            }
+            state.history.write().await.push(play);
+        }
+    }
+
+    let track = {
+        let cache = hls_cache.read().await;
+        cache
+            .iter()
+            .find(|(_, s)| s.id == session_id)
+            .map(|(hash, s)| (hash.clone(), s.clone()))
+    };
+
+    if let Some((hash, session)) = track {
+        let Ok(content) = tokio::fs::read_to_string(&session.playlist_path).await else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        // Mirrors store segments under the names they fetch
+        let content = if headers.contains_key(SYNC_HEADER) {
+            content
+        } else {
+            hash_segment_uris(&state, &session.segments_dir, content).await
+        };
+        let private_token = private_uri_token(&state, &session, &query);
+        let share_token = share_key_token(&state, &hash, &session, &query, true).await;
+        let content = rewrite_playlist(&state, content, token.or(private_token).or(share_token));
+        Ok(playlist_response(
+            match private_token {
+                Some(private_token) => token_segment_uris(content, private_token),
+                None => content,
+            },
+            if_none_match,
+            accept_encoding,
+        ))
+    } else if state.removals.contains(&session_id) {
+        Ok(track_removed())
+    } else if let Some(upstream) = &state.upstream {
+        match fetch_upstream_file(upstream, &session_id, "playlist.m3u8").await {
+            Ok(data) => Ok(playlist_response(
+                rewrite_playlist(&state, String::from_utf8_lossy(&data).into_owned(), token),
+                if_none_match,
+                accept_encoding,
+            )),
+            Err(e) => {
+                eprintln!("Warning: Upstream playlist fetch failed: {}", e);
+                Err(StatusCode::NOT_FOUND)
+            }
+        }
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+/// A track's artwork in one of `THUMBNAIL_SIZES`, as `{size}` or, named by its
+/// content hash, `{size}.{hash}.jpg`. Hashed names are cached for good like
+/// segments; plain ones are revalidated, as uploaded artwork replaces the files.
+pub(super) async fn serve_thumbnail(
+    State(state): State<AppState>,
+    Path((session_id, size)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let (size, hash) = match split_hashed_name(&size) {
+        Some((name, hash)) => match name.strip_suffix(".jpg") {
+            Some(size) => (size.to_string(), Some(hash)),
+            None => return Err(StatusCode::NOT_FOUND),
+        },
+        None => (size.clone(), None),
+    };
+    if !THUMBNAIL_SIZES.iter().any(|(name, _)| *name == size) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let found = {
+        let cache = state.hls_cache.read().await;
+        cache
+            .values()
+            .find(|s| {
+                s.id == session_id
+                    && s.has_thumbnail
+                    && hash.is_none_or(|hash| s.artwork_hash.as_deref() == Some(hash))
+            })
+            .map(|s| (s.segments_dir.clone(), s.artwork_hash.clone()))
+    };
+    let Some((segments_dir, artwork_hash)) = found else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let file_name = thumbnail_file(&size);
+    let etag = segment_etag(
+        &session_id,
+        &format!("{}-{}", artwork_hash.unwrap_or_default(), file_name),
+    );
+    let cache_control = match hash {
+        Some(_) => SEGMENT_CACHE_CONTROL,
+        None => PLAYLIST_CACHE_CONTROL,
+    };
+    if etag_matches(header_str(&headers, header::IF_NONE_MATCH.as_str()), &etag) {
+        return Ok(not_modified(cache_control, &etag));
+    }
+    let data = tokio::fs::read(segments_dir.join(file_name))
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok(Response::builder()
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::ETAG, etag)
+        .header(header::CONTENT_TYPE, THUMBNAIL_CONTENT_TYPE)
+        .body(Body::from(data))
+        .expect("valid response headers"))
+}
+
+/// The video rendition of a track downloaded with `"video": true`. Video segments are
+/// served by [`serve_hls_segment`] like audio ones.
+pub(super) async fn serve_video_playlist(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let if_none_match = header_str(&headers, header::IF_NONE_MATCH.as_str());
+    let accept_encoding = header_str(&headers, header::ACCEPT_ENCODING.as_str());
+    let token = uri_token(&state, &headers, &query).await;
+    let track = {
+        let cache = state.hls_cache.read().await;
+        cache
+            .iter()
+            .find(|(_, s)| s.id == session_id)
+            .map(|(hash, s)| (hash.clone(), s.clone()))
+    };
+
+    if let Some((hash, session)) = track {
+        if !session.has_video {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let Ok(content) =
+            tokio::fs::read_to_string(session.segments_dir.join(VIDEO_PLAYLIST)).await
+        else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        let content = hash_segment_uris(&state, &session.segments_dir, content).await;
+        let private_token = private_uri_token(&state, &session, &query);
+        let share_token = share_key_token(&state, &hash, &session, &query, false).await;
+        let content = rewrite_playlist(&state, content, token.or(private_token).or(share_token));
+        Ok(playlist_response(
+            match private_token {
+                Some(private_token) => token_segment_uris(content, private_token),
+                None => content,
+            },
+            if_none_match,
+            accept_encoding,
+        ))
+    } else if let Some(upstream) = &state.upstream {
+        match fetch_upstream_file(upstream, &session_id, VIDEO_PLAYLIST).await {
+            Ok(data) => Ok(playlist_response(
+                rewrite_playlist(&state, String::from_utf8_lossy(&data).into_owned(), token),
+                if_none_match,
+                accept_encoding,
+            )),
+            Err(e) => {
+                eprintln!("Warning: Upstream video playlist fetch failed: {}", e);
+                Err(StatusCode::NOT_FOUND)
+            }
+        }
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+pub(super) async fn serve_master_playlist(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let if_none_match = header_str(&headers, header::IF_NONE_MATCH.as_str());
+    let accept_encoding = header_str(&headers, header::ACCEPT_ENCODING.as_str());
+    let token = uri_token(&state, &headers, &query).await;
+    let track = {
+        let cache = state.hls_cache.read().await;
+        cache
+            .iter()
+            .find(|(_, s)| s.id == session_id)
+            .map(|(hash, s)| (hash.clone(), s.clone()))
+    };
+
+    let Some((hash, session)) = track else {
+        if state.removals.contains(&session_id) {
+            return Ok(track_removed());
+        }
+        // Replicas can't size upstream segments without fetching them all
+        let upstream = state.upstream.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+        return match fetch_upstream_file(upstream, &session_id, "master.m3u8").await {
+            Ok(data) => Ok(playlist_response(
+                String::from_utf8_lossy(&data).into_owned(),
+                if_none_match,
+                accept_encoding,
+            )),
+            Err(e) => {
+                eprintln!("Warning: Upstream master playlist fetch failed: {}", e);
+                Err(StatusCode::NOT_FOUND)
+            }
+        };
+    };
+
+    let playlist = tokio::fs::read_to_string(&session.playlist_path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    // BANDWIDTH is the peak bitrate of any segment, AVERAGE-BANDWIDTH the overall one
+    let mut peak = 0.0_f64;
+    let (mut total_bits, mut total_duration) = (0.0_f64, 0.0_f64);
+    for segment in playlist_segments(&playlist) {
+        let len = match segment.byte_range {
+            Some((len, _)) => len,
+            None => match tokio::fs::metadata(session.segments_dir.join(segment.uri)).await {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            },
+        };
+        let (bits, duration) = (len as f64 * 8.0, segment.duration);
+        if duration > 0.0 {
+            peak = peak.max(bits / duration);
+        }
+        total_bits += bits;
+        total_duration += duration;
+    }
+    let average = if total_duration > 0.0 {
+        total_bits / total_duration
+    } else {
+        0.0
+    };
+    let lyrics = tokio::fs::try_exists(session.segments_dir.join(LYRICS_FILE))
+        .await
+        .unwrap_or(false);
+
+    Ok(playlist_response(
+        master_playlist(
+            peak.ceil() as u64,
+            average.ceil() as u64,
+            session.codec,
+            state.hls_profile,
+            token
+                .or(private_uri_token(&state, &session, &query))
+                .or(share_key_token(&state, &hash, &session, &query, false).await),
+            lyrics,
+            !session.chapters.is_empty(),
+        ),
+        if_none_match,
+        accept_encoding,
+    ))
+}
+
+/// The subtitle playlist of a track's lyrics.
+pub(super) async fn serve_lyrics_playlist(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let if_none_match = header_str(&headers, header::IF_NONE_MATCH.as_str());
+    let accept_encoding = header_str(&headers, header::ACCEPT_ENCODING.as_str());
+    let session = {
+        let cache = state.hls_cache.read().await;
+        cache.values().find(|s| s.id == session_id).cloned()
+    };
+
+    let Some(session) = session else {
+        let upstream = state.upstream.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+        return match fetch_upstream_file(upstream, &session_id, "lyrics.m3u8").await {
+            Ok(data) => Ok(playlist_response(
+                String::from_utf8_lossy(&data).into_owned(),
+                if_none_match,
+                accept_encoding,
+            )),
+            Err(_) => Err(StatusCode::NOT_FOUND),
+        };
+    };
+    if !tokio::fs::try_exists(session.segments_dir.join(LYRICS_FILE))
+        .await
+        .unwrap_or(false)
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(playlist_response(
+        lyrics_playlist(
+            session.duration,
+            private_uri_token(&state, &session, &query),
+        ),
+        if_none_match,
+        accept_encoding,
+    ))
+}
+
+/// A track's lyrics as WebVTT, timed against its first segment.
+pub(super) async fn serve_lyrics(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let if_none_match = header_str(&headers, header::IF_NONE_MATCH.as_str());
+    let accept_encoding = header_str(&headers, header::ACCEPT_ENCODING.as_str());
+    let session = {
+        let cache = state.hls_cache.read().await;
+        cache.values().find(|s| s.id == session_id).cloned()
+    };
+
+    let Some(session) = session else {
+        let upstream = state.upstream.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+        return match fetch_upstream_file(upstream, &session_id, "lyrics.vtt").await {
+            Ok(data) => Ok(text_response(
+                String::from_utf8_lossy(&data).into_owned(),
+                LYRICS_CONTENT_TYPE,
+                if_none_match,
+                accept_encoding,
+            )),
+            Err(_) => Err(StatusCode::NOT_FOUND),
+        };
+    };
+    let lrc = tokio::fs::read_to_string(session.segments_dir.join(LYRICS_FILE))
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let start_pts = first_segment_pts(&session.playlist_path)
+        .await
+        .unwrap_or(FFMPEG_START_PTS);
+    Ok(text_response(
+        to_webvtt(&parse_lrc(&lrc), session.duration, start_pts),
+        LYRICS_CONTENT_TYPE,
+        if_none_match,
+        accept_encoding,
+    ))
+}
+
+/// Which form of a track's chapters is asked for.
+#[derive(Clone, Copy)]
+enum ChaptersFormat {
+    WebVtt,
+    Json,
+}
+
+impl ChaptersFormat {
+    fn file_name(self) -> &'static str {
+        match self {
+            ChaptersFormat::WebVtt => "chapters.vtt",
+            ChaptersFormat::Json => "chapters.json",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ChaptersFormat::WebVtt => LYRICS_CONTENT_TYPE,
+            ChaptersFormat::Json => CHAPTERS_JSON_CONTENT_TYPE,
+        }
+    }
+}
+
+/// A track's chapters as WebVTT.
+pub(super) async fn serve_chapters_vtt(
+    state: State<AppState>,
+    session_id: Path<String>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    serve_chapters(state, session_id, headers, ChaptersFormat::WebVtt).await
+}
+
+/// A track's chapters as AVPlayer's chapter JSON, listed in the master playlist.
+pub(super) async fn serve_chapters_json(
+    state: State<AppState>,
+    session_id: Path<String>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    serve_chapters(state, session_id, headers, ChaptersFormat::Json).await
+}
+
+async fn serve_chapters(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    format: ChaptersFormat,
+) -> Result<Response<Body>, StatusCode> {
+    let if_none_match = header_str(&headers, header::IF_NONE_MATCH.as_str());
+    let accept_encoding = header_str(&headers, header::ACCEPT_ENCODING.as_str());
+    let session = {
+        let cache = state.hls_cache.read().await;
+        cache.values().find(|s| s.id == session_id).cloned()
+    };
+
+    let content = match session {
+        Some(session) if session.chapters.is_empty() => return Err(StatusCode::NOT_FOUND),
+        Some(session) => match format {
+            ChaptersFormat::WebVtt => chapters_webvtt(&session.chapters),
+            ChaptersFormat::Json => chapters_json(&session.chapters),
+        },
+        None => {
+            let upstream = state.upstream.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+            let data = fetch_upstream_file(upstream, &session_id, format.file_name())
+                .await
+                .map_err(|_| StatusCode::NOT_FOUND)?;
+            String::from_utf8_lossy(&data).into_owned()
+        }
+    };
+    Ok(text_response(
+        content,
+        format.content_type(),
+        if_none_match,
+        accept_encoding,
+    ))
+}
+
+/// The timestamp a track's audio starts at, read from the start of its first segment.
+async fn first_segment_pts(playlist_path: &std::path::Path) -> Option<u64> {
+    let playlist = tokio::fs::read_to_string(playlist_path).await.ok()?;
+    let segments = playlist_segments(&playlist);
+    let first = segments.first()?;
+    let mut file = File::open(playlist_path.parent()?.join(first.uri))
+        .await
+        .ok()?;
+    let offset = first.byte_range.map_or(0, |(_, offset)| offset);
+    file.seek(std::io::SeekFrom::Start(offset)).await.ok()?;
+    let mut start = Vec::new();
+    file.take(64 * 1024).read_to_end(&mut start).await.ok()?;
+    first_pts(&start)
+}
+
+/// The AES-128 key of an encrypted track, for clients holding the key token, a grant
+/// or a share link to the track.
+pub(super) async fn serve_key(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    let track = {
+        let cache = state.hls_cache.read().await;
+        cache
+            .iter()
+            .find(|(_, s)| s.id == session_id && s.encrypted)
+            .map(|(hash, s)| (hash.clone(), s.clone()))
+    };
+    let shared = match (&track, presented_token(&headers, &query)) {
+        (Some((hash, session)), Some(token)) => {
+            share_access(&state, hash, token, false, track_duration(session)).await
+        }
+        _ => false,
+    };
+    if !shared && !has_key_access(&state, &headers, &query).await {
+        return Ok(unauthorized());
+    }
+
+    let segments_dir = track
+        .map(|(_, session)| session.segments_dir)
+        .or_else(|| state.removals.segments_dir(&session_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let key = tokio::fs::read(segments_dir.join(KEY_FILE))
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok(Response::builder()
+        .header(header::CACHE_CONTROL, KEY_CACHE_CONTROL)
+        .header(header::CONTENT_TYPE, KEY_CONTENT_TYPE)
+        .body(Body::from(key))
+        .expect("valid response headers"))
+}
+
+pub(super) async fn serve_hls_segment(
+    State(state): State<AppState>,
+    Path((session_id, segment_name)): Path<(String, String)>,
+    ClientIp(client): ClientIp,
+    DeviceId(device_id): DeviceId,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    // Path parameters arrive percent-decoded, so "..%2F" must not escape the session
+    // directory, and "%6Bey" must not bypass the key endpoint
+    if !is_safe_path_component(&segment_name) || segment_name == KEY_FILE {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let if_none_match = header_str(&headers, header::IF_NONE_MATCH.as_str());
+    let etag = segment_etag(&session_id, &segment_name);
+    let user_agent = header_str(&headers, header::USER_AGENT.as_str());
+    let record = |len: u64| {
+        state
+            .connections
+            .record(client, device_id.clone(), user_agent, &session_id, len)
+    };
+    // Responds with a segment held in memory, or the part of it a `Range` header asks for
+    let range = header_str(&headers, header::RANGE.as_str());
+    let data_response = |data: Bytes| {
+        let total = data.len() as u64;
+        match range.and_then(|value| parse_range(value, total)) {
+            Some(Ok((start, end))) => {
+                record(end - start + 1);
+                let part = data.slice(start as usize..=end as usize);
+                let body = state.throttle.limit(client, Body::from(part));
+                partial_segment_response(body, (start, end), total, &etag)
+            }
+            Some(Err(())) => range_not_satisfiable(total),
+            None => {
+                record(total);
+                let body = state.throttle.limit(client, Body::from(data));
+                segment_response(body, total, &etag)
+            }
+        }
+    };
+    // Segments of a track deleted mid-playback are still served to its listeners
+    let segments_dir = {
+        let cache = state.hls_cache.read().await;
+        cache
+            .values()
+            .find(|s| s.id == session_id)
+            .map(|s| s.segments_dir.clone())
+    }
+    .or_else(|| state.removals.segments_dir(&session_id));
+
+    if let Some(segments_dir) = segments_dir {
+        // Hashed names from the playlists are served as the file they name, as long as
+        // its content still matches; upstreams check their own
+        let segment_name = match split_hashed_name(&segment_name) {
+            Some((name, hash)) => {
+                let actual = state.file_hashes.hash(&segments_dir.join(&name)).await;
+                if actual.as_deref() != Some(hash) {
+                    return Err(StatusCode::NOT_FOUND);
+                }
+                name
+            }
+            None => segment_name.clone(),
+        };
+        if state.segment_cache.enabled() {
+            prefetch_local(&state, segments_dir.clone(), &segment_name);
+        }
+        if etag_matches(if_none_match, &etag) {
+            return Ok(not_modified(SEGMENT_CACHE_CONTROL, &etag));
+        }
+
+        let segment_path = segments_dir.join(&segment_name);
+        if let Some(data) = state.segment_cache.get(&segment_path) {
+            return Ok(data_response(data));
+        }
+
+        let mut file = File::open(&segment_path)
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        let len = file
+            .metadata()
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?
+            .len();
+
+        // Segments that fit the cache are read once and shared; anything else is
+        // streamed from disk instead of being buffered per request. Single-file
+        // tracks are cached whole and served by range from memory.
+        if state.segment_cache.accepts(len) {
+            let mut data = Vec::with_capacity(len as usize);
+            file.read_to_end(&mut data)
+                .await
+                .map_err(|_| StatusCode::NOT_FOUND)?;
+            let data = Bytes::from(data);
+            state.segment_cache.insert(segment_path, data.clone());
+            return Ok(data_response(data));
+        }
+
+        match range.and_then(|value| parse_range(value, len)) {
+            Some(Ok((start, end))) => {
+                file.seek(std::io::SeekFrom::Start(start))
+                    .await
+                    .map_err(|_| StatusCode::NOT_FOUND)?;
+                record(end - start + 1);
+                let body = Body::from_stream(ReaderStream::new(file.take(end - start + 1)));
+                Ok(partial_segment_response(
+                    state.throttle.limit(client, body),
+                    (start, end),
+                    len,
+                    &etag,
+                ))
+            }
+            Some(Err(())) => Ok(range_not_satisfiable(len)),
+            None => {
+                record(len);
+                let body = Body::from_stream(ReaderStream::new(file));
+                Ok(segment_response(
+                    state.throttle.limit(client, body),
+                    len,
+                    &etag,
+                ))
+            }
+        }
+    } else if let Some(upstream) = &state.upstream {
+        prefetch_upstream(&state, Arc::clone(upstream), &session_id, &segment_name);
+        if etag_matches(if_none_match, &etag) {
+            return Ok(not_modified(SEGMENT_CACHE_CONTROL, &etag));
+        }
+
+        match fetch_upstream_file(upstream, &session_id, &segment_name).await {
+            Ok(data) => Ok(data_response(Bytes::from(data))),
+            Err(e) => {
+                eprintln!("Warning: Upstream segment fetch failed: {}", e);
+                Err(StatusCode::NOT_FOUND)
+            }
+        }
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+/// The `count` segments following `segment_name` in `playlist`, each once.
+fn following_segments(playlist: &str, segment_name: &str, count: usize) -> Vec<String> {
+    let mut uris: Vec<&str> = playlist_segments(playlist)
+        .iter()
+        .map(|segment| segment.uri)
+        .collect();
+    // Single-file tracks list the same file once per byte range
+    uris.dedup();
+    match uris.iter().position(|uri| *uri == segment_name) {
+        Some(index) => uris[index + 1..]
+            .iter()
+            .take(count)
+            .map(|uri| uri.to_string())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Reads the segments after `segment_name` into the segment cache in the
+/// background, so a slow disk doesn't hold up the listener's next requests.
+fn prefetch_local(state: &AppState, segments_dir: PathBuf, segment_name: &str) {
+    if state.prefetch_segments == 0 {
+        return;
+    }
+    let cache = Arc::clone(&state.segment_cache);
+    let count = state.prefetch_segments;
+    let segment_name = segment_name.to_string();
+    tokio::spawn(async move {
+        for playlist in ["playlist.m3u8", VIDEO_PLAYLIST] {
+            let Ok(content) = tokio::fs::read_to_string(segments_dir.join(playlist)).await else {
+                continue;
+            };
+            let following = following_segments(&content, &segment_name, count);
+            if following.is_empty() {
+                continue;
+            }
+            for name in following {
+                let path = segments_dir.join(name);
+                if cache.contains(&path) {
+                    continue;
+                }
+                let fits = tokio::fs::metadata(&path)
+                    .await
+                    .is_ok_and(|metadata| cache.accepts(metadata.len()));
+                if !fits {
+                    break;
+                }
+                if let Ok(data) = tokio::fs::read(&path).await {
+                    cache.insert_prefetched(path, Bytes::from(data));
+                }
+            }
+            break;
+        }
+    });
+}
+
+/// Fetches the segments after `segment_name` from the upstream in the background,
+/// so the listener's next requests are served from the local copy.
+fn prefetch_upstream(
+    state: &AppState,
+    upstream: Arc<Upstream>,
+    session_id: &str,
+    segment_name: &str,
+) {
+    if state.prefetch_segments == 0 {
+        return;
+    }
+    let count = state.prefetch_segments;
+    let session_id = session_id.to_string();
+    let segment_name = segment_name.to_string();
+    tokio::spawn(async move {
+        for playlist in ["playlist.m3u8", VIDEO_PLAYLIST] {
+            let Ok(content) = fetch_upstream_file(&upstream, &session_id, playlist).await else {
+                continue;
+            };
+            let following =
+                following_segments(&String::from_utf8_lossy(&content), &segment_name, count);
+            if following.is_empty() {
+                continue;
+            }
+            for name in following {
+                if upstream.dir.join(&session_id).join(&name).exists() {
+                    continue;
+                }
+                if let Err(e) = fetch_upstream_file(&upstream, &session_id, &name).await {
+                    eprintln!("Warning: Upstream segment prefetch failed: {}", e);
+                    break;
+                }
+            }
+            break;
+        }
+    });
+}
+
+/// Sends requests for `/api/hls/{slug}/...` on to the track's current session, so
+/// playlists reached by slug list segments under the session id, which is what
+/// lets segments be cached as immutable even though a refresh gives a slug new ones.
+pub(super) async fn resolve_slug(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response<Body> {
+    let key = request
+        .uri()
+        .path()
+        .strip_prefix("/api/hls/")
+        .and_then(|rest| rest.split_once('/'));
+    if let Some((key, rest)) = key.filter(|(key, _)| Uuid::parse_str(key).is_err()) {
+        let track = {
+            let cache = state.hls_cache.read().await;
+            cache
+                .iter()
+                .find(|(_, s)| s.slug == key)
+                .map(|(track_id, s)| (track_id.clone(), s.clone()))
+        };
+        if let Some((track_id, session)) = track {
+            // A private track's slug is as unknown to guests as the track itself
+            if !reaches_track(
+                &state,
+                &track_id,
+                &session,
+                request.headers(),
+                request.uri(),
+            )
+            .await
+            {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            let session_id = session.id;
+            let query = request
+                .uri()
+                .query()
+                .map(|query| format!("?{}", query))
+                .unwrap_or_default();
+            return Redirect::temporary(&format!("/api/hls/{}/{}{}", session_id, rest, query))
+                .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Short link to a track by slug or id: redirects to its master playlist, which
+/// keeps working after the track is refreshed or re-transcoded. A `?token=` is passed
+/// on, so share links work through it.
+pub(super) async fn short_link(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response<Body>, StatusCode> {
+    let (track_id, session) = {
+        let cache = state.hls_cache.read().await;
+        cache
+            .get_key_value(&key)
+            .or_else(|| cache.iter().find(|(_, s)| s.slug == key || s.id == key))
+            .map(|(track_id, s)| (track_id.clone(), s.clone()))
+            .ok_or(StatusCode::NOT_FOUND)?
+    };
+    if !reaches_track(&state, &track_id, &session, &headers, &uri).await {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let query = uri
+        .query()
+        .map(|query| format!("?{}", query))
+        .unwrap_or_default();
+    Ok(
+        Redirect::temporary(&format!("/api/hls/{}/master.m3u8{}", session.id, query))
+            .into_response(),
+    )
+}
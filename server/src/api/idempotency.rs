@@ -0,0 +1,80 @@
+//! Idempotency keys for POST /api/download: a client that retries a download after
+//! losing the connection sends the same `Idempotency-Key` and gets the original job's
+//! result rather than a second download.
+
+use super::{download_error, ApiError, AppState, ErrorCode};
+use crate::downloader::{DownloadError, DownloadFailure};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+pub(super) const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Longest key accepted; UUIDs and similar random strings fit easily.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// The request's `Idempotency-Key`, if it sent one.
+pub(super) fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    match value.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => Ok(Some(key.to_string())),
+        _ => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Idempotency-Key must be 1 to {} visible ASCII characters",
+                MAX_KEY_LENGTH
+            ),
+        )),
+    }
+}
+
+/// The response of the job created with `key`, once it has finished: its track, or
+/// the error it failed with. `None` when `job_id` was created without the key.
+pub(super) async fn replay(
+    state: &AppState,
+    job_id: &str,
+    key: &str,
+    url: &str,
+) -> Option<Response> {
+    loop {
+        let notified = state.download_finished.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        {
+            let queue = state.download_queue.read().await;
+            let job = queue.get(job_id)?;
+            if job.idempotency_key.as_deref() != Some(key) {
+                return None;
+            }
+            if job.url != url {
+                return Some(
+                    ApiError::new(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "Idempotency-Key was already used for another URL",
+                    )
+                    .with_code(ErrorCode::IdempotencyKeyReused)
+                    .with_details(serde_json::json!({ "download_id": job_id }))
+                    .into_response(),
+                );
+            }
+            match (job.status.as_str(), &job.session) {
+                ("ready", Some(session)) => return Some(Json(session.clone()).into_response()),
+                ("error", _) => {
+                    let error = DownloadError::new(
+                        job.error_code.unwrap_or(DownloadFailure::DownloadFailed),
+                        job.error.clone().unwrap_or_default(),
+                    );
+                    return Some(
+                        download_error(&error)
+                            .with_details(serde_json::json!({ "download_id": job_id }))
+                            .into_response(),
+                    );
+                }
+                _ => {}
+            }
+        }
+        notified.await;
+    }
+}
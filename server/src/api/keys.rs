@@ -0,0 +1,193 @@
+//! Who may fetch segment keys: the operator's `--key-token`, and expiring grants it
+//! hands out to single devices.
+
+use super::{header_str, json_error, AppState};
+use crate::storage::{save_key_grants, unix_timestamp, KeyGrant};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Grants last a day unless the request asks otherwise.
+const DEFAULT_GRANT_TTL: u64 = 24 * 60 * 60;
+const MAX_GRANT_TTL: u64 = 30 * 24 * 60 * 60;
+
+/// `?token=` on playlist and key requests, for players that can't send headers.
+#[derive(Debug, Deserialize)]
+pub(super) struct TokenQuery {
+    pub(super) token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct GrantRequest {
+    device_id: String,
+    /// Lifetime in seconds
+    ttl: Option<u64>,
+}
+
+/// The token a request presents, as a bearer token or `?token=`.
+pub(super) fn presented_token<'a>(
+    headers: &'a HeaderMap,
+    query: &'a TokenQuery,
+) -> Option<&'a str> {
+    header_str(headers, header::AUTHORIZATION.as_str())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .or(query.token.as_deref())
+}
+
+pub(super) fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Whether `token` is the operator's key token. Both sides are hashed first so the
+/// comparison takes the same time however much of the token matches.
+fn is_master_token(state: &AppState, token: Option<&str>) -> bool {
+    match (state.key_token.as_deref(), token) {
+        (Some(expected), Some(token)) => {
+            Sha256::digest(expected.as_bytes()) == Sha256::digest(token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Whether `token` unlocks segment keys: the key token, or a live grant. A grant
+/// presented along with another device's `X-Device-Id` is refused.
+async fn grants_key_access(state: &AppState, headers: &HeaderMap, token: Option<&str>) -> bool {
+    if is_master_token(state, token) {
+        return true;
+    }
+    let Some(token) = token else {
+        return false;
+    };
+
+    let grants = state.key_grants.read().await;
+    grants.get(&token_hash(token)).is_some_and(|grant| {
+        grant.expires_at > unix_timestamp()
+            && header_str(headers, "x-device-id").is_none_or(|device| device == grant.device_id)
+    })
+}
+
+/// Whether the request may fetch segment keys.
+pub(super) async fn has_key_access(
+    state: &AppState,
+    headers: &HeaderMap,
+    query: &TokenQuery,
+) -> bool {
+    grants_key_access(state, headers, presented_token(headers, query)).await
+}
+
+/// The request's `?token=` if it unlocks keys, to be passed on in playlist URIs.
+pub(super) async fn uri_token<'a>(
+    state: &AppState,
+    headers: &HeaderMap,
+    query: &'a TokenQuery,
+) -> Option<&'a str> {
+    let token = query.token.as_deref()?;
+    grants_key_access(state, headers, Some(token))
+        .await
+        .then_some(token)
+}
+
+/// `401` with a bearer challenge.
+pub(super) fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+    )
+        .into_response()
+}
+
+fn grant_json(grant: &KeyGrant) -> serde_json::Value {
+    serde_json::json!({
+        "id": grant.id,
+        "device_id": grant.device_id,
+        "created_at": grant.created_at,
+        "expires_at": grant.expires_at,
+    })
+}
+
+/// Hands a device its own key token; only the operator's key token may do this.
+pub(super) async fn create_grant(
+    State(state): State<AppState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+    Json(request): Json<GrantRequest>,
+) -> Response {
+    if !is_master_token(&state, presented_token(&headers, &query)) {
+        return unauthorized();
+    }
+    let ttl = request.ttl.unwrap_or(DEFAULT_GRANT_TTL);
+    if ttl == 0 || ttl > MAX_GRANT_TTL {
+        return json_error(
+            &format!("ttl must be between 1 and {} seconds", MAX_GRANT_TTL),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    if !state.devices.read().await.contains_key(&request.device_id) {
+        return json_error("Unknown device", StatusCode::NOT_FOUND);
+    }
+
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let now = unix_timestamp();
+    let grant = KeyGrant {
+        id: Uuid::new_v4().to_string(),
+        token_hash: token_hash(&token),
+        device_id: request.device_id,
+        created_at: now,
+        expires_at: now + ttl,
+    };
+
+    let mut grants = state.key_grants.write().await;
+    grants.retain(|_, grant| grant.expires_at > now);
+    grants.insert(grant.token_hash.clone(), grant.clone());
+    if let Err(e) = save_key_grants(&state.cache_dir, &grants).await {
+        eprintln!("Warning: Failed to save key grants: {}", e);
+    }
+
+    let mut body = grant_json(&grant);
+    body["token"] = token.into();
+    (StatusCode::CREATED, Json(body)).into_response()
+}
+
+pub(super) async fn list_grants(
+    State(state): State<AppState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !is_master_token(&state, presented_token(&headers, &query)) {
+        return unauthorized();
+    }
+
+    let now = unix_timestamp();
+    let grants = state.key_grants.read().await;
+    let mut grants: Vec<&KeyGrant> = grants.values().filter(|g| g.expires_at > now).collect();
+    grants.sort_by_key(|g| g.created_at);
+    Json(grants.into_iter().map(grant_json).collect::<Vec<_>>()).into_response()
+}
+
+pub(super) async fn revoke_grant(
+    State(state): State<AppState>,
+    Path(grant_id): Path<String>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if !is_master_token(&state, presented_token(&headers, &query)) {
+        return unauthorized();
+    }
+
+    let mut grants = state.key_grants.write().await;
+    let before = grants.len();
+    grants.retain(|_, grant| grant.id != grant_id);
+    if grants.len() == before {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if let Err(e) = save_key_grants(&state.cache_dir, &grants).await {
+        eprintln!("Warning: Failed to save key grants: {}", e);
+    }
+
+    Json(serde_json::json!({ "success": true })).into_response()
+}
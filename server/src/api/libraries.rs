@@ -0,0 +1,93 @@
+//! Several libraries on one server (`--library`), each with its own routes and state.
+//! Requests pick one with a `/libraries/{name}` path prefix or an `X-Library` header.
+
+use super::{header_str, json_error};
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tower::ServiceExt;
+
+pub(super) const LIBRARY_HEADER: &str = "x-library";
+
+const PATH_PREFIX: &str = "/libraries/";
+
+/// Path prefix under which absolute URLs reach the library, for `request_origin`
+pub(super) const PREFIX_HEADER: &str = "x-forwarded-prefix";
+
+type Libraries = Arc<BTreeMap<String, Router>>;
+
+/// Routes each request to the router of the library it names.
+pub(super) fn libraries_router(libraries: BTreeMap<String, Router>) -> Router {
+    Router::new()
+        .route("/api/libraries", get(list_libraries))
+        .fallback(dispatch)
+        .with_state(Arc::new(libraries))
+}
+
+async fn list_libraries(State(libraries): State<Libraries>) -> Response {
+    Json(serde_json::json!({
+        "libraries": libraries.keys().collect::<Vec<_>>(),
+    }))
+    .into_response()
+}
+
+async fn dispatch(State(libraries): State<Libraries>, mut request: Request<Body>) -> Response {
+    let path = request.uri().path();
+    let (name, rest) = match path.strip_prefix(PATH_PREFIX) {
+        Some(rest) => {
+            let (name, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            (Some(name.to_string()), Some(rest.to_string()))
+        }
+        None => (
+            header_str(request.headers(), LIBRARY_HEADER).map(str::to_string),
+            None,
+        ),
+    };
+    let Some(name) = name else {
+        return json_error(
+            "Choose a library with a /libraries/{name} path prefix or an X-Library header",
+            StatusCode::BAD_REQUEST,
+        );
+    };
+    let Some(router) = libraries.get(&name).cloned() else {
+        return json_error("Unknown library", StatusCode::NOT_FOUND);
+    };
+
+    let prefix = format!("{}{}", PATH_PREFIX, name);
+    if let Some(rest) = rest {
+        // The web UI's relative URLs need the trailing slash
+        if rest.is_empty() {
+            return Redirect::permanent(&format!("{}/", prefix)).into_response();
+        }
+        let uri = match request.uri().query() {
+            Some(query) => format!("{}?{}", rest, query),
+            None => rest,
+        };
+        *request.uri_mut() = Uri::try_from(uri).expect("a valid URI's path and query");
+    }
+    // URLs made absolute for external players and redirects lead back to the library
+    let forwarded = header_str(request.headers(), PREFIX_HEADER)
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    if let Ok(value) = HeaderValue::from_str(&format!("{}{}", forwarded, prefix)) {
+        request.headers_mut().insert(PREFIX_HEADER, value);
+    }
+
+    let mut response = router.oneshot(request).await.into_response();
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .filter(|location| location.starts_with('/') && !location.starts_with("//"));
+    if let Some(location) = location {
+        if let Ok(value) = HeaderValue::from_str(&format!("{}{}", prefix, location)) {
+            response.headers_mut().insert(header::LOCATION, value);
+        }
+    }
+    response
+}
@@ -0,0 +1,97 @@
+//! Time-synced lyrics of tracks, uploaded as LRC and streamed as a WebVTT subtitle
+//! rendition of the track's master playlist.
+
+use super::{json_error, read_only_track, AppState};
+use crate::lyrics::{parse_lrc, LYRICS_FILE};
+use axum::extract::{Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+const MAX_LYRICS_SIZE: usize = 256 * 1024;
+
+const LRC_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// The track's lyrics as uploaded, in LRC
+pub(super) async fn get_lyrics(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+) -> Response {
+    let path = {
+        let cache = state.hls_cache.read().await;
+        let Some(session) = cache.get(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        session.segments_dir.join(LYRICS_FILE)
+    };
+    match tokio::fs::read_to_string(&path).await {
+        Ok(lrc) => ([(header::CONTENT_TYPE, LRC_CONTENT_TYPE)], lrc).into_response(),
+        Err(_) => json_error("Track has no lyrics", StatusCode::NOT_FOUND),
+    }
+}
+
+/// Stores LRC lyrics for the track, replacing any it had
+pub(super) async fn set_lyrics(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+    body: String,
+) -> Response {
+    if body.len() > MAX_LYRICS_SIZE {
+        return json_error(
+            &format!("Lyrics are larger than {} KiB", MAX_LYRICS_SIZE / 1024),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        );
+    }
+    let lines = parse_lrc(&body);
+    if lines.iter().all(|line| line.text.is_empty()) {
+        return json_error(
+            "No timed lines found; lyrics must be in LRC format ([mm:ss.xx] text)",
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    let path = {
+        let cache = state.hls_cache.read().await;
+        let Some(session) = cache.get(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        session.segments_dir.join(LYRICS_FILE)
+    };
+    if let Err(e) = tokio::fs::write(&path, body).await {
+        return json_error(
+            &format!("Failed to save lyrics: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        );
+    }
+
+    Json(serde_json::json!({ "lines": lines.len() })).into_response()
+}
+
+pub(super) async fn delete_lyrics(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+) -> Response {
+    let path = {
+        let cache = state.hls_cache.read().await;
+        let Some(session) = cache.get(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        session.segments_dir.join(LYRICS_FILE)
+    };
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => Json(serde_json::json!({ "success": true })).into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            json_error("Track has no lyrics", StatusCode::NOT_FOUND)
+        }
+        Err(e) => json_error(
+            &format!("Failed to delete lyrics: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    }
+}
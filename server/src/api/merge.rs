@@ -0,0 +1,149 @@
+//! Merging another instance's library into this one.
+
+use super::{header_str, json_error, AppState};
+use crate::merge::{merge_archive, merge_remote};
+use axum::body::Body;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct MergeQuery {
+    /// Only report what would be merged
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoteSource {
+    /// Base URL of the other instance
+    url: String,
+}
+
+/// Imports the tracks of another instance this library doesn't have, from a tar
+/// archive of its cache directory or, with a JSON body, from its URL
+pub(super) async fn merge_library(
+    State(state): State<AppState>,
+    Query(query): Query<MergeQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if state.merging.swap(true, Ordering::SeqCst) {
+        return json_error("A merge is already running", StatusCode::CONFLICT);
+    }
+    let running = Running(Arc::clone(&state.merging));
+    merge(&state, running, query.dry_run, &headers, body).await
+}
+
+/// Clears the running flag once the merge is over, or the request was given up
+/// before it started.
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+async fn merge(
+    state: &AppState,
+    running: Running,
+    dry_run: bool,
+    headers: &HeaderMap,
+    body: Body,
+) -> Response {
+    let cache_dirs = &state.ingest_options.cache_dirs;
+    let json = header_str(headers, header::CONTENT_TYPE.as_str())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    // An unreachable server is a gateway error, an unreadable archive the client's
+    let (result, error_status) = if json {
+        let source: RemoteSource = match axum::body::to_bytes(body, 64 * 1024)
+            .await
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        {
+            Some(source) => source,
+            None => {
+                return json_error(
+                    "Expected {\"url\": \"...\"} or an archive",
+                    StatusCode::BAD_REQUEST,
+                );
+            }
+        };
+        if !source.url.starts_with("http://") && !source.url.starts_with("https://") {
+            return json_error("The URL must be http or https", StatusCode::BAD_REQUEST);
+        }
+        // Runs to the end even if the client gives up waiting
+        let cache_dirs = cache_dirs.clone();
+        let hls_cache = state.hls_cache.clone();
+        let result = tokio::spawn(async move {
+            let _running = running;
+            merge_remote(&source.url, &cache_dirs, &hls_cache, dry_run)
+                .await
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        (result, StatusCode::BAD_GATEWAY)
+    } else {
+        // Archives can be larger than memory, so they are spooled to disk first
+        let archive = state
+            .cache_dir
+            .join(format!(".merge-{}.tar", Uuid::new_v4()));
+        if let Err(e) = receive_file(body, &archive).await {
+            let _ = tokio::fs::remove_file(&archive).await;
+            return json_error(
+                &format!("Failed to receive the archive: {}", e),
+                StatusCode::BAD_REQUEST,
+            );
+        }
+        let cache_dirs = cache_dirs.clone();
+        let hls_cache = state.hls_cache.clone();
+        let result = tokio::spawn(async move {
+            let _running = running;
+            let result = merge_archive(&archive, &cache_dirs, &hls_cache, dry_run).await;
+            let _ = tokio::fs::remove_file(&archive).await;
+            result.map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        (result, StatusCode::BAD_REQUEST)
+    };
+
+    match result {
+        Ok(report) => {
+            if !report.imported.is_empty() {
+                println!(
+                    "✓ Merged {} tracks from another library",
+                    report.imported.len()
+                );
+            }
+            Json(report).into_response()
+        }
+        Err(e) => json_error(&format!("Merge failed: {}", e), error_status),
+    }
+}
+
+async fn receive_file(body: Body, path: &std::path::Path) -> Result<(), String> {
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut stream = body.into_data_stream();
+    let mut received = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        received += chunk.len();
+        file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+    }
+    if received == 0 {
+        return Err("The body is empty".to_string());
+    }
+    file.flush().await.map_err(|e| e.to_string())
+}
@@ -0,0 +1,291 @@
+//! Converting the whole library to one audio format in the background, a few tracks
+//! at a time. Progress is saved after every track, and a migration interrupted by a
+//! restart carries on when the server is back.
+
+use super::retranscode::{check_format, retranscode};
+use super::{json_error, AppState};
+use crate::downloader::Priority;
+use crate::storage::{
+    save_migration, unix_timestamp, AudioCodec, Migration, MigrationFailure, MigrationStatus,
+    RetranscodeSource,
+};
+use crate::transcode::AudioFormat;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+
+/// The current or last migration, and whether a task is working on it.
+pub(super) struct Migrations {
+    pub(super) current: RwLock<Option<Migration>>,
+    /// Still set while a cancelled migration finishes the tracks it had started
+    active: AtomicBool,
+}
+
+impl Migrations {
+    pub(super) fn new(current: Option<Migration>) -> Self {
+        Migrations {
+            current: RwLock::new(current),
+            active: AtomicBool::new(false),
+        }
+    }
+
+    /// Marks the migration as being worked on; `false` if a task already is.
+    pub(super) fn claim(&self) -> bool {
+        !self.active.swap(true, Ordering::SeqCst)
+    }
+}
+
+/// Settings left out are the ones new downloads are converted with.
+#[derive(Debug, Deserialize)]
+pub(super) struct MigrationRequest {
+    codec: Option<AudioCodec>,
+    /// kbit/s
+    bitrate: Option<u32>,
+    /// Seconds
+    segment_duration: Option<u32>,
+    source: Option<RetranscodeSource>,
+    /// Tracks converted at the same time, 1 by default
+    concurrency: Option<usize>,
+}
+
+fn target(migration: &Migration) -> AudioFormat {
+    AudioFormat {
+        codec: migration.codec,
+        bitrate: migration.bitrate,
+        segment_duration: migration.segment_duration,
+    }
+}
+
+/// Tracks still to convert: not in the target format yet, and not failed before.
+async fn remaining(state: &AppState, migration: &Migration) -> Vec<String> {
+    let audio = target(migration);
+    let failed: HashSet<&str> = migration
+        .failed
+        .iter()
+        .map(|failure| failure.track_id.as_str())
+        .collect();
+    let cache = state.hls_cache.read().await;
+    let mut remaining: Vec<(&String, &str)> = cache
+        .iter()
+        .filter(|(id, session)| {
+            // Overlay tracks are left to their own library's migration
+            !session.read_only && AudioFormat::of(session) != audio && !failed.contains(id.as_str())
+        })
+        .map(|(id, session)| (id, session.title.as_str()))
+        .collect();
+    remaining.sort_by_key(|&(_, title)| title);
+    remaining.into_iter().map(|(id, _)| id.clone()).collect()
+}
+
+async fn progress(state: &AppState, migration: &Migration) -> serde_json::Value {
+    let mut body = serde_json::to_value(migration).unwrap_or_default();
+    body["remaining"] = remaining(state, migration).await.len().into();
+    body
+}
+
+async fn save(state: &AppState, migration: &Migration) {
+    if let Err(e) = save_migration(&state.cache_dir, migration).await {
+        eprintln!("Warning: Failed to save migration progress: {}", e);
+    }
+}
+
+pub(super) async fn migration_status(State(state): State<AppState>) -> Response {
+    match state.migrations.current.read().await.as_ref() {
+        Some(migration) => Json(progress(&state, migration).await).into_response(),
+        None => json_error("No migration has been started", StatusCode::NOT_FOUND),
+    }
+}
+
+/// Starts converting every track that isn't in the requested format yet.
+pub(super) async fn start_migration(
+    State(state): State<AppState>,
+    Json(request): Json<MigrationRequest>,
+) -> Response {
+    let defaults = state.ingest_options.transcode.audio;
+    let audio = AudioFormat {
+        codec: request.codec.unwrap_or(defaults.codec),
+        bitrate: request.bitrate.unwrap_or(defaults.bitrate),
+        segment_duration: request
+            .segment_duration
+            .unwrap_or(defaults.segment_duration),
+    };
+    if let Err(message) = check_format(&audio) {
+        return json_error(&message, StatusCode::BAD_REQUEST);
+    }
+    let concurrency = request.concurrency.unwrap_or(1);
+    if concurrency == 0 {
+        return json_error("concurrency must be at least 1", StatusCode::BAD_REQUEST);
+    }
+
+    let migration = {
+        let mut current = state.migrations.current.write().await;
+        if !state.migrations.claim() {
+            return json_error("A migration is already running", StatusCode::CONFLICT);
+        }
+        let migration = Migration {
+            codec: audio.codec,
+            bitrate: audio.bitrate,
+            segment_duration: audio.segment_duration,
+            source: request.source,
+            concurrency,
+            status: MigrationStatus::Running,
+            started_at: unix_timestamp(),
+            finished_at: None,
+            converted: 0,
+            failed: Vec::new(),
+        };
+        *current = Some(migration.clone());
+        migration
+    };
+    save(&state, &migration).await;
+
+    let body = progress(&state, &migration).await;
+    tokio::spawn(run_migration(state));
+    (StatusCode::ACCEPTED, Json(body)).into_response()
+}
+
+/// Stops starting new conversions; the ones in progress still finish.
+pub(super) async fn cancel_migration(State(state): State<AppState>) -> Response {
+    let mut current = state.migrations.current.write().await;
+    let Some(migration) = current
+        .as_mut()
+        .filter(|migration| migration.status == MigrationStatus::Running)
+    else {
+        return json_error("No migration is running", StatusCode::CONFLICT);
+    };
+    migration.status = MigrationStatus::Cancelled;
+    let migration = migration.clone();
+    drop(current);
+
+    save(&state, &migration).await;
+    Json(progress(&state, &migration).await).into_response()
+}
+
+/// Continues a cancelled migration with the tracks it hasn't converted yet.
+pub(super) async fn resume_migration(State(state): State<AppState>) -> Response {
+    let mut current = state.migrations.current.write().await;
+    let Some(migration) = current
+        .as_mut()
+        .filter(|migration| migration.status == MigrationStatus::Cancelled)
+    else {
+        return json_error("No cancelled migration to resume", StatusCode::CONFLICT);
+    };
+    if !state.migrations.claim() {
+        return json_error(
+            "The migration is still finishing its current tracks",
+            StatusCode::CONFLICT,
+        );
+    }
+    migration.status = MigrationStatus::Running;
+    migration.finished_at = None;
+    let migration = migration.clone();
+    drop(current);
+
+    save(&state, &migration).await;
+    let body = progress(&state, &migration).await;
+    tokio::spawn(run_migration(state));
+    (StatusCode::ACCEPTED, Json(body)).into_response()
+}
+
+/// Whether the migration is still meant to go on.
+async fn is_running(state: &AppState) -> bool {
+    state
+        .migrations
+        .current
+        .read()
+        .await
+        .as_ref()
+        .is_some_and(|migration| migration.status == MigrationStatus::Running)
+}
+
+/// Works through the current migration until every track is converted or it is
+/// cancelled; the caller must have claimed it. Conversions run at low priority, so
+/// downloads go first.
+pub(super) async fn run_migration(state: AppState) {
+    let Some(migration) = state.migrations.current.read().await.clone() else {
+        state.migrations.active.store(false, Ordering::SeqCst);
+        return;
+    };
+    let audio = target(&migration);
+    let mut queue = remaining(&state, &migration).await.into_iter();
+    println!(
+        "🔁 Migrating {} tracks to {} {} kbit/s in {} second segments",
+        queue.len(),
+        audio.codec.name(),
+        audio.bitrate,
+        audio.segment_duration
+    );
+
+    let mut tasks = JoinSet::new();
+    loop {
+        while tasks.len() < migration.concurrency.max(1) && is_running(&state).await {
+            let Some(track_id) = queue.next() else {
+                break;
+            };
+            // Looked up again in case the track changed since the migration started
+            let session = state.hls_cache.read().await.get(&track_id).cloned();
+            let Some(session) = session.filter(|s| AudioFormat::of(s) != audio) else {
+                continue;
+            };
+            let state = state.clone();
+            tasks.spawn(async move {
+                let result = retranscode(
+                    &state,
+                    &track_id,
+                    &session,
+                    audio,
+                    migration.source,
+                    Priority::Low,
+                )
+                .await;
+                (track_id, session.title, result)
+            });
+        }
+
+        let Some(joined) = tasks.join_next().await else {
+            break;
+        };
+        let Ok((track_id, title, result)) = joined else {
+            continue;
+        };
+        let mut current = state.migrations.current.write().await;
+        let Some(migration) = current.as_mut() else {
+            break;
+        };
+        match result {
+            Ok(()) => migration.converted += 1,
+            Err(error) => migration.failed.push(MigrationFailure {
+                track_id,
+                title,
+                error: error.message().to_string(),
+            }),
+        }
+        let migration = migration.clone();
+        drop(current);
+        save(&state, &migration).await;
+    }
+
+    let mut current = state.migrations.current.write().await;
+    if let Some(migration) = current
+        .as_mut()
+        .filter(|migration| migration.status == MigrationStatus::Running)
+    {
+        migration.status = MigrationStatus::Finished;
+        migration.finished_at = Some(unix_timestamp());
+        println!(
+            "✓ Migration finished: {} tracks converted, {} failed",
+            migration.converted,
+            migration.failed.len()
+        );
+        let migration = migration.clone();
+        drop(current);
+        save(&state, &migration).await;
+    }
+    state.migrations.active.store(false, Ordering::SeqCst);
+}
@@ -0,0 +1,2266 @@
+//! HTTP API routes.
+
+mod artwork;
+mod backups;
+mod batch;
+mod bot;
+mod bundle;
+mod changes;
+mod collections;
+mod compression;
+mod embed;
+mod error;
+mod export;
+mod frontend;
+mod hls;
+mod idempotency;
+mod keys;
+mod libraries;
+mod lyrics;
+mod merge;
+mod migration;
+mod notes;
+mod playback;
+mod ratings;
+mod retranscode;
+mod search;
+mod shares;
+mod sources;
+mod stats;
+mod subscriptions;
+mod tasks;
+mod transcodes;
+mod visibility;
+mod ytdlp;
+
+use crate::acoustid::AcoustId;
+use crate::backup::{first_backup_in, scheduled_backup, BackupTarget, Backups};
+use crate::config::{Config, HlsProfile};
+use crate::connections::{ConnectionInfo, Connections};
+use crate::content_hash::FileHashes;
+use crate::downloader::{
+    download_from_url, expire_download_history, expire_downloads, parse_clip_url, read_source_info,
+    DownloadError, DownloadFailure, DownloadLimits, DownloadQueue, DownloadRequest,
+    DownloadResponse, DownloadStatus, ExtractorOptions, IngestOptions, Priority, Refresh,
+    SourceInfo, UrlPolicy,
+};
+#[cfg(feature = "fake-tools")]
+use crate::fake_tools::FakeTools;
+use crate::federation::{
+    apply_overlay, evict_upstream, load_overlays, rescan_overlays, sync, upstream_tracks, Upstream,
+};
+use crate::library::{
+    group_by_album, group_by_artist, track_info, AlbumInfo, ArtistInfo, TrackInfo,
+};
+use crate::media_tools::{MediaTools, SystemTools};
+use crate::notifications::Notifications;
+use crate::party::{
+    expire_idle_rooms, handle_party_socket, PartyRoom, PartyRooms, PartyState, MAX_ROOMS,
+};
+use crate::playback::{device_key, NowPlayingMap};
+use crate::process::{ProcessLimits, CGROUP_PROCS};
+use crate::radio::{radio_response, run_radio, Radio};
+use crate::removals::{run_removals, Removals};
+use crate::scheduler::Scheduler;
+use crate::segment_cache::{warm_up, SegmentCache, SegmentCacheStats};
+use crate::sources::{check_sources, SourceChecker};
+use crate::storage::{
+    load_collections, load_devices, load_downloads, load_history, load_hls_cache, load_key_grants,
+    load_migration, load_positions, load_queues, load_ratings, load_share_links,
+    load_source_checks, load_subscriptions, load_transcodes, run_saver, save_collections,
+    save_downloads, save_history, save_queues, save_ratings, unix_timestamp, CacheDirs, Chapter,
+    Collections, Devices, History, HlsCache, HlsSession, Identification, IdentificationStatus,
+    KeyGrants, MigrationStatus, PlayQueues, Ratings, ResumePositions, ShareLinks, SourceChecks,
+    Subscriptions, TrackStore, SECONDS_PER_DAY,
+};
+use crate::systemd;
+use crate::throttle::Throttle;
+use crate::tools::{ffmpeg_version, version_line, ytdlp_version, MIN_FFMPEG, MIN_YTDLP};
+use crate::transcode::{AudioFormat, TranscodeOptions, TranscodeSlots};
+use crate::webhooks::Webhooks;
+use artwork::{delete_artwork, set_artwork, MAX_ARTWORK_SIZE};
+use axum::body::Bytes;
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{
+    ConnectInfo, DefaultBodyLimit, FromRequestParts, Path, Query, RawQuery, Request, State,
+};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, patch, post, put};
+use axum::{middleware, Json, Router};
+use backups::{create_backup, list_backups};
+use batch::{batch_status, create_batch, finish_batches, DownloadBatches};
+use bot::{run_telegram_bot, TelegramBot};
+use bundle::{collection_bundle, track_bundle};
+use changes::{list_changes, ChangeFeed};
+use collections::{
+    add_track_to_collection, create_collection, delete_collection, import_collection,
+    list_collections, remove_track_from_collection, rename_collection,
+};
+use compression::{compressed_body, compressed_json, negotiate};
+use embed::{embed_page, oembed};
+use error::{json_error, structured_errors, ApiError, ErrorCode};
+use export::{
+    export_collection_m3u, export_collection_xspf, export_library_m3u, export_library_xspf,
+};
+use frontend::with_frontend;
+use hls::{
+    etag_matches, not_modified, resolve_slug, serve_chapters_json, serve_chapters_vtt,
+    serve_hls_playlist, serve_hls_segment, serve_key, serve_lyrics, serve_lyrics_playlist,
+    serve_master_playlist, serve_thumbnail, serve_video_playlist, short_link,
+};
+use idempotency::{idempotency_key, replay, IDEMPOTENCY_KEY};
+use keys::{create_grant, list_grants, revoke_grant};
+use libraries::{libraries_router, LIBRARY_HEADER};
+use lyrics::{delete_lyrics, get_lyrics, set_lyrics};
+use merge::merge_library;
+use migration::{
+    cancel_migration, migration_status, resume_migration, run_migration, start_migration,
+    Migrations,
+};
+use notes::{add_note, delete_note, edit_note, list_notes};
+use playback::{
+    append_to_queue, clear_queue, delete_device, get_device, get_queue, insert_next_in_queue,
+    list_devices, list_now_playing, move_in_queue, register_device, remove_from_queue,
+    report_now_playing, update_position,
+};
+use ratings::{apply_ratings, rate_track};
+use retranscode::retranscode_track;
+use search::download_query;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shares::{create_share, list_shares, open_share, revoke_share};
+use sources::{list_sources, start_source_check};
+use stats::{overview, set_listen_count, top_tracks, track_daily};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use subscriptions::{
+    check_subscription_now, check_subscriptions, import_opml, list_subscriptions, subscribe,
+    unsubscribe,
+};
+use tasks::{list_tasks, run_task};
+use tokio::fs::create_dir_all;
+use tokio::process::Command;
+use tokio::sync::{broadcast, Notify, RwLock};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use transcodes::{list_transcodes, run_job, transcode_status, Transcodes};
+use uuid::Uuid;
+use visibility::{
+    hide_private_tracks, is_visible, public_tracks, require_admin, set_visibility, Owner,
+};
+
+/// Shared state handed to every handler.
+#[derive(Clone)]
+struct AppState {
+    cache_dir: Arc<PathBuf>,
+    hls_cache: HlsCache,
+    resume_positions: ResumePositions,
+    ratings: Ratings,
+    devices: Devices,
+    play_queues: PlayQueues,
+    collections: Collections,
+    /// Tracks and collections changed, for clients syncing incrementally
+    changes: Arc<ChangeFeed>,
+    download_queue: DownloadQueue,
+    /// Woken whenever a download job finishes
+    download_finished: Arc<Notify>,
+    download_batches: DownloadBatches,
+    history: History,
+    party_rooms: PartyRooms,
+    now_playing: NowPlayingMap,
+    radio: Arc<Radio>,
+    radio_enabled: bool,
+    segment_cache: Arc<SegmentCache>,
+    /// Segments read ahead of each segment request
+    prefetch_segments: usize,
+    /// Content hashes of segments, named in playlist URLs
+    file_hashes: Arc<FileHashes>,
+    throttle: Arc<Throttle>,
+    connections: Arc<Connections>,
+    /// Deleted tracks whose segments are kept for their listeners
+    removals: Arc<Removals>,
+    /// Keep no client IPs or identifiers in stats
+    private_stats: bool,
+    hls_profile: HlsProfile,
+    /// Grants access to segment keys, and hands out per-device grants
+    key_token: Option<Arc<str>>,
+    key_grants: KeyGrants,
+    /// Shows private tracks; without it every track is visible
+    owner_token: Option<Arc<str>>,
+    /// Links that let guests play single tracks
+    share_links: ShareLinks,
+    /// Whether tracks' origin URLs still resolve
+    sources: Arc<SourceChecker>,
+    subscriptions: Subscriptions,
+    /// Library-wide conversion to another audio format
+    migrations: Arc<Migrations>,
+    /// Set while another instance's library is merged in
+    merging: Arc<AtomicBool>,
+    /// Conversions of tracks' segments, queued for the transcode slots
+    transcodes: Arc<Transcodes>,
+    readonly: bool,
+    webhooks: Arc<Webhooks>,
+    ingest_options: Arc<IngestOptions>,
+    upstream: Option<Arc<Upstream>>,
+    /// Scheduled metadata backups, when a backup target is configured
+    backups: Option<Arc<Backups>>,
+    /// Runs the periodic jobs
+    scheduler: Arc<Scheduler>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PartyJoinQuery {
+    token: Option<String>,
+}
+
+/// Filters for /api/downloads.
+#[derive(Debug, Deserialize)]
+struct DownloadsQuery {
+    /// Only jobs created at or after this Unix time
+    since: Option<u64>,
+    /// Only jobs created before this Unix time
+    until: Option<u64>,
+    batch_id: Option<String>,
+    /// Comma separated statuses, e.g. "queued,downloading"
+    status: Option<String>,
+}
+
+/// The track listing is tagged with the library revision; clients revalidate it.
+const LISTING_CACHE_CONTROL: &str = "no-cache";
+
+/// How often the upstream cache is checked against --upstream-cache-mb.
+const UPSTREAM_EVICTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Every status a download job goes through, in order.
+const DOWNLOAD_STATUSES: [&str; 6] = [
+    "queued",
+    "downloading",
+    "waiting",
+    "converting",
+    "ready",
+    "error",
+];
+
+/// Filters for /api/tracks: tempo and key for DJs, rating and recently added.
+#[derive(Debug, Deserialize)]
+struct TrackQuery {
+    min_bpm: Option<f64>,
+    max_bpm: Option<f64>,
+    /// Key name ("A minor") or Camelot code ("8A")
+    key: Option<String>,
+    /// Minimum average star rating; unrated tracks are left out
+    min_rating: Option<f64>,
+    /// Only tracks added at or after this Unix time
+    added_after: Option<u64>,
+    sort: Option<TrackSort>,
+    /// Keep at most this many tracks, after sorting
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TrackSort {
+    Bpm,
+    /// Camelot wheel order, so harmonically compatible keys sit together
+    Key,
+    /// Newest first
+    Added,
+}
+
+/// The fields of a track the query looks at.
+struct TrackFields<'a> {
+    bpm: Option<f64>,
+    key: Option<&'a str>,
+    camelot: Option<&'a str>,
+    rating: Option<f64>,
+    date_added: Option<u64>,
+}
+
+impl TrackQuery {
+    fn is_empty(&self) -> bool {
+        self.min_bpm.is_none()
+            && self.max_bpm.is_none()
+            && self.key.is_none()
+            && self.min_rating.is_none()
+            && self.added_after.is_none()
+            && self.sort.is_none()
+            && self.limit.is_none()
+    }
+
+    /// Filters, sorts and truncates `tracks` given a way to read their fields.
+    fn apply<T>(&self, tracks: &mut Vec<T>, fields: for<'a> fn(&'a T) -> TrackFields<'a>) {
+        tracks.retain(|track| {
+            let track = fields(track);
+            let in_range = |bpm: Option<f64>, bound: Option<f64>, keep: fn(f64, f64) -> bool| {
+                bound.is_none_or(|bound| bpm.is_some_and(|bpm| keep(bpm, bound)))
+            };
+            in_range(track.bpm, self.min_bpm, |bpm, min| bpm >= min)
+                && in_range(track.bpm, self.max_bpm, |bpm, max| bpm <= max)
+                && in_range(track.rating, self.min_rating, |rating, min| rating >= min)
+                && self
+                    .added_after
+                    .is_none_or(|after| track.date_added.is_some_and(|added| added >= after))
+                && self.key.as_deref().is_none_or(|wanted| {
+                    track.key.is_some_and(|k| k.eq_ignore_ascii_case(wanted))
+                        || track
+                            .camelot
+                            .is_some_and(|c| c.eq_ignore_ascii_case(wanted))
+                })
+        });
+
+        // Tracks without analysis go last
+        match self.sort {
+            Some(TrackSort::Bpm) => tracks.sort_by(|a, b| {
+                let (a, b) = (fields(a).bpm, fields(b).bpm);
+                b.is_some()
+                    .cmp(&a.is_some())
+                    .then(a.unwrap_or(0.0).total_cmp(&b.unwrap_or(0.0)))
+            }),
+            Some(TrackSort::Key) => tracks.sort_by_key(|track| {
+                let camelot = fields(track).camelot.unwrap_or("");
+                let (number, letter) = camelot.split_at(camelot.len().saturating_sub(1));
+                (
+                    number.parse::<u32>().unwrap_or(u32::MAX),
+                    letter.to_string(),
+                )
+            }),
+            Some(TrackSort::Added) => {
+                tracks.sort_by_key(|track| std::cmp::Reverse(fields(track).date_added))
+            }
+            None => {}
+        }
+
+        if let Some(limit) = self.limit {
+            tracks.truncate(limit);
+        }
+    }
+}
+
+/// The `X-Device-Id` request header, if the client sent one.
+struct DeviceId(Option<String>);
+
+impl<S: Send + Sync> FromRequestParts<S> for DeviceId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(DeviceId(
+            header_str(&parts.headers, "x-device-id").map(str::to_string),
+        ))
+    }
+}
+
+/// The peer address of the connection, when the server was started with connect info.
+struct ClientIp(Option<IpAddr>);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientIp(
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip()),
+        ))
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Starts the server and runs until the process is stopped.
+pub async fn run(config: Config) {
+    let tools = match fake_tools(&config) {
+        Some(tools) => {
+            println!("🧪 Making tracks with the fake tools; yt-dlp and ffmpeg aren't run");
+            tools
+        }
+        None => {
+            check_tools(&config).await;
+            Arc::new(SystemTools)
+        }
+    };
+
+    // Processes that can't join the cgroup fail to start, so a wrong path shows now
+    if let Some(cgroup) = &config.process_cgroup {
+        if !cgroup.join(CGROUP_PROCS).is_file() {
+            eprintln!(
+                "❌ {} is not a cgroup v2 directory (it has no {})",
+                cgroup.display(),
+                CGROUP_PROCS
+            );
+            std::process::exit(1);
+        }
+        println!(
+            "✓ Starting ffmpeg, yt-dlp and hooks in cgroup {}",
+            cgroup.display()
+        );
+    }
+
+    // Encrypted segments are pointless if anyone can fetch the keys
+    if config.encrypt_segments && config.key_token.is_none() {
+        eprintln!("❌ --encrypt-segments needs a --key-token to protect the keys with");
+        std::process::exit(1);
+    }
+
+    let shared = Shared {
+        segment_cache: Arc::new(SegmentCache::new(config.segment_cache_mb * 1024 * 1024)),
+        throttle: Arc::new(Throttle::new(
+            config.stream_rate_limit,
+            config.client_rate_limit,
+        )),
+        transcode_slots: TranscodeSlots::new(config.max_transcodes.max(1)),
+        tools,
+    };
+    let mut libraries = Vec::new();
+    if config.libraries.is_empty() {
+        let state = library_state(&config, config.cache.dirs(), None, &shared).await;
+        libraries.push((None, state));
+    } else {
+        for (i, name) in config.libraries.iter().enumerate() {
+            if config.libraries[..i].contains(name) {
+                eprintln!("❌ The library '{}' is given twice", name);
+                std::process::exit(1);
+            }
+            let cache_dirs = config.cache.library_dirs(name);
+            let state = library_state(&config, cache_dirs, Some(name), &shared).await;
+            libraries.push((Some(name.clone()), state));
+        }
+    }
+
+    println!("🎵 Starting HLS music server on port {}", config.port);
+    if config.radio {
+        println!("📻 Radio stream enabled at /stream.mp3");
+    }
+    if config.segment_cache_mb > 0 {
+        println!("🧠 Segment cache: {} MiB", config.segment_cache_mb);
+    }
+    if let Some(rate) = config.stream_rate_limit {
+        println!("🚦 Streaming limited to {} bytes/s overall", rate);
+    }
+    if let Some(rate) = config.client_rate_limit {
+        println!("🚦 Streaming limited to {} bytes/s per client", rate);
+    }
+    if config.private_stats {
+        println!("🕶️ Private stats: no client IPs or identifiers are kept");
+    }
+    if config.encrypt_segments {
+        println!("🔐 New tracks are AES-128 encrypted; keys require the key token");
+        if config.timed_metadata {
+            eprintln!("Warning: Encrypted tracks can't carry timed metadata; --timed-metadata has no effect");
+        }
+    }
+    if config.single_file_hls {
+        println!("📦 New tracks are written as single-file HLS");
+        if config.timed_metadata {
+            eprintln!("Warning: Single-file tracks can't carry timed metadata; --timed-metadata has no effect");
+        }
+    }
+    if config.hls_profile == HlsProfile::Legacy {
+        println!("📺 Serving legacy-compatible HLS playlists");
+    }
+    if let Some(dir) = &config.static_dir {
+        println!("🖥️ Serving frontend from {}", dir.display());
+    }
+    if config.readonly {
+        println!("Running in READONLY mode - adding/removing tracks disabled");
+    } else {
+        println!("🔗 URL downloads enabled with yt-dlp");
+    }
+
+    // A bot token can only be polled once, so with several libraries the bot adds to
+    // the first
+    if let Some(token) = &config.telegram_token {
+        let (name, state) = &libraries[0];
+        let mut public_url = config.public_url.clone().unwrap_or_default();
+        if let Some(name) = name {
+            public_url = format!("{}/libraries/{}", public_url.trim_end_matches('/'), name);
+        }
+        if state.readonly {
+            println!("🤖 Telegram bot disabled in readonly mode");
+        } else {
+            let bot = TelegramBot::new(
+                &config.telegram_api_url,
+                token,
+                config.telegram_chats.clone(),
+                &public_url,
+            );
+            if config.telegram_chats.is_empty() {
+                println!("🤖 Telegram bot running; allow chats with --telegram-chat");
+            } else {
+                println!("🤖 Telegram bot adding links sent to it");
+            }
+            if let Some(name) = name {
+                println!("🤖 Telegram bot adding to the library '{}'", name);
+            }
+            tokio::spawn(run_telegram_bot(state.clone(), bot));
+        }
+    }
+
+    let cors = match cors_layer(&config.cors_origins, config.cors_credentials) {
+        Ok(cors) => cors,
+        Err(e) => {
+            eprintln!("Invalid CORS configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let stores: Vec<HlsCache> = libraries
+        .iter()
+        .map(|(_, state)| Arc::clone(&state.hls_cache))
+        .collect();
+    let app = if config.libraries.is_empty() {
+        let (_, state) = libraries.remove(0);
+        router(state, config.static_dir.clone())
+    } else {
+        libraries_router(
+            libraries
+                .into_iter()
+                .filter_map(|(name, state)| Some((name?, router(state, config.static_dir.clone()))))
+                .collect(),
+        )
+    }
+    .layer(cors);
+
+    let activated = systemd::activated_listener()
+        .and_then(|listener| listener.map(tokio::net::TcpListener::from_std).transpose());
+    let listener = match activated {
+        Ok(Some(listener)) => {
+            println!("🔌 Listening on the socket passed by systemd; --port is ignored");
+            listener
+        }
+        Ok(None) => match tokio::net::TcpListener::bind(("0.0.0.0", config.port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Failed to bind port {}: {}", config.port, e);
+                std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to use the socket passed by systemd: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(timeout) = systemd::watchdog_interval() {
+        tokio::spawn(systemd::run_watchdog(timeout));
+    }
+    systemd::notify("READY=1");
+
+    // Changes still waiting for --save-delay are written before exiting
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        systemd::notify("STOPPING=1");
+        for store in &stores {
+            store.flush().await;
+        }
+        std::process::exit(0);
+    });
+
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(listener, app).await {
+        eprintln!("Server error: {}", e);
+    }
+}
+
+/// Checks that ffmpeg is installed and recent enough, and warns about yt-dlp and
+/// fpcalc missing.
+async fn check_tools(config: &Config) {
+    // Check if ffmpeg is available, and recent enough
+    let ffmpeg = config.ffmpeg_path.display();
+    let Some(line) = version_line(&config.ffmpeg_path, "-version").await else {
+        eprintln!(
+            "❌ FFmpeg not found at {}! Please install FFmpeg for HLS streaming.",
+            ffmpeg
+        );
+        eprintln!("Ubuntu/Debian: sudo apt install ffmpeg");
+        eprintln!("macOS: brew install ffmpeg");
+        eprintln!("Or point --ffmpeg-path at the ffmpeg binary");
+        std::process::exit(1);
+    };
+    match ffmpeg_version(&line) {
+        Some((major, minor)) if (major, minor) < MIN_FFMPEG => {
+            eprintln!(
+                "❌ FFmpeg {}.{} ({}) is too old; at least {}.{} is needed",
+                major, minor, ffmpeg, MIN_FFMPEG.0, MIN_FFMPEG.1
+            );
+            std::process::exit(1);
+        }
+        Some((major, minor)) => println!("✓ FFmpeg {}.{} found", major, minor),
+        // Builds from git don't say which release they follow
+        None => println!("✓ FFmpeg found ({})", line),
+    }
+
+    // Check if yt-dlp is available; old releases break on sites that changed since
+    let ytdlp = config.ytdlp_path.display();
+    match version_line(&config.ytdlp_path, "--version").await {
+        Some(line) => match ytdlp_version(&line) {
+            Some(version) if version < MIN_YTDLP => {
+                let (year, month, day) = MIN_YTDLP;
+                eprintln!(
+                    "❌ yt-dlp {} ({}) is too old; at least {}.{:02}.{:02} is needed",
+                    line, ytdlp, year, month, day
+                );
+                eprintln!("Update with: yt-dlp -U, or pip install -U yt-dlp");
+                std::process::exit(1);
+            }
+            _ => println!("✓ yt-dlp {} found", line),
+        },
+        None => {
+            eprintln!(
+                "⚠️  yt-dlp not found at {}! Only direct links to audio files can be downloaded.",
+                ytdlp
+            );
+            eprintln!(
+                "Install with: pip install yt-dlp, or point --ytdlp-path at the yt-dlp binary"
+            );
+        }
+    }
+
+    // fpcalc is only needed when AcoustID identification is enabled
+    if config.acoustid_key.is_some() {
+        match Command::new("fpcalc").arg("-version").output().await {
+            Ok(output) if output.status.success() => {
+                println!("✓ fpcalc found, identifying untitled tracks with AcoustID");
+            }
+            _ => {
+                eprintln!("⚠️  fpcalc not found! Untitled tracks will not be identified.");
+                eprintln!("Ubuntu/Debian: sudo apt install libchromaprint-tools");
+                eprintln!("macOS: brew install chromaprint");
+            }
+        }
+    }
+}
+
+/// The fake tools, when `--fake-tools` is given.
+#[cfg(feature = "fake-tools")]
+fn fake_tools(config: &Config) -> Option<Arc<dyn MediaTools>> {
+    config
+        .fake_tools
+        .then(|| Arc::new(FakeTools) as Arc<dyn MediaTools>)
+}
+
+/// Servers built without the fake tools always run the real ones.
+#[cfg(not(feature = "fake-tools"))]
+fn fake_tools(_config: &Config) -> Option<Arc<dyn MediaTools>> {
+    None
+}
+
+/// Process-wide limits the libraries hosted with `--library` share: the transcode
+/// slots, the segment cache and the streaming rate limits, and the tools tracks are
+/// made with.
+struct Shared {
+    segment_cache: Arc<SegmentCache>,
+    throttle: Arc<Throttle>,
+    transcode_slots: TranscodeSlots,
+    tools: Arc<dyn MediaTools>,
+}
+
+/// Loads a library from `cache_dirs` and starts its background jobs. `name` is set for
+/// the libraries hosted with `--library`.
+async fn library_state(
+    config: &Config,
+    cache_dirs: CacheDirs,
+    name: Option<&str>,
+    shared: &Shared,
+) -> AppState {
+    let cache_dir = Arc::new(cache_dirs.primary().to_path_buf());
+
+    // Create cache directories
+    for dir in cache_dirs.all() {
+        if let Err(e) = create_dir_all(dir).await {
+            eprintln!("Failed to create cache directory {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    // Load existing HLS cache from disk
+    // Starting with an empty library would overwrite the file on the first save
+    let mut initial_cache = match load_hls_cache(&cache_dirs).await {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Failed to load HLS cache: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let initial_collections = match load_collections(&cache_dir).await {
+        Ok(collections) => collections,
+        Err(e) => {
+            eprintln!("Warning: Failed to load collections: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let initial_queues = match load_queues(&cache_dir).await {
+        Ok(queues) => queues,
+        Err(e) => {
+            eprintln!("Warning: Failed to load play queues: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let initial_devices = match load_devices(&cache_dir).await {
+        Ok(devices) => devices,
+        Err(e) => {
+            eprintln!("Warning: Failed to load devices: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let initial_positions = match load_positions(&cache_dir).await {
+        Ok(positions) => positions,
+        Err(e) => {
+            eprintln!("Warning: Failed to load resume positions: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let initial_ratings = match load_ratings(&cache_dir).await {
+        Ok(ratings) => ratings,
+        Err(e) => {
+            eprintln!("Warning: Failed to load ratings: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let initial_grants = match load_key_grants(&cache_dir).await {
+        Ok(grants) => grants,
+        Err(e) => {
+            eprintln!("Warning: Failed to load key grants: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let initial_share_links = match load_share_links(&cache_dir).await {
+        Ok(links) => links,
+        Err(e) => {
+            eprintln!("Warning: Failed to load share links: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let initial_source_checks = match load_source_checks(&cache_dir).await {
+        Ok(checks) => checks,
+        Err(e) => {
+            eprintln!("Warning: Failed to load source checks: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let initial_subscriptions = match load_subscriptions(&cache_dir).await {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            eprintln!("Warning: Failed to load subscriptions: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let download_history = Duration::from_secs(config.download_history_days * SECONDS_PER_DAY);
+    let mut initial_downloads = match load_downloads(&cache_dir).await {
+        Ok(downloads) => downloads,
+        Err(e) => {
+            eprintln!("Warning: Failed to load download history: {}", e);
+            HashMap::new()
+        }
+    };
+    expire_downloads(&mut initial_downloads, download_history);
+
+    let initial_transcodes = match load_transcodes(&cache_dir).await {
+        Ok(transcodes) => transcodes,
+        Err(e) => {
+            eprintln!("Warning: Failed to load transcode jobs: {}", e);
+            HashMap::new()
+        }
+    };
+
+    let initial_migration = match load_migration(&cache_dir).await {
+        Ok(migration) => migration,
+        Err(e) => {
+            eprintln!("Warning: Failed to load migration progress: {}", e);
+            None
+        }
+    };
+
+    let mut initial_history = match load_history(&cache_dir).await {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("Warning: Failed to load listening history: {}", e);
+            Vec::new()
+        }
+    };
+    if config.private_stats && initial_history.iter().any(|play| play.listener.is_some()) {
+        for play in &mut initial_history {
+            play.listener = None;
+        }
+        match save_history(&cache_dir, &initial_history).await {
+            Ok(()) => println!("🕶️ Removed listener IDs from the listening history"),
+            Err(e) => eprintln!("Warning: Failed to save listening history: {}", e),
+        }
+    }
+
+    // Tracks of overlay libraries sit next to this library's, read-only
+    if !config.overlay_paths.is_empty() {
+        let (added, _) = apply_overlay(
+            &mut initial_cache,
+            load_overlays(&config.overlay_paths).await,
+        );
+        println!("✓ Loaded {} tracks from overlay libraries", added);
+    }
+
+    let hls_cache: HlsCache = Arc::new(TrackStore::new(
+        cache_dir.to_path_buf(),
+        initial_cache,
+        Duration::from_secs(config.save_delay),
+        config.save_batch.max(1),
+    ));
+    tokio::spawn(run_saver(Arc::clone(&hls_cache)));
+    let history: History = Arc::new(RwLock::new(initial_history));
+    let resume_positions: ResumePositions = Arc::new(RwLock::new(initial_positions));
+    let ratings: Ratings = Arc::new(RwLock::new(initial_ratings));
+    let devices: Devices = Arc::new(RwLock::new(initial_devices));
+    let key_grants: KeyGrants = Arc::new(RwLock::new(initial_grants));
+    let share_links: ShareLinks = Arc::new(RwLock::new(initial_share_links));
+    let source_checks: SourceChecks = Arc::new(RwLock::new(initial_source_checks));
+    let process_limits = ProcessLimits::from_config(config);
+    let sources = Arc::new(SourceChecker::new(
+        source_checks,
+        config.ytdlp_path.clone(),
+        process_limits.clone(),
+    ));
+    let subscriptions: Subscriptions = Arc::new(RwLock::new(initial_subscriptions));
+    let play_queues: PlayQueues = Arc::new(RwLock::new(initial_queues));
+    let collections: Collections = Arc::new(RwLock::new(initial_collections));
+    let changes = Arc::new(ChangeFeed::new(
+        &*hls_cache.read().await,
+        hls_cache.revision(),
+        &*collections.read().await,
+    ));
+    let download_queue: DownloadQueue = Arc::new(RwLock::new(initial_downloads));
+    let scheduler = Arc::new(Scheduler::new());
+    {
+        let download_queue = Arc::clone(&download_queue);
+        let cache_dir = Arc::clone(&cache_dir);
+        scheduler.add(
+            "download_history",
+            "Drops finished downloads older than --download-history-days from the history",
+            Duration::from_secs(3600),
+            Duration::from_secs(3600),
+            move || {
+                let download_queue = Arc::clone(&download_queue);
+                let cache_dir = Arc::clone(&cache_dir);
+                async move {
+                    expire_download_history(&download_queue, &cache_dir, download_history).await
+                }
+            },
+        );
+    }
+    let download_batches: DownloadBatches = Arc::new(RwLock::new(HashMap::new()));
+    let party_rooms: PartyRooms = Arc::new(RwLock::new(HashMap::new()));
+    let now_playing: NowPlayingMap = Arc::new(RwLock::new(HashMap::new()));
+    let radio = Arc::new(Radio {
+        chunks: broadcast::channel(64).0,
+        on_air: RwLock::new(None),
+        ffmpeg_path: config.ffmpeg_path.clone(),
+        limits: process_limits.without_timeout(),
+    });
+
+    let notifications = match Notifications::from_config(config) {
+        Ok(notifications) => notifications,
+        Err(e) => {
+            eprintln!("Invalid notification configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(description) = notifications.describe() {
+        println!("📣 Pushing {}", description);
+    }
+    let webhooks = Arc::new(Webhooks {
+        urls: config.webhook_urls.clone(),
+        secret: config.webhook_secret.clone(),
+        client: reqwest::Client::new(),
+        notifications,
+    });
+    let removals = Arc::new(Removals::new());
+    let url_policy = UrlPolicy {
+        allowed: config.allowed_domains.clone(),
+        blocked: config.blocked_domains.clone(),
+    };
+    let ingest_options = Arc::new(IngestOptions {
+        cache_dirs: cache_dirs.clone(),
+        max_tracks: config.max_tracks,
+        hooks: config.hooks.clone(),
+        transcode_slots: shared.transcode_slots.clone(),
+        transcode: TranscodeOptions {
+            ffmpeg_path: config.ffmpeg_path.clone(),
+            input_args: config
+                .ffmpeg_input_args
+                .as_deref()
+                .map(|args| args.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+            nice: config.transcode_nice,
+            ionice: config.transcode_ionice,
+            limits: process_limits.clone(),
+            clip: None,
+            single_file: config.single_file_hls,
+            encrypt: config.encrypt_segments,
+            audio: AudioFormat {
+                codec: config.audio_codec,
+                bitrate: config.audio_bitrate,
+                segment_duration: config.segment_duration,
+            },
+        },
+        limits: DownloadLimits {
+            timeout: config.download_timeout.map(Duration::from_secs),
+            max_filesize: config.max_filesize.clone(),
+            max_duration: config.max_duration,
+            limit_rate: config.limit_rate,
+        },
+        url_policy: url_policy.clone(),
+        extractor: ExtractorOptions {
+            ytdlp_path: config.ytdlp_path.clone(),
+            args: config.extractor_args.clone(),
+            allowed: config.allowed_extractor_args.clone(),
+            format: config.download_format.clone(),
+        },
+        processes: process_limits,
+        acoustid: config.acoustid_key.clone().map(|api_key| AcoustId {
+            api_key,
+            min_score: config.acoustid_min_score,
+            client: reqwest::Client::new(),
+        }),
+        timed_metadata: config.timed_metadata,
+        client: reqwest::Client::builder()
+            .redirect(url_policy.redirect_policy())
+            .build()
+            .expect("HTTP client"),
+        tools: Arc::clone(&shared.tools),
+        removals: Arc::clone(&removals),
+    });
+
+    let upstream = config.upstream.as_ref().map(|base_url| {
+        Arc::new(Upstream::new(
+            base_url,
+            cache_dir.join("upstream"),
+            config.upstream_cache_mb.map(|mb| mb * 1024 * 1024),
+        ))
+    });
+    if let Some(upstream) = &upstream {
+        println!("🌐 Serving upstream tracks from {}", upstream.base_url);
+        if let Some(mb) = config.upstream_cache_mb {
+            println!("🌐 Keeping at most {} MiB of upstream tracks", mb);
+            let upstream = Arc::clone(upstream);
+            scheduler.add(
+                "upstream_eviction",
+                "Deletes the least recently played upstream tracks beyond --upstream-cache-mb",
+                UPSTREAM_EVICTION_INTERVAL,
+                Duration::ZERO,
+                move || {
+                    let upstream = Arc::clone(&upstream);
+                    async move { evict_upstream(&upstream).await }
+                },
+            );
+        }
+    }
+
+    if !config.overlay_paths.is_empty() {
+        for dir in &config.overlay_paths {
+            println!("📚 Overlay library: {}", dir.display());
+        }
+        let dirs = Arc::new(config.overlay_paths.clone());
+        let hls_cache = Arc::clone(&hls_cache);
+        let interval = Duration::from_secs(config.overlay_interval.max(1));
+        scheduler.add(
+            "overlay_rescan",
+            "Re-reads the overlay libraries",
+            interval,
+            interval,
+            move || {
+                let dirs = Arc::clone(&dirs);
+                let hls_cache = Arc::clone(&hls_cache);
+                async move {
+                    rescan_overlays(&dirs, &hls_cache).await;
+                    Ok(())
+                }
+            },
+        );
+    }
+
+    if let Some(primary) = config.sync_from.clone() {
+        println!("🔄 Mirroring tracks from {}", primary);
+        let client = reqwest::Client::new();
+        let primary: Arc<str> = Arc::from(primary);
+        let cache_dirs = cache_dirs.clone();
+        let hls_cache = Arc::clone(&hls_cache);
+        scheduler.add(
+            "sync",
+            "Pulls new tracks from the --sync-from server",
+            Duration::from_secs(config.sync_interval.max(1)),
+            Duration::ZERO,
+            move || {
+                let client = client.clone();
+                let primary = Arc::clone(&primary);
+                let cache_dirs = cache_dirs.clone();
+                let hls_cache = Arc::clone(&hls_cache);
+                async move { sync(&client, &primary, &cache_dirs, &hls_cache).await }
+            },
+        );
+    }
+
+    if let Some(hours) = config.source_check_interval {
+        println!("🔎 Checking track sources every {} hours", hours.max(1));
+        let sources = Arc::clone(&sources);
+        let hls_cache = Arc::clone(&hls_cache);
+        let cache_dir = Arc::clone(&cache_dir);
+        let webhooks = Arc::clone(&webhooks);
+        scheduler.add(
+            "source_check",
+            "Checks that tracks' origin URLs still resolve",
+            Duration::from_secs(hours.max(1) * 60 * 60),
+            Duration::ZERO,
+            move || {
+                let sources = Arc::clone(&sources);
+                let hls_cache = Arc::clone(&hls_cache);
+                let cache_dir = Arc::clone(&cache_dir);
+                let webhooks = Arc::clone(&webhooks);
+                async move {
+                    check_sources(&sources, &hls_cache, &cache_dir, &webhooks).await;
+                    Ok(())
+                }
+            },
+        );
+    }
+
+    let backups = match BackupTarget::from_config(config) {
+        Ok(target) => target.map(|target| {
+            let target = match name {
+                Some(name) => target.for_library(name),
+                None => target,
+            };
+            Arc::new(Backups::new(
+                target,
+                cache_dir.to_path_buf(),
+                config.backup_include.clone(),
+                Duration::from_secs(config.backup_interval.max(1) * 60 * 60),
+                config.backup_keep,
+            ))
+        }),
+        Err(e) => {
+            eprintln!("Invalid backup configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(backups) = &backups {
+        println!(
+            "💾 Backing up library metadata to {} every {} hours, keeping {}",
+            backups.location(),
+            config.backup_interval.max(1),
+            backups.keep
+        );
+        // Listing the backups may take a round trip to S3, which startup doesn't wait for
+        let backups = Arc::clone(backups);
+        let scheduler = Arc::clone(&scheduler);
+        tokio::spawn(async move {
+            let first_run = first_backup_in(&backups).await;
+            scheduler.add(
+                "backup",
+                "Backs up the library metadata",
+                backups.interval,
+                first_run,
+                move || {
+                    let backups = Arc::clone(&backups);
+                    async move { scheduled_backup(&backups).await }
+                },
+            );
+        });
+    }
+
+    if config.radio {
+        tokio::spawn(run_radio(
+            Arc::clone(&hls_cache),
+            Arc::clone(&radio),
+            config.radio_order,
+            config.owner_token.is_some(),
+        ));
+    }
+
+    match name {
+        Some(name) => println!("📚 Library '{}': {}", name, cache_dir.display()),
+        None => println!("🗄️ HLS cache directory: {}", cache_dir.display()),
+    }
+    for dir in &cache_dirs.all()[1..] {
+        println!("🗄️ Extra directory for track segments: {}", dir.display());
+    }
+    let state = AppState {
+        cache_dir,
+        hls_cache,
+        resume_positions,
+        ratings,
+        devices,
+        play_queues,
+        collections,
+        changes,
+        download_queue,
+        download_finished: Arc::new(Notify::new()),
+        download_batches,
+        history,
+        party_rooms,
+        now_playing,
+        radio,
+        radio_enabled: config.radio,
+        segment_cache: Arc::clone(&shared.segment_cache),
+        prefetch_segments: config.prefetch_segments,
+        file_hashes: Arc::new(FileHashes::default()),
+        throttle: Arc::clone(&shared.throttle),
+        connections: Arc::new(Connections::new(config.private_stats)),
+        removals: Arc::clone(&removals),
+        private_stats: config.private_stats,
+        hls_profile: config.hls_profile,
+        key_token: config.key_token.as_deref().map(Arc::from),
+        key_grants,
+        owner_token: config.owner_token.as_deref().map(Arc::from),
+        share_links,
+        sources,
+        subscriptions,
+        migrations: Arc::new(Migrations::new(initial_migration)),
+        merging: Arc::new(AtomicBool::new(false)),
+        transcodes: Arc::new(Transcodes::new(initial_transcodes, download_history)),
+        readonly: config.readonly,
+        webhooks,
+        ingest_options,
+        upstream,
+        backups,
+        scheduler,
+    };
+    tokio::spawn(run_removals(
+        Arc::clone(&state.removals),
+        Arc::clone(&state.connections),
+        Arc::clone(&state.segment_cache),
+    ));
+    if config.warm_tracks > 0 && state.segment_cache.enabled() {
+        let segment_cache = Arc::clone(&state.segment_cache);
+        let mut hottest: Vec<HlsSession> = state.hls_cache.read().await.values().cloned().collect();
+        hottest.sort_by_key(|session| std::cmp::Reverse(session.listen_count));
+        hottest.truncate(config.warm_tracks);
+        tokio::spawn(async move {
+            let warmed = warm_up(&segment_cache, hottest).await;
+            println!(
+                "🔥 Warmed the segment cache with the first segments of {} tracks",
+                warmed
+            );
+        });
+    }
+    if !state.readonly {
+        let interrupted = state.transcodes.interrupted().await;
+        if !interrupted.is_empty() {
+            println!(
+                "🔁 Resuming {} interrupted transcode jobs",
+                interrupted.len()
+            );
+        }
+        for job_id in interrupted {
+            let state = state.clone();
+            tokio::spawn(async move { run_job(&state, &job_id).await });
+        }
+    }
+    let interrupted = state
+        .migrations
+        .current
+        .read()
+        .await
+        .as_ref()
+        .is_some_and(|migration| migration.status == MigrationStatus::Running);
+    if interrupted && !state.readonly && state.migrations.claim() {
+        tokio::spawn(run_migration(state.clone()));
+    }
+    if !state.readonly {
+        let hours = config.subscription_interval.max(1);
+        println!("🎙️ Checking subscriptions every {} hours", hours);
+        let subscriptions_state = state.clone();
+        state.scheduler.add(
+            "subscriptions",
+            "Downloads the new episodes and uploads of the subscriptions",
+            Duration::from_secs(hours * 60 * 60),
+            Duration::ZERO,
+            move || {
+                let state = subscriptions_state.clone();
+                async move { check_subscriptions(&state).await }
+            },
+        );
+    }
+    state
+}
+
+/// Resolves on SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => eprintln!("Warning: Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// CORS policy for the configured origins; "*" allows any origin.
+fn cors_layer(origins: &[String], credentials: bool) -> Result<CorsLayer, String> {
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        if credentials {
+            return Err("--cors-credentials requires explicit --cors-origins".to_string());
+        }
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin.trim_end_matches('/'))
+                    .map_err(|_| format!("invalid origin \"{}\"", origin))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_credentials(credentials)
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::RANGE,
+            header::AUTHORIZATION,
+            HeaderName::from_static("x-device-id"),
+            HeaderName::from_static("x-client-id"),
+            HeaderName::from_static(LIBRARY_HEADER),
+            HeaderName::from_static(IDEMPOTENCY_KEY),
+            header::IF_NONE_MATCH,
+        ])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+            Method::OPTIONS,
+        ]))
+}
+
+/// Builds the route table. Routes that modify the library are only added in readwrite mode.
+fn router(state: AppState, static_dir: Option<PathBuf>) -> Router {
+    let mut router = Router::new()
+        // Library
+        .route("/api/tracks", get(list_tracks))
+        .route("/api/artists", get(list_artists))
+        .route("/api/albums", get(list_albums))
+        .route("/api/collections", get(list_collections))
+        .route("/api/changes", get(list_changes))
+        .route(
+            "/api/collections/{id}/export.m3u8",
+            get(export_collection_m3u),
+        )
+        .route(
+            "/api/collections/{id}/export.xspf",
+            get(export_collection_xspf),
+        )
+        // Playlists are collections; the aliases are where playlist tools look
+        .route(
+            "/api/playlists/{id}/export.m3u8",
+            get(export_collection_m3u),
+        )
+        .route(
+            "/api/playlists/{id}/export.xspf",
+            get(export_collection_xspf),
+        )
+        .route("/api/collections/{id}/bundle", get(collection_bundle))
+        .route("/api/export.m3u8", get(export_library_m3u))
+        .route("/api/export.xspf", get(export_library_xspf))
+        // Devices - the returned id is used as X-Device-Id
+        .route("/api/devices", get(list_devices).post(register_device))
+        .route("/api/devices/{id}", get(get_device).delete(delete_device))
+        // Play queue, scoped by the X-Device-Id header
+        .route(
+            "/api/queue",
+            get(get_queue)
+                .post(append_to_queue)
+                .put(move_in_queue)
+                .delete(clear_queue),
+        )
+        .route("/api/queue/next", post(insert_next_in_queue))
+        .route("/api/queue/{index}", delete(remove_from_queue))
+        // Playback reporting and resume positions
+        .route("/api/tracks/{id}/chapters", get(track_chapters))
+        .route("/api/tracks/{id}/source", get(track_source))
+        .route("/api/tracks/{id}/identification", get(track_identification))
+        .route("/api/tracks/{id}/notes", get(list_notes))
+        .route("/api/tracks/{id}/lyrics", get(get_lyrics))
+        .route("/api/tracks/{id}/bundle", get(track_bundle))
+        .route("/api/tracks/{id}/position", put(update_position))
+        .route("/api/tracks/{id}/rating", post(rate_track))
+        .route(
+            "/api/now-playing",
+            get(list_now_playing).post(report_now_playing),
+        )
+        // Radio and listening parties
+        .route("/stream.mp3", get(radio_stream))
+        .route("/api/radio", get(radio_status))
+        .route("/api/party/{id}/ws", get(party_socket))
+        .route("/api/mode", get(mode))
+        .route("/api/stats/segment-cache", get(segment_cache_stats))
+        .route("/api/stats/top-tracks", get(top_tracks))
+        .route("/api/stats/overview", get(overview))
+        .route("/api/stats/tracks/{id}/daily", get(track_daily))
+        .route("/api/sources", get(list_sources))
+        // HLS streaming
+        .route("/api/hls/{session}/playlist.m3u8", get(serve_hls_playlist))
+        .route("/api/hls/{session}/master.m3u8", get(serve_master_playlist))
+        .route("/api/hls/{session}/video.m3u8", get(serve_video_playlist))
+        .route("/t/{key}", get(short_link))
+        .route("/s/{token}", get(open_share))
+        .route("/embed/{key}", get(embed_page))
+        .route("/api/oembed", get(oembed))
+        .route("/api/hls/{session}/lyrics.m3u8", get(serve_lyrics_playlist))
+        .route("/api/hls/{session}/lyrics.vtt", get(serve_lyrics))
+        .route("/api/hls/{session}/chapters.vtt", get(serve_chapters_vtt))
+        .route("/api/hls/{session}/chapters.json", get(serve_chapters_json))
+        .route("/api/hls/{session}/key", get(serve_key))
+        .route("/api/keys/grants", get(list_grants).post(create_grant))
+        .route("/api/keys/grants/{id}", delete(revoke_grant))
+        .route("/api/tracks/{id}/share", post(create_share))
+        .route("/api/shares", get(list_shares))
+        .route("/api/shares/{id}", delete(revoke_share))
+        .route("/api/hls/{session}/thumbnail/{size}", get(serve_thumbnail))
+        .route("/api/hls/{session}/{segment}", get(serve_hls_segment));
+
+    // Changes to the library and its admin; a readonly server answers them with
+    // `readonly_mode` rather than not knowing them
+    let mut mutating = Router::new()
+        .route("/api/tracks/{id}", delete(delete_track))
+        .route("/api/tracks/{id}/refresh", post(refresh_track))
+        .route("/api/tracks/{id}/retranscode", post(retranscode_track))
+        .route("/api/tracks/{id}/pin", post(pin_track).delete(unpin_track))
+        .route("/api/tracks/{id}/visibility", put(set_visibility))
+        .route("/api/sources/check", post(start_source_check))
+        .route("/api/tracks/{id}/listen_count", patch(set_listen_count))
+        .route(
+            "/api/tracks/{id}/identification",
+            put(confirm_identification),
+        )
+        .route("/api/tracks/{id}/notes", post(add_note))
+        .route(
+            "/api/tracks/{id}/lyrics",
+            put(set_lyrics).delete(delete_lyrics),
+        )
+        .route(
+            "/api/tracks/{id}/artwork",
+            put(set_artwork)
+                .delete(delete_artwork)
+                .layer(DefaultBodyLimit::max(MAX_ARTWORK_SIZE)),
+        )
+        .route(
+            "/api/tracks/{id}/notes/{note_id}",
+            put(edit_note).delete(delete_note),
+        )
+        .route("/api/download", post(download))
+        .route("/api/download/query", post(download_query))
+        .route("/api/downloads", get(list_downloads))
+        .route("/api/download/{id}", get(download_status))
+        .route("/api/transcodes", get(list_transcodes))
+        .route("/api/transcodes/{id}", get(transcode_status))
+        .route("/api/download/batch", post(create_batch))
+        .route("/api/download/batch/{id}", get(batch_status))
+        .route("/api/collections", post(create_collection))
+        .route("/api/collections/import", post(import_collection))
+        // Playlists are collections; the alias is where playlist tools look
+        .route("/api/playlists/import", post(import_collection))
+        .route("/api/party", post(create_party))
+        .route(
+            "/api/subscriptions",
+            get(list_subscriptions).post(subscribe),
+        )
+        .route("/api/subscriptions/opml", post(import_opml))
+        .route("/api/subscriptions/{id}", delete(unsubscribe))
+        .route(
+            "/api/subscriptions/{id}/check",
+            post(check_subscription_now),
+        )
+        .route(
+            "/api/collections/{id}",
+            put(rename_collection).delete(delete_collection),
+        )
+        .route(
+            "/api/collections/{id}/tracks",
+            post(add_track_to_collection),
+        )
+        .route(
+            "/api/collections/{id}/tracks/{track_id}",
+            delete(remove_track_from_collection),
+        );
+    // Routes for the admin alone, who presents the owner token
+    let admin = Router::new()
+        .route("/api/admin/connections", get(admin_connections))
+        .route("/api/admin/backups", get(list_backups).post(create_backup))
+        .route("/api/admin/tasks", get(list_tasks))
+        .route("/api/admin/tasks/{name}/run", post(run_task))
+        .route(
+            "/api/admin/migration",
+            get(migration_status)
+                .post(start_migration)
+                .delete(cancel_migration),
+        )
+        .route("/api/admin/migration/resume", post(resume_migration))
+        .route("/api/admin/merge", post(merge_library))
+        .route("/api/admin/ytdlp/update", post(ytdlp::update))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin));
+    mutating = mutating.merge(admin);
+    if state.readonly {
+        mutating = mutating.route_layer(middleware::from_fn(refuse_readonly));
+    }
+    router = router.merge(mutating);
+
+    with_frontend(router, static_dir)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            hide_private_tracks,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), resolve_slug))
+        .layer(middleware::map_response(structured_errors))
+        .with_state(state)
+}
+
+async fn refuse_readonly(_request: Request, _next: Next) -> Response {
+    ApiError::new(StatusCode::FORBIDDEN, "The server is in read-only mode")
+        .with_code(ErrorCode::ReadonlyMode)
+        .into_response()
+}
+
+/// List all tracks from the HLS cache (plus the upstream's, in replica mode)
+async fn list_tracks(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Owner(owner): Owner,
+    Query(query): Query<TrackQuery>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let accept_encoding = header_str(&headers, header::ACCEPT_ENCODING.as_str());
+    let encoding = negotiate(accept_encoding);
+    let device = device_key(device_id);
+    let positions = state
+        .resume_positions
+        .read()
+        .await
+        .get(&device)
+        .cloned()
+        .unwrap_or_default();
+    let ratings = state.ratings.read().await;
+    let source_checks = state.sources.checks.read().await;
+
+    let cache = state.hls_cache.read().await;
+    let revision = state.hls_cache.revision();
+    // Upstream tracks change without the revision knowing, so replicas tag nothing
+    let etag = state.upstream.is_none().then(|| {
+        let variant = format!(
+            "{}\n{}\n{}",
+            owner,
+            raw_query.as_deref().unwrap_or_default(),
+            positions
+                .iter()
+                .collect::<BTreeMap<_, _>>()
+                .iter()
+                .map(|(id, position)| format!("{}={}", id, position))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        let variant = hex::encode(Sha256::digest(variant.as_bytes()));
+        match encoding {
+            Some(encoding) => format!("\"{}-{}-{}\"", revision, &variant[..16], encoding.name()),
+            None => format!("\"{}-{}\"", revision, &variant[..16]),
+        }
+    });
+    if let Some(etag) = &etag {
+        if etag_matches(header_str(&headers, header::IF_NONE_MATCH.as_str()), etag) {
+            return not_modified(LISTING_CACHE_CONTROL, etag);
+        }
+    }
+
+    let mut tracks: Vec<TrackInfo> = cache
+        .iter()
+        .filter(|(_, session)| is_visible(session, owner))
+        .map(|(hash, session)| {
+            let mut track = TrackInfo {
+                resume_position: positions.get(hash).copied(),
+                source_status: source_checks.get(hash).map(|check| check.status),
+                ..track_info(hash, session)
+            };
+            apply_ratings(&mut track, ratings.get(hash), &device);
+            track
+        })
+        .collect();
+    drop(cache);
+    drop(ratings);
+    drop(source_checks);
+
+    if let Some(upstream) = &state.upstream {
+        let local_ids: Vec<String> = tracks.iter().map(|t| t.id.clone()).collect();
+        let mut all: Vec<serde_json::Value> = tracks
+            .iter()
+            .filter_map(|t| serde_json::to_value(t).ok())
+            .collect();
+        all.extend(upstream_tracks(upstream, &local_ids).await);
+        if !query.is_empty() {
+            query.apply(&mut all, |track| TrackFields {
+                bpm: track["bpm"].as_f64(),
+                key: track["key"].as_str(),
+                camelot: track["camelot"].as_str(),
+                rating: track["average_rating"].as_f64(),
+                date_added: track["date_added"].as_u64(),
+            });
+        }
+        return compressed_json(&all, accept_encoding);
+    }
+
+    if !query.is_empty() {
+        query.apply(&mut tracks, |track| TrackFields {
+            bpm: track.bpm,
+            key: track.key.as_deref(),
+            camelot: track.camelot.as_deref(),
+            rating: track.average_rating,
+            date_added: Some(track.date_added),
+        });
+    }
+    let mut builder = Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, LISTING_CACHE_CONTROL);
+    if let Some(etag) = &etag {
+        builder = builder.header(header::ETAG, etag);
+    }
+    let data = Bytes::from(serde_json::to_vec(&tracks).unwrap_or_default());
+    compressed_body(builder, data, encoding)
+}
+
+/// List artists with their tracks
+async fn list_artists(State(state): State<AppState>, Owner(owner): Owner) -> Json<Vec<ArtistInfo>> {
+    let cache = state.hls_cache.read().await;
+    match owner {
+        true => Json(group_by_artist(&cache)),
+        false => Json(group_by_artist(&public_tracks(&cache))),
+    }
+}
+
+/// List albums with their tracks
+async fn list_albums(State(state): State<AppState>, Owner(owner): Owner) -> Json<Vec<AlbumInfo>> {
+    let cache = state.hls_cache.read().await;
+    match owner {
+        true => Json(group_by_album(&cache)),
+        false => Json(group_by_album(&public_tracks(&cache))),
+    }
+}
+
+/// Download from URL; responds once the track is converted and in the library
+async fn download(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<DownloadRequest>,
+) -> Response {
+    let idempotency_key = match idempotency_key(&headers) {
+        Ok(key) => key,
+        Err(error) => return error.into_response(),
+    };
+    if let Err(reason) = state.ingest_options.url_policy.check(&request.url) {
+        return ApiError::new(StatusCode::FORBIDDEN, reason)
+            .with_code(ErrorCode::UrlNotAllowed)
+            .into_response();
+    }
+    if let Err(reason) = state.ingest_options.extractor.check(&request) {
+        return json_error(&reason, StatusCode::BAD_REQUEST);
+    }
+
+    let download_id = Uuid::new_v4().to_string();
+    let key = idempotency_key.as_deref();
+    if let Err(existing) = queue_download(&state, &download_id, None, key, &request).await {
+        if let Some(key) = key {
+            if let Some(response) = replay(&state, &existing, key, &request.url).await {
+                return response;
+            }
+        }
+        return already_downloading(&existing).into_response();
+    }
+
+    // Apart from the request, so the download carries on when the client drops the
+    // connection, and a retry with the same Idempotency-Key gets its result
+    let job = {
+        let state = state.clone();
+        let download_id = download_id.clone();
+        tokio::spawn(async move { run_download(&state, &download_id, request).await })
+    };
+    match job.await {
+        Ok(Ok(response)) => Json(response).into_response(),
+        Ok(Err(error)) => download_error(&error)
+            .with_details(serde_json::json!({ "download_id": download_id }))
+            .into_response(),
+        Err(e) => json_error(
+            &format!("Download failed: {}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+    }
+}
+
+/// Re-download a track from its origin URL, keeping its id, stats and notes
+async fn refresh_track(State(state): State<AppState>, Path(track_id): Path<String>) -> Response {
+    let Some(session) = state.hls_cache.read().await.get(&track_id).cloned() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if session.read_only {
+        return read_only_track().into_response();
+    }
+
+    let audio = AudioFormat::of(&session);
+    match redownload(&state, track_id, &session, audio, Priority::Normal).await {
+        Ok(response) => Json(response).into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+/// Downloads a track again from its origin URL and converts it to `audio`; the new
+/// segments replace the old ones once they are ready.
+async fn redownload(
+    state: &AppState,
+    track_id: String,
+    session: &HlsSession,
+    audio: AudioFormat,
+    priority: Priority,
+) -> Result<DownloadResponse, ApiError> {
+    if session.origin_url.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Track has no origin URL to refresh from",
+        ));
+    }
+    let (url, clip) = parse_clip_url(&session.origin_url);
+    if let Err(reason) = state.ingest_options.url_policy.check(url) {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, reason));
+    }
+
+    let request = DownloadRequest {
+        url: url.to_string(),
+        title: Some(session.title.clone()),
+        artist: session.artist.clone(),
+        album: session.album.clone(),
+        split_chapters: false,
+        video: session.has_video,
+        priority,
+        limit_rate: None,
+        extractor_args: Vec::new(),
+        format: None,
+        refresh: Some(Refresh {
+            track_id,
+            origin_url: session.origin_url.clone(),
+            clip,
+            audio,
+        }),
+    };
+    let download_id = Uuid::new_v4().to_string();
+    if let Err(existing) = queue_download(state, &download_id, None, None, &request).await {
+        return Err(already_downloading(&existing));
+    }
+
+    match run_download(state, &download_id, request).await {
+        Ok(response) => {
+            // The old segments are gone from disk; drop them from memory too
+            state.segment_cache.remove_dir(&session.segments_dir);
+            Ok(response)
+        }
+        Err(error) => {
+            Err(download_error(&error)
+                .with_details(serde_json::json!({ "download_id": download_id })))
+        }
+    }
+}
+
+/// The response to a failed download.
+fn download_error(error: &DownloadError) -> ApiError {
+    let (status, code) = match error.failure {
+        DownloadFailure::UrlNotAllowed => (StatusCode::FORBIDDEN, ErrorCode::UrlNotAllowed),
+        DownloadFailure::InvalidRequest => (StatusCode::BAD_REQUEST, ErrorCode::BadRequest),
+        DownloadFailure::DuplicateTrack => (StatusCode::CONFLICT, ErrorCode::DuplicateTrack),
+        DownloadFailure::QuotaExceeded => {
+            (StatusCode::INSUFFICIENT_STORAGE, ErrorCode::QuotaExceeded)
+        }
+        DownloadFailure::SourceTooLarge => {
+            (StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::SourceTooLarge)
+        }
+        DownloadFailure::DownloadTimedOut => {
+            (StatusCode::GATEWAY_TIMEOUT, ErrorCode::DownloadTimedOut)
+        }
+        DownloadFailure::SourceUnavailable => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::SourceUnavailable,
+        ),
+        DownloadFailure::DownloadFailed => {
+            (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::DownloadFailed)
+        }
+        DownloadFailure::TranscodeFailed => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::TranscodeFailed,
+        ),
+    };
+    ApiError::new(status, error.message.clone()).with_code(code)
+}
+
+/// Adds a download job in the queued state. While another job for the same URL is
+/// unfinished, or a job was created with the same idempotency key, nothing is added
+/// and that job's id is returned instead.
+async fn queue_download(
+    state: &AppState,
+    download_id: &str,
+    batch_id: Option<&str>,
+    idempotency_key: Option<&str>,
+    request: &DownloadRequest,
+) -> Result<(), String> {
+    let url = match &request.refresh {
+        Some(refresh) => &refresh.origin_url,
+        None => &request.url,
+    };
+    let mut queue = state.download_queue.write().await;
+    if let Some(existing) = idempotency_key.and_then(|key| {
+        queue
+            .values()
+            .find(|job| job.idempotency_key.as_deref() == Some(key))
+    }) {
+        return Err(existing.id.clone());
+    }
+    if let Some(existing) = queue
+        .values()
+        .find(|job| job.url == *url && job.status != "ready" && job.status != "error")
+    {
+        return Err(existing.id.clone());
+    }
+    queue.insert(
+        download_id.to_string(),
+        DownloadStatus {
+            id: download_id.to_string(),
+            url: url.clone(),
+            batch_id: batch_id.map(str::to_string),
+            priority: request.priority,
+            created_at: unix_timestamp(),
+            finished_at: None,
+            status: "queued".to_string(),
+            stage: None,
+            progress: Some("Starting download...".to_string()),
+            percent: None,
+            bytes_downloaded: None,
+            total_bytes: None,
+            speed: None,
+            eta_seconds: None,
+            error: None,
+            error_code: None,
+            idempotency_key: idempotency_key.map(str::to_string),
+            session: None,
+        },
+    );
+    Ok(())
+}
+
+/// A second request for a URL that is still being downloaded.
+fn already_downloading(download_id: &str) -> ApiError {
+    ApiError::new(StatusCode::CONFLICT, "This URL is already being downloaded")
+        .with_code(ErrorCode::AlreadyDownloading)
+        .with_details(serde_json::json!({ "download_id": download_id }))
+}
+
+/// Runs a queued download job. Failures are recorded in its status and sent to webhooks.
+async fn run_download(
+    state: &AppState,
+    download_id: &str,
+    request: DownloadRequest,
+) -> Result<DownloadResponse, DownloadError> {
+    let url = request.url.clone();
+    let error = match download_from_url(
+        request,
+        Arc::clone(&state.hls_cache),
+        Arc::clone(&state.download_queue),
+        download_id,
+        &state.webhooks,
+        &state.ingest_options,
+    )
+    .await
+    {
+        Ok(response) => {
+            finish_download(state, download_id, None).await;
+            return Ok(response);
+        }
+        Err(e) => DownloadError::from_boxed(e),
+    };
+    finish_download(state, download_id, Some(&error)).await;
+
+    state.webhooks.emit(
+        "download_failed",
+        serde_json::json!({
+            "download_id": download_id,
+            "url": url,
+            "error": error.message,
+            "error_code": error.failure,
+        }),
+    );
+
+    Err(error)
+}
+
+/// Marks a job as finished, failed with `error` when given, and writes the download
+/// history.
+async fn finish_download(state: &AppState, download_id: &str, error: Option<&DownloadError>) {
+    let mut queue = state.download_queue.write().await;
+    let Some(status) = queue.get_mut(download_id) else {
+        return;
+    };
+    status.finished_at = Some(unix_timestamp());
+    status.stage = None;
+    status.speed = None;
+    status.eta_seconds = None;
+    if let Some(error) = error {
+        status.status = "error".to_string();
+        status.error = Some(error.message.clone());
+        status.error_code = Some(error.failure);
+    }
+    if let Err(e) = save_downloads(&state.cache_dir, &queue).await {
+        eprintln!("Warning: Failed to save download history: {}", e);
+    }
+    drop(queue);
+    state.download_finished.notify_waiters();
+    finish_batches(state, download_id).await;
+}
+
+/// All download jobs, running ones and the history, oldest first
+async fn list_downloads(
+    State(state): State<AppState>,
+    Query(query): Query<DownloadsQuery>,
+) -> Response {
+    let statuses: Option<Vec<&str>> = query
+        .status
+        .as_deref()
+        .map(|status| status.split(',').map(str::trim).collect());
+    if let Some(unknown) = statuses
+        .iter()
+        .flatten()
+        .find(|status| !DOWNLOAD_STATUSES.contains(status))
+    {
+        return json_error(
+            &format!(
+                "Unknown status \"{}\"; expected one of {}",
+                unknown,
+                DOWNLOAD_STATUSES.join(", ")
+            ),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    let queue = state.download_queue.read().await;
+    let mut jobs: Vec<DownloadStatus> = queue
+        .values()
+        .filter(|job| {
+            statuses
+                .as_ref()
+                .is_none_or(|statuses| statuses.contains(&job.status.as_str()))
+        })
+        .filter(|job| query.since.is_none_or(|since| job.created_at >= since))
+        .filter(|job| query.until.is_none_or(|until| job.created_at < until))
+        .filter(|job| {
+            query
+                .batch_id
+                .as_ref()
+                .is_none_or(|batch_id| job.batch_id.as_ref() == Some(batch_id))
+        })
+        .cloned()
+        .collect();
+    jobs.sort_by_key(|job| job.created_at);
+    Json(jobs).into_response()
+}
+
+/// Download status check
+async fn download_status(
+    State(state): State<AppState>,
+    Path(download_id): Path<String>,
+) -> Result<Json<DownloadStatus>, StatusCode> {
+    let queue = state.download_queue.read().await;
+    queue
+        .get(&download_id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Chapter marks of a track, empty when the source had none
+async fn track_chapters(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+) -> Result<Json<Vec<Chapter>>, StatusCode> {
+    let cache = state.hls_cache.read().await;
+    cache
+        .get(&track_id)
+        .map(|session| Json(session.chapters.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Uploader, upload date, description and page of the download a track came from
+async fn track_source(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+) -> Result<Json<SourceInfo>, StatusCode> {
+    let segments_dir = {
+        let cache = state.hls_cache.read().await;
+        cache
+            .get(&track_id)
+            .map(|session| session.segments_dir.clone())
+    }
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    match read_source_info(&segments_dir).await {
+        Ok(Some(source)) => Ok(Json(source)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            eprintln!("Warning: Failed to read source info: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// The AcoustID match applied to a track, if it was identified
+async fn track_identification(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+) -> Result<Json<Identification>, StatusCode> {
+    let cache = state.hls_cache.read().await;
+    cache
+        .get(&track_id)
+        .and_then(|session| session.identification.clone())
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize)]
+struct ConfirmIdentification {
+    confirmed: bool,
+}
+
+/// Confirms or rejects a pending AcoustID match; rejecting restores the previous tags
+async fn confirm_identification(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+    Json(request): Json<ConfirmIdentification>,
+) -> Response {
+    let identification = {
+        let mut cache = state.hls_cache.write().await;
+        let Some(session) = cache.get_mut(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        let Some(identification) = session.identification.as_mut() else {
+            return json_error("Track was not identified", StatusCode::NOT_FOUND);
+        };
+        if identification.status != IdentificationStatus::Pending {
+            return json_error(
+                "Identification was already confirmed or rejected",
+                StatusCode::CONFLICT,
+            );
+        }
+
+        if request.confirmed {
+            identification.status = IdentificationStatus::Confirmed;
+        } else {
+            identification.status = IdentificationStatus::Rejected;
+            session.title = identification.previous_title.clone();
+            session.artist = identification.previous_artist.clone();
+            session.album = identification.previous_album.clone();
+        }
+        identification.clone()
+    };
+
+    state.hls_cache.changed();
+
+    Json(identification).into_response()
+}
+
+/// Delete a track along with its segments, collection entries and queue entries
+async fn delete_track(State(state): State<AppState>, Path(track_id): Path<String>) -> Response {
+    // Find and remove the session from cache
+    let session = {
+        let mut cache = state.hls_cache.write().await;
+        match cache.get(&track_id) {
+            Some(session) if session.read_only => return read_only_track().into_response(),
+            _ => cache.remove(&track_id),
+        }
+    };
+    let Some(session) = session else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Delete the segments directory, unless someone is streaming the track: then it
+    // goes once they stop, instead of their player failing mid-track
+    let listeners = state.connections.listeners(&session.id);
+    if listeners > 0 {
+        println!(
+            "🗑️ Keeping the segments of '{}' until its {} listener(s) stop",
+            session.title, listeners
+        );
+        state
+            .removals
+            .add(&session.id, Some(session.segments_dir.clone()));
+    } else {
+        state.removals.add(&session.id, None);
+        state.segment_cache.remove_dir(&session.segments_dir);
+        if session.segments_dir.exists() {
+            if let Err(e) = tokio::fs::remove_dir_all(&session.segments_dir).await {
+                eprintln!("Warning: Failed to delete segments dir: {}", e);
+            }
+        }
+    }
+
+    state.hls_cache.changed();
+
+    // Drop the track from any collections it belonged to
+    {
+        let mut collections = state.collections.write().await;
+        for collection in collections.values_mut() {
+            collection.track_ids.retain(|id| *id != track_id);
+        }
+        if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+            eprintln!("Warning: Failed to save collections: {}", e);
+        }
+    }
+
+    // ...and from every device's play queue
+    {
+        let mut queues = state.play_queues.write().await;
+        for queue in queues.values_mut() {
+            queue.retain(|id| *id != track_id);
+        }
+        if let Err(e) = save_queues(&state.cache_dir, &queues).await {
+            eprintln!("Warning: Failed to save play queues: {}", e);
+        }
+    }
+
+    // ...and forget its ratings
+    {
+        let mut ratings = state.ratings.write().await;
+        if ratings.remove(&track_id).is_some() {
+            if let Err(e) = save_ratings(&state.cache_dir, &ratings).await {
+                eprintln!("Warning: Failed to save ratings: {}", e);
+            }
+        }
+    }
+
+    state.webhooks.emit(
+        "track_deleted",
+        serde_json::json!({ "id": track_id, "title": session.title }),
+    );
+
+    Json(serde_json::json!({
+        "success": true,
+        "message": format!("Track '{}' deleted", session.title),
+        "active_listeners": listeners
+    }))
+    .into_response()
+}
+
+/// Tracks of overlay libraries belong to another instance, which alone changes them.
+fn read_only_track() -> ApiError {
+    ApiError::new(
+        StatusCode::FORBIDDEN,
+        "Track belongs to a read-only overlay library",
+    )
+    .with_code(ErrorCode::ReadOnlyTrack)
+}
+
+/// Pin a track so it is never evicted or purged automatically
+async fn pin_track(State(state): State<AppState>, Path(track_id): Path<String>) -> Response {
+    set_pinned(&state, track_id, true).await
+}
+
+/// Let a track be evicted or purged again
+async fn unpin_track(State(state): State<AppState>, Path(track_id): Path<String>) -> Response {
+    set_pinned(&state, track_id, false).await
+}
+
+async fn set_pinned(state: &AppState, track_id: String, pinned: bool) -> Response {
+    let track = {
+        let mut cache = state.hls_cache.write().await;
+        let Some(session) = cache.get_mut(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        if session.pinned == pinned {
+            return Json(track_info(&track_id, session)).into_response();
+        }
+        session.pinned = pinned;
+        track_info(&track_id, session)
+    };
+
+    state.hls_cache.changed();
+    Json(track).into_response()
+}
+
+/// Create a listening party room; the returned token grants playback control
+async fn create_party(State(state): State<AppState>) -> Response {
+    let room_id = Uuid::new_v4().to_string();
+    let host_token = Uuid::new_v4().to_string();
+    let (events, _) = broadcast::channel(64);
+
+    let mut rooms = state.party_rooms.write().await;
+    expire_idle_rooms(&mut rooms);
+    if rooms.len() >= MAX_ROOMS {
+        return json_error(
+            "Too many listening parties are open; try again later",
+            StatusCode::SERVICE_UNAVAILABLE,
+        );
+    }
+    rooms.insert(
+        room_id.clone(),
+        PartyRoom {
+            host_token: host_token.clone(),
+            state: PartyState {
+                queue: Vec::new(),
+                track_id: None,
+                position: 0.0,
+                playing: false,
+            },
+            updated_at: Instant::now(),
+            events,
+            listeners: 0,
+        },
+    );
+    drop(rooms);
+
+    Json(serde_json::json!({
+        "room_id": room_id,
+        "host_token": host_token,
+        "socket_url": format!("/api/party/{}/ws", room_id),
+    }))
+    .into_response()
+}
+
+/// Join a listening party over WebSocket
+async fn party_socket(
+    State(state): State<AppState>,
+    Path(room_id): Path<String>,
+    Query(query): Query<PartyJoinQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let is_host = {
+        let rooms = state.party_rooms.read().await;
+        match rooms.get(&room_id) {
+            Some(room) => room.is_host(query.token.as_deref()),
+            None => return Err(StatusCode::NOT_FOUND),
+        }
+    };
+
+    let party_rooms = Arc::clone(&state.party_rooms);
+    Ok(ws.on_upgrade(move |socket| handle_party_socket(socket, party_rooms, room_id, is_host)))
+}
+
+/// Continuous radio stream of the whole library
+async fn radio_stream(State(state): State<AppState>) -> Result<Response, StatusCode> {
+    if !state.radio_enabled {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(radio_response(&state.radio))
+}
+
+/// Radio status - what's on air and how many are tuned in
+async fn radio_status(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+) -> Json<serde_json::Value> {
+    let on_air = state.radio.on_air.read().await.clone();
+    let track = match on_air {
+        Some(hash) => {
+            let cache = state.hls_cache.read().await;
+            cache
+                .get(&hash)
+                .filter(|session| is_visible(session, owner))
+                .map(|session| track_info(&hash, session))
+        }
+        None => None,
+    };
+
+    Json(serde_json::json!({
+        "enabled": state.radio_enabled,
+        "stream_url": "/stream.mp3",
+        "listeners": state.radio.chunks.receiver_count(),
+        "track": track,
+    }))
+}
+
+/// Segment cache size and hit/miss counters
+async fn segment_cache_stats(State(state): State<AppState>) -> Json<SegmentCacheStats> {
+    Json(state.segment_cache.stats())
+}
+
+#[derive(Serialize)]
+struct ConnectionsResponse {
+    active: usize,
+    /// Combined bandwidth of all listed clients
+    bytes_per_second: f64,
+    connections: Vec<ClientConnection>,
+}
+
+#[derive(Serialize)]
+struct ClientConnection {
+    #[serde(flatten)]
+    connection: ConnectionInfo,
+    track_id: Option<String>,
+    title: Option<String>,
+}
+
+/// Clients that fetched segments within the last minute and what they are playing
+async fn admin_connections(State(state): State<AppState>) -> Json<ConnectionsResponse> {
+    let active = state.connections.active();
+    let connections: Vec<ClientConnection> = {
+        let cache = state.hls_cache.read().await;
+        active
+            .into_iter()
+            .map(|connection| {
+                let track = cache
+                    .iter()
+                    .find(|(_, session)| session.id == connection.session_id);
+                ClientConnection {
+                    track_id: track.map(|(hash, _)| hash.clone()),
+                    title: track.map(|(_, session)| session.title.clone()),
+                    connection,
+                }
+            })
+            .collect()
+    };
+
+    Json(ConnectionsResponse {
+        active: connections.len(),
+        bytes_per_second: connections
+            .iter()
+            .map(|c| c.connection.bytes_per_second)
+            .sum(),
+        connections,
+    })
+}
+
+/// Returns the current mode (readonly/readwrite)
+async fn mode(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "readonly": state.readonly,
+        "mode": if state.readonly { "readonly" } else { "readwrite" }
+    }))
+}
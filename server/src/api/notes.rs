@@ -0,0 +1,134 @@
+//! Free-text notes attached to tracks.
+
+use super::{json_error, read_only_track, AppState};
+use crate::storage::{unix_timestamp, TrackNote};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use uuid::Uuid;
+
+const MAX_NOTE_LENGTH: usize = 2000;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct NoteRequest {
+    text: String,
+}
+
+impl NoteRequest {
+    /// The trimmed text, or the reason it can't be stored.
+    fn text(&self) -> Result<String, String> {
+        let text = self.text.trim();
+        if text.is_empty() {
+            Err("Note must not be empty".to_string())
+        } else if text.chars().count() > MAX_NOTE_LENGTH {
+            Err(format!(
+                "Note is longer than {} characters",
+                MAX_NOTE_LENGTH
+            ))
+        } else {
+            Ok(text.to_string())
+        }
+    }
+}
+
+pub(super) async fn list_notes(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+) -> Result<Json<Vec<TrackNote>>, StatusCode> {
+    let cache = state.hls_cache.read().await;
+    cache
+        .get(&track_id)
+        .map(|session| Json(session.notes.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+pub(super) async fn add_note(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+    Json(request): Json<NoteRequest>,
+) -> Response {
+    let text = match request.text() {
+        Ok(text) => text,
+        Err(reason) => return json_error(&reason, StatusCode::BAD_REQUEST),
+    };
+
+    let note = {
+        let mut cache = state.hls_cache.write().await;
+        let Some(session) = cache.get_mut(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        let now = unix_timestamp();
+        let note = TrackNote {
+            id: Uuid::new_v4().to_string(),
+            text,
+            created_at: now,
+            updated_at: now,
+        };
+        session.notes.push(note.clone());
+        note
+    };
+
+    state.hls_cache.changed();
+
+    (StatusCode::CREATED, Json(note)).into_response()
+}
+
+pub(super) async fn edit_note(
+    State(state): State<AppState>,
+    Path((track_id, note_id)): Path<(String, String)>,
+    Json(request): Json<NoteRequest>,
+) -> Response {
+    let text = match request.text() {
+        Ok(text) => text,
+        Err(reason) => return json_error(&reason, StatusCode::BAD_REQUEST),
+    };
+
+    let note = {
+        let mut cache = state.hls_cache.write().await;
+        let Some(session) = cache.get_mut(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        let Some(note) = session.notes.iter_mut().find(|n| n.id == note_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        note.text = text;
+        note.updated_at = unix_timestamp();
+        note.clone()
+    };
+
+    state.hls_cache.changed();
+
+    Json(note).into_response()
+}
+
+pub(super) async fn delete_note(
+    State(state): State<AppState>,
+    Path((track_id, note_id)): Path<(String, String)>,
+) -> Response {
+    {
+        let mut cache = state.hls_cache.write().await;
+        let Some(session) = cache.get_mut(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        let before = session.notes.len();
+        session.notes.retain(|n| n.id != note_id);
+        if session.notes.len() == before {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    }
+
+    state.hls_cache.changed();
+
+    Json(serde_json::json!({ "success": true })).into_response()
+}
@@ -0,0 +1,417 @@
+//! Queue, now-playing, resume position and device handlers.
+
+use super::visibility::{is_visible, Owner};
+use super::{json_error, AppState, DeviceId};
+use crate::library::{track_duration, track_info, TrackInfo};
+use crate::playback::{
+    device_key, queue_response, record_position, NowPlaying, QueueOp, NOW_PLAYING_TIMEOUT,
+};
+use crate::storage::{
+    save_devices, save_key_grants, save_positions, save_queues, unix_timestamp, Device,
+};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct QueueTrackRequest {
+    pub(super) track_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct QueueMoveRequest {
+    pub(super) from: usize,
+    pub(super) to: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct PositionRequest {
+    pub(super) position: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct NowPlayingRequest {
+    pub(super) track_id: String,
+    #[serde(default)]
+    pub(super) position: f64,
+    #[serde(default = "default_true")]
+    pub(super) playing: bool,
+}
+
+pub(super) fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct NowPlayingInfo {
+    pub(super) device: String,
+    pub(super) track: TrackInfo,
+    pub(super) position: f64,
+    pub(super) playing: bool,
+    pub(super) seconds_since_update: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct DeviceRequest {
+    pub(super) name: String,
+}
+
+pub(super) async fn get_queue(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Owner(owner): Owner,
+) -> Json<serde_json::Value> {
+    let device = device_key(device_id);
+    let track_ids = state
+        .play_queues
+        .read()
+        .await
+        .get(&device)
+        .cloned()
+        .unwrap_or_default();
+
+    let cache = state.hls_cache.read().await;
+    Json(queue_response(&device, &track_ids, &cache, |session| {
+        is_visible(session, owner)
+    }))
+}
+
+pub(super) async fn append_to_queue(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Owner(owner): Owner,
+    Json(request): Json<QueueTrackRequest>,
+) -> Result<Response, StatusCode> {
+    update_queue(state, device_id, owner, QueueOp::Append(request.track_id)).await
+}
+
+pub(super) async fn insert_next_in_queue(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Owner(owner): Owner,
+    Json(request): Json<QueueTrackRequest>,
+) -> Result<Response, StatusCode> {
+    update_queue(
+        state,
+        device_id,
+        owner,
+        QueueOp::InsertNext(request.track_id),
+    )
+    .await
+}
+
+pub(super) async fn move_in_queue(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Owner(owner): Owner,
+    Json(request): Json<QueueMoveRequest>,
+) -> Result<Response, StatusCode> {
+    let op = QueueOp::Move {
+        from: request.from,
+        to: request.to,
+    };
+    update_queue(state, device_id, owner, op).await
+}
+
+pub(super) async fn remove_from_queue(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Owner(owner): Owner,
+    Path(index): Path<usize>,
+) -> Result<Response, StatusCode> {
+    update_queue(state, device_id, owner, QueueOp::Remove(index)).await
+}
+
+pub(super) async fn clear_queue(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Owner(owner): Owner,
+) -> Result<Response, StatusCode> {
+    update_queue(state, device_id, owner, QueueOp::Clear).await
+}
+
+async fn update_queue(
+    state: AppState,
+    device_id: Option<String>,
+    owner: bool,
+    op: QueueOp,
+) -> Result<Response, StatusCode> {
+    let device = device_key(device_id);
+
+    if let QueueOp::Append(track_id) | QueueOp::InsertNext(track_id) = &op {
+        let cache = state.hls_cache.read().await;
+        if !cache
+            .get(track_id)
+            .is_some_and(|session| is_visible(session, owner))
+        {
+            return Err(StatusCode::NOT_FOUND);
+        }
+    }
+
+    let mut queues = state.play_queues.write().await;
+    let queue = queues.entry(device.clone()).or_default();
+
+    match op {
+        QueueOp::Append(track_id) => queue.push(track_id),
+        QueueOp::InsertNext(track_id) => queue.insert(0, track_id),
+        QueueOp::Remove(index) => {
+            if index >= queue.len() {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            queue.remove(index);
+        }
+        QueueOp::Move { from, to } => {
+            if from >= queue.len() || to >= queue.len() {
+                return Ok(json_error(
+                    "Queue index out of range",
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+            let track_id = queue.remove(from);
+            queue.insert(to, track_id);
+        }
+        QueueOp::Clear => queue.clear(),
+    }
+
+    let track_ids = queue.clone();
+    if let Err(e) = save_queues(&state.cache_dir, &queues).await {
+        eprintln!("Warning: Failed to save play queues: {}", e);
+    }
+
+    let cache = state.hls_cache.read().await;
+    Ok(Json(queue_response(&device, &track_ids, &cache, |session| {
+        is_visible(session, owner)
+    }))
+    .into_response())
+}
+
+pub(super) async fn report_now_playing(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Json(request): Json<NowPlayingRequest>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let duration = {
+        let cache = state.hls_cache.read().await;
+        cache.get(&request.track_id).map(track_duration)
+    };
+    let Some(duration) = duration else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let device = device_key(device_id);
+    record_position(
+        &state.resume_positions,
+        &state.cache_dir,
+        &device,
+        &request.track_id,
+        request.position,
+        duration,
+    )
+    .await;
+
+    state.now_playing.write().await.insert(
+        device,
+        NowPlaying {
+            track_id: request.track_id,
+            position: request.position.max(0.0),
+            playing: request.playing,
+            updated_at: Instant::now(),
+        },
+    );
+
+    Ok(Json(serde_json::json!({ "success": true })))
+}
+
+pub(super) async fn update_position(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Path(track_id): Path<String>,
+    Json(request): Json<PositionRequest>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let duration = {
+        let cache = state.hls_cache.read().await;
+        cache.get(&track_id).map(track_duration)
+    };
+    let Some(duration) = duration else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let device = device_key(device_id);
+    record_position(
+        &state.resume_positions,
+        &state.cache_dir,
+        &device,
+        &track_id,
+        request.position,
+        duration,
+    )
+    .await;
+
+    let resume_position = state
+        .resume_positions
+        .read()
+        .await
+        .get(&device)
+        .and_then(|p| p.get(&track_id).copied());
+
+    Ok(Json(serde_json::json!({
+        "track_id": track_id,
+        "resume_position": resume_position,
+    })))
+}
+
+pub(super) async fn list_now_playing(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+) -> Json<Vec<NowPlayingInfo>> {
+    let mut now_playing = state.now_playing.write().await;
+    now_playing.retain(|_, entry| entry.updated_at.elapsed() < NOW_PLAYING_TIMEOUT);
+
+    let cache = state.hls_cache.read().await;
+    let mut sessions: Vec<NowPlayingInfo> = now_playing
+        .iter()
+        .filter_map(|(device, entry)| {
+            let session = cache
+                .get(&entry.track_id)
+                .filter(|session| is_visible(session, owner))?;
+            let elapsed = entry.updated_at.elapsed();
+            let position = if entry.playing {
+                entry.position + elapsed.as_secs_f64()
+            } else {
+                entry.position
+            };
+
+            Some(NowPlayingInfo {
+                device: device.clone(),
+                track: track_info(&entry.track_id, session),
+                position,
+                playing: entry.playing,
+                seconds_since_update: elapsed.as_secs(),
+            })
+        })
+        .collect();
+    sessions.sort_by(|a, b| a.device.cmp(&b.device));
+
+    Json(sessions)
+}
+
+pub(super) async fn list_devices(State(state): State<AppState>) -> Json<Vec<Device>> {
+    let devices = state.devices.read().await;
+    let mut list: Vec<Device> = devices.values().cloned().collect();
+    list.sort_by_key(|d| d.registered_at);
+    Json(list)
+}
+
+pub(super) async fn register_device(
+    State(state): State<AppState>,
+    Json(request): Json<DeviceRequest>,
+) -> Response {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return json_error("Device name must not be empty", StatusCode::BAD_REQUEST);
+    }
+
+    let device = Device {
+        id: Uuid::new_v4().to_string(),
+        name: name.to_string(),
+        registered_at: unix_timestamp(),
+    };
+
+    let mut devices = state.devices.write().await;
+    devices.insert(device.id.clone(), device.clone());
+    if let Err(e) = save_devices(&state.cache_dir, &devices).await {
+        eprintln!("Warning: Failed to save devices: {}", e);
+    }
+
+    (StatusCode::CREATED, Json(device)).into_response()
+}
+
+/// A device together with its queue and current playback.
+pub(super) async fn get_device(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(device_id): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let Some(device) = state.devices.read().await.get(&device_id).cloned() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let queue = state
+        .play_queues
+        .read()
+        .await
+        .get(&device_id)
+        .cloned()
+        .unwrap_or_default();
+    let playback = state
+        .now_playing
+        .read()
+        .await
+        .get(&device_id)
+        .filter(|entry| entry.updated_at.elapsed() < NOW_PLAYING_TIMEOUT)
+        .map(|entry| {
+            serde_json::json!({
+                "track_id": entry.track_id,
+                "position": entry.position,
+                "playing": entry.playing,
+            })
+        });
+
+    let cache = state.hls_cache.read().await;
+    Ok(Json(serde_json::json!({
+        "device": device,
+        "queue": queue_response(&device_id, &queue, &cache, |session| {
+            is_visible(session, owner)
+        }),
+        "now_playing": playback,
+    })))
+}
+
+pub(super) async fn delete_device(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let device = {
+        let mut devices = state.devices.write().await;
+        let Some(device) = devices.remove(&device_id) else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        if let Err(e) = save_devices(&state.cache_dir, &devices).await {
+            eprintln!("Warning: Failed to save devices: {}", e);
+        }
+        device
+    };
+
+    let mut queues = state.play_queues.write().await;
+    if queues.remove(&device_id).is_some() {
+        if let Err(e) = save_queues(&state.cache_dir, &queues).await {
+            eprintln!("Warning: Failed to save play queues: {}", e);
+        }
+    }
+
+    let mut positions = state.resume_positions.write().await;
+    if positions.remove(&device_id).is_some() {
+        if let Err(e) = save_positions(&state.cache_dir, &positions).await {
+            eprintln!("Warning: Failed to save resume positions: {}", e);
+        }
+    }
+
+    // A removed device loses its access to segment keys
+    let mut grants = state.key_grants.write().await;
+    let before = grants.len();
+    grants.retain(|_, grant| grant.device_id != device_id);
+    if grants.len() != before {
+        if let Err(e) = save_key_grants(&state.cache_dir, &grants).await {
+            eprintln!("Warning: Failed to save key grants: {}", e);
+        }
+    }
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "message": format!("Device '{}' removed", device.name)
+    })))
+}
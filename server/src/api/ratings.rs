@@ -0,0 +1,95 @@
+//! Star ratings, kept per device like resume positions.
+
+use super::{json_error, AppState, DeviceId};
+use crate::library::TrackInfo;
+use crate::playback::device_key;
+use crate::storage::save_ratings;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct RatingRequest {
+    /// 1 to 5 stars; `null` removes the device's rating
+    rating: Option<u8>,
+}
+
+/// Average (to two decimals), count and the device's own rating of a track.
+fn rating_summary(
+    ratings: Option<&HashMap<String, u8>>,
+    device: &str,
+) -> (Option<f64>, usize, Option<u8>) {
+    let Some(ratings) = ratings.filter(|ratings| !ratings.is_empty()) else {
+        return (None, 0, None);
+    };
+    let total: u32 = ratings.values().map(|&stars| u32::from(stars)).sum();
+    let average = f64::from(total) / ratings.len() as f64;
+    (
+        Some((average * 100.0).round() / 100.0),
+        ratings.len(),
+        ratings.get(device).copied(),
+    )
+}
+
+/// Fills in a track's rating fields from its ratings by device.
+pub(super) fn apply_ratings(
+    track: &mut TrackInfo,
+    ratings: Option<&HashMap<String, u8>>,
+    device: &str,
+) {
+    (track.average_rating, track.rating_count, track.user_rating) = rating_summary(ratings, device);
+}
+
+pub(super) async fn rate_track(
+    State(state): State<AppState>,
+    DeviceId(device_id): DeviceId,
+    Path(track_id): Path<String>,
+    Json(request): Json<RatingRequest>,
+) -> Response {
+    if request
+        .rating
+        .is_some_and(|stars| !(1..=5).contains(&stars))
+    {
+        return json_error("Rating must be between 1 and 5", StatusCode::BAD_REQUEST);
+    }
+    if !state.hls_cache.read().await.contains_key(&track_id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let device = device_key(device_id);
+    let mut ratings = state.ratings.write().await;
+    match request.rating {
+        Some(stars) => {
+            ratings
+                .entry(track_id.clone())
+                .or_default()
+                .insert(device.clone(), stars);
+        }
+        None => {
+            if let Some(track_ratings) = ratings.get_mut(&track_id) {
+                track_ratings.remove(&device);
+                if track_ratings.is_empty() {
+                    ratings.remove(&track_id);
+                }
+            }
+        }
+    }
+    state.hls_cache.touch();
+    if let Err(e) = save_ratings(&state.cache_dir, &ratings).await {
+        eprintln!("Warning: Failed to save ratings: {}", e);
+    }
+
+    let (average_rating, rating_count, user_rating) =
+        rating_summary(ratings.get(&track_id), &device);
+
+    Json(serde_json::json!({
+        "track_id": track_id,
+        "user_rating": user_rating,
+        "average_rating": average_rating,
+        "rating_count": rating_count,
+    }))
+    .into_response()
+}
@@ -0,0 +1,255 @@
+//! Converting a track again with another codec, bitrate or segment length.
+
+use super::transcodes::transcode;
+use super::{json_error, read_only_track, redownload, ApiError, AppState, ErrorCode};
+use crate::downloader::Priority;
+use crate::id3::tag_track;
+use crate::library::track_info;
+use crate::media_tools::NewTrack;
+use crate::storage::{playlist_segments, AudioCodec, HlsSession, RetranscodeSource};
+use crate::transcode::{AudioFormat, TranscodeOptions};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+use tokio::sync::watch;
+
+/// Audio bitrates accepted, in kbit/s.
+pub(super) const BITRATES: RangeInclusive<u32> = 32..=320;
+/// Segment lengths accepted, in seconds.
+pub(super) const SEGMENT_DURATIONS: RangeInclusive<u32> = 1..=30;
+
+/// Settings left out keep the track's current ones.
+#[derive(Debug, Deserialize)]
+pub(super) struct RetranscodeRequest {
+    codec: Option<AudioCodec>,
+    /// kbit/s
+    bitrate: Option<u32>,
+    /// Seconds
+    segment_duration: Option<u32>,
+    /// What to convert from; by default the origin URL when the track has one
+    source: Option<RetranscodeSource>,
+}
+
+/// Rebuilds a track's audio rendition with new settings, keeping its id, stats and notes.
+pub(super) async fn retranscode_track(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+    Json(request): Json<RetranscodeRequest>,
+) -> Response {
+    let Some(session) = state.hls_cache.read().await.get(&track_id).cloned() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if session.read_only {
+        return read_only_track().into_response();
+    }
+
+    let current = AudioFormat::of(&session);
+    let audio = AudioFormat {
+        codec: request.codec.unwrap_or(current.codec),
+        bitrate: request.bitrate.unwrap_or(current.bitrate),
+        segment_duration: request.segment_duration.unwrap_or(current.segment_duration),
+    };
+    if let Err(message) = check_format(&audio) {
+        return json_error(&message, StatusCode::BAD_REQUEST);
+    }
+
+    let converted = retranscode(
+        &state,
+        &track_id,
+        &session,
+        audio,
+        request.source,
+        Priority::Normal,
+    )
+    .await;
+    if let Err(error) = converted {
+        return error.into_response();
+    }
+
+    let cache = state.hls_cache.read().await;
+    match cache.get(&track_id) {
+        Some(session) => Json(track_info(&track_id, session)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Checks bitrate and segment length against the accepted ranges.
+pub(super) fn check_format(audio: &AudioFormat) -> Result<(), String> {
+    if !BITRATES.contains(&audio.bitrate) {
+        return Err(format!(
+            "bitrate must be between {} and {} kbit/s",
+            BITRATES.start(),
+            BITRATES.end()
+        ));
+    }
+    if !SEGMENT_DURATIONS.contains(&audio.segment_duration) {
+        return Err(format!(
+            "segment_duration must be between {} and {} seconds",
+            SEGMENT_DURATIONS.start(),
+            SEGMENT_DURATIONS.end()
+        ));
+    }
+    Ok(())
+}
+
+/// Converts a track to `audio` from `source`, or by default from its origin URL
+/// when it has one.
+pub(super) async fn retranscode(
+    state: &AppState,
+    track_id: &str,
+    session: &HlsSession,
+    audio: AudioFormat,
+    source: Option<RetranscodeSource>,
+    priority: Priority,
+) -> Result<(), ApiError> {
+    let source = source.unwrap_or(if session.origin_url.is_empty() {
+        RetranscodeSource::Segments
+    } else {
+        RetranscodeSource::Download
+    });
+    match source {
+        RetranscodeSource::Download => {
+            redownload(state, track_id.to_string(), session, audio, priority).await?;
+            Ok(())
+        }
+        RetranscodeSource::Segments => transcode(state, track_id, session, audio, priority).await,
+    }
+}
+
+/// Converts the track's current audio segments to `audio` in the new session
+/// `session_id` and swaps it in. Everything else in the directory (artwork, key,
+/// video rendition, source info) is carried over unchanged. The caller holds the
+/// transcode slot.
+pub(super) async fn from_segments(
+    state: &AppState,
+    track_id: &str,
+    session: &HlsSession,
+    session_id: &str,
+    audio: AudioFormat,
+    progress: &watch::Sender<f64>,
+) -> Result<(), ApiError> {
+    let internal_error = |message: String| {
+        eprintln!("Warning: Retranscode of {} failed: {}", track_id, message);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    };
+
+    let options = &state.ingest_options;
+    let track_dir = options.cache_dirs.for_new_track();
+    let segments_dir = track_dir.join(session_id);
+    if let Err(e) = copy_track_files(session, &segments_dir).await {
+        let _ = tokio::fs::remove_dir_all(&segments_dir).await;
+        return Err(internal_error(format!("Failed to copy track files: {}", e)));
+    }
+
+    // The copied key keeps the video rendition playable
+    let transcode = TranscodeOptions {
+        clip: None,
+        encrypt: session.encrypted,
+        audio,
+        ..options.transcode.clone()
+    };
+    let converted = options
+        .tools
+        .segment(
+            &session.playlist_path,
+            NewTrack {
+                cache_dir: track_dir,
+                session_id,
+                title: &session.title,
+                origin_url: &session.origin_url,
+            },
+            &transcode,
+            Some(progress),
+        )
+        .await;
+    let converted = match converted {
+        Ok(converted) => converted,
+        Err(e) => {
+            let _ = tokio::fs::remove_dir_all(&segments_dir).await;
+            return Err(internal_error(e.to_string()).with_code(ErrorCode::TranscodeFailed));
+        }
+    };
+    if options.timed_metadata && !transcode.single_file && !transcode.encrypt {
+        let tagged = HlsSession {
+            artist: session.artist.clone(),
+            album: session.album.clone(),
+            has_thumbnail: session.has_thumbnail,
+            ..converted.clone()
+        };
+        if let Err(e) = tag_track(&tagged).await {
+            eprintln!("Warning: Failed to add timed metadata: {}", e);
+        }
+    }
+
+    // Swapped in from the current entry, so plays counted meanwhile are kept
+    let updated = {
+        let mut cache = state.hls_cache.write().await;
+        match cache.get_mut(track_id) {
+            Some(current) if current.id == session.id => {
+                *current = HlsSession {
+                    id: converted.id,
+                    segments_dir: converted.segments_dir,
+                    playlist_path: converted.playlist_path,
+                    total_segments: converted.total_segments,
+                    segment_duration: converted.segment_duration,
+                    duration: converted.duration,
+                    codec: converted.codec,
+                    bitrate: converted.bitrate,
+                    ..current.clone()
+                };
+                Some(current.clone())
+            }
+            _ => None,
+        }
+    };
+    let Some(updated) = updated else {
+        let _ = tokio::fs::remove_dir_all(&segments_dir).await;
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Track was changed or deleted during the conversion",
+        ));
+    };
+    state.hls_cache.changed();
+
+    // Deleted once the players still going through the old segments are done
+    state
+        .removals
+        .replace(&session.id, session.segments_dir.clone());
+    state.webhooks.emit(
+        "track_refreshed",
+        serde_json::to_value(track_info(track_id, &updated)).unwrap_or_default(),
+    );
+    Ok(())
+}
+
+/// Copies every file of the track except its audio playlist and segments into
+/// `segments_dir`.
+async fn copy_track_files(
+    session: &HlsSession,
+    segments_dir: &std::path::Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tokio::fs::create_dir_all(segments_dir).await?;
+    let playlist = tokio::fs::read_to_string(&session.playlist_path).await?;
+    let audio_files: HashSet<&str> = playlist_segments(&playlist)
+        .into_iter()
+        .map(|segment| segment.uri)
+        .collect();
+
+    let mut entries = tokio::fs::read_dir(&session.segments_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if path == session.playlist_path || audio_files.contains(name.as_ref()) {
+            continue;
+        }
+        if entry.file_type().await?.is_file() {
+            tokio::fs::copy(&path, segments_dir.join(name.as_ref())).await?;
+        }
+    }
+    Ok(())
+}
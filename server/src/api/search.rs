@@ -0,0 +1,137 @@
+//! Adding a track by search query: the best YouTube match for "artist - song" is
+//! downloaded in one call, for voice assistants and other automation.
+
+use super::{
+    already_downloading, download_error, json_error, queue_download, run_download, ApiError,
+    AppState,
+};
+use crate::downloader::{DownloadRequest, Priority};
+use crate::search::{rank, search, Heuristics};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use uuid::Uuid;
+
+/// How many search results are compared unless the request says otherwise.
+const DEFAULT_RESULTS: usize = 10;
+
+/// Most search results a request may compare.
+const MAX_RESULTS: usize = 25;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct QueryRequest {
+    query: String,
+    /// How many search results to choose from
+    results: Option<usize>,
+    /// Seconds; shorter results are skipped
+    #[serde(default = "default_min_duration")]
+    min_duration: f64,
+    /// Seconds; longer results are skipped
+    #[serde(default = "default_max_duration")]
+    max_duration: f64,
+    #[serde(default = "default_prefer_official")]
+    prefer_official: bool,
+    /// Only rank the results, without downloading the match
+    #[serde(default)]
+    dry_run: bool,
+    /// Taken from the match unless given
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    #[serde(default)]
+    video: bool,
+    #[serde(default)]
+    priority: Priority,
+}
+
+fn default_min_duration() -> f64 {
+    45.0
+}
+
+fn default_max_duration() -> f64 {
+    900.0
+}
+
+fn default_prefer_official() -> bool {
+    true
+}
+
+/// Searches YouTube for the query and downloads the best match
+pub(super) async fn download_query(
+    State(state): State<AppState>,
+    Json(request): Json<QueryRequest>,
+) -> Response {
+    let query = request.query.trim();
+    if query.is_empty() {
+        return json_error("The query is empty", StatusCode::BAD_REQUEST);
+    }
+    let count = request
+        .results
+        .unwrap_or(DEFAULT_RESULTS)
+        .clamp(1, MAX_RESULTS);
+    let ytdlp = &state.ingest_options.extractor.ytdlp_path;
+    let results = match search(ytdlp, &state.ingest_options.processes, query, count).await {
+        Ok(results) => results,
+        Err(e) => {
+            return json_error(&format!("Search failed: {}", e), StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    let heuristics = Heuristics {
+        min_duration: request.min_duration,
+        max_duration: request.max_duration,
+        prefer_official: request.prefer_official,
+    };
+    let mut candidates = rank(results, query, &heuristics);
+    for candidate in candidates.iter_mut() {
+        if let Err(reason) = state.ingest_options.url_policy.check(&candidate.result.url) {
+            candidate.rejected.get_or_insert(reason);
+        }
+    }
+    candidates.sort_by_key(|candidate| candidate.rejected.is_some());
+
+    if request.dry_run {
+        return Json(serde_json::json!({ "candidates": candidates })).into_response();
+    }
+    let Some(best) = candidates
+        .iter()
+        .find(|candidate| candidate.rejected.is_none())
+        .cloned()
+    else {
+        return ApiError::new(StatusCode::NOT_FOUND, "No search result fits the query")
+            .with_details(serde_json::json!({ "candidates": candidates }))
+            .into_response();
+    };
+
+    let (artist, title) = best.result.track_names();
+    let download = DownloadRequest {
+        url: best.result.url.clone(),
+        title: request.title.or(Some(title)),
+        artist: request.artist.or(artist),
+        album: request.album,
+        split_chapters: false,
+        video: request.video,
+        priority: request.priority,
+        limit_rate: None,
+        extractor_args: Vec::new(),
+        format: None,
+        refresh: None,
+    };
+    let download_id = Uuid::new_v4().to_string();
+    if let Err(existing) = queue_download(&state, &download_id, None, None, &download).await {
+        return already_downloading(&existing).into_response();
+    }
+
+    match run_download(&state, &download_id, download).await {
+        Ok(response) => {
+            let mut body = serde_json::to_value(response).unwrap_or_default();
+            body["match"] = serde_json::json!(best);
+            Json(body).into_response()
+        }
+        Err(error) => download_error(&error)
+            .with_details(serde_json::json!({ "download_id": download_id, "match": best }))
+            .into_response(),
+    }
+}
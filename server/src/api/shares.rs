@@ -0,0 +1,212 @@
+//! Links that let someone without the owner token play one track, until they expire
+//! or their plays run out.
+
+use super::keys::{token_hash, unauthorized, TokenQuery};
+use super::visibility::Owner;
+use super::{json_error, AppState};
+use crate::library::track_duration;
+use crate::storage::{save_share_links, unix_timestamp, HlsSession, ShareLink};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Json;
+use serde::Deserialize;
+use uuid::Uuid;
+
+const MAX_SHARE_TTL: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ShareRequest {
+    /// Lifetime in seconds; links without one last until revoked
+    ttl: Option<u64>,
+    /// Plays the link allows; unlimited without one
+    max_plays: Option<u64>,
+}
+
+fn link_json(link: &ShareLink) -> serde_json::Value {
+    serde_json::json!({
+        "id": link.id,
+        "track_id": link.track_id,
+        "created_at": link.created_at,
+        "expires_at": link.expires_at,
+        "max_plays": link.max_plays,
+        "plays": link.plays,
+    })
+}
+
+/// How long after a play the track's files stay reachable through its link: twice
+/// the track's length, allowing for pauses.
+fn play_window(duration: f64) -> u64 {
+    (duration * 2.0).ceil() as u64
+}
+
+/// Whether `token` is a link to the track that still lets the request through. A
+/// fetch of the media playlist starts a play and counts against `max_plays`, unless
+/// it comes while the last play is still going on.
+pub(super) async fn share_access(
+    state: &AppState,
+    track_id: &str,
+    token: &str,
+    playlist: bool,
+    duration: f64,
+) -> bool {
+    let now = unix_timestamp();
+    let mut links = state.share_links.write().await;
+    let Some(link) = links.get_mut(&token_hash(token)) else {
+        return false;
+    };
+    if link.track_id != track_id || link.is_expired(now) {
+        return false;
+    }
+    let playing = link
+        .last_played_at
+        .is_some_and(|played_at| now < played_at + play_window(duration));
+    if playing {
+        return true;
+    }
+    if !link.has_plays_left() {
+        return false;
+    }
+    if playlist {
+        link.plays += 1;
+        link.last_played_at = Some(now);
+        if let Err(e) = save_share_links(&state.cache_dir, &links).await {
+            eprintln!("Warning: Failed to save share links: {}", e);
+        }
+    }
+    true
+}
+
+/// The `?token=` of a request for an encrypted track's playlist if it's a link to the
+/// track, to be passed on in the key URI. Fetching the media playlist counts a play,
+/// as it does for a private track.
+pub(super) async fn share_key_token<'a>(
+    state: &AppState,
+    track_id: &str,
+    session: &HlsSession,
+    query: &'a TokenQuery,
+    playlist: bool,
+) -> Option<&'a str> {
+    let token = query.token.as_deref().filter(|_| session.encrypted)?;
+    share_access(state, track_id, token, playlist, track_duration(session))
+        .await
+        .then_some(token)
+}
+
+/// Creates a link to a track; only the owner may. The token is only returned here.
+pub(super) async fn create_share(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(track_id): Path<String>,
+    Json(request): Json<ShareRequest>,
+) -> Response {
+    if !owner {
+        return unauthorized();
+    }
+    if request
+        .ttl
+        .is_some_and(|ttl| ttl == 0 || ttl > MAX_SHARE_TTL)
+    {
+        return json_error(
+            &format!("ttl must be between 1 and {} seconds", MAX_SHARE_TTL),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    if request.max_plays == Some(0) {
+        return json_error("max_plays must be at least 1", StatusCode::BAD_REQUEST);
+    }
+    if !state.hls_cache.read().await.contains_key(&track_id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let now = unix_timestamp();
+    let link = ShareLink {
+        id: Uuid::new_v4().to_string(),
+        token_hash: token_hash(&token),
+        track_id,
+        created_at: now,
+        expires_at: request.ttl.map(|ttl| now + ttl),
+        max_plays: request.max_plays,
+        plays: 0,
+        last_played_at: None,
+    };
+
+    let mut links = state.share_links.write().await;
+    links.retain(|_, link| !link.is_expired(now));
+    links.insert(link.token_hash.clone(), link.clone());
+    if let Err(e) = save_share_links(&state.cache_dir, &links).await {
+        eprintln!("Warning: Failed to save share links: {}", e);
+    }
+
+    let mut body = link_json(&link);
+    body["url"] = format!("/s/{}", token).into();
+    body["token"] = token.into();
+    (StatusCode::CREATED, Json(body)).into_response()
+}
+
+/// Links that haven't expired, oldest first, without their tokens
+pub(super) async fn list_shares(State(state): State<AppState>, Owner(owner): Owner) -> Response {
+    if !owner {
+        return unauthorized();
+    }
+
+    let now = unix_timestamp();
+    let links = state.share_links.read().await;
+    let mut links: Vec<&ShareLink> = links.values().filter(|l| !l.is_expired(now)).collect();
+    links.sort_by_key(|l| l.created_at);
+    Json(links.into_iter().map(link_json).collect::<Vec<_>>()).into_response()
+}
+
+pub(super) async fn revoke_share(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(link_id): Path<String>,
+) -> Response {
+    if !owner {
+        return unauthorized();
+    }
+
+    let mut links = state.share_links.write().await;
+    let before = links.len();
+    links.retain(|_, link| link.id != link_id);
+    if links.len() == before {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if let Err(e) = save_share_links(&state.cache_dir, &links).await {
+        eprintln!("Warning: Failed to save share links: {}", e);
+    }
+
+    Json(serde_json::json!({ "success": true })).into_response()
+}
+
+/// What a share link's URL leads to: the track's master playlist, with the token
+/// passed on so the playlists it lists carry it too.
+pub(super) async fn open_share(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Response {
+    let track_id = {
+        let links = state.share_links.read().await;
+        let Some(link) = links.get(&token_hash(&token)) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if link.is_expired(unix_timestamp()) {
+            return json_error("Share link has expired", StatusCode::GONE);
+        }
+        if !link.has_plays_left() {
+            return json_error("Share link has no plays left", StatusCode::GONE);
+        }
+        link.track_id.clone()
+    };
+
+    let cache = state.hls_cache.read().await;
+    let Some(session) = cache.get(&track_id) else {
+        return json_error("Track was removed", StatusCode::GONE);
+    };
+    Redirect::temporary(&format!(
+        "/api/hls/{}/master.m3u8?token={}",
+        session.id, token
+    ))
+    .into_response()
+}
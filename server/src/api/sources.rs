@@ -0,0 +1,84 @@
+//! Results of the source availability checks, and a way to start one by hand.
+
+use super::{json_error, AppState};
+use crate::sources::check_sources;
+use crate::storage::SourceStatus;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct SourcesQuery {
+    /// Only tracks whose last check had this outcome
+    status: Option<SourceStatus>,
+}
+
+/// Every checked track with its last result, unavailable ones first.
+pub(super) async fn list_sources(
+    State(state): State<AppState>,
+    Query(query): Query<SourcesQuery>,
+) -> Json<serde_json::Value> {
+    let checks = state.sources.checks.read().await;
+    let mut tracks: Vec<(SourceStatus, serde_json::Value)> = {
+        let cache = state.hls_cache.read().await;
+        checks
+            .iter()
+            .filter(|(_, check)| query.status.is_none_or(|status| check.status == status))
+            .filter_map(|(id, check)| {
+                let session = cache.get(id)?;
+                Some((
+                    check.status,
+                    serde_json::json!({
+                        "id": id,
+                        "title": session.title,
+                        "origin_url": session.origin_url,
+                        "status": check.status,
+                        "checked_at": check.checked_at,
+                        "unavailable_since": check.unavailable_since,
+                        "error": check.error,
+                    }),
+                ))
+            })
+            .collect()
+    };
+    tracks.sort_by(|(a_status, a), (b_status, b)| {
+        let rank = |status: &SourceStatus| match status {
+            SourceStatus::Unavailable => 0,
+            SourceStatus::Unknown => 1,
+            SourceStatus::Available => 2,
+        };
+        rank(a_status)
+            .cmp(&rank(b_status))
+            .then_with(|| a["title"].as_str().cmp(&b["title"].as_str()))
+    });
+
+    Json(serde_json::json!({
+        "running": state.sources.is_running(),
+        "tracks": tracks.into_iter().map(|(_, track)| track).collect::<Vec<_>>(),
+    }))
+}
+
+/// Starts checking every track's source in the background.
+pub(super) async fn start_source_check(State(state): State<AppState>) -> Response {
+    if state.sources.is_running() {
+        return json_error("A source check is already running", StatusCode::CONFLICT);
+    }
+
+    tokio::spawn(async move {
+        check_sources(
+            &state.sources,
+            &state.hls_cache,
+            &state.cache_dir,
+            &state.webhooks,
+        )
+        .await
+    });
+
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "started": true })),
+    )
+        .into_response()
+}
@@ -0,0 +1,264 @@
+//! Listening statistics built from the history log.
+
+use super::keys::unauthorized;
+use super::visibility::{is_visible, Admin, Owner};
+use super::{read_only_track, AppState};
+use crate::library::{track_duration, track_info, TrackInfo};
+use crate::storage::{unix_timestamp, utc_date, PlayEvent, SECONDS_PER_DAY};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+const DEFAULT_TOP_TRACKS: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum StatsWindow {
+    /// The last 24 hours
+    Day,
+    Week,
+    /// The last 30 days
+    Month,
+    #[default]
+    All,
+}
+
+impl StatsWindow {
+    /// Unix time the window starts at; `None` for all time.
+    fn since(self) -> Option<u64> {
+        let days = match self {
+            StatsWindow::Day => 1,
+            StatsWindow::Week => 7,
+            StatsWindow::Month => 30,
+            StatsWindow::All => return None,
+        };
+        Some(unix_timestamp().saturating_sub(days * SECONDS_PER_DAY))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct StatsQuery {
+    #[serde(default)]
+    window: StatsWindow,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ListenCountRequest {
+    listen_count: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct TopTrack {
+    plays: u64,
+    /// Distinct listeners per day within the window, summed
+    window_listeners: u64,
+    #[serde(flatten)]
+    track: TrackInfo,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct DailyPlays {
+    /// UTC date, e.g. "2025-01-31"
+    date: String,
+    plays: u64,
+    unique_listeners: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct StatsOverview {
+    window: StatsWindow,
+    total_plays: u64,
+    /// Estimated from track lengths, as if every play ran to the end
+    hours_listened: f64,
+    unique_tracks: usize,
+    /// Distinct listeners over the whole window
+    unique_listeners: usize,
+    library_tracks: usize,
+    library_hours: f64,
+    /// Size of everything under the cache directory
+    disk_usage_bytes: u64,
+}
+
+/// Plays within the window, oldest first.
+async fn plays_in(state: &AppState, window: StatsWindow) -> Vec<PlayEvent> {
+    let since = window.since().unwrap_or(0);
+    let history = state.history.read().await;
+    history
+        .iter()
+        .filter(|play| play.played_at >= since)
+        .cloned()
+        .collect()
+}
+
+/// Most played tracks still in the library
+pub(super) async fn top_tracks(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Query(query): Query<StatsQuery>,
+) -> Json<Vec<TopTrack>> {
+    let mut plays: HashMap<String, u64> = HashMap::new();
+    let mut listeners: HashMap<String, HashSet<(u64, String)>> = HashMap::new();
+    for play in plays_in(&state, query.window).await {
+        *plays.entry(play.track_id.clone()).or_default() += 1;
+        if let Some(listener) = &play.listener {
+            listeners
+                .entry(play.track_id.clone())
+                .or_default()
+                .insert((play.day(), listener.clone()));
+        }
+    }
+
+    let cache = state.hls_cache.read().await;
+    let mut top: Vec<TopTrack> = plays
+        .into_iter()
+        .filter_map(|(track_id, plays)| {
+            let session = cache
+                .get(&track_id)
+                .filter(|session| is_visible(session, owner))?;
+            Some(TopTrack {
+                plays,
+                window_listeners: listeners.get(&track_id).map_or(0, |l| l.len() as u64),
+                track: track_info(&track_id, session),
+            })
+        })
+        .collect();
+    top.sort_by(|a, b| {
+        b.plays
+            .cmp(&a.plays)
+            .then_with(|| a.track.title.cmp(&b.track.title))
+    });
+    top.truncate(query.limit.unwrap_or(DEFAULT_TOP_TRACKS));
+    Json(top)
+}
+
+/// Play totals for the window plus library size and disk usage
+pub(super) async fn overview(
+    State(state): State<AppState>,
+    Query(query): Query<StatsQuery>,
+) -> Json<StatsOverview> {
+    let plays = plays_in(&state, query.window).await;
+    let unique_tracks = plays
+        .iter()
+        .map(|play| play.track_id.as_str())
+        .collect::<HashSet<_>>()
+        .len();
+    let unique_listeners = plays
+        .iter()
+        .filter_map(|play| play.listener.as_deref())
+        .collect::<HashSet<_>>()
+        .len();
+    let seconds_listened: f64 = plays.iter().map(|play| play.duration).sum();
+
+    let (library_tracks, library_seconds) = {
+        let cache = state.hls_cache.read().await;
+        (cache.len(), cache.values().map(track_duration).sum::<f64>())
+    };
+
+    Json(StatsOverview {
+        window: query.window,
+        total_plays: plays.len() as u64,
+        hours_listened: hours(seconds_listened),
+        unique_tracks,
+        unique_listeners,
+        library_tracks,
+        library_hours: hours(library_seconds),
+        disk_usage_bytes: dir_size(&state.cache_dir).await,
+    })
+}
+
+/// Plays and unique listeners of one track per day, oldest first; days without
+/// plays are left out
+pub(super) async fn track_daily(
+    State(state): State<AppState>,
+    Path(track_id): Path<String>,
+    Query(query): Query<StatsQuery>,
+) -> Result<Json<Vec<DailyPlays>>, StatusCode> {
+    if !state.hls_cache.read().await.contains_key(&track_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let mut days: BTreeMap<u64, (u64, HashSet<String>)> = BTreeMap::new();
+    for play in plays_in(&state, query.window).await {
+        if play.track_id != track_id {
+            continue;
+        }
+        let (plays, listeners) = days.entry(play.day()).or_default();
+        *plays += 1;
+        listeners.extend(play.listener);
+    }
+
+    Ok(Json(
+        days.into_iter()
+            .map(|(day, (plays, listeners))| DailyPlays {
+                date: utc_date(day),
+                plays,
+                unique_listeners: listeners.len() as u64,
+            })
+            .collect(),
+    ))
+}
+
+/// Overrides a track's listen counter, e.g. after a client stuck in a loop
+/// inflated it. The history log is left as it is. Only the admin may.
+pub(super) async fn set_listen_count(
+    State(state): State<AppState>,
+    Admin(admin): Admin,
+    Path(track_id): Path<String>,
+    Json(request): Json<ListenCountRequest>,
+) -> Response {
+    if !admin {
+        return unauthorized();
+    }
+    let (previous, title) = {
+        let mut cache = state.hls_cache.write().await;
+        let Some(session) = cache.get_mut(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        let previous = session.listen_count;
+        session.listen_count = request.listen_count;
+        (previous, session.title.clone())
+    };
+    println!(
+        "✏️ Listen count of \"{}\" ({}) changed from {} to {}",
+        title, track_id, previous, request.listen_count
+    );
+
+    state.hls_cache.changed();
+
+    Json(serde_json::json!({
+        "track_id": track_id,
+        "previous": previous,
+        "listen_count": request.listen_count,
+    }))
+    .into_response()
+}
+
+fn hours(seconds: f64) -> f64 {
+    (seconds / 3600.0 * 100.0).round() / 100.0
+}
+
+/// Total size of the files below `dir`; unreadable entries are skipped.
+async fn dir_size(dir: &std::path::Path) -> u64 {
+    let mut total = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            match entry.metadata().await {
+                Ok(metadata) if metadata.is_dir() => pending.push(entry.path()),
+                Ok(metadata) => total += metadata.len(),
+                Err(_) => {}
+            }
+        }
+    }
+    total
+}
@@ -0,0 +1,499 @@
+//! Subscriptions: podcast feeds and YouTube channels or playlists checked on a
+//! schedule, whose new episodes or uploads are downloaded into a collection per
+//! subscription.
+
+use super::batch::MAX_BATCH_SIZE;
+use super::collections::download_into_collection;
+use super::{json_error, AppState};
+use crate::downloader::{DownloadRequest, Priority};
+use crate::feeds::{list_channel, parse_feed, parse_opml, Feed};
+use crate::library::normalize_collection_path;
+use crate::storage::{
+    generate_url_hash, save_collections, save_subscriptions, track_with_origin, unix_timestamp,
+    Collection, Subscription, SubscriptionKind,
+};
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How many of the latest episodes or uploads are downloaded when subscribing,
+/// unless the request says otherwise.
+const DEFAULT_BACKFILL: usize = 1;
+
+/// How long fetching a feed may take.
+const FEED_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+pub(super) struct SubscribeRequest {
+    url: String,
+    /// A podcast feed unless given
+    kind: Option<SubscriptionKind>,
+    /// How many of the latest episodes to download now; older ones are skipped
+    backfill: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct OpmlQuery {
+    backfill: Option<usize>,
+}
+
+/// Every subscription, by title.
+pub(super) async fn list_subscriptions(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let subscriptions = state.subscriptions.read().await;
+    let mut list: Vec<&Subscription> = subscriptions.values().collect();
+    list.sort_by_key(|subscription| subscription.title.to_lowercase());
+    Json(serde_json::Value::Array(
+        list.into_iter().map(subscription_json).collect(),
+    ))
+}
+
+/// Subscribes to a podcast feed or a channel, downloading its latest episodes or
+/// uploads right away.
+pub(super) async fn subscribe(
+    State(state): State<AppState>,
+    Json(request): Json<SubscribeRequest>,
+) -> Response {
+    let backfill = request.backfill.unwrap_or(DEFAULT_BACKFILL);
+    if backfill > MAX_BATCH_SIZE {
+        return json_error(
+            &format!("backfill must not be more than {}", MAX_BATCH_SIZE),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    let kind = request.kind.unwrap_or(SubscriptionKind::Podcast);
+    match add_subscription(&state, kind, request.url.trim(), backfill).await {
+        Ok((subscription, batch_id)) => (
+            StatusCode::CREATED,
+            Json(serde_json::json!({
+                "subscription": subscription_json(&subscription),
+                "batch_id": batch_id,
+            })),
+        )
+            .into_response(),
+        Err((status, message)) => json_error(&message, status),
+    }
+}
+
+/// Subscribes to every feed of an uploaded OPML file, e.g. one exported from a
+/// podcast app. Feeds already subscribed to are skipped.
+pub(super) async fn import_opml(
+    State(state): State<AppState>,
+    Query(query): Query<OpmlQuery>,
+    body: Bytes,
+) -> Response {
+    let backfill = query.backfill.unwrap_or(DEFAULT_BACKFILL);
+    if backfill > MAX_BATCH_SIZE {
+        return json_error(
+            &format!("backfill must not be more than {}", MAX_BATCH_SIZE),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+    let feeds = parse_opml(&String::from_utf8_lossy(&body));
+    if feeds.is_empty() {
+        return json_error("The OPML file lists no feeds", StatusCode::BAD_REQUEST);
+    }
+
+    let mut subscribed = Vec::new();
+    let mut existing = Vec::new();
+    let mut failed = Vec::new();
+    for feed in feeds {
+        match add_subscription(&state, SubscriptionKind::Podcast, &feed.url, backfill).await {
+            Ok((subscription, _)) => subscribed.push(subscription_json(&subscription)),
+            Err((StatusCode::CONFLICT, _)) => existing.push(feed.url),
+            Err((_, error)) => failed.push(serde_json::json!({
+                "url": feed.url,
+                "title": feed.title,
+                "error": error,
+            })),
+        }
+    }
+
+    Json(serde_json::json!({
+        "subscribed": subscribed,
+        "existing": existing,
+        "failed": failed,
+    }))
+    .into_response()
+}
+
+/// Unsubscribes; the downloaded tracks and the subscription's collection are kept.
+pub(super) async fn unsubscribe(
+    State(state): State<AppState>,
+    Path(subscription_id): Path<String>,
+) -> Response {
+    let mut subscriptions = state.subscriptions.write().await;
+    let Some(subscription) = subscriptions.remove(&subscription_id) else {
+        return json_error("Subscription not found", StatusCode::NOT_FOUND);
+    };
+    if let Err(e) = save_subscriptions(&state.cache_dir, &subscriptions).await {
+        eprintln!("Warning: Failed to save subscriptions: {}", e);
+    }
+
+    Json(serde_json::json!({
+        "success": true,
+        "message": format!("Unsubscribed from '{}'", subscription.title)
+    }))
+    .into_response()
+}
+
+/// Checks one subscription now instead of waiting for the schedule.
+pub(super) async fn check_subscription_now(
+    State(state): State<AppState>,
+    Path(subscription_id): Path<String>,
+) -> Response {
+    if !state
+        .subscriptions
+        .read()
+        .await
+        .contains_key(&subscription_id)
+    {
+        return json_error("Subscription not found", StatusCode::NOT_FOUND);
+    }
+    match check_subscription(&state, &subscription_id).await {
+        Ok((episodes, batch_id)) => {
+            let subscriptions = state.subscriptions.read().await;
+            Json(serde_json::json!({
+                "subscription": subscriptions.get(&subscription_id).map(subscription_json),
+                "new_episodes": episodes,
+                "batch_id": batch_id,
+            }))
+            .into_response()
+        }
+        Err(e) => json_error(&e, StatusCode::BAD_GATEWAY),
+    }
+}
+
+/// Checks every subscription, the scheduled task.
+pub(super) async fn check_subscriptions(state: &AppState) -> Result<(), String> {
+    let ids: Vec<String> = state.subscriptions.read().await.keys().cloned().collect();
+    let mut failed = 0;
+    for id in &ids {
+        if let Err(e) = check_subscription(state, id).await {
+            eprintln!("Warning: Failed to check subscription {}: {}", id, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(format!(
+            "{} of {} subscriptions failed to check",
+            failed,
+            ids.len()
+        ));
+    }
+    Ok(())
+}
+
+/// A subscription as listed; the seen items are only counted.
+fn subscription_json(subscription: &Subscription) -> serde_json::Value {
+    serde_json::json!({
+        "id": subscription.id,
+        "kind": subscription.kind,
+        "url": subscription.url,
+        "title": subscription.title,
+        "author": subscription.author,
+        "collection_id": subscription.collection_id,
+        "created_at": subscription.created_at,
+        "checked_at": subscription.checked_at,
+        "error": subscription.error,
+        "seen": subscription.seen.len(),
+    })
+}
+
+/// Collection folder the subscriptions' collections are created in.
+fn collection_folder(kind: SubscriptionKind) -> &'static str {
+    match kind {
+        SubscriptionKind::Podcast => "Podcasts",
+        SubscriptionKind::Channel => "Channels",
+    }
+}
+
+async fn fetch_feed(
+    state: &AppState,
+    kind: SubscriptionKind,
+    url: &str,
+) -> Result<Feed, (StatusCode, String)> {
+    if let Err(reason) = state.ingest_options.url_policy.check(url) {
+        return Err((StatusCode::FORBIDDEN, reason));
+    }
+    if kind == SubscriptionKind::Channel {
+        return list_channel(
+            &state.ingest_options.extractor.ytdlp_path,
+            &state.ingest_options.processes,
+            url,
+        )
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e));
+    }
+    let unreachable = |e: reqwest::Error| {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to fetch the feed: {}", e),
+        )
+    };
+    let response = state
+        .ingest_options
+        .client
+        .get(url)
+        .timeout(FEED_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(unreachable)?;
+    let body = response.text().await.map_err(unreachable)?;
+    parse_feed(&body).ok_or((
+        StatusCode::BAD_REQUEST,
+        "The URL is not an RSS feed".to_string(),
+    ))
+}
+
+/// Subscribes to the feed or channel at `url`, downloading its latest `backfill`
+/// items. Returns the subscription and the batch downloading them.
+async fn add_subscription(
+    state: &AppState,
+    kind: SubscriptionKind,
+    url: &str,
+    backfill: usize,
+) -> Result<(Subscription, Option<String>), (StatusCode, String)> {
+    if state
+        .subscriptions
+        .read()
+        .await
+        .values()
+        .any(|subscription| subscription.url == url)
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Already subscribed to {}", url),
+        ));
+    }
+    let feed = fetch_feed(state, kind, url).await?;
+    if feed.title.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "The feed has no title".to_string()));
+    }
+
+    let skipped = feed.episodes.len().saturating_sub(backfill);
+    let subscription = Subscription {
+        id: Uuid::new_v4().to_string(),
+        kind,
+        url: url.to_string(),
+        title: feed.title.clone(),
+        author: feed.author.clone(),
+        collection_id: String::new(),
+        created_at: unix_timestamp(),
+        checked_at: None,
+        error: None,
+        seen: feed.episodes[..skipped]
+            .iter()
+            .map(|episode| episode.guid.clone())
+            .collect(),
+    };
+    {
+        let mut subscriptions = state.subscriptions.write().await;
+        // Another request may have subscribed while the feed was being fetched
+        if subscriptions.values().any(|existing| existing.url == url) {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("Already subscribed to {}", url),
+            ));
+        }
+        subscriptions.insert(subscription.id.clone(), subscription.clone());
+    }
+
+    let (_, batch_id) = apply_feed(state, &subscription.id, feed).await;
+    let subscription = state
+        .subscriptions
+        .read()
+        .await
+        .get(&subscription.id)
+        .cloned()
+        .unwrap_or(subscription);
+    Ok((subscription, batch_id))
+}
+
+/// Fetches a subscription's feed or listing and downloads its new items. Returns how
+/// many there were and the batch downloading them; failures are kept on the
+/// subscription.
+async fn check_subscription(
+    state: &AppState,
+    subscription_id: &str,
+) -> Result<(usize, Option<String>), String> {
+    let Some((kind, url)) = state
+        .subscriptions
+        .read()
+        .await
+        .get(subscription_id)
+        .map(|subscription| (subscription.kind, subscription.url.clone()))
+    else {
+        return Ok((0, None));
+    };
+
+    match fetch_feed(state, kind, &url).await {
+        Ok(feed) => Ok(apply_feed(state, subscription_id, feed).await),
+        Err((_, error)) => {
+            let mut subscriptions = state.subscriptions.write().await;
+            if let Some(subscription) = subscriptions.get_mut(subscription_id) {
+                subscription.checked_at = Some(unix_timestamp());
+                subscription.error = Some(error.clone());
+            }
+            if let Err(e) = save_subscriptions(&state.cache_dir, &subscriptions).await {
+                eprintln!("Warning: Failed to save subscriptions: {}", e);
+            }
+            Err(error)
+        }
+    }
+}
+
+/// Marks the feed's unseen items seen and downloads them into the subscription's
+/// collection, oldest first. Items already in the library are added right away.
+async fn apply_feed(
+    state: &AppState,
+    subscription_id: &str,
+    feed: Feed,
+) -> (usize, Option<String>) {
+    let (kind, title, artist, collection_id, episodes) = {
+        let mut subscriptions = state.subscriptions.write().await;
+        let Some(subscription) = subscriptions.get_mut(subscription_id) else {
+            return (0, None);
+        };
+        let episodes: Vec<_> = feed
+            .episodes
+            .into_iter()
+            .filter(|episode| !subscription.seen.contains(&episode.guid))
+            .take(MAX_BATCH_SIZE)
+            .collect();
+        subscription
+            .seen
+            .extend(episodes.iter().map(|episode| episode.guid.clone()));
+        subscription.checked_at = Some(unix_timestamp());
+        subscription.error = None;
+        let collection_id = subscription_collection(state, subscription).await;
+        let artist = subscription
+            .author
+            .clone()
+            .unwrap_or_else(|| subscription.title.clone());
+        let details = (
+            subscription.kind,
+            subscription.title.clone(),
+            artist,
+            collection_id,
+            episodes,
+        );
+        if let Err(e) = save_subscriptions(&state.cache_dir, &subscriptions).await {
+            eprintln!("Warning: Failed to save subscriptions: {}", e);
+        }
+        details
+    };
+    if episodes.is_empty() {
+        return (0, None);
+    }
+
+    let count = episodes.len();
+    let episodes: Vec<_> = {
+        let cache = state.hls_cache.read().await;
+        // Uploads downloaded by hand before subscribing are known by their origin URL
+        let by_origin: HashMap<&str, &String> = cache
+            .iter()
+            .map(|(id, session)| (session.origin_url.as_str(), id))
+            .collect();
+        episodes
+            .into_iter()
+            .map(|episode| match by_origin.get(episode.url.as_str()) {
+                Some(track_id) => ((*track_id).clone(), true, episode),
+                // Until downloaded, an episode is planned by the hash of its URL
+                None => {
+                    let origin_hash = generate_url_hash(&episode.url);
+                    match track_with_origin(&cache, &origin_hash) {
+                        Some(track_id) => (track_id, true, episode),
+                        None => (origin_hash, false, episode),
+                    }
+                }
+            })
+            .collect()
+    };
+    let mut collections = state.collections.write().await;
+    let Some(collection) = collections.get_mut(&collection_id) else {
+        return (count, None);
+    };
+    let mut planned = collection.track_ids.clone();
+    let mut requests = Vec::new();
+    for (track_id, downloaded, episode) in episodes {
+        if collection.track_ids.contains(&track_id) {
+            continue;
+        }
+        planned.push(track_id.clone());
+        if downloaded {
+            collection.track_ids.push(track_id);
+            continue;
+        }
+        // Podcast enclosures are bare audio files, while uploads come with yt-dlp's
+        // metadata; either way the album names the subscription
+        let podcast = kind == SubscriptionKind::Podcast;
+        requests.push(DownloadRequest {
+            url: episode.url,
+            title: Some(episode.title).filter(|title| podcast && !title.is_empty()),
+            artist: Some(artist.clone()).filter(|_| podcast),
+            album: Some(title.clone()),
+            split_chapters: false,
+            video: false,
+            // New episodes shouldn't hold up tracks someone wants to play now
+            priority: Priority::Low,
+            limit_rate: None,
+            extractor_args: Vec::new(),
+            format: None,
+            refresh: None,
+        });
+    }
+    if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+        eprintln!("Warning: Failed to save collections: {}", e);
+    }
+    drop(collections);
+
+    if requests.is_empty() {
+        return (count, None);
+    }
+    let batch_id = download_into_collection(state, collection_id, planned, requests).await;
+    (count, Some(batch_id))
+}
+
+/// Id of the subscription's collection, recreating it under the kind's
+/// [`collection_folder`] when it was deleted.
+async fn subscription_collection(state: &AppState, subscription: &mut Subscription) -> String {
+    let mut collections = state.collections.write().await;
+    if collections.contains_key(&subscription.collection_id) {
+        return subscription.collection_id.clone();
+    }
+
+    // Titles may contain slashes, which would nest collections
+    let folder = collection_folder(subscription.kind);
+    let path = normalize_collection_path(&format!(
+        "{}/{}",
+        folder,
+        subscription.title.replace('/', "-")
+    ))
+    .unwrap_or_else(|| folder.to_string());
+    let id = match collections.values().find(|c| c.path == path) {
+        // Subscribed before, or a collection of the same name was made by hand
+        Some(collection) => collection.id.clone(),
+        None => {
+            let collection = Collection {
+                id: Uuid::new_v4().to_string(),
+                path,
+                track_ids: Vec::new(),
+            };
+            let id = collection.id.clone();
+            collections.insert(id.clone(), collection);
+            if let Err(e) = save_collections(&state.cache_dir, &collections).await {
+                eprintln!("Warning: Failed to save collections: {}", e);
+            }
+            id
+        }
+    };
+    subscription.collection_id = id.clone();
+    id
+}
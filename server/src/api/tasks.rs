@@ -0,0 +1,31 @@
+//! The scheduled tasks, and running one outside its schedule.
+
+use super::{json_error, AppState};
+use crate::scheduler::TriggerError;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+
+/// Every scheduled task with its interval, last run and next run.
+pub(super) async fn list_tasks(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "tasks": state.scheduler.list() }))
+}
+
+/// Starts a task now; its next scheduled run counts from the end of this one.
+pub(super) async fn run_task(State(state): State<AppState>, Path(name): Path<String>) -> Response {
+    match state.scheduler.trigger(&name) {
+        Ok(()) => (
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({ "started": true })),
+        )
+            .into_response(),
+        Err(TriggerError::NotFound) => {
+            json_error(&format!("No task named {}", name), StatusCode::NOT_FOUND)
+        }
+        Err(TriggerError::Running) => json_error(
+            &format!("Task {} is already running", name),
+            StatusCode::CONFLICT,
+        ),
+    }
+}
@@ -0,0 +1,320 @@
+//! Conversions of library tracks as jobs: queued for the bounded transcode slots,
+//! listed with their progress, and started again when a restart interrupted them.
+
+use super::retranscode::from_segments;
+use super::{json_error, ApiError, AppState, ErrorCode};
+use crate::downloader::Priority;
+use crate::storage::{save_transcodes, unix_timestamp, HlsSession, TranscodeJob, TranscodeStatus};
+use crate::transcode::AudioFormat;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify, RwLock};
+use uuid::Uuid;
+
+const TRANSCODE_STATUSES: [&str; 4] = ["pending", "running", "ready", "failed"];
+
+/// Every transcode job, unfinished ones and the history.
+pub(super) struct Transcodes {
+    jobs: RwLock<HashMap<String, TranscodeJob>>,
+    /// Woken whenever a job finishes
+    finished: Notify,
+    /// How long finished jobs are listed, like `--download-history-days` for downloads
+    history: Duration,
+}
+
+impl Transcodes {
+    pub(super) fn new(jobs: HashMap<String, TranscodeJob>, history: Duration) -> Self {
+        Transcodes {
+            jobs: RwLock::new(jobs),
+            finished: Notify::new(),
+            history,
+        }
+    }
+
+    /// Jobs a restart interrupted, oldest first.
+    pub(super) async fn interrupted(&self) -> Vec<String> {
+        let jobs = self.jobs.read().await;
+        let mut interrupted: Vec<&TranscodeJob> = jobs
+            .values()
+            .filter(|job| !job.status.is_finished())
+            .collect();
+        interrupted.sort_by_key(|job| job.created_at);
+        interrupted.iter().map(|job| job.id.clone()).collect()
+    }
+
+    /// Waits for the job to finish, as the error it failed with.
+    async fn wait(&self, job_id: &str) -> Result<(), ApiError> {
+        loop {
+            let notified = self.finished.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            match self.jobs.read().await.get(job_id) {
+                Some(job) if job.status == TranscodeStatus::Ready => return Ok(()),
+                Some(job) if job.status == TranscodeStatus::Failed => {
+                    return Err(ApiError::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        job.error.clone().unwrap_or_default(),
+                    )
+                    .with_code(ErrorCode::TranscodeFailed));
+                }
+                Some(_) => {}
+                None => {
+                    return Err(ApiError::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Transcode job disappeared",
+                    ));
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Writes the jobs, dropping finished ones older than the history.
+async fn save(state: &AppState) {
+    let cutoff = unix_timestamp().saturating_sub(state.transcodes.history.as_secs());
+    let mut jobs = state.transcodes.jobs.write().await;
+    jobs.retain(|_, job| {
+        job.finished_at
+            .is_none_or(|finished_at| finished_at >= cutoff)
+    });
+    if let Err(e) = save_transcodes(&state.cache_dir, &jobs).await {
+        eprintln!("Warning: Failed to save transcode jobs: {}", e);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct TranscodesQuery {
+    /// Comma-separated statuses to include
+    status: Option<String>,
+}
+
+/// All transcode jobs, oldest first
+pub(super) async fn list_transcodes(
+    State(state): State<AppState>,
+    Query(query): Query<TranscodesQuery>,
+) -> Response {
+    let statuses: Option<Vec<&str>> = query
+        .status
+        .as_deref()
+        .map(|status| status.split(',').map(str::trim).collect());
+    if let Some(unknown) = statuses
+        .iter()
+        .flatten()
+        .find(|status| !TRANSCODE_STATUSES.contains(status))
+    {
+        return json_error(
+            &format!(
+                "Unknown status \"{}\"; expected one of {}",
+                unknown,
+                TRANSCODE_STATUSES.join(", ")
+            ),
+            StatusCode::BAD_REQUEST,
+        );
+    }
+
+    let jobs = state.transcodes.jobs.read().await;
+    let mut jobs: Vec<TranscodeJob> = jobs
+        .values()
+        .filter(|job| {
+            statuses
+                .as_ref()
+                .is_none_or(|statuses| statuses.contains(&status_name(job.status)))
+        })
+        .cloned()
+        .collect();
+    jobs.sort_by_key(|job| job.created_at);
+    Json(jobs).into_response()
+}
+
+pub(super) async fn transcode_status(
+    State(state): State<AppState>,
+    Path(job_id): Path<String>,
+) -> Result<Json<TranscodeJob>, StatusCode> {
+    let jobs = state.transcodes.jobs.read().await;
+    jobs.get(&job_id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Queues a conversion of the track's segments to `audio` and waits for it. A
+/// conversion of the track to the same format that is already queued is joined
+/// instead of starting another.
+pub(super) async fn transcode(
+    state: &AppState,
+    track_id: &str,
+    session: &HlsSession,
+    audio: AudioFormat,
+    priority: Priority,
+) -> Result<(), ApiError> {
+    let job_id = {
+        let mut jobs = state.transcodes.jobs.write().await;
+        let queued = jobs.values().find(|job| {
+            job.track_id == track_id && !job.status.is_finished() && target(job) == audio
+        });
+        if let Some(job) = queued {
+            let job_id = job.id.clone();
+            drop(jobs);
+            return state.transcodes.wait(&job_id).await;
+        }
+
+        let job_id = Uuid::new_v4().to_string();
+        jobs.insert(
+            job_id.clone(),
+            TranscodeJob {
+                id: job_id.clone(),
+                track_id: track_id.to_string(),
+                title: session.title.clone(),
+                codec: audio.codec,
+                bitrate: audio.bitrate,
+                segment_duration: audio.segment_duration,
+                priority,
+                status: TranscodeStatus::Pending,
+                percent: None,
+                session_id: None,
+                created_at: unix_timestamp(),
+                started_at: None,
+                finished_at: None,
+                error: None,
+            },
+        );
+        job_id
+    };
+    save(state).await;
+
+    // Runs to the end even if the client gives up waiting
+    let state = state.clone();
+    tokio::spawn(async move { run_job(&state, &job_id).await })
+        .await
+        .unwrap_or_else(|e| {
+            Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                e.to_string(),
+            ))
+        })
+}
+
+fn status_name(status: TranscodeStatus) -> &'static str {
+    match status {
+        TranscodeStatus::Pending => "pending",
+        TranscodeStatus::Running => "running",
+        TranscodeStatus::Ready => "ready",
+        TranscodeStatus::Failed => "failed",
+    }
+}
+
+fn target(job: &TranscodeJob) -> AudioFormat {
+    AudioFormat {
+        codec: job.codec,
+        bitrate: job.bitrate,
+        segment_duration: job.segment_duration,
+    }
+}
+
+/// Runs a queued job once it gets a transcode slot. A job a restart interrupted
+/// starts over, after its partial output is removed.
+pub(super) async fn run_job(state: &AppState, job_id: &str) -> Result<(), ApiError> {
+    let Some(job) = state.transcodes.jobs.read().await.get(job_id).cloned() else {
+        return Ok(());
+    };
+    if let Some(session_id) = &job.session_id {
+        if let Some(dir) = state
+            .ingest_options
+            .cache_dirs
+            .find(std::path::Path::new(session_id))
+        {
+            let _ = tokio::fs::remove_dir_all(dir).await;
+        }
+    }
+
+    let result = async {
+        let _slot = state
+            .ingest_options
+            .transcode_slots
+            .acquire(job.priority)
+            .await
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+        // Looked up once the job starts, so it converts the track as it is by then
+        let Some(session) = state.hls_cache.read().await.get(&job.track_id).cloned() else {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Track was deleted before the conversion started",
+            ));
+        };
+        let session_id = Uuid::new_v4().to_string();
+        update(state, job_id, |job| {
+            job.status = TranscodeStatus::Running;
+            job.started_at = Some(unix_timestamp());
+            job.percent = Some(0.0);
+            job.session_id = Some(session_id.clone());
+        })
+        .await;
+        save(state).await;
+
+        let (progress_tx, progress_task) = mirror_progress(state, job_id);
+        let result = from_segments(
+            state,
+            &job.track_id,
+            &session,
+            &session_id,
+            target(&job),
+            &progress_tx,
+        )
+        .await;
+        drop(progress_tx);
+        let _ = progress_task.await;
+        result
+    }
+    .await;
+
+    update(state, job_id, |job| {
+        job.finished_at = Some(unix_timestamp());
+        match &result {
+            Ok(()) => {
+                job.status = TranscodeStatus::Ready;
+                job.percent = Some(100.0);
+            }
+            Err(error) => {
+                job.status = TranscodeStatus::Failed;
+                job.error = Some(error.message().to_string());
+            }
+        }
+    })
+    .await;
+    save(state).await;
+    state.transcodes.finished.notify_waiters();
+    result
+}
+
+async fn update(state: &AppState, job_id: &str, apply: impl FnOnce(&mut TranscodeJob)) {
+    if let Some(job) = state.transcodes.jobs.write().await.get_mut(job_id) {
+        apply(job);
+    }
+}
+
+/// Copies ffmpeg's progress into the job's `percent` until the sender is dropped.
+fn mirror_progress(
+    state: &AppState,
+    job_id: &str,
+) -> (watch::Sender<f64>, tokio::task::JoinHandle<()>) {
+    let (progress_tx, mut progress_rx) = watch::channel(0.0);
+    let transcodes = Arc::clone(&state.transcodes);
+    let job_id = job_id.to_string();
+    let task = tokio::spawn(async move {
+        while progress_rx.changed().await.is_ok() {
+            let percent = *progress_rx.borrow_and_update();
+            if let Some(job) = transcodes.jobs.write().await.get_mut(&job_id) {
+                job.percent = Some(percent);
+            }
+        }
+    });
+    (progress_tx, task)
+}
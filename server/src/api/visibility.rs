@@ -0,0 +1,232 @@
+//! Private tracks: with `--owner-token` set they are only listed and served to
+//! requests presenting it, while everyone else sees the public ones.
+
+use super::keys::{presented_token, unauthorized, TokenQuery};
+use super::shares::share_access;
+use super::{read_only_track, AppState};
+use crate::library::{track_duration, track_info};
+use crate::storage::{Collection, HlsSession, Visibility};
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Path, Query, Request, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct VisibilityRequest {
+    visibility: Visibility,
+}
+
+/// Whether the request comes from the library's owner. Without an owner token
+/// every request does.
+pub(super) struct Owner(pub(super) bool);
+
+impl FromRequestParts<AppState> for Owner {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Owner(is_owner(
+            state,
+            &parts.headers,
+            &token_query(&parts.uri),
+        )))
+    }
+}
+
+/// Whether the request may use admin routes: it presents the owner token. Unlike
+/// [`Owner`], nobody is admin on a server without one.
+pub(super) struct Admin(pub(super) bool);
+
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Admin(
+            state.owner_token.is_some()
+                && is_owner(state, &parts.headers, &token_query(&parts.uri)),
+        ))
+    }
+}
+
+fn token_query(uri: &Uri) -> TokenQuery {
+    Query::try_from_uri(uri)
+        .map(|Query(query)| query)
+        .unwrap_or(TokenQuery { token: None })
+}
+
+fn is_owner_token(state: &AppState, token: Option<&str>) -> bool {
+    match (state.owner_token.as_deref(), token) {
+        (None, _) => true,
+        // Hashed first, like the key token, so the comparison takes the same time
+        // however much of the token matches
+        (Some(expected), Some(token)) => {
+            Sha256::digest(expected.as_bytes()) == Sha256::digest(token.as_bytes())
+        }
+        (Some(_), None) => false,
+    }
+}
+
+fn is_owner(state: &AppState, headers: &HeaderMap, query: &TokenQuery) -> bool {
+    is_owner_token(state, presented_token(headers, query))
+}
+
+pub(super) fn is_visible(session: &HlsSession, owner: bool) -> bool {
+    owner || session.visibility == Visibility::Public
+}
+
+/// The public tracks of a library, for guests.
+pub(super) fn public_tracks(cache: &HashMap<String, HlsSession>) -> HashMap<String, HlsSession> {
+    cache
+        .iter()
+        .filter(|(_, session)| session.visibility == Visibility::Public)
+        .map(|(id, session)| (id.clone(), session.clone()))
+        .collect()
+}
+
+/// `collections` as the request may see them: guests don't get the ids of private
+/// tracks. Takes the track lock, so the caller must not hold the collections lock.
+pub(super) async fn visible_collections(
+    state: &AppState,
+    mut collections: Vec<Collection>,
+    owner: bool,
+) -> Vec<Collection> {
+    if owner {
+        return collections;
+    }
+    let cache = state.hls_cache.read().await;
+    for collection in &mut collections {
+        collection.track_ids.retain(|id| {
+            cache
+                .get(id)
+                .is_none_or(|session| is_visible(session, owner))
+        });
+    }
+    collections
+}
+
+/// The `?token=` of a request for a private track's playlist, to be passed on in the
+/// URIs it lists for players that can't send headers. Such requests only get this
+/// far with the owner token or a share link to the track.
+pub(super) fn private_uri_token<'a>(
+    state: &AppState,
+    session: &HlsSession,
+    query: &'a TokenQuery,
+) -> Option<&'a str> {
+    (state.owner_token.is_some() && session.visibility == Visibility::Private)
+        .then_some(query.token.as_deref())
+        .flatten()
+}
+
+/// Whether the request may learn that the track exists: anyone for a public track,
+/// the owner, and holders of a share link to it. Checking a share link doesn't start
+/// a play.
+pub(super) async fn reaches_track(
+    state: &AppState,
+    track_id: &str,
+    session: &HlsSession,
+    headers: &HeaderMap,
+    uri: &Uri,
+) -> bool {
+    if session.visibility == Visibility::Public {
+        return true;
+    }
+    let query = token_query(uri);
+    let token = presented_token(headers, &query);
+    if is_owner_token(state, token) {
+        return true;
+    }
+    match token {
+        Some(token) => share_access(state, track_id, token, false, track_duration(session)).await,
+        None => false,
+    }
+}
+
+/// Answers `404` for a private track's HLS files and `/api/tracks/{id}/...` routes,
+/// as if it didn't exist, unless the owner asks or the request holds a share link
+/// to the track. Plays through share links are counted here.
+pub(super) async fn hide_private_tracks(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let key = match path.strip_prefix("/api/hls/") {
+        Some(rest) => Some((rest, true)),
+        None => path.strip_prefix("/api/tracks/").map(|rest| (rest, false)),
+    };
+    if let Some((rest, by_session)) = key {
+        let id = rest.split('/').next().unwrap_or_default();
+        let track = {
+            let cache = state.hls_cache.read().await;
+            let track = match by_session {
+                true => cache.iter().find(|(_, s)| s.id == id),
+                false => cache.get_key_value(id),
+            };
+            track.map(|(track_id, s)| (track_id.clone(), s.visibility, track_duration(s)))
+        };
+        if let Some((track_id, visibility, duration)) = track {
+            let query = token_query(request.uri());
+            let token = presented_token(request.headers(), &query);
+            if !is_owner_token(&state, token) {
+                let playlist = by_session && rest.ends_with("/playlist.m3u8");
+                let shared = match token {
+                    Some(token) => share_access(&state, &track_id, token, playlist, duration).await,
+                    None => false,
+                };
+                if visibility == Visibility::Private && !shared {
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+            }
+        }
+    }
+    next.run(request).await
+}
+
+/// Answers `401` for the `/api/admin/...` routes unless the admin asks.
+pub(super) async fn require_admin(Admin(admin): Admin, request: Request, next: Next) -> Response {
+    match admin {
+        true => next.run(request).await,
+        false => unauthorized(),
+    }
+}
+
+/// Make a track public or private; only the owner may
+pub(super) async fn set_visibility(
+    State(state): State<AppState>,
+    Owner(owner): Owner,
+    Path(track_id): Path<String>,
+    Json(request): Json<VisibilityRequest>,
+) -> Response {
+    if !owner {
+        return unauthorized();
+    }
+    let track = {
+        let mut cache = state.hls_cache.write().await;
+        let Some(session) = cache.get_mut(&track_id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if session.read_only {
+            return read_only_track().into_response();
+        }
+        if session.visibility == request.visibility {
+            return Json(track_info(&track_id, session)).into_response();
+        }
+        session.visibility = request.visibility;
+        track_info(&track_id, session)
+    };
+
+    state.hls_cache.changed();
+    Json(track).into_response()
+}
@@ -0,0 +1,35 @@
+//! Updating yt-dlp from the admin API, as its extractors break when sites change.
+
+use super::{json_error, AppState};
+use crate::tools::update_ytdlp;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set while an update runs. Libraries hosted with `--library` share the binary, so
+/// it's one per process rather than per library.
+static UPDATING: AtomicBool = AtomicBool::new(false);
+
+/// Runs `yt-dlp -U` and reports the version before and after. Downloads already
+/// running keep the old version; the next ones use the new one.
+pub(super) async fn update(State(state): State<AppState>) -> Response {
+    if UPDATING.swap(true, Ordering::SeqCst) {
+        return json_error("yt-dlp is already being updated", StatusCode::CONFLICT);
+    }
+    let result = update_ytdlp(
+        &state.ingest_options.extractor.ytdlp_path,
+        &state.ingest_options.processes,
+    )
+    .await;
+    UPDATING.store(false, Ordering::SeqCst);
+
+    match result {
+        Ok(update) => Json(update).into_response(),
+        Err(e) => json_error(
+            &format!("yt-dlp update failed: {}", e),
+            StatusCode::BAD_GATEWAY,
+        ),
+    }
+}
@@ -0,0 +1,508 @@
+//! Periodic snapshots of the library's metadata files to a backup directory or an S3
+//! bucket, keeping the newest few.
+
+use crate::config::{BackupExtra, Config};
+use crate::storage::{unix_timestamp, utc_date, SECONDS_PER_DAY};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Files holding the tracks, their notes and identifications, ratings, resume
+/// positions, devices, key grants and subscriptions. Always backed up.
+const METADATA_FILES: [&str; 7] = [
+    "hls_cache.json",
+    "ratings.json",
+    "positions.json",
+    "devices.json",
+    "key_grants.json",
+    "source_checks.json",
+    "subscriptions.json",
+];
+
+const PLAYLIST_FILES: [&str; 2] = ["collections.json", "queues.json"];
+
+const HISTORY_FILES: [&str; 1] = ["history.jsonl"];
+
+const NAME_PREFIX: &str = "music-lib-";
+const NAME_SUFFIX: &str = ".json.gz";
+
+/// Where backups are written.
+pub(crate) enum BackupTarget {
+    Dir(PathBuf),
+    S3(S3Bucket),
+}
+
+impl BackupTarget {
+    /// The target given by --backup-dir or --backup-s3, if any.
+    pub(crate) fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        if let Some(dir) = &config.backup_dir {
+            return Ok(Some(BackupTarget::Dir(dir.clone())));
+        }
+        let Some(location) = &config.backup_s3 else {
+            return Ok(None);
+        };
+
+        let location = location.trim_start_matches("s3://").trim_matches('/');
+        let (bucket, prefix) = match location.split_once('/') {
+            Some((bucket, prefix)) => (bucket, format!("{}/", prefix)),
+            None => (location, String::new()),
+        };
+        if bucket.is_empty() {
+            return Err("--backup-s3 needs a bucket name".to_string());
+        }
+        let (Ok(access_key), Ok(secret_key)) = (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) else {
+            return Err(
+                "--backup-s3 needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY to be set"
+                    .to_string(),
+            );
+        };
+        Ok(Some(BackupTarget::S3(S3Bucket {
+            endpoint: config.backup_s3_endpoint.clone(),
+            region: config.backup_s3_region.clone(),
+            bucket: bucket.to_string(),
+            prefix,
+            access_key,
+            secret_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            client: reqwest::Client::new(),
+        })))
+    }
+
+    /// Where the backups of the library `name` hosted with `--library` go, so libraries
+    /// don't rotate out each other's backups.
+    pub(crate) fn for_library(self, name: &str) -> Self {
+        match self {
+            BackupTarget::Dir(dir) => BackupTarget::Dir(dir.join(name)),
+            BackupTarget::S3(mut s3) => {
+                s3.prefix = format!("{}{}/", s3.prefix, name);
+                BackupTarget::S3(s3)
+            }
+        }
+    }
+}
+
+/// A bucket on S3 or an S3 compatible store, addressed path-style
+/// (`<endpoint>/<bucket>/<key>`) so MinIO and friends work too.
+pub(crate) struct S3Bucket {
+    pub(crate) endpoint: String,
+    pub(crate) region: String,
+    pub(crate) bucket: String,
+    /// Key prefix, empty or ending in `/`
+    pub(crate) prefix: String,
+    pub(crate) access_key: String,
+    pub(crate) secret_key: String,
+    pub(crate) session_token: Option<String>,
+    pub(crate) client: reqwest::Client,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct BackupInfo {
+    pub(crate) name: String,
+    /// Unix time the snapshot was taken
+    pub(crate) created_at: u64,
+    pub(crate) size: u64,
+}
+
+/// The snapshot: every backed up file's contents by name.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) created_at: u64,
+    pub(crate) files: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    /// Reads a backup file, or the newest backup in a directory of them.
+    pub(crate) async fn read(path: &Path) -> Result<(PathBuf, Snapshot), Error> {
+        let path = if path.is_dir() {
+            let newest = list_dir(path)
+                .await?
+                .into_iter()
+                .max_by_key(|backup| backup.created_at)
+                .ok_or_else(|| format!("No backups in {}", path.display()))?;
+            path.join(newest.name)
+        } else {
+            path.to_path_buf()
+        };
+
+        let data = tokio::fs::read(&path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let snapshot = serde_json::from_reader(GzDecoder::new(data.as_slice()))
+            .map_err(|e| format!("{} is not a backup: {}", path.display(), e))?;
+        Ok((path, snapshot))
+    }
+}
+
+pub(crate) struct Backups {
+    pub(crate) target: BackupTarget,
+    pub(crate) cache_dir: PathBuf,
+    pub(crate) include: Vec<BackupExtra>,
+    pub(crate) interval: Duration,
+    /// How many backups are kept; older ones are deleted after each backup
+    pub(crate) keep: usize,
+    /// Held while a backup is taken, so two never run at once
+    running: tokio::sync::Mutex<()>,
+    last_error: std::sync::Mutex<Option<String>>,
+}
+
+impl Backups {
+    pub(crate) fn new(
+        target: BackupTarget,
+        cache_dir: PathBuf,
+        include: Vec<BackupExtra>,
+        interval: Duration,
+        keep: usize,
+    ) -> Self {
+        Backups {
+            target,
+            cache_dir,
+            include,
+            interval,
+            keep: keep.max(1),
+            running: tokio::sync::Mutex::new(()),
+            last_error: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Where backups go, for display.
+    pub(crate) fn location(&self) -> String {
+        match &self.target {
+            BackupTarget::Dir(dir) => dir.display().to_string(),
+            BackupTarget::S3(s3) => format!("s3://{}/{}", s3.bucket, s3.prefix),
+        }
+    }
+
+    /// The error of the last scheduled or requested backup, if it failed.
+    pub(crate) fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Backups at the target, newest first.
+    pub(crate) async fn list(&self) -> Result<Vec<BackupInfo>, Error> {
+        let mut backups = match &self.target {
+            BackupTarget::Dir(dir) => list_dir(dir).await?,
+            BackupTarget::S3(s3) => s3.list().await?,
+        };
+        backups.sort_by_key(|backup| std::cmp::Reverse(backup.created_at));
+        Ok(backups)
+    }
+
+    /// Snapshots the metadata files, uploads the snapshot and deletes the backups
+    /// beyond `keep`.
+    pub(crate) async fn backup(&self) -> Result<BackupInfo, Error> {
+        let _running = self.running.lock().await;
+        let result = self.take().await;
+        *self.last_error.lock().unwrap() = result.as_ref().err().map(ToString::to_string);
+        result
+    }
+
+    async fn take(&self) -> Result<BackupInfo, Error> {
+        let created_at = unix_timestamp();
+        let mut snapshot = Snapshot {
+            created_at,
+            files: BTreeMap::new(),
+        };
+        for name in self.files() {
+            match tokio::fs::read_to_string(self.cache_dir.join(name)).await {
+                Ok(content) => {
+                    snapshot.files.insert(name.to_string(), content);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("Failed to read {}: {}", name, e).into()),
+            }
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&serde_json::to_vec(&snapshot)?)?;
+        let data = encoder.finish()?;
+
+        let name = format!("{}{}{}", NAME_PREFIX, created_at, NAME_SUFFIX);
+        let info = BackupInfo {
+            name: name.clone(),
+            created_at,
+            size: data.len() as u64,
+        };
+        match &self.target {
+            BackupTarget::Dir(dir) => {
+                tokio::fs::create_dir_all(dir).await?;
+                // Written under another name first, so a crash never leaves a truncated
+                // backup that looks complete
+                let partial = dir.join(format!("{}.partial", name));
+                tokio::fs::write(&partial, &data).await?;
+                tokio::fs::rename(&partial, dir.join(&name)).await?;
+            }
+            BackupTarget::S3(s3) => s3.put(&name, data).await?,
+        }
+
+        for old in self.list().await?.into_iter().skip(self.keep) {
+            let deleted = match &self.target {
+                BackupTarget::Dir(dir) => tokio::fs::remove_file(dir.join(&old.name))
+                    .await
+                    .map_err(Error::from),
+                BackupTarget::S3(s3) => s3.delete(&old.name).await,
+            };
+            if let Err(e) = deleted {
+                eprintln!("Warning: Failed to delete old backup {}: {}", old.name, e);
+            }
+        }
+        Ok(info)
+    }
+
+    fn files(&self) -> Vec<&'static str> {
+        let mut files = METADATA_FILES.to_vec();
+        if self.include.contains(&BackupExtra::Playlists) {
+            files.extend(PLAYLIST_FILES);
+        }
+        if self.include.contains(&BackupExtra::History) {
+            files.extend(HISTORY_FILES);
+        }
+        files
+    }
+}
+
+/// How long until the first scheduled backup: as soon as the newest existing backup
+/// is `interval` old, so restarts don't reset the schedule.
+pub(crate) async fn first_backup_in(backups: &Backups) -> Duration {
+    match backups.list().await {
+        Ok(list) => list.first().map_or(Duration::ZERO, |backup| {
+            let age = Duration::from_secs(unix_timestamp().saturating_sub(backup.created_at));
+            backups.interval.saturating_sub(age)
+        }),
+        Err(e) => {
+            eprintln!("Warning: Failed to list backups: {}", e);
+            Duration::ZERO
+        }
+    }
+}
+
+/// A scheduled backup.
+pub(crate) async fn scheduled_backup(backups: &Backups) -> Result<(), String> {
+    let backup = backups.backup().await.map_err(|e| e.to_string())?;
+    println!(
+        "✓ Backed up library metadata to {} ({} KiB)",
+        backup.name,
+        backup.size / 1024
+    );
+    Ok(())
+}
+
+/// Unix time a backup was taken, from its name; None for files that aren't backups.
+fn backup_time(name: &str) -> Option<u64> {
+    name.strip_prefix(NAME_PREFIX)?
+        .strip_suffix(NAME_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+async fn list_dir(dir: &Path) -> Result<Vec<BackupInfo>, Error> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut backups = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(created_at) = backup_time(&name) else {
+            continue;
+        };
+        let size = entry.metadata().await.map(|m| m.len()).unwrap_or_default();
+        backups.push(BackupInfo {
+            name,
+            created_at,
+            size,
+        });
+    }
+    Ok(backups)
+}
+
+impl S3Bucket {
+    async fn put(&self, name: &str, data: Vec<u8>) -> Result<(), Error> {
+        let key = format!("{}{}", self.prefix, name);
+        self.send(reqwest::Method::PUT, &key, &[], data).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), Error> {
+        let key = format!("{}{}", self.prefix, name);
+        self.send(reqwest::Method::DELETE, &key, &[], Vec::new())
+            .await?;
+        Ok(())
+    }
+
+    /// Backups under the prefix, via ListObjectsV2. Only the first 1000 keys are read,
+    /// which retention keeps well clear of.
+    async fn list(&self) -> Result<Vec<BackupInfo>, Error> {
+        let query = [("list-type", "2"), ("prefix", self.prefix.as_str())];
+        let body = self
+            .send(reqwest::Method::GET, "", &query, Vec::new())
+            .await?;
+
+        let mut backups = Vec::new();
+        for contents in body.split("<Contents>").skip(1) {
+            let Some(key) = xml_value(contents, "Key") else {
+                continue;
+            };
+            let name = key.strip_prefix(&self.prefix).unwrap_or(key);
+            let Some(created_at) = backup_time(name) else {
+                continue;
+            };
+            backups.push(BackupInfo {
+                name: name.to_string(),
+                created_at,
+                size: xml_value(contents, "Size")
+                    .and_then(|size| size.parse().ok())
+                    .unwrap_or_default(),
+            });
+        }
+        Ok(backups)
+    }
+
+    /// Sends a request signed with AWS Signature Version 4 and returns the response body.
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<String, Error> {
+        let now = unix_timestamp();
+        let date = utc_date(now / SECONDS_PER_DAY).replace('-', "");
+        let time = now % SECONDS_PER_DAY;
+        let amz_date = format!(
+            "{}T{:02}{:02}{:02}Z",
+            date,
+            time / 3600,
+            time / 60 % 60,
+            time % 60
+        );
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let mut path = format!("/{}", uri_encode(&self.bucket, true));
+        if !key.is_empty() {
+            path.push('/');
+            path.push_str(&uri_encode(key, false));
+        }
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let endpoint = self.endpoint.trim_end_matches('/');
+        let url = reqwest::Url::parse(&format!("{}{}", endpoint, path))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(format!("Invalid S3 endpoint: {}", endpoint).into()),
+        };
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, payload_hash
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut signing_key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part);
+        }
+        let signature = hex::encode(hmac(&signing_key, &string_to_sign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let full_url = if query.is_empty() {
+            url.to_string()
+        } else {
+            format!("{}?{}", url, query)
+        };
+        let mut request = self
+            .client
+            .request(method, full_url)
+            .header("Authorization", authorization)
+            .body(body);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            let message = xml_value(&text, "Message").unwrap_or(text.trim());
+            return Err(format!("S3 answered {}: {}", status.as_u16(), message).into());
+        }
+        Ok(text)
+    }
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` too unless it
+/// separates key segments, as Signature Version 4 requires.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Text of the first `<tag>` element in a snippet of XML.
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..end])
+}
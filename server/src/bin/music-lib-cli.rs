@@ -0,0 +1,406 @@
+//! Command line client for a running music-lib server: add URLs, list and delete
+//! tracks, and follow downloads, for scripts and headless use over SSH.
+
+use clap::{Parser, Subcommand};
+use music_lib::downloader::{DownloadStatus, Priority};
+use reqwest::{Method, StatusCode};
+use std::collections::HashMap;
+use std::time::Duration;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const DEFAULT_SERVER: &str = "http://localhost:8080";
+
+/// How often download statuses are polled while following them.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Command line client for the music-lib HTTP API", long_about = None)]
+struct Cli {
+    /// Server URL; defaults to $MUSIC_LIB_SERVER, then http://localhost:8080
+    #[arg(long, global = true)]
+    server: Option<String>,
+
+    /// Print the server's JSON responses instead of text
+    #[arg(long, global = true, default_value = "false")]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Download URLs into the library and follow them until they are converted
+    Add {
+        #[arg(required = true)]
+        urls: Vec<String>,
+
+        /// Track title; only with a single URL
+        #[arg(long)]
+        title: Option<String>,
+
+        #[arg(long)]
+        artist: Option<String>,
+
+        #[arg(long)]
+        album: Option<String>,
+
+        /// Turn an upload with chapters into one track per chapter
+        #[arg(long, default_value = "false")]
+        split_chapters: bool,
+
+        /// Keep the music video next to the audio
+        #[arg(long, default_value = "false")]
+        video: bool,
+
+        #[arg(long, value_enum, default_value = "normal")]
+        priority: Priority,
+
+        /// Return once the downloads are queued instead of following them
+        #[arg(long, default_value = "false")]
+        detach: bool,
+    },
+    /// List the tracks in the library
+    List,
+    /// Delete tracks by id
+    Delete {
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Follow downloads until they finish; every unfinished download when no ids are given
+    Watch { ids: Vec<String> },
+}
+
+struct Client {
+    http: reqwest::Client,
+    server: String,
+}
+
+/// A failed request, with the response status when the server answered.
+#[derive(Debug)]
+struct RequestError {
+    status: Option<StatusCode>,
+    message: String,
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} ({})", self.message, status.as_u16()),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl Client {
+    /// Sends a request and returns the JSON body, turning error responses into their
+    /// `message`.
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, RequestError> {
+        let mut request = self
+            .http
+            .request(method, format!("{}{}", self.server, path));
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await.map_err(|e| RequestError {
+            status: None,
+            message: format!("Could not reach {}: {}", self.server, e),
+        })?;
+
+        let status = response.status();
+        let body: serde_json::Value = response.json().await.unwrap_or_default();
+        if !status.is_success() {
+            let message = body["message"]
+                .as_str()
+                .or(status.canonical_reason())
+                .unwrap_or("Request failed");
+            return Err(RequestError {
+                status: Some(status),
+                message: message.to_string(),
+            });
+        }
+        Ok(body)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let server = cli
+        .server
+        .or_else(|| std::env::var("MUSIC_LIB_SERVER").ok())
+        .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+    let client = Client {
+        http: reqwest::Client::new(),
+        server: server.trim_end_matches('/').to_string(),
+    };
+
+    let result = match cli.command {
+        Command::Add {
+            urls,
+            title,
+            artist,
+            album,
+            split_chapters,
+            video,
+            priority,
+            detach,
+        } => {
+            if title.is_some() && urls.len() > 1 {
+                Err("--title can only be given with a single URL".into())
+            } else {
+                let requests = urls
+                    .iter()
+                    .map(|url| {
+                        serde_json::json!({
+                            "url": url,
+                            "title": title,
+                            "artist": artist,
+                            "album": album,
+                            "split_chapters": split_chapters,
+                            "video": video,
+                            "priority": priority,
+                        })
+                    })
+                    .collect();
+                add(&client, requests, detach, cli.json).await
+            }
+        }
+        Command::List => list(&client, cli.json).await,
+        Command::Delete { ids } => delete(&client, &ids, cli.json).await,
+        Command::Watch { ids } => watch(&client, ids, cli.json).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Queues the downloads as one batch, so the server answers right away with their ids.
+async fn add(
+    client: &Client,
+    requests: Vec<serde_json::Value>,
+    detach: bool,
+    json: bool,
+) -> Result<(), Error> {
+    let batch = client
+        .request(
+            Method::POST,
+            "/api/download/batch",
+            Some(serde_json::Value::Array(requests)),
+        )
+        .await
+        .map_err(|e| -> Error {
+            match e.status {
+                Some(StatusCode::NOT_FOUND) => {
+                    "The server doesn't accept downloads; is it in readonly mode?".into()
+                }
+                _ => e.into(),
+            }
+        })?;
+
+    let downloads = batch["downloads"].as_array().cloned().unwrap_or_default();
+    let ids: Vec<String> = downloads
+        .iter()
+        .filter_map(|download| Some(download["download_id"].as_str()?.to_string()))
+        .collect();
+    if json {
+        if detach {
+            println!("{}", serde_json::to_string_pretty(&batch)?);
+        }
+    } else {
+        for download in &downloads {
+            println!(
+                "Queued {} as {}",
+                download["url"].as_str().unwrap_or_default(),
+                download["download_id"].as_str().unwrap_or_default()
+            );
+        }
+    }
+
+    if detach {
+        return Ok(());
+    }
+    watch(client, ids, json).await
+}
+
+async fn list(client: &Client, json: bool) -> Result<(), Error> {
+    let body = client.request(Method::GET, "/api/tracks", None).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&body)?);
+        return Ok(());
+    }
+
+    let mut tracks = body.as_array().cloned().unwrap_or_default();
+    tracks.sort_by(|a, b| {
+        let key = |track: &serde_json::Value| {
+            (
+                track["artist"].as_str().map(str::to_lowercase),
+                track["album"].as_str().map(str::to_lowercase),
+                track["title"].as_str().map(str::to_lowercase),
+            )
+        };
+        key(a).cmp(&key(b))
+    });
+    for track in &tracks {
+        let title = track["title"].as_str().unwrap_or_default();
+        let name = match track["artist"].as_str() {
+            Some(artist) => format!("{} - {}", artist, title),
+            None => title.to_string(),
+        };
+        let seconds = track["duration"].as_f64().unwrap_or_default().round() as u64;
+        println!(
+            "{}  {:>3}:{:02}  {}",
+            track["id"].as_str().unwrap_or_default(),
+            seconds / 60,
+            seconds % 60,
+            name
+        );
+    }
+    if tracks.is_empty() {
+        eprintln!("The library is empty");
+    }
+    Ok(())
+}
+
+async fn delete(client: &Client, ids: &[String], json: bool) -> Result<(), Error> {
+    let mut failed = 0;
+    for id in ids {
+        match client
+            .request(Method::DELETE, &format!("/api/tracks/{}", id), None)
+            .await
+        {
+            Ok(body) if json => println!("{}", serde_json::to_string(&body)?),
+            Ok(body) => println!("✓ {}", body["message"].as_str().unwrap_or("Deleted")),
+            Err(e) => {
+                eprintln!("✗ {}: {}", id, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} tracks could not be deleted", failed, ids.len()).into());
+    }
+    Ok(())
+}
+
+/// Polls downloads until each is ready or failed, printing a line whenever one moves on.
+/// With `json`, each change is printed as the status object, one per line.
+async fn watch(client: &Client, ids: Vec<String>, json: bool) -> Result<(), Error> {
+    let mut pending = if ids.is_empty() {
+        let body = client.request(Method::GET, "/api/downloads", None).await?;
+        let jobs: Vec<DownloadStatus> = serde_json::from_value(body)?;
+        let ids: Vec<String> = jobs
+            .into_iter()
+            .filter(|job| !is_finished(job))
+            .map(|job| job.id)
+            .collect();
+        if ids.is_empty() && !json {
+            println!("No downloads in progress");
+        }
+        ids
+    } else {
+        ids
+    };
+
+    let mut shown: HashMap<String, String> = HashMap::new();
+    let mut failed = 0;
+    while !pending.is_empty() {
+        let mut unfinished = Vec::with_capacity(pending.len());
+        for id in pending {
+            let body = client
+                .request(Method::GET, &format!("/api/download/{}", id), None)
+                .await
+                .map_err(|e| -> Error {
+                    match e.status {
+                        Some(StatusCode::NOT_FOUND) => format!("No download with id {}", id).into(),
+                        _ => e.into(),
+                    }
+                })?;
+            let status: DownloadStatus = serde_json::from_value(body)?;
+            if status.status == "error" {
+                failed += 1;
+            }
+
+            let line = describe(&status);
+            if shown.get(&id) != Some(&line) {
+                if json {
+                    println!("{}", serde_json::to_string(&status)?);
+                } else if status.status == "error" {
+                    eprintln!("{}  {}", short_id(&id), line);
+                } else {
+                    println!("{}  {}", short_id(&id), line);
+                }
+                shown.insert(id.clone(), line);
+            }
+            if !is_finished(&status) {
+                unfinished.push(id);
+            }
+        }
+
+        pending = unfinished;
+        if !pending.is_empty() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} downloads failed", failed).into());
+    }
+    Ok(())
+}
+
+fn is_finished(status: &DownloadStatus) -> bool {
+    matches!(status.status.as_str(), "ready" | "error")
+}
+
+/// One line of progress; it only changes when the stage or whole percentage does, so
+/// a slow download doesn't print a line per poll.
+fn describe(status: &DownloadStatus) -> String {
+    match status.status.as_str() {
+        "ready" => {
+            let titles: Vec<&str> = match &status.session {
+                Some(session) if !session.tracks.is_empty() => session
+                    .tracks
+                    .iter()
+                    .map(|track| track.title.as_str())
+                    .collect(),
+                Some(session) => vec![session.title.as_str()],
+                None => Vec::new(),
+            };
+            format!("✓ Ready: {}", titles.join(", "))
+        }
+        "error" => format!(
+            "✗ Failed: {}",
+            status.error.as_deref().unwrap_or("unknown error")
+        ),
+        _ => {
+            let mut line = status
+                .progress
+                .clone()
+                .unwrap_or_else(|| status.status.clone());
+            if let (Some(speed), Some(eta)) = (status.speed, status.eta_seconds) {
+                line.push_str(&format!(
+                    " ({:.1} MiB/s, {}s left)",
+                    speed / (1024.0 * 1024.0),
+                    eta
+                ));
+            }
+            line
+        }
+    }
+}
+
+fn short_id(id: &str) -> &str {
+    &id[..id.len().min(8)]
+}
@@ -0,0 +1,776 @@
+//! Command line configuration.
+
+use crate::storage::{AudioCodec, CacheDirs};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+// Runs the server when no command is given, so `music-server --port 9000` keeps working
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub serve: Config,
+}
+
+// The maintenance commands work on the cache directory directly; they are meant to run
+// while the server is stopped, as it would overwrite their changes with its own copy
+// of the library.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server (the default)
+    Serve(Box<Config>),
+    /// Convert local audio files into library tracks, split by a cue sheet next to them
+    Import(ImportArgs),
+    /// Write tracks out of the library as tagged audio files
+    Export(ExportArgs),
+    /// Check that every track's playlist, segments and key are in place
+    Verify(VerifyArgs),
+    /// Delete track directories the library no longer refers to, and references
+    /// to deleted tracks
+    Gc(GcArgs),
+    /// Put the library metadata back as it was in a backup, checked against the
+    /// track directories on disk
+    Restore(RestoreArgs),
+    /// Update yt-dlp to its latest release with `yt-dlp -U`
+    UpdateYtdlp(UpdateYtdlpArgs),
+}
+
+#[derive(Debug, Clone, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct Config {
+    #[arg(long, default_value = "8080")]
+    pub port: u16,
+
+    #[command(flatten)]
+    pub cache: CachePaths,
+
+    /// Host a separate library under this name (repeatable). Each keeps its tracks and
+    /// metadata in `libraries/<name>` of the cache directories, and is reached at
+    /// `/libraries/<name>/...` or with an `X-Library: <name>` header
+    #[arg(long = "library", value_parser = parse_library_name)]
+    pub libraries: Vec<String>,
+
+    /// Enable readonly mode - disables adding and removing tracks
+    #[arg(long, default_value = "false")]
+    pub readonly: bool,
+
+    /// Seconds a change to the library may wait before hls_cache.json is written, so
+    /// changes close together are written once; 0 writes every change right away
+    #[arg(long, default_value = "5")]
+    pub save_delay: u64,
+
+    /// Write hls_cache.json as soon as this many changes are waiting
+    #[arg(long, default_value = "100")]
+    pub save_batch: usize,
+
+    /// Days finished downloads stay in the download history
+    #[arg(long, default_value = "7")]
+    pub download_history_days: u64,
+
+    /// Enable the continuous radio stream at /stream.mp3
+    #[arg(long, default_value = "false")]
+    pub radio: bool,
+
+    /// Track order for the radio stream
+    #[arg(long, value_enum, default_value = "shuffle")]
+    pub radio_order: RadioOrder,
+
+    /// Origins allowed to call the API from a browser, comma separated, or "*" for any
+    #[arg(long, value_delimiter = ',', default_value = "*")]
+    pub cors_origins: Vec<String>,
+
+    /// Allow browsers to send cookies and auth headers cross-origin
+    /// (requires explicit --cors-origins)
+    #[arg(long, default_value = "false")]
+    pub cors_credentials: bool,
+
+    /// Mirror tracks from a primary music-lib server (e.g. http://primary:8080)
+    #[arg(long)]
+    pub sync_from: Option<String>,
+
+    /// Seconds between sync runs
+    #[arg(long, default_value = "300")]
+    pub sync_interval: u64,
+
+    /// Check every this many hours that tracks' origin URLs still resolve, flagging
+    /// tracks whose source was taken down
+    #[arg(long)]
+    pub source_check_interval: Option<u64>,
+
+    /// Hours between checks of the subscribed podcast feeds and channels for new uploads
+    #[arg(long, default_value = "6")]
+    pub subscription_interval: u64,
+
+    /// Serve tracks of another music-lib server, caching playlists and segments on first request
+    #[arg(long)]
+    pub upstream: Option<String>,
+
+    /// Size limit in MiB of the cached upstream files; the least recently played
+    /// tracks are deleted beyond it (default: unlimited)
+    #[arg(long)]
+    pub upstream_cache_mb: Option<u64>,
+
+    /// Cache directory of another music-lib instance (e.g. a read-only network share)
+    /// whose tracks are listed and streamed next to this library's (repeatable)
+    #[arg(long = "overlay-path")]
+    pub overlay_paths: Vec<PathBuf>,
+
+    /// Seconds between re-reads of the overlay libraries
+    #[arg(long, default_value = "300")]
+    pub overlay_interval: u64,
+
+    /// URL receiving webhook events (can be given multiple times)
+    #[arg(long = "webhook-url")]
+    pub webhook_urls: Vec<String>,
+
+    /// Secret used to sign webhook payloads (X-Music-Lib-Signature header)
+    #[arg(long)]
+    pub webhook_secret: Option<String>,
+
+    /// ntfy topic URL to push notifications to, e.g. https://ntfy.sh/my-music-lib
+    #[arg(long)]
+    pub ntfy_url: Option<String>,
+
+    /// Access token for a protected ntfy topic
+    #[arg(long)]
+    pub ntfy_token: Option<String>,
+
+    /// Gotify server URL to push notifications to
+    #[arg(long)]
+    pub gotify_url: Option<String>,
+
+    /// Gotify application token
+    #[arg(long)]
+    pub gotify_token: Option<String>,
+
+    /// Events pushed to ntfy or Gotify (comma separated)
+    #[arg(
+        long = "notify-event",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "download_failed,batch_finished"
+    )]
+    pub notify_events: Vec<NotifyEvent>,
+
+    /// Telegram bot token from @BotFather; links sent to the bot are downloaded into
+    /// the library
+    #[arg(long)]
+    pub telegram_token: Option<String>,
+
+    /// Telegram chat allowed to add tracks through the bot (repeatable); the bot
+    /// tells other chats their id
+    #[arg(long = "telegram-chat", allow_negative_numbers = true)]
+    pub telegram_chats: Vec<i64>,
+
+    /// Telegram Bot API server, for a self-hosted one
+    #[arg(long, default_value = "https://api.telegram.org")]
+    pub telegram_api_url: String,
+
+    /// Address the server is reached at (e.g. https://music.example.com), put in
+    /// front of the playback links the bot sends
+    #[arg(long)]
+    pub public_url: Option<String>,
+
+    /// Maximum number of tracks in the library
+    #[arg(long)]
+    pub max_tracks: Option<usize>,
+
+    /// Maximum number of tracks converted with ffmpeg at the same time
+    #[arg(long, default_value = "2")]
+    pub max_transcodes: usize,
+
+    /// Only accept downloads from this domain and its subdomains (repeatable)
+    #[arg(long = "allow-domain")]
+    pub allowed_domains: Vec<String>,
+
+    /// Refuse downloads from this domain and its subdomains (repeatable)
+    #[arg(long = "block-domain")]
+    pub blocked_domains: Vec<String>,
+
+    /// Abort a download that runs longer than this many seconds
+    #[arg(long)]
+    pub download_timeout: Option<u64>,
+
+    /// Refuse sources larger than this size (e.g. 200M, 1G)
+    #[arg(long)]
+    pub max_filesize: Option<String>,
+
+    /// Refuse sources longer than this many seconds, or whose length is unknown
+    /// (such as livestreams)
+    #[arg(long)]
+    pub max_duration: Option<u64>,
+
+    /// Cap each download's speed, in bytes per second with an optional K, M or G
+    /// suffix (e.g. 2M); requests may ask for a lower rate
+    #[arg(long, value_parser = parse_rate)]
+    pub limit_rate: Option<u64>,
+
+    /// ffmpeg binary to run, when it isn't `ffmpeg` on the PATH
+    #[arg(long, default_value = "ffmpeg")]
+    pub ffmpeg_path: PathBuf,
+
+    /// yt-dlp binary to run, when it isn't `yt-dlp` on the PATH
+    #[arg(long, default_value = "yt-dlp")]
+    pub ytdlp_path: PathBuf,
+
+    /// Make tracks with the built-in fake tools rather than yt-dlp and ffmpeg: every
+    /// source downloads as a short made-up track, converted into the same segments
+    /// each time. For tests only
+    #[cfg(feature = "fake-tools")]
+    #[arg(long, hide = true)]
+    pub fake_tools: bool,
+
+    /// Kill an ffmpeg, yt-dlp, fpcalc or hook process still running after this many
+    /// seconds, with everything it started (0 for no limit); --download-timeout may
+    /// set a shorter one for downloads
+    #[arg(long, default_value = "3600")]
+    pub process_timeout: u64,
+
+    /// Cap the address space of each of those processes, in bytes with an optional
+    /// K, M or G suffix (e.g. 4G)
+    #[arg(long, value_parser = parse_size)]
+    pub process_memory: Option<u64>,
+
+    /// Cap the CPU time of each of those processes, in seconds
+    #[arg(long)]
+    pub process_cpu_time: Option<u64>,
+
+    /// Start those processes in this cgroup v2 directory, whose memory.max, cpu.max
+    /// and pids.max then limit them together
+    #[arg(long)]
+    pub process_cgroup: Option<PathBuf>,
+
+    /// yt-dlp extractor argument for every download, as EXTRACTOR:KEY=VALUE
+    /// (e.g. youtube:lang=de; repeatable)
+    #[arg(long = "extractor-arg", value_parser = parse_extractor_arg)]
+    pub extractor_args: Vec<ExtractorArg>,
+
+    /// Extractor argument that download requests may set besides the built-in
+    /// ones, as EXTRACTOR:KEY (repeatable)
+    #[arg(long = "allow-extractor-arg", value_parser = parse_extractor_key)]
+    pub allowed_extractor_args: Vec<String>,
+
+    /// yt-dlp format selector for downloads that don't ask for one
+    /// (e.g. "flac/bestaudio")
+    #[arg(long, value_parser = parse_format_selector)]
+    pub download_format: Option<String>,
+
+    /// AcoustID API key; downloads without a title are then identified by their
+    /// audio fingerprint (requires fpcalc from Chromaprint)
+    #[arg(long)]
+    pub acoustid_key: Option<String>,
+
+    /// Minimum AcoustID score (0-1) for a match to be applied to a track
+    #[arg(long, default_value = "0.8")]
+    pub acoustid_min_score: f64,
+
+    /// Extra ffmpeg arguments for transcode jobs, placed before the input
+    /// (e.g. --ffmpeg-input-args "-hwaccel auto")
+    #[arg(long, allow_hyphen_values = true)]
+    pub ffmpeg_input_args: Option<String>,
+
+    /// Run transcode jobs with this CPU niceness (0-19)
+    #[arg(long, value_parser = clap::value_parser!(i32).range(0..=19))]
+    pub transcode_nice: Option<i32>,
+
+    /// Run transcode jobs in this IO scheduling class (Linux ionice)
+    #[arg(long, value_enum)]
+    pub transcode_ionice: Option<IoClass>,
+
+    /// Write each new track as a single .ts file addressed with EXT-X-BYTERANGE,
+    /// instead of one file per segment
+    #[arg(long, default_value = "false")]
+    pub single_file_hls: bool,
+
+    /// Encrypt new tracks' segments with a per-track AES-128 key, served only to
+    /// clients presenting --key-token
+    #[arg(long, default_value = "false")]
+    pub encrypt_segments: bool,
+
+    /// Token required to fetch segment keys, as `Authorization: Bearer <token>` or
+    /// `?token=<token>`
+    #[arg(long)]
+    pub key_token: Option<String>,
+
+    /// Token that shows private tracks, as `Authorization: Bearer <token>` or
+    /// `?token=<token>`; without it set, every track is visible to everyone
+    #[arg(long)]
+    pub owner_token: Option<String>,
+
+    /// Audio codec new tracks are converted to
+    #[arg(long, value_enum, default_value = "aac")]
+    pub audio_codec: AudioCodec,
+
+    /// Audio bitrate of new tracks in kbit/s
+    #[arg(long, default_value = "128", value_parser = clap::value_parser!(u32).range(32..=320))]
+    pub audio_bitrate: u32,
+
+    /// Target segment length of new tracks in seconds
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..=30))]
+    pub segment_duration: u32,
+
+    /// Embed title, artist and artwork as timed ID3 metadata in new tracks' segments,
+    /// for native HLS players such as the iOS lock screen
+    #[arg(long, default_value = "false")]
+    pub timed_metadata: bool,
+
+    /// Run a shell command at a pipeline stage: post-download, pre-segmentation or
+    /// post-ingest (e.g. --hook "post-ingest=notify-send \"$MUSIC_LIB_TITLE\"")
+    #[arg(long = "hook", value_parser = parse_hook)]
+    pub hooks: Vec<Hook>,
+
+    /// Cap the combined speed of HLS segment downloads for all listeners, in bytes
+    /// per second with an optional K, M or G suffix (e.g. 4M)
+    #[arg(long, value_parser = parse_rate)]
+    pub stream_rate_limit: Option<u64>,
+
+    /// Cap the speed of HLS segment downloads per client IP (e.g. 512K)
+    #[arg(long, value_parser = parse_rate)]
+    pub client_rate_limit: Option<u64>,
+
+    /// Store no client IPs or identifiers: plays are counted per track only,
+    /// without unique listeners, and existing listener IDs are removed from the history
+    #[arg(long, default_value = "false")]
+    pub private_stats: bool,
+
+    /// Playlist flavour served to players: `legacy` rewrites playlists for older iOS
+    /// and Smart TV players (HLS version 3, TARGETDURATION rounded up)
+    #[arg(long, value_enum, default_value = "standard")]
+    pub hls_profile: HlsProfile,
+
+    /// Memory budget in MiB for caching hot HLS segments (0 disables the cache)
+    #[arg(long, default_value = "64")]
+    pub segment_cache_mb: usize,
+
+    /// Segments after the one a listener requests to read ahead into the segment
+    /// cache, or from the upstream (0 disables read-ahead)
+    #[arg(long, default_value = "3")]
+    pub prefetch_segments: usize,
+
+    /// At startup, read the first segment of this many of the most played tracks
+    /// into the segment cache
+    #[arg(long, default_value = "0")]
+    pub warm_tracks: usize,
+
+    /// Serve a built single page app from this directory instead of the bundled web UI
+    #[arg(long)]
+    pub static_dir: Option<PathBuf>,
+
+    /// Periodically back up the library metadata to this directory
+    #[arg(long, conflicts_with = "backup_s3")]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Periodically back up the library metadata to this S3 bucket, optionally with a
+    /// key prefix (e.g. my-bucket/music-lib); credentials are read from
+    /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN
+    #[arg(long)]
+    pub backup_s3: Option<String>,
+
+    /// S3 endpoint, for S3 compatible stores such as MinIO
+    #[arg(long, default_value = "https://s3.amazonaws.com")]
+    pub backup_s3_endpoint: String,
+
+    #[arg(long, default_value = "us-east-1")]
+    pub backup_s3_region: String,
+
+    /// Hours between backups
+    #[arg(long, default_value = "24")]
+    pub backup_interval: u64,
+
+    /// Number of backups to keep; older ones are deleted
+    #[arg(long, default_value = "7")]
+    pub backup_keep: usize,
+
+    /// Also back up collections and play queues, or the play history (comma separated)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub backup_include: Vec<BackupExtra>,
+}
+
+/// The cache directory, which holds the library's files and track segments, and extra
+/// directories for segments on other disks.
+#[derive(Debug, Clone, Args)]
+pub struct CachePaths {
+    #[arg(long, default_value = "./hls_cache")]
+    pub cache_path: PathBuf,
+
+    /// Another directory for track segments, e.g. on a second disk (repeatable); new
+    /// tracks go to whichever cache directory has the most free space
+    #[arg(long = "extra-cache-path")]
+    pub extra_cache_paths: Vec<PathBuf>,
+}
+
+impl CachePaths {
+    pub fn dirs(&self) -> CacheDirs {
+        CacheDirs::new(self.cache_path.clone(), self.extra_cache_paths.clone())
+    }
+
+    /// The cache directories of the library `name` hosted with `--library`.
+    pub fn library_dirs(&self, name: &str) -> CacheDirs {
+        let dir = |path: &PathBuf| path.join(LIBRARIES_DIR).join(name);
+        CacheDirs::new(
+            dir(&self.cache_path),
+            self.extra_cache_paths.iter().map(dir).collect(),
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// Audio files, or directories searched recursively for them
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub cache: CachePaths,
+
+    /// Title of the track; only for a single file without a cue sheet
+    #[arg(long)]
+    pub title: Option<String>,
+
+    /// Artist of every imported track, unless its cue sheet names one
+    #[arg(long)]
+    pub artist: Option<String>,
+
+    /// Album of every imported track, unless its cue sheet names one
+    #[arg(long)]
+    pub album: Option<String>,
+
+    /// Import files as one track each even when a cue sheet sits next to them
+    #[arg(long, default_value = "false")]
+    pub ignore_cue: bool,
+
+    /// AcoustID API key; tracks without a title from --title or a cue sheet are
+    /// then identified by their audio fingerprint (requires fpcalc from Chromaprint)
+    #[arg(long)]
+    pub acoustid_key: Option<String>,
+
+    /// Minimum AcoustID score (0-1) for a match to be applied to a track
+    #[arg(long, default_value = "0.8")]
+    pub acoustid_min_score: f64,
+
+    /// Audio codec the tracks are converted to
+    #[arg(long, value_enum, default_value = "aac")]
+    pub audio_codec: AudioCodec,
+
+    /// Audio bitrate in kbit/s
+    #[arg(long, default_value = "128", value_parser = clap::value_parser!(u32).range(32..=320))]
+    pub audio_bitrate: u32,
+
+    /// Target segment length in seconds
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(1..=30))]
+    pub segment_duration: u32,
+
+    /// Write each track as a single .ts file addressed with EXT-X-BYTERANGE
+    #[arg(long, default_value = "false")]
+    pub single_file_hls: bool,
+
+    /// Encrypt the tracks' segments with a per-track AES-128 key
+    #[arg(long, default_value = "false")]
+    pub encrypt_segments: bool,
+
+    /// ffmpeg binary to run, when it isn't `ffmpeg` on the PATH
+    #[arg(long, default_value = "ffmpeg")]
+    pub ffmpeg_path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// Directory the audio files are written to
+    #[arg(long, short)]
+    pub output: PathBuf,
+
+    /// Tracks to export; every track when none are given
+    pub track_ids: Vec<String>,
+
+    #[command(flatten)]
+    pub cache: CachePaths,
+
+    /// Replace files that already exist in the output directory
+    #[arg(long, default_value = "false")]
+    pub overwrite: bool,
+
+    /// ffmpeg binary to run, when it isn't `ffmpeg` on the PATH
+    #[arg(long, default_value = "ffmpeg")]
+    pub ffmpeg_path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    pub cache: CachePaths,
+
+    /// Drop unplayable tracks from the library and clear artwork and video flags
+    /// whose files are missing
+    #[arg(long, default_value = "false")]
+    pub fix: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct GcArgs {
+    #[command(flatten)]
+    pub cache: CachePaths,
+
+    /// Only list what would be deleted
+    #[arg(long, default_value = "false")]
+    pub dry_run: bool,
+
+    /// Leave directories modified within this many minutes, which may still be
+    /// in use by a download
+    #[arg(long, default_value = "60")]
+    pub min_age: u64,
+}
+
+#[derive(Debug, Args)]
+pub struct RestoreArgs {
+    /// Backup file to restore, or a backup directory to restore its newest backup
+    pub backup: PathBuf,
+
+    #[command(flatten)]
+    pub cache: CachePaths,
+
+    /// Keep the tracks added after the backup was taken, instead of leaving their
+    /// directories for gc
+    #[arg(long, default_value = "false")]
+    pub keep_new: bool,
+
+    /// Only report what would be restored
+    #[arg(long, default_value = "false")]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct UpdateYtdlpArgs {
+    /// yt-dlp binary to update, when it isn't `yt-dlp` on the PATH
+    #[arg(long, default_value = "yt-dlp")]
+    pub ytdlp_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BackupExtra {
+    /// Collections and play queues
+    Playlists,
+    /// The play history, which grows with every listen
+    History,
+}
+
+/// Webhook events that can also be pushed as notifications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum NotifyEvent {
+    TrackAdded,
+    TrackRefreshed,
+    TrackDeleted,
+    SourceUnavailable,
+    /// A download or conversion failed
+    DownloadFailed,
+    QuotaExceeded,
+    /// Every job of a download batch (or playlist import) has finished
+    BatchFinished,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum RadioOrder {
+    Shuffle,
+    Sequential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HlsProfile {
+    /// Playlists as ffmpeg writes them
+    Standard,
+    /// Version 3 VOD playlists whose target duration is never below a segment's length
+    Legacy,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum IoClass {
+    /// Only gets disk time when nothing else needs it
+    Idle,
+    BestEffort,
+}
+
+impl IoClass {
+    /// The class number `ionice -c` expects.
+    pub fn number(self) -> u8 {
+        match self {
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    /// After the source was downloaded, before anything else touches the file
+    PostDownload,
+    /// Right before ffmpeg segments the file; the hook may modify it in place
+    PreSegmentation,
+    /// After the track has been added to the library
+    PostIngest,
+}
+
+impl HookStage {
+    pub fn name(self) -> &'static str {
+        match self {
+            HookStage::PostDownload => "post-download",
+            HookStage::PreSegmentation => "pre-segmentation",
+            HookStage::PostIngest => "post-ingest",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub stage: HookStage,
+    pub command: String,
+}
+
+pub fn parse_hook(value: &str) -> Result<Hook, String> {
+    let (stage, command) = value.split_once('=').ok_or("expected <stage>=<command>")?;
+    let stage = match stage.trim() {
+        "post-download" => HookStage::PostDownload,
+        "pre-segmentation" => HookStage::PreSegmentation,
+        "post-ingest" => HookStage::PostIngest,
+        other => return Err(format!("unknown hook stage '{}'", other)),
+    };
+    if command.trim().is_empty() {
+        return Err("hook command must not be empty".to_string());
+    }
+
+    Ok(Hook {
+        stage,
+        command: command.to_string(),
+    })
+}
+
+/// An argument for one of yt-dlp's extractors, as given to its `--extractor-args`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractorArg {
+    pub extractor: String,
+    pub key: String,
+    pub value: String,
+}
+
+impl ExtractorArg {
+    /// `EXTRACTOR:KEY`, as listed in allowlists.
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.extractor, self.key)
+    }
+}
+
+/// Longest extractor argument value or format selector accepted.
+const MAX_YTDLP_VALUE: usize = 512;
+
+/// Parses `EXTRACTOR:KEY=VALUE`. Values can't contain `;`, which separates
+/// arguments in yt-dlp's notation, or control characters.
+pub fn parse_extractor_arg(value: &str) -> Result<ExtractorArg, String> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or("expected <extractor>:<key>=<value>")?;
+    let name = parse_extractor_key(name)?;
+    let (extractor, key) = name
+        .split_once(':')
+        .expect("checked by parse_extractor_key");
+    if value.len() > MAX_YTDLP_VALUE {
+        return Err(format!(
+            "value of {} is longer than {} characters",
+            name, MAX_YTDLP_VALUE
+        ));
+    }
+    if value.contains(';') || value.chars().any(char::is_control) {
+        return Err(format!(
+            "value of {} must not contain ';' or control characters",
+            name
+        ));
+    }
+
+    Ok(ExtractorArg {
+        extractor: extractor.to_string(),
+        key: key.to_string(),
+        value: value.to_string(),
+    })
+}
+
+/// Parses `EXTRACTOR:KEY`, lowercased like yt-dlp does.
+pub fn parse_extractor_key(value: &str) -> Result<String, String> {
+    let (extractor, key) = value
+        .trim()
+        .split_once(':')
+        .ok_or("expected <extractor>:<key>")?;
+    let is_name = |name: &str| {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    if !is_name(extractor) || !is_name(key) {
+        return Err(format!(
+            "invalid extractor argument name '{}'; expected letters, digits and _",
+            value
+        ));
+    }
+    Ok(format!("{}:{}", extractor, key).to_ascii_lowercase())
+}
+
+/// Checks a yt-dlp format selector such as `bestaudio[ext=m4a]/bestaudio`.
+pub fn parse_format_selector(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    if value.is_empty() || value.len() > MAX_YTDLP_VALUE {
+        return Err(format!(
+            "format must be 1 to {} characters long",
+            MAX_YTDLP_VALUE
+        ));
+    }
+    // Selectors never start with a dash; one that does would be read as an option
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "[]()<>=!*?+/,.:_-^$~' ".contains(c);
+    if value.starts_with('-') || !value.chars().all(allowed) {
+        return Err(format!("invalid format selector '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+/// Directory of the cache directories holding the `--library` libraries.
+pub const LIBRARIES_DIR: &str = "libraries";
+
+const MAX_LIBRARY_NAME: usize = 64;
+
+/// Checks a `--library` name: lowercase letters, digits, `-` and `_`, as it names a
+/// directory and a path segment.
+pub fn parse_library_name(value: &str) -> Result<String, String> {
+    let valid = !value.is_empty()
+        && value.len() <= MAX_LIBRARY_NAME
+        && value
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        return Err(format!(
+            "invalid library name '{}'; expected up to {} lowercase letters, digits, - and _",
+            value, MAX_LIBRARY_NAME
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Parses a size in bytes, optionally followed by K, M or G (powers of 1024).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    parse_rate(value)
+        .map_err(|_| format!("invalid size '{}', expected e.g. 512M or 4G", value.trim()))
+}
+
+/// Parses a transfer rate like yt-dlp's `--limit-rate`: bytes per second, optionally
+/// followed by K, M or G (powers of 1024).
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024.0),
+        Some('M') => (&value[..value.len() - 1], 1024.0 * 1024.0),
+        Some('G') => (&value[..value.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (value, 1.0),
+    };
+    let rate = number
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite() && *n > 0.0)
+        .ok_or_else(|| format!("invalid rate '{}', expected e.g. 500K or 2M", value))?;
+    Ok((rate * multiplier).round().max(1.0) as u64)
+}
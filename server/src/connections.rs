@@ -0,0 +1,156 @@
+//! Who is streaming what: per-client segment counters for the admin view.
+
+use crate::storage::unix_timestamp;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A client that fetched no segment for this long is no longer listed.
+const CONNECTION_IDLE: Duration = Duration::from_secs(60);
+/// Bandwidth is averaged over this window.
+const BANDWIDTH_WINDOW: Duration = Duration::from_secs(30);
+
+/// Clients are told apart by address and, when sent, their `X-Device-Id`. With
+/// private stats nothing about the client is kept and listeners of the same HLS
+/// session share one entry.
+#[derive(PartialEq, Eq, Hash)]
+enum ClientKey {
+    Client(Option<IpAddr>, Option<String>),
+    Session(String),
+}
+
+struct Connection {
+    session_id: String,
+    user_agent: Option<String>,
+    connected_at: u64,
+    last_active: Instant,
+    segments_served: u64,
+    bytes_served: u64,
+    /// Segment sizes sent within the bandwidth window
+    recent: VecDeque<(Instant, u64)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ConnectionInfo {
+    pub(crate) client_ip: Option<String>,
+    pub(crate) device_id: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    /// HLS session of the segment fetched last
+    pub(crate) session_id: String,
+    pub(crate) connected_at: u64,
+    /// Seconds since the last segment request
+    pub(crate) idle_seconds: u64,
+    pub(crate) segments_served: u64,
+    pub(crate) bytes_served: u64,
+    pub(crate) bytes_per_second: f64,
+}
+
+pub(crate) struct Connections {
+    private: bool,
+    clients: Mutex<HashMap<ClientKey, Connection>>,
+}
+
+impl Connections {
+    pub(crate) fn new(private: bool) -> Self {
+        Connections {
+            private,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a segment sent to a client.
+    pub(crate) fn record(
+        &self,
+        client_ip: Option<IpAddr>,
+        device_id: Option<String>,
+        user_agent: Option<&str>,
+        session_id: &str,
+        bytes: u64,
+    ) {
+        let (key, user_agent) = if self.private {
+            (ClientKey::Session(session_id.to_string()), None)
+        } else {
+            (ClientKey::Client(client_ip, device_id), user_agent)
+        };
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        clients
+            .retain(|_, connection| now.duration_since(connection.last_active) < CONNECTION_IDLE);
+
+        let connection = clients.entry(key).or_insert_with(|| Connection {
+            session_id: session_id.to_string(),
+            user_agent: None,
+            connected_at: unix_timestamp(),
+            last_active: now,
+            segments_served: 0,
+            bytes_served: 0,
+            recent: VecDeque::new(),
+        });
+        connection.session_id = session_id.to_string();
+        connection.user_agent = user_agent.map(str::to_string);
+        connection.last_active = now;
+        connection.segments_served += 1;
+        connection.bytes_served += bytes;
+        connection.recent.push_back((now, bytes));
+        while connection
+            .recent
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > BANDWIDTH_WINDOW)
+        {
+            connection.recent.pop_front();
+        }
+    }
+
+    /// How many clients fetched a segment of `session_id` last, within the idle timeout.
+    pub(crate) fn listeners(&self, session_id: &str) -> usize {
+        let now = Instant::now();
+        self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| {
+                connection.session_id == session_id
+                    && now.duration_since(connection.last_active) < CONNECTION_IDLE
+            })
+            .count()
+    }
+
+    /// Clients active within the idle timeout, busiest first.
+    pub(crate) fn active(&self) -> Vec<ConnectionInfo> {
+        let now = Instant::now();
+        let clients = self.clients.lock().unwrap();
+        let mut active: Vec<ConnectionInfo> = clients
+            .iter()
+            .filter(|(_, connection)| now.duration_since(connection.last_active) < CONNECTION_IDLE)
+            .map(|(key, connection)| {
+                let recent_bytes: u64 = connection
+                    .recent
+                    .iter()
+                    .filter(|(at, _)| now.duration_since(*at) <= BANDWIDTH_WINDOW)
+                    .map(|(_, bytes)| bytes)
+                    .sum();
+                let (client_ip, device_id) = match key {
+                    ClientKey::Client(client_ip, device_id) => {
+                        (client_ip.map(|ip| ip.to_string()), device_id.clone())
+                    }
+                    ClientKey::Session(_) => (None, None),
+                };
+                ConnectionInfo {
+                    client_ip,
+                    device_id,
+                    user_agent: connection.user_agent.clone(),
+                    session_id: connection.session_id.clone(),
+                    connected_at: connection.connected_at,
+                    idle_seconds: now.duration_since(connection.last_active).as_secs(),
+                    segments_served: connection.segments_served,
+                    bytes_served: connection.bytes_served,
+                    bytes_per_second: recent_bytes as f64 / BANDWIDTH_WINDOW.as_secs_f64(),
+                }
+            })
+            .collect();
+        active.sort_by(|a, b| b.bytes_per_second.total_cmp(&a.bytes_per_second));
+        active
+    }
+}
@@ -0,0 +1,79 @@
+//! Content hashes in asset URLs: playlists name segments as `000.<hash>.ts` and
+//! artwork as `large.<hash>.jpg`, so CDNs and browsers can keep them forever and a
+//! file whose bytes change is fetched under a new URL.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+
+/// Hex digits of the SHA-256 kept in URLs.
+const HASH_LENGTH: usize = 16;
+
+pub(crate) fn content_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))[..HASH_LENGTH].to_string()
+}
+
+/// [`content_hash`] of a file, read in chunks so single-file tracks aren't held in
+/// memory whole.
+async fn file_hash(path: &Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize())[..HASH_LENGTH].to_string())
+}
+
+/// `name` with `hash` before its extension.
+pub(crate) fn hashed_name(name: &str, hash: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) => format!("{}.{}.{}", stem, hash, extension),
+        None => format!("{}.{}", name, hash),
+    }
+}
+
+/// The file name and hash of a name made by [`hashed_name`]; `None` for names
+/// without a hash.
+pub(crate) fn split_hashed_name(name: &str) -> Option<(String, &str)> {
+    let (rest, extension) = name.rsplit_once('.')?;
+    let (stem, hash) = rest.rsplit_once('.')?;
+    let is_hash = hash.len() == HASH_LENGTH
+        && hash
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte));
+    is_hash.then(|| (format!("{}.{}", stem, extension), hash))
+}
+
+/// Hashes of files served by the server, computed on first use and again when a
+/// file's size or modification time changes.
+#[derive(Default)]
+pub(crate) struct FileHashes {
+    known: Mutex<HashMap<PathBuf, (u64, SystemTime, String)>>,
+}
+
+impl FileHashes {
+    pub(crate) async fn hash(&self, path: &Path) -> Option<String> {
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        let modified = metadata.modified().ok()?;
+        if let Some((len, time, hash)) = self.known.lock().unwrap().get(path) {
+            if *len == metadata.len() && *time == modified {
+                return Some(hash.clone());
+            }
+        }
+
+        let hash = file_hash(path).await.ok()?;
+        self.known
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (metadata.len(), modified, hash.clone()));
+        Some(hash)
+    }
+}
@@ -0,0 +1,183 @@
+//! Cue sheets that accompany ripped albums: one audio file plus the track list within it.
+
+use crate::storage::Chapter;
+use std::path::{Path, PathBuf};
+
+/// CD frames per second, the unit of the last field in cue timestamps.
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Debug, Clone, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+/// An audio file referenced by a `FILE` line and the tracks it contains.
+#[derive(Debug, Clone)]
+pub struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Start of `INDEX 01` in seconds from the start of the file
+    pub start: f64,
+}
+
+impl CueFile {
+    /// The file's tracks as chapters; the last one runs until `duration`.
+    pub fn chapters(&self, duration: f64) -> Vec<Chapter> {
+        self.tracks
+            .iter()
+            .enumerate()
+            .map(|(index, track)| Chapter {
+                title: track
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| format!("Track {:02}", track.number)),
+                start: track.start,
+                end: self
+                    .tracks
+                    .get(index + 1)
+                    .map_or(duration, |next| next.start),
+            })
+            .collect()
+    }
+}
+
+impl CueSheet {
+    /// Performer of a track, falling back to the sheet's performer.
+    pub fn performer_of<'a>(&'a self, track: &'a CueTrack) -> Option<&'a str> {
+        track.performer.as_deref().or(self.performer.as_deref())
+    }
+}
+
+/// The `.cue` next to an audio file, e.g. `album.cue` for `album.flac`.
+pub fn find_cue(audio_path: &Path) -> Option<PathBuf> {
+    ["cue", "CUE"]
+        .iter()
+        .map(|ext| audio_path.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+/// Reads a cue sheet. Sheets written by older rippers are often Latin-1 rather than UTF-8.
+pub async fn load_cue(path: &Path) -> Result<CueSheet, Box<dyn std::error::Error + Send + Sync>> {
+    let bytes = tokio::fs::read(path).await?;
+    let content = match String::from_utf8(bytes) {
+        Ok(content) => content,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    parse_cue(&content)
+}
+
+pub fn parse_cue(content: &str) -> Result<CueSheet, Box<dyn std::error::Error + Send + Sync>> {
+    let mut sheet = CueSheet::default();
+    let mut in_track = false;
+
+    for line in content.lines() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                sheet.files.push(CueFile {
+                    name: unquote(file_name(rest)),
+                    tracks: Vec::new(),
+                });
+                in_track = false;
+            }
+            "TRACK" => {
+                let number = rest
+                    .split_whitespace()
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| format!("Invalid TRACK line: {}", line))?;
+                let file = sheet
+                    .files
+                    .last_mut()
+                    .ok_or("TRACK before any FILE in cue sheet")?;
+                file.tracks.push(CueTrack {
+                    number,
+                    title: None,
+                    performer: None,
+                    start: 0.0,
+                });
+                in_track = true;
+            }
+            "TITLE" | "PERFORMER" => {
+                let value = Some(unquote(rest)).filter(|v| !v.is_empty());
+                let is_title = command.eq_ignore_ascii_case("TITLE");
+                match current_track(&mut sheet, in_track) {
+                    Some(track) if is_title => track.title = value,
+                    Some(track) => track.performer = value,
+                    None if is_title => sheet.title = value,
+                    None => sheet.performer = value,
+                }
+            }
+            "INDEX" => {
+                let mut fields = rest.split_whitespace();
+                if fields.next().and_then(|n| n.parse::<u32>().ok()) == Some(1) {
+                    let time = fields.next().unwrap_or("");
+                    let start = parse_cue_time(time)
+                        .ok_or_else(|| format!("Invalid INDEX time: {}", time))?;
+                    if let Some(track) = current_track(&mut sheet, in_track) {
+                        track.start = start;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if sheet.files.iter().all(|file| file.tracks.is_empty()) {
+        return Err("Cue sheet lists no tracks".into());
+    }
+    Ok(sheet)
+}
+
+fn current_track(sheet: &mut CueSheet, in_track: bool) -> Option<&mut CueTrack> {
+    if !in_track {
+        return None;
+    }
+    sheet
+        .files
+        .last_mut()
+        .and_then(|file| file.tracks.last_mut())
+}
+
+/// `FILE "name.flac" WAVE`: the name is everything before the trailing file type.
+fn file_name(rest: &str) -> &str {
+    match rest.rfind('"') {
+        Some(end) if end > 0 && rest.starts_with('"') => &rest[..=end],
+        _ => rest
+            .rsplit_once(char::is_whitespace)
+            .map_or(rest, |(name, _)| name),
+    }
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// Parses an `MM:SS:FF` cue timestamp into seconds.
+fn parse_cue_time(time: &str) -> Option<f64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    let minutes = parts.next()??;
+    let seconds = parts.next()??;
+    let frames = parts.next()??;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(minutes as f64 * 60.0 + seconds as f64 + frames as f64 / FRAMES_PER_SECOND)
+}
@@ -0,0 +1,1684 @@
+//! yt-dlp downloads and the ingest pipeline that turns them into library tracks.
+
+use crate::acoustid::AcoustId;
+use crate::config::{
+    parse_extractor_arg, parse_format_selector, parse_rate, ExtractorArg, Hook, HookStage,
+};
+use crate::id3::tag_track;
+use crate::library::{assign_slug, track_info};
+use crate::lyrics::LYRICS_FILE;
+use crate::media_tools::{Fetch, MediaTools, NewTrack};
+use crate::process::{within, ProcessError, ProcessLimits};
+use crate::removals::Removals;
+use crate::sources;
+use crate::storage::{
+    new_track_id, save_downloads, unix_timestamp, CacheDirs, Chapter, HlsCache, HlsSession,
+    SourceStatus,
+};
+use crate::transcode::{
+    thumbnail_file, AudioFormat, TranscodeOptions, TranscodeSlots, THUMBNAIL_SIZES,
+};
+use crate::webhooks::Webhooks;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::{create_dir_all, remove_file};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{watch, RwLock};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadRequest {
+    pub url: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Turn each chapter of the source into its own track
+    #[serde(default)]
+    pub split_chapters: bool,
+    /// Also keep the music video as a video HLS rendition
+    #[serde(default)]
+    pub video: bool,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Download speed cap in bytes per second, given as a number or a string like "500K"
+    #[serde(default, deserialize_with = "deserialize_rate")]
+    pub limit_rate: Option<u64>,
+    /// yt-dlp extractor arguments as `EXTRACTOR:KEY=VALUE`, limited to an allowlist
+    #[serde(default)]
+    pub extractor_args: Vec<String>,
+    /// yt-dlp format selector, e.g. `flac/bestaudio`
+    #[serde(default)]
+    pub format: Option<String>,
+    /// Set when re-downloading an existing track instead of adding a new one
+    #[serde(skip)]
+    pub refresh: Option<Refresh>,
+}
+
+/// Re-download of a library track from its origin URL.
+#[derive(Debug, Clone)]
+pub struct Refresh {
+    pub track_id: String,
+    /// The stored origin URL; for chapter tracks it carries the chapter's range
+    pub origin_url: String,
+    pub clip: Option<(f64, f64)>,
+    /// Format to convert the new download to, normally the track's current one
+    pub audio: AudioFormat,
+}
+
+fn deserialize_rate<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Rate {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Option::<Rate>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Rate::Bytes(0)) => Err(serde::de::Error::custom("rate must be positive")),
+        Some(Rate::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Rate::Text(text)) => parse_rate(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// How urgently a download should get a transcode slot.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Background work such as backfilling a playlist
+    Low,
+    #[default]
+    Normal,
+    /// Something a listener is waiting to play
+    High,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadResponse {
+    pub id: String,
+    pub title: String,
+    pub session_id: String,
+    pub playlist_url: String,
+    pub total_segments: u32,
+    pub segment_duration: f32,
+    /// Every track created when the download was split by chapters
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tracks: Vec<DownloadResponse>,
+}
+
+/// One library track produced from a download: the whole file or one chapter of it.
+struct TrackPart {
+    session_id: String,
+    title: String,
+    album: Option<String>,
+    origin_url: String,
+    clip: Option<(f64, f64)>,
+    chapters: Vec<Chapter>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadStatus {
+    pub id: String,
+    /// The URL being downloaded; for a refresh, the track's origin URL
+    #[serde(default)]
+    pub url: String,
+    /// Set for jobs created by a batch download
+    pub batch_id: Option<String>,
+    pub priority: Priority,
+    pub created_at: u64,
+    /// When the job became `ready` or failed
+    #[serde(default)]
+    pub finished_at: Option<u64>,
+    pub status: String,
+    /// What the job is doing right now, while it runs
+    #[serde(default)]
+    pub stage: Option<DownloadStage>,
+    pub progress: Option<String>,
+    /// Completion of the current stage, 0-100
+    pub percent: Option<f64>,
+    /// Bytes of the source fetched so far
+    #[serde(default)]
+    pub bytes_downloaded: Option<u64>,
+    /// Size of the source, or yt-dlp's estimate when the site doesn't tell
+    #[serde(default)]
+    pub total_bytes: Option<u64>,
+    /// Download speed in bytes per second
+    pub speed: Option<f64>,
+    /// Estimated seconds until the download finishes
+    #[serde(default, alias = "eta")]
+    pub eta_seconds: Option<u64>,
+    pub error: Option<String>,
+    /// Why the job failed, for clients to branch on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<DownloadFailure>,
+    /// `Idempotency-Key` of the request that created the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    pub session: Option<DownloadResponse>,
+}
+
+/// Why a download failed. The API reports it as the error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadFailure {
+    /// `--allow-domain` or `--block-domain` refuses the URL
+    UrlNotAllowed,
+    /// Extractor arguments outside the allowlist, or an invalid format
+    InvalidRequest,
+    /// The URL is in the library already
+    DuplicateTrack,
+    /// The library is at `--max-tracks`
+    QuotaExceeded,
+    /// The source is over `--max-filesize` or `--max-duration`
+    SourceTooLarge,
+    /// The download ran past `--download-timeout`
+    DownloadTimedOut,
+    /// The source is gone, private or blocked
+    SourceUnavailable,
+    /// Anything else, possibly passing
+    DownloadFailed,
+    /// ffmpeg couldn't convert the audio
+    TranscodeFailed,
+}
+
+/// A failed download: what the job's `error` says, and why.
+#[derive(Debug, Clone)]
+pub struct DownloadError {
+    pub failure: DownloadFailure,
+    pub message: String,
+}
+
+impl DownloadError {
+    pub fn new(failure: DownloadFailure, message: impl Into<String>) -> Self {
+        DownloadError {
+            failure,
+            message: message.into(),
+        }
+    }
+
+    /// Why `error` failed a download; errors without a reason of their own, e.g. from
+    /// the disk, count as `DownloadFailed`.
+    pub fn from_boxed(error: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        match error.downcast::<DownloadError>() {
+            Ok(error) => *error,
+            Err(error) => DownloadError::new(DownloadFailure::DownloadFailed, error.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+/// The step a running job is in; finer than `status`, which clients match on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadStage {
+    Downloading,
+    Identifying,
+    Waiting,
+    Converting,
+    ConvertingVideo,
+}
+
+pub type DownloadQueue = Arc<RwLock<HashMap<String, DownloadStatus>>>;
+
+/// Forgets finished jobs that ended more than `keep` ago; true when any were dropped.
+pub fn expire_downloads(jobs: &mut HashMap<String, DownloadStatus>, keep: Duration) -> bool {
+    let cutoff = unix_timestamp().saturating_sub(keep.as_secs());
+    let before = jobs.len();
+    jobs.retain(|_, job| {
+        job.finished_at
+            .is_none_or(|finished_at| finished_at >= cutoff)
+    });
+    jobs.len() != before
+}
+
+/// Drops expired jobs from the download history, saving it if any were.
+pub async fn expire_download_history(
+    download_queue: &DownloadQueue,
+    cache_dir: &Path,
+    keep: Duration,
+) -> Result<(), String> {
+    let mut queue = download_queue.write().await;
+    if expire_downloads(&mut queue, keep) {
+        save_downloads(cache_dir, &queue)
+            .await
+            .map_err(|e| format!("Failed to save download history: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Limits and extension points applied to every ingested track.
+pub struct IngestOptions {
+    /// Where new tracks' directories are created
+    pub cache_dirs: CacheDirs,
+    pub max_tracks: Option<usize>,
+    pub hooks: Vec<Hook>,
+    /// Bounds how many tracks are converted with ffmpeg at the same time
+    pub transcode_slots: TranscodeSlots,
+    pub transcode: TranscodeOptions,
+    pub limits: DownloadLimits,
+    pub url_policy: UrlPolicy,
+    pub extractor: ExtractorOptions,
+    /// Timeout and resource limits yt-dlp, fpcalc and the hooks run under
+    pub processes: ProcessLimits,
+    /// Identifies downloads that came without a title
+    pub acoustid: Option<AcoustId>,
+    /// Write the tags into the segments as timed ID3 metadata
+    pub timed_metadata: bool,
+    /// Fetches direct links to audio files, which don't need yt-dlp
+    pub client: reqwest::Client,
+    /// Run to fetch sources other than direct links and to convert every source
+    pub tools: Arc<dyn MediaTools>,
+    /// Where a refresh leaves the track's old segments to be deleted
+    pub(crate) removals: Arc<Removals>,
+}
+
+/// Bounds on what a single download may fetch.
+#[derive(Default)]
+pub struct DownloadLimits {
+    /// Wall-clock budget for the whole download
+    pub timeout: Option<Duration>,
+    /// Size such as "200M", in yt-dlp's notation
+    pub max_filesize: Option<String>,
+    /// Longest accepted source, in seconds
+    pub max_duration: Option<u64>,
+    /// Speed cap in bytes per second; requests can only lower it
+    pub limit_rate: Option<u64>,
+}
+
+impl DownloadLimits {
+    /// The speed cap for one download: the server's, unless the request asks for less.
+    fn rate(&self, requested_rate: Option<u64>) -> Option<u64> {
+        match (self.limit_rate, requested_rate) {
+            (Some(limit), Some(requested)) => Some(limit.min(requested)),
+            (limit, requested) => limit.or(requested),
+        }
+    }
+
+    fn ytdlp_args(&self, requested_rate: Option<u64>) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(rate) = self.rate(requested_rate) {
+            args.extend(["--limit-rate".to_string(), rate.to_string()]);
+        }
+        if let Some(max_filesize) = &self.max_filesize {
+            args.extend(["--max-filesize".to_string(), max_filesize.clone()]);
+        }
+        // Without "?" the filter also rejects sources of unknown length, e.g. livestreams
+        if let Some(max_duration) = self.max_duration {
+            args.extend([
+                "--match-filter".to_string(),
+                format!("duration <= {}", max_duration),
+            ]);
+        }
+        args
+    }
+
+    /// yt-dlp skips filtered or oversized sources and still exits successfully,
+    /// so its log is checked for the reason.
+    fn rejection(&self, log: &str) -> Option<String> {
+        if log.contains("larger than max-filesize") {
+            return Some(format!(
+                "Source file exceeds the limit of {}",
+                self.max_filesize.as_deref().unwrap_or("the maximum size")
+            ));
+        }
+        if log.contains("does not pass filter") {
+            return Some(match self.max_duration {
+                Some(max_duration) => format!(
+                    "Source length exceeds the limit of {} seconds or is unknown",
+                    max_duration
+                ),
+                None => "Source was rejected by the download filter".to_string(),
+            });
+        }
+        None
+    }
+}
+
+/// How many redirects a direct link may take, as many as reqwest follows by default.
+const MAX_REDIRECTS: usize = 10;
+
+/// Which source domains POST /api/download accepts.
+#[derive(Clone, Default)]
+pub struct UrlPolicy {
+    /// When non-empty, only these domains (and their subdomains) are accepted
+    pub allowed: Vec<String>,
+    pub blocked: Vec<String>,
+}
+
+impl UrlPolicy {
+    /// Returns the reason a URL is refused, if it is.
+    pub fn check(&self, url: &str) -> Result<(), String> {
+        if self.allowed.is_empty() && self.blocked.is_empty() {
+            return Ok(());
+        }
+
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .and_then(|url| url.host_str().map(|host| host.to_ascii_lowercase()))
+            .ok_or_else(|| "Only http(s) URLs can be downloaded".to_string())?;
+
+        let matches = |domain: &String| {
+            let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+            host == domain || host.ends_with(&format!(".{}", domain))
+        };
+
+        if let Some(domain) = self.blocked.iter().find(|d| matches(d)) {
+            return Err(format!(
+                "Downloads from {} are blocked on this server",
+                domain
+            ));
+        }
+        if !self.allowed.is_empty() && !self.allowed.iter().any(matches) {
+            return Err(format!(
+                "Downloads from {} are not allowed; accepted domains: {}",
+                host,
+                self.allowed.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Follows redirects only to URLs the policy accepts, so an allowed link can't lead
+    /// to a blocked host.
+    pub fn redirect_policy(&self) -> reqwest::redirect::Policy {
+        let policy = self.clone();
+        reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("Too many redirects");
+            }
+            match policy.check(attempt.url().as_str()) {
+                Ok(()) => attempt.follow(),
+                Err(reason) => {
+                    attempt.error(DownloadError::new(DownloadFailure::UrlNotAllowed, reason))
+                }
+            }
+        })
+    }
+}
+
+/// Extractor arguments download requests may set without the server allowing them
+/// with `--allow-extractor-arg`: ones that pick clients, languages and formats, but
+/// can't make yt-dlp read or write files.
+const REQUEST_EXTRACTOR_ARGS: [&str; 6] = [
+    "youtube:player_client",
+    "youtube:lang",
+    "youtube:skip",
+    "youtube:player_skip",
+    "soundcloud:formats",
+    "generic:impersonate",
+];
+
+/// The YouTube clients that work for downloads without a PO token.
+const DEFAULT_EXTRACTOR_ARG: (&str, &str, &str) =
+    ("youtube", "player_client", "web_creator,android");
+
+/// The yt-dlp binary with its extractor arguments and format selection, from the
+/// server's options and each download request.
+pub struct ExtractorOptions {
+    pub ytdlp_path: PathBuf,
+    /// Passed to every download; a request's own arguments win
+    pub args: Vec<ExtractorArg>,
+    /// `EXTRACTOR:KEY` names requests may set besides `REQUEST_EXTRACTOR_ARGS`
+    pub allowed: Vec<String>,
+    /// Format selector for downloads that don't ask for one
+    pub format: Option<String>,
+}
+
+impl Default for ExtractorOptions {
+    fn default() -> Self {
+        ExtractorOptions {
+            ytdlp_path: PathBuf::from("yt-dlp"),
+            args: Vec::new(),
+            allowed: Vec::new(),
+            format: None,
+        }
+    }
+}
+
+impl ExtractorOptions {
+    /// Returns the reason a request's extractor arguments or format are refused.
+    pub fn check(&self, request: &DownloadRequest) -> Result<(), String> {
+        for arg in &request.extractor_args {
+            let arg = parse_extractor_arg(arg)
+                .map_err(|e| format!("Invalid extractor argument: {}", e))?;
+            let name = arg.name();
+            if !REQUEST_EXTRACTOR_ARGS.contains(&name.as_str()) && !self.allowed.contains(&name) {
+                return Err(format!(
+                    "Extractor argument {} is not allowed; accepted: {}",
+                    name,
+                    self.allowed_names().join(", ")
+                ));
+            }
+        }
+        if let Some(format) = &request.format {
+            parse_format_selector(format)?;
+        }
+        Ok(())
+    }
+
+    fn allowed_names(&self) -> Vec<&str> {
+        REQUEST_EXTRACTOR_ARGS
+            .iter()
+            .copied()
+            .chain(self.allowed.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// `--extractor-args` for a download, one per extractor so later arguments
+    /// override earlier ones key by key. Requests must have passed `check`.
+    fn ytdlp_args(&self, request: &DownloadRequest) -> Vec<String> {
+        let (extractor, key, value) = DEFAULT_EXTRACTOR_ARG;
+        let mut merged: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+        merged
+            .entry(extractor.to_string())
+            .or_default()
+            .insert(key.to_string(), value.to_string());
+        let requested = request
+            .extractor_args
+            .iter()
+            .filter_map(|arg| parse_extractor_arg(arg).ok());
+        for arg in self.args.iter().cloned().chain(requested) {
+            merged
+                .entry(arg.extractor)
+                .or_default()
+                .insert(arg.key, arg.value);
+        }
+
+        merged
+            .into_iter()
+            .flat_map(|(extractor, args)| {
+                let args: Vec<String> = args
+                    .into_iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect();
+                [
+                    "--extractor-args".to_string(),
+                    format!("{}:{}", extractor, args.join(";")),
+                ]
+            })
+            .collect()
+    }
+
+    /// The format selector for a download, if any is set.
+    fn format<'a>(&'a self, request: &'a DownloadRequest) -> Option<&'a str> {
+        request.format.as_deref().or(self.format.as_deref())
+    }
+}
+
+#[derive(Deserialize)]
+struct YtDlpInfo {
+    #[serde(default)]
+    chapters: Option<Vec<YtDlpChapter>>,
+}
+
+#[derive(Deserialize)]
+struct YtDlpChapter {
+    start_time: f64,
+    end_time: f64,
+    #[serde(default)]
+    title: Option<String>,
+}
+
+/// File name of a track's yt-dlp metadata inside its segments directory.
+pub const INFO_FILE: &str = "info.json";
+
+/// Parts of yt-dlp's info JSON that only bloat it: every available format, captions
+/// and thumbnail variants.
+const BULKY_INFO_FIELDS: [&str; 6] = [
+    "formats",
+    "requested_formats",
+    "thumbnails",
+    "automatic_captions",
+    "subtitles",
+    "heatmap",
+];
+
+/// The info JSON yt-dlp wrote next to the audio, without its bulky listings. The
+/// file is removed; each track made from the download keeps its own copy.
+async fn take_ytdlp_info(download_dir: &Path) -> Option<serde_json::Value> {
+    let info_path = std::fs::read_dir(download_dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.to_string_lossy().ends_with(".info.json"))?;
+
+    let info = match tokio::fs::read_to_string(&info_path).await {
+        Ok(content) => match serde_json::from_str::<serde_json::Value>(&content) {
+            Ok(info) => Some(info),
+            Err(e) => {
+                eprintln!("Warning: Failed to parse yt-dlp info JSON: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            eprintln!("Warning: Failed to read yt-dlp info JSON: {}", e);
+            None
+        }
+    };
+    let _ = remove_file(&info_path).await;
+
+    let mut info = info?;
+    if let Some(fields) = info.as_object_mut() {
+        for field in BULKY_INFO_FIELDS {
+            fields.remove(field);
+        }
+    }
+    Some(info)
+}
+
+/// Chapters listed in yt-dlp's info JSON.
+fn ytdlp_chapters(info: Option<&serde_json::Value>) -> Vec<Chapter> {
+    let chapters = match info.map(YtDlpInfo::deserialize) {
+        Some(Ok(info)) => info.chapters.unwrap_or_default(),
+        Some(Err(e)) => {
+            eprintln!("Warning: Unexpected chapters in yt-dlp info JSON: {}", e);
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+
+    chapters
+        .into_iter()
+        .enumerate()
+        .map(|(index, chapter)| Chapter {
+            title: chapter
+                .title
+                .filter(|title| !title.trim().is_empty())
+                .unwrap_or_else(|| format!("Chapter {}", index + 1)),
+            start: chapter.start_time,
+            end: chapter.end_time,
+        })
+        .collect()
+}
+
+/// Where a track came from, as yt-dlp reported it at download time.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SourceInfo {
+    /// Title on the source site, which the library title may differ from
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub uploader: Option<String>,
+    #[serde(default)]
+    pub uploader_url: Option<String>,
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// `YYYY-MM-DD`
+    #[serde(default)]
+    pub upload_date: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub webpage_url: Option<String>,
+    /// Site the track was downloaded from, e.g. "Youtube"
+    #[serde(default, rename(deserialize = "extractor_key"))]
+    pub site: Option<String>,
+}
+
+/// Reads the source metadata kept in a track's segments directory.
+pub async fn read_source_info(
+    segments_dir: &Path,
+) -> Result<Option<SourceInfo>, Box<dyn std::error::Error + Send + Sync>> {
+    let content = match tokio::fs::read_to_string(segments_dir.join(INFO_FILE)).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut source: SourceInfo = serde_json::from_str(&content)?;
+    // yt-dlp writes dates as YYYYMMDD
+    source.upload_date = source.upload_date.map(|date| match date.len() {
+        8 if date.bytes().all(|b| b.is_ascii_digit()) => {
+            format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..])
+        }
+        _ => date,
+    });
+    Ok(Some(source))
+}
+
+/// The thumbnail yt-dlp wrote next to the audio. It is converted to JPEG when
+/// ffmpeg is available, but other formats are accepted too.
+fn find_thumbnail(download_dir: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(download_dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.extension().is_some_and(|ext| {
+                matches!(
+                    ext.to_string_lossy().to_lowercase().as_str(),
+                    "jpg" | "jpeg" | "png" | "webp"
+                )
+            })
+        })
+}
+
+/// Marks the machine readable progress lines requested from yt-dlp.
+const PROGRESS_PREFIX: &str = "[music-lib-progress]";
+
+/// How far a download got, from a yt-dlp progress line or a direct fetch; values
+/// that aren't known are `None`.
+struct TransferProgress {
+    downloaded: Option<u64>,
+    total: Option<u64>,
+    percent: Option<f64>,
+    speed: Option<f64>,
+    eta: Option<u64>,
+}
+
+/// Reads one progress line; yt-dlp prints "NA" for values it doesn't know.
+fn parse_ytdlp_progress(line: &str) -> Option<TransferProgress> {
+    let fields: Vec<&str> = line
+        .strip_prefix(PROGRESS_PREFIX)?
+        .split_whitespace()
+        .collect();
+    let [downloaded, total, estimate, speed, eta] = fields[..] else {
+        return None;
+    };
+
+    let number = |value: &str| value.parse::<f64>().ok();
+    let downloaded = number(downloaded);
+    let total = number(total)
+        .or_else(|| number(estimate))
+        .filter(|total| *total > 0.0);
+
+    Some(TransferProgress {
+        downloaded: downloaded.map(|downloaded| downloaded as u64),
+        total: total.map(|total| total as u64),
+        percent: downloaded
+            .zip(total)
+            .map(|(downloaded, total)| (downloaded / total * 100.0).min(100.0)),
+        speed: number(speed),
+        eta: number(eta).map(|eta| eta as u64),
+    })
+}
+
+/// Writes how far the download got into its status.
+async fn report_transfer(
+    download_queue: &DownloadQueue,
+    download_id: &str,
+    progress: TransferProgress,
+) {
+    let mut queue = download_queue.write().await;
+    if let Some(status) = queue.get_mut(download_id) {
+        status.progress = Some(match progress.percent {
+            Some(percent) => format!("Downloading... {:.0}%", percent),
+            None => "Downloading...".to_string(),
+        });
+        status.percent = progress.percent;
+        status.bytes_downloaded = progress.downloaded;
+        status.total_bytes = progress.total;
+        status.speed = progress.speed;
+        status.eta_seconds = progress.eta;
+    }
+}
+
+/// Content types a direct link may answer with, and the extension the file is saved as.
+const AUDIO_CONTENT_TYPES: [(&str, &str); 12] = [
+    ("audio/mpeg", "mp3"),
+    ("audio/mp3", "mp3"),
+    ("audio/flac", "flac"),
+    ("audio/x-flac", "flac"),
+    ("audio/ogg", "ogg"),
+    ("application/ogg", "ogg"),
+    ("audio/mp4", "m4a"),
+    ("audio/x-m4a", "m4a"),
+    ("audio/aac", "aac"),
+    ("audio/wav", "wav"),
+    ("audio/x-wav", "wav"),
+    ("audio/wave", "wav"),
+];
+
+/// How often a direct fetch updates its download status.
+const TRANSFER_REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Whether `url` points straight at an audio file rather than at a page for yt-dlp.
+pub fn is_direct_audio_url(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .is_some_and(|url| is_audio_file(Path::new(url.path())))
+}
+
+/// The lowercase extension of a URL path naming an audio file.
+fn audio_extension(path: &str) -> Option<String> {
+    let path = Path::new(path);
+    if !is_audio_file(path) {
+        return None;
+    }
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+}
+
+pub fn is_audio_file(path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) => {
+            let ext = ext.to_string_lossy().to_lowercase();
+            matches!(
+                ext.as_str(),
+                "wav" | "mp3" | "mp4" | "flac" | "ogg" | "m4a" | "aac"
+            )
+        }
+        None => false,
+    }
+}
+
+/// Runs every hook configured for `stage`. Hooks get the track details as
+/// MUSIC_LIB_* environment variables; a failing hook aborts the pipeline.
+pub async fn run_hooks(
+    hooks: &[Hook],
+    limits: &ProcessLimits,
+    stage: HookStage,
+    env: &[(&str, String)],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    for hook in hooks.iter().filter(|h| h.stage == stage) {
+        let output = limits
+            .output(
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(&hook.command)
+                    .env("MUSIC_LIB_STAGE", stage.name())
+                    .envs(env.iter().map(|(k, v)| (*k, v.as_str()))),
+            )
+            .await
+            .map_err(|e| format!("{} hook failed: {}", stage.name(), e))?;
+
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("{} hook failed: {}", stage.name(), error.trim()).into());
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn download_from_url(
+    request: DownloadRequest,
+    hls_cache: HlsCache,
+    download_queue: DownloadQueue,
+    download_id: &str,
+    webhooks: &Webhooks,
+    options: &IngestOptions,
+) -> Result<DownloadResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let url = request.url.as_str();
+
+    // Check if this URL already exists in cache, whole or split by chapters; a
+    // refresh replaces exactly that track
+    if request.refresh.is_none() {
+        let cache = hls_cache.read().await;
+        for session in cache.values() {
+            if session.origin_url == url || session.origin_url.starts_with(&clip_url_prefix(url)) {
+                return Err(DownloadError::new(
+                    DownloadFailure::DuplicateTrack,
+                    format!("This song is already downloaded: \"{}\"", session.title),
+                )
+                .into());
+            }
+        }
+    }
+    if request.refresh.is_none() {
+        check_quota(&hls_cache, options, webhooks, url, 1).await?;
+    }
+
+    let session_id = Uuid::new_v4().to_string();
+    // Every track of the download goes to the same disk as the download itself
+    let track_dir = options.cache_dirs.for_new_track();
+    let download_dir = track_dir.join(&session_id);
+    create_dir_all(&download_dir).await?;
+
+    {
+        let mut queue = download_queue.write().await;
+        if let Some(status) = queue.get_mut(download_id) {
+            status.status = "downloading".to_string();
+            status.stage = Some(DownloadStage::Downloading);
+            status.progress = Some("Starting download...".to_string());
+        }
+    }
+
+    // Direct links to audio files are fetched without yt-dlp
+    let actual_file = if is_direct_audio_url(url) {
+        let fetch = fetch_direct(
+            &request,
+            &download_dir,
+            &download_queue,
+            download_id,
+            options,
+        );
+        let fetched = match options.limits.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, fetch)
+                .await
+                .unwrap_or_else(|_| Err(timed_out(timeout).into())),
+            None => fetch.await,
+        };
+        match fetched {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = tokio::fs::remove_dir_all(&download_dir).await;
+                return Err(e);
+            }
+        }
+    } else {
+        options
+            .tools
+            .fetch(Fetch {
+                request: &request,
+                download_dir: &download_dir,
+                download_queue: &download_queue,
+                download_id,
+                options,
+            })
+            .await?
+    };
+
+    let info = take_ytdlp_info(&download_dir).await;
+    let chapters = ytdlp_chapters(info.as_ref());
+    let thumbnail = find_thumbnail(&download_dir);
+
+    // Use provided title or generate from URL
+    let track_title = request
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("Track {}", &session_id[..8]));
+
+    let hook_env = vec![
+        ("MUSIC_LIB_FILE", actual_file.to_string_lossy().to_string()),
+        ("MUSIC_LIB_TITLE", track_title.clone()),
+        (
+            "MUSIC_LIB_ARTIST",
+            request.artist.clone().unwrap_or_default(),
+        ),
+        ("MUSIC_LIB_ALBUM", request.album.clone().unwrap_or_default()),
+        ("MUSIC_LIB_URL", url.to_string()),
+        ("MUSIC_LIB_SESSION_ID", session_id.clone()),
+    ];
+    run_hooks(
+        &options.hooks,
+        &options.processes,
+        HookStage::PostDownload,
+        &hook_env,
+    )
+    .await?;
+
+    let split = request.refresh.is_none() && request.split_chapters && chapters.len() > 1;
+    let mut track_title = track_title;
+    let mut artist = request.artist.clone();
+    let mut album = request.album.clone();
+    let mut identification = None;
+    if let (Some(acoustid), None, false) = (&options.acoustid, &request.title, split) {
+        {
+            let mut queue = download_queue.write().await;
+            if let Some(status) = queue.get_mut(download_id) {
+                status.stage = Some(DownloadStage::Identifying);
+                status.progress = Some("Identifying track...".to_string());
+            }
+        }
+        match acoustid.identify(&actual_file, &options.processes).await {
+            Ok(Some(mut found)) => {
+                found.previous_title = std::mem::replace(&mut track_title, found.title.clone());
+                found.previous_artist = artist.clone();
+                found.previous_album = album.clone();
+                // Tags given with the request win over the match
+                artist = artist.or_else(|| found.artist.clone());
+                album = album.or_else(|| found.album.clone());
+                identification = Some(found);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Warning: AcoustID identification failed: {}", e),
+        }
+    }
+
+    // A split upload becomes one track per chapter, grouped as an album named
+    // after the upload unless an album was given
+    let parts = if split {
+        if let Err(e) = check_quota(&hls_cache, options, webhooks, url, chapters.len()).await {
+            let _ = tokio::fs::remove_dir_all(&download_dir).await;
+            return Err(e);
+        }
+        chapters
+            .iter()
+            .enumerate()
+            .map(|(index, chapter)| TrackPart {
+                session_id: if index == 0 {
+                    session_id.clone()
+                } else {
+                    Uuid::new_v4().to_string()
+                },
+                title: chapter.title.clone(),
+                album: request.album.clone().or_else(|| request.title.clone()),
+                origin_url: format!("{}{},{}", clip_url_prefix(url), chapter.start, chapter.end),
+                clip: Some((chapter.start, chapter.end)),
+                chapters: Vec::new(),
+            })
+            .collect()
+    } else if let Some(refresh) = &request.refresh {
+        vec![TrackPart {
+            session_id: session_id.clone(),
+            title: track_title,
+            album,
+            origin_url: refresh.origin_url.clone(),
+            clip: refresh.clip,
+            // The source's chapters only line up with a whole-file track
+            chapters: if refresh.clip.is_some() {
+                Vec::new()
+            } else {
+                chapters
+            },
+        }]
+    } else {
+        vec![TrackPart {
+            session_id: session_id.clone(),
+            title: track_title,
+            album,
+            origin_url: url.to_string(),
+            clip: None,
+            chapters,
+        }]
+    };
+
+    {
+        let mut queue = download_queue.write().await;
+        if let Some(status) = queue.get_mut(download_id) {
+            status.status = "waiting".to_string();
+            status.stage = Some(DownloadStage::Waiting);
+            status.progress = Some("Waiting for a free transcoder...".to_string());
+            status.percent = None;
+            status.speed = None;
+            status.eta_seconds = None;
+        }
+    }
+
+    // Held until segmenting and analysis are done
+    let transcode_slot = options.transcode_slots.acquire(request.priority).await?;
+
+    {
+        let mut queue = download_queue.write().await;
+        if let Some(status) = queue.get_mut(download_id) {
+            status.status = "converting".to_string();
+            status.stage = Some(DownloadStage::Converting);
+            status.progress = Some("Converting to HLS format...".to_string());
+        }
+    }
+
+    let part_count = parts.len();
+    let steps_per_part = if request.video { 2 } else { 1 };
+    let steps = part_count * steps_per_part;
+    let mut ingested = Vec::with_capacity(part_count);
+    for (index, part) in parts.into_iter().enumerate() {
+        let mut part_env = hook_env.clone();
+        part_env.retain(|(key, _)| {
+            !matches!(
+                *key,
+                "MUSIC_LIB_TITLE" | "MUSIC_LIB_ARTIST" | "MUSIC_LIB_ALBUM" | "MUSIC_LIB_SESSION_ID"
+            )
+        });
+        part_env.push(("MUSIC_LIB_TITLE", part.title.clone()));
+        part_env.push(("MUSIC_LIB_ARTIST", artist.clone().unwrap_or_default()));
+        part_env.push(("MUSIC_LIB_ALBUM", part.album.clone().unwrap_or_default()));
+        part_env.push(("MUSIC_LIB_SESSION_ID", part.session_id.clone()));
+        run_hooks(
+            &options.hooks,
+            &options.processes,
+            HookStage::PreSegmentation,
+            &part_env,
+        )
+        .await?;
+
+        let transcode = TranscodeOptions {
+            clip: part.clip,
+            audio: request
+                .refresh
+                .as_ref()
+                .map_or(options.transcode.audio, |refresh| refresh.audio),
+            ..options.transcode.clone()
+        };
+
+        // Create HLS segments, mirroring ffmpeg's progress into the download status
+        let step = index * steps_per_part;
+        let label = if part_count > 1 {
+            format!("Converting track {} of {}", index + 1, part_count)
+        } else {
+            "Converting to HLS format".to_string()
+        };
+        let (progress_tx, progress_task) = mirror_progress(
+            &download_queue,
+            download_id,
+            DownloadStage::Converting,
+            label,
+            step,
+            steps,
+        );
+        let segmented = options
+            .tools
+            .segment(
+                &actual_file,
+                NewTrack {
+                    cache_dir: track_dir,
+                    session_id: &part.session_id,
+                    title: &part.title,
+                    origin_url: &part.origin_url,
+                },
+                &transcode,
+                Some(&progress_tx),
+            )
+            .await;
+        drop(progress_tx);
+        let _ = progress_task.await;
+
+        let mut session = segmented.map_err(|e| {
+            DownloadError::new(
+                DownloadFailure::TranscodeFailed,
+                format!("Failed to convert the audio: {}", e),
+            )
+        })?;
+        if let Some(info) = &info {
+            let json = serde_json::to_vec_pretty(info)?;
+            if let Err(e) = tokio::fs::write(session.segments_dir.join(INFO_FILE), json).await {
+                eprintln!("Warning: Failed to keep yt-dlp info JSON: {}", e);
+            }
+        }
+        session.artist = artist.clone();
+        session.album = part.album;
+        session.chapters = part.chapters;
+        session.identification = identification.take();
+
+        match options.tools.crossfade(&actual_file, &transcode).await {
+            Ok(hints) => session.crossfade = Some(hints),
+            Err(e) => eprintln!("Warning: Crossfade analysis failed: {}", e),
+        }
+
+        let duration = session.crossfade.map(|hints| hints.duration);
+        match options
+            .tools
+            .tempo_key(&actual_file, &transcode, duration)
+            .await
+        {
+            Ok(tempo_key) => session.tempo_key = Some(tempo_key),
+            Err(e) => eprintln!("Warning: Tempo and key analysis failed: {}", e),
+        }
+
+        if let Some(thumbnail) = &thumbnail {
+            match options
+                .tools
+                .thumbnails(thumbnail, &session.segments_dir, &transcode)
+                .await
+            {
+                Ok(artwork_hash) => {
+                    session.has_thumbnail = true;
+                    session.artwork_hash = Some(artwork_hash);
+                }
+                Err(e) => eprintln!("Warning: Thumbnail conversion failed: {}", e),
+            }
+        }
+
+        if options.timed_metadata && !options.transcode.single_file && !options.transcode.encrypt {
+            if let Err(e) = tag_track(&session).await {
+                eprintln!("Warning: Failed to add timed metadata: {}", e);
+            }
+        }
+
+        // A source without a video stream still makes a usable audio track
+        if request.video {
+            let (progress_tx, progress_task) = mirror_progress(
+                &download_queue,
+                download_id,
+                DownloadStage::ConvertingVideo,
+                "Converting video".to_string(),
+                step + 1,
+                steps,
+            );
+            let video = options
+                .tools
+                .video(
+                    &actual_file,
+                    &session.segments_dir,
+                    &transcode,
+                    Some(&progress_tx),
+                )
+                .await;
+            drop(progress_tx);
+            let _ = progress_task.await;
+
+            match video {
+                Ok(_) => session.has_video = true,
+                Err(e) => {
+                    eprintln!("Warning: Video conversion failed: {}", e);
+                    remove_video_files(&session.segments_dir).await;
+                }
+            }
+        }
+
+        let track_id = match &request.refresh {
+            Some(refresh) => refresh.track_id.clone(),
+            None => new_track_id(),
+        };
+        ingested.push((track_id, session, part_env));
+    }
+    drop(transcode_slot);
+
+    // Delete the downloaded source file after conversion
+    if let Err(e) = remove_file(&actual_file).await {
+        eprintln!("Warning: Failed to delete source file: {}", e);
+    }
+    if let Some(thumbnail) = &thumbnail {
+        let _ = remove_file(thumbnail).await;
+    }
+
+    let mut responses = Vec::with_capacity(ingested.len());
+    for (track_id, mut session, mut part_env) in ingested {
+        // A refreshed track swaps in its new segments in one step and keeps its history
+        let replaced = {
+            let mut cache = hls_cache.write().await;
+            let replaced = match (&request.refresh, cache.get(&track_id)) {
+                (Some(_), Some(old)) => {
+                    carry_over(&mut session, old);
+                    Some((old.id.clone(), old.segments_dir.clone()))
+                }
+                _ => None,
+            };
+            assign_slug(&cache, &mut session);
+            cache.insert(track_id.clone(), session.clone());
+            replaced
+        };
+        let event = if replaced.is_some() {
+            "track_refreshed"
+        } else {
+            "track_added"
+        };
+        webhooks.emit(
+            event,
+            serde_json::to_value(track_info(&track_id, &session))?,
+        );
+        if let Some((old_id, old_dir)) = replaced.filter(|(_, dir)| *dir != session.segments_dir) {
+            // Lyrics were added to the track, not downloaded with it
+            let lyrics = old_dir.join(LYRICS_FILE);
+            if lyrics.is_file() {
+                if let Err(e) =
+                    tokio::fs::copy(&lyrics, session.segments_dir.join(LYRICS_FILE)).await
+                {
+                    eprintln!("Warning: Failed to keep the track's lyrics: {}", e);
+                }
+            }
+            // So was uploaded artwork, which replaces the source's
+            if session.custom_artwork {
+                for (size, _) in THUMBNAIL_SIZES {
+                    let file = thumbnail_file(size);
+                    if let Err(e) =
+                        tokio::fs::copy(old_dir.join(&file), session.segments_dir.join(&file)).await
+                    {
+                        eprintln!("Warning: Failed to keep the track's artwork: {}", e);
+                    }
+                }
+            }
+            // Players part way through the old segments get to finish them
+            options.removals.replace(&old_id, old_dir);
+        }
+
+        hls_cache.changed();
+
+        // The source file is gone by now, so post-ingest hooks get the segments instead
+        part_env.retain(|(key, _)| *key != "MUSIC_LIB_FILE");
+        part_env.push((
+            "MUSIC_LIB_FILE",
+            session.playlist_path.to_string_lossy().to_string(),
+        ));
+        part_env.push(("MUSIC_LIB_TRACK_ID", track_id.clone()));
+        part_env.push((
+            "MUSIC_LIB_SEGMENTS_DIR",
+            session.segments_dir.to_string_lossy().to_string(),
+        ));
+        if let Err(e) = run_hooks(
+            &options.hooks,
+            &options.processes,
+            HookStage::PostIngest,
+            &part_env,
+        )
+        .await
+        {
+            eprintln!("Warning: {}", e);
+        }
+
+        responses.push(DownloadResponse {
+            id: download_id.to_string(),
+            title: session.title.clone(),
+            session_id: session.id.clone(),
+            playlist_url: format!("/api/hls/{}/playlist.m3u8", session.id),
+            total_segments: session.total_segments,
+            segment_duration: session.segment_duration,
+            tracks: Vec::new(),
+        });
+    }
+
+    let mut response = responses[0].clone();
+    if responses.len() > 1 {
+        response.tracks = responses;
+    }
+
+    {
+        let mut queue = download_queue.write().await;
+        if let Some(status) = queue.get_mut(download_id) {
+            status.status = "ready".to_string();
+            status.stage = None;
+            status.progress = None;
+            status.percent = Some(100.0);
+            status.session = Some(response.clone());
+        }
+    }
+
+    Ok(response)
+}
+
+/// Downloads `request.url` with yt-dlp into `download_dir`, mirroring its progress
+/// into the download status, and returns the audio file.
+pub(crate) async fn fetch_with_ytdlp(
+    Fetch {
+        request,
+        download_dir,
+        download_queue,
+        download_id,
+        options,
+    }: Fetch<'_>,
+) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let url = request.url.as_str();
+    let output_template = download_dir.join("audio.%(ext)s");
+    let progress_template = format!(
+        "download:{} %(progress.downloaded_bytes)s %(progress.total_bytes)s \
+         %(progress.total_bytes_estimate)s %(progress.speed)s %(progress.eta)s",
+        PROGRESS_PREFIX
+    );
+    // Music videos are fetched whole; the audio-only rendition is cut from them later
+    let format = options.extractor.format(request);
+    let format_args: Vec<&str> = if request.video {
+        vec![
+            "-f",
+            format.unwrap_or("bv*[height<=720]+ba/b"),
+            "--merge-output-format",
+            "mp4",
+            "--remux-video",
+            "mp4",
+        ]
+    } else {
+        let mut args = vec!["-x", "--audio-format", "mp3", "--audio-quality", "0"];
+        if let Some(format) = format {
+            args.extend(["-f", format]);
+        }
+        args
+    };
+    let mut command = Command::new(&options.extractor.ytdlp_path);
+    command
+        .args(format_args)
+        .args(options.extractor.ytdlp_args(request))
+        .args([
+            "--js-runtimes",
+            "bun",
+            "--no-cache-dir",
+            "-o",
+            output_template.to_str().unwrap(),
+            "--no-playlist",
+            "--force-overwrites",
+            "--newline",
+            "--progress-template",
+            &progress_template,
+            "--write-info-json",
+            "--write-thumbnail",
+            "--convert-thumbnails",
+            "jpg",
+        ])
+        .args(options.limits.ytdlp_args(request.limit_rate))
+        .arg(url)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // yt-dlp converts with ffmpeg, which the process group takes down with it
+    let mut child = options
+        .processes
+        .at_most(options.limits.timeout)
+        .spawn(&mut command)?;
+
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_task = tokio::spawn(async move {
+        let mut output = String::new();
+        let _ = stderr.read_to_string(&mut output).await;
+        output
+    });
+
+    // Progress lines are reported as they arrive; everything else is kept for errors
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let timeout = child.timeout();
+    let mut log = String::new();
+    let transfer = async {
+        while let Some(line) = stdout.next_line().await? {
+            let Some(progress) = parse_ytdlp_progress(&line) else {
+                log.push_str(&line);
+                log.push('\n');
+                continue;
+            };
+
+            report_transfer(download_queue, download_id, progress).await;
+        }
+        child.wait().await
+    };
+
+    let exit_status = match within(timeout, transfer).await {
+        Ok(result) => result?,
+        Err(ProcessError::TimedOut(timeout)) => {
+            child.kill_group();
+            let _ = tokio::fs::remove_dir_all(download_dir).await;
+            return Err(timed_out(timeout).into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let stderr_output = stderr_task.await.unwrap_or_default();
+    if !exit_status.success() {
+        let failure = match sources::classify(&stderr_output) {
+            SourceStatus::Unavailable => DownloadFailure::SourceUnavailable,
+            _ => DownloadFailure::DownloadFailed,
+        };
+        return Err(DownloadError::new(
+            failure,
+            format!("yt-dlp error: {} {}", stderr_output, log),
+        )
+        .into());
+    }
+    if let Some(reason) = options.limits.rejection(&log) {
+        let _ = tokio::fs::remove_dir_all(download_dir).await;
+        return Err(DownloadError::new(DownloadFailure::SourceTooLarge, reason).into());
+    }
+
+    // Find the downloaded audio file
+    let mut downloaded_file: Option<PathBuf> = None;
+    for entry in (std::fs::read_dir(download_dir)?).flatten() {
+        let path = entry.path();
+        if is_audio_file(&path) {
+            downloaded_file = Some(path);
+            break;
+        }
+    }
+
+    downloaded_file.ok_or_else(|| "Downloaded file not found after yt-dlp completed".into())
+}
+
+/// Fetches a direct link to an audio file into `download_dir`, following redirects,
+/// and returns the file. yt-dlp isn't involved, so the size, speed and length limits
+/// are applied here.
+async fn fetch_direct(
+    request: &DownloadRequest,
+    download_dir: &Path,
+    download_queue: &DownloadQueue,
+    download_id: &str,
+    options: &IngestOptions,
+) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let mut response = options.client.get(&request.url).send().await.map_err(
+        |e| -> Box<dyn std::error::Error + Send + Sync> {
+            // A redirect the URL policy refused
+            match std::error::Error::source(&e).and_then(|e| e.downcast_ref::<DownloadError>()) {
+                Some(refused) => refused.clone().into(),
+                None => e.into(),
+            }
+        },
+    )?;
+    if let Err(e) = response.error_for_status_ref() {
+        let failure = match response.status() {
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE => {
+                DownloadFailure::SourceUnavailable
+            }
+            _ => DownloadFailure::DownloadFailed,
+        };
+        return Err(DownloadError::new(failure, e.to_string()).into());
+    }
+
+    // Plenty of servers send audio as octet-stream, but a web page means the link
+    // wasn't the file after all
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+    let typed_extension = match content_type.as_deref() {
+        None | Some("application/octet-stream" | "binary/octet-stream") => None,
+        Some(content_type) => match AUDIO_CONTENT_TYPES
+            .iter()
+            .find(|(audio_type, _)| *audio_type == content_type)
+        {
+            Some((_, extension)) => Some(extension.to_string()),
+            None if content_type.starts_with("audio/") => None,
+            None => {
+                return Err(format!(
+                    "The URL did not return an audio file (Content-Type: {})",
+                    content_type
+                )
+                .into())
+            }
+        },
+    };
+    // Without a telling content type, the name the redirects ended at decides
+    let extension = typed_extension
+        .or_else(|| audio_extension(response.url().path()))
+        .or_else(|| {
+            reqwest::Url::parse(&request.url)
+                .ok()
+                .and_then(|url| audio_extension(url.path()))
+        })
+        .unwrap_or_else(|| "mp3".to_string());
+
+    let limits = &options.limits;
+    let max_size = limits
+        .max_filesize
+        .as_deref()
+        .and_then(|size| parse_rate(size).ok());
+    let too_large = || {
+        DownloadError::new(
+            DownloadFailure::SourceTooLarge,
+            format!(
+                "Source file exceeds the limit of {}",
+                limits.max_filesize.as_deref().unwrap_or("the maximum size")
+            ),
+        )
+    };
+    let total = response.content_length();
+    if total.zip(max_size).is_some_and(|(total, max)| total > max) {
+        return Err(too_large().into());
+    }
+
+    let file_path = download_dir.join(format!("audio.{}", extension));
+    let mut file = tokio::fs::File::create(&file_path).await?;
+    let rate = limits.rate(request.limit_rate);
+    let started = Instant::now();
+    let mut reported: Option<Instant> = None;
+    let mut downloaded: u64 = 0;
+    while let Some(chunk) = response.chunk().await? {
+        downloaded += chunk.len() as u64;
+        if max_size.is_some_and(|max| downloaded > max) {
+            return Err(too_large().into());
+        }
+        file.write_all(&chunk).await?;
+
+        // Pausing until the average speed is back under the cap throttles the transfer
+        if let Some(rate) = rate {
+            let due = Duration::from_secs_f64(downloaded as f64 / rate as f64);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        if reported.is_some_and(|at| at.elapsed() < TRANSFER_REPORT_INTERVAL) {
+            continue;
+        }
+        reported = Some(Instant::now());
+        let elapsed = started.elapsed().as_secs_f64();
+        let speed = (elapsed > 0.0).then(|| downloaded as f64 / elapsed);
+        let progress = TransferProgress {
+            downloaded: Some(downloaded),
+            total,
+            percent: total
+                .filter(|total| *total > 0)
+                .map(|total| (downloaded as f64 / total as f64 * 100.0).min(100.0)),
+            speed,
+            eta: total
+                .zip(speed)
+                .filter(|(_, speed)| *speed > 0.0)
+                .map(|(total, speed)| (total.saturating_sub(downloaded) as f64 / speed) as u64),
+        };
+        report_transfer(download_queue, download_id, progress).await;
+    }
+    file.flush().await?;
+    drop(file);
+
+    // yt-dlp knows a source's length up front; here the file has to be looked at
+    if let Some(max_duration) = limits.max_duration {
+        let duration = options
+            .tools
+            .probe_duration(&file_path, &options.transcode)
+            .await;
+        if duration.is_none_or(|duration| duration > max_duration as f64) {
+            return Err(DownloadError::new(
+                DownloadFailure::SourceTooLarge,
+                format!(
+                    "Source length exceeds the limit of {} seconds or is unknown",
+                    max_duration
+                ),
+            )
+            .into());
+        }
+    }
+
+    Ok(file_path)
+}
+
+/// Mirrors ffmpeg's progress for conversion `step` of `steps` into the download status.
+fn mirror_progress(
+    download_queue: &DownloadQueue,
+    download_id: &str,
+    stage: DownloadStage,
+    label: String,
+    step: usize,
+    steps: usize,
+) -> (watch::Sender<f64>, tokio::task::JoinHandle<()>) {
+    let (progress_tx, mut progress_rx) = watch::channel(0.0);
+    let download_queue = Arc::clone(download_queue);
+    let download_id = download_id.to_string();
+    let task = tokio::spawn(async move {
+        while progress_rx.changed().await.is_ok() {
+            let step_percent = *progress_rx.borrow_and_update();
+            let percent = (step as f64 * 100.0 + step_percent) / steps as f64;
+            let mut queue = download_queue.write().await;
+            if let Some(status) = queue.get_mut(&download_id) {
+                status.stage = Some(stage);
+                status.percent = Some(percent);
+                status.progress = Some(format!("{}... {:.0}%", label, percent));
+            }
+        }
+    });
+    (progress_tx, task)
+}
+
+/// Keeps what belongs to the track rather than to its audio when a refresh replaces
+/// `old` with `session`.
+fn carry_over(session: &mut HlsSession, old: &HlsSession) {
+    session.listen_count = old.listen_count;
+    session.unique_listeners = old.unique_listeners;
+    session.last_listen = old.last_listen;
+    session.notes = old.notes.clone();
+    session.date_added = old.date_added;
+    session.identification = old.identification.clone();
+    session.pinned = old.pinned;
+    session.visibility = old.visibility;
+    session.slug = old.slug.clone();
+    // The files come over with the lyrics once the new entry is in place
+    if old.custom_artwork {
+        session.has_thumbnail = true;
+        session.artwork_hash = old.artwork_hash.clone();
+        session.custom_artwork = true;
+    }
+}
+
+/// Removes a partial video rendition after a failed conversion.
+async fn remove_video_files(segments_dir: &Path) {
+    let Ok(mut entries) = tokio::fs::read_dir(segments_dir).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with("video") {
+            let _ = remove_file(entry.path()).await;
+        }
+    }
+}
+
+fn timed_out(timeout: Duration) -> DownloadError {
+    DownloadError::new(
+        DownloadFailure::DownloadTimedOut,
+        format!("Download timed out after {} seconds", timeout.as_secs()),
+    )
+}
+
+/// Origin URLs of chapter tracks are the upload URL plus a media fragment with the
+/// chapter's range, e.g. `https://youtu.be/x#t=94.5,341`.
+fn clip_url_prefix(url: &str) -> String {
+    format!("{}#t=", url)
+}
+
+/// Splits an origin URL into the upload URL and, for chapter tracks, the chapter's range.
+pub fn parse_clip_url(origin_url: &str) -> (&str, Option<(f64, f64)>) {
+    let clip = origin_url.rsplit_once("#t=").and_then(|(url, range)| {
+        let (start, end) = range.split_once(',')?;
+        Some((url, (start.parse().ok()?, end.parse().ok()?)))
+    });
+    match clip {
+        Some((url, range)) => (url, Some(range)),
+        None => (origin_url, None),
+    }
+}
+
+/// Refuses a download that would take the library past `--max-tracks`.
+async fn check_quota(
+    hls_cache: &HlsCache,
+    options: &IngestOptions,
+    webhooks: &Webhooks,
+    url: &str,
+    adding: usize,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(max_tracks) = options.max_tracks else {
+        return Ok(());
+    };
+    let track_count = hls_cache.read().await.len();
+    if track_count + adding > max_tracks {
+        webhooks.emit(
+            "quota_exceeded",
+            serde_json::json!({ "url": url, "max_tracks": max_tracks }),
+        );
+        return Err(DownloadError::new(
+            DownloadFailure::QuotaExceeded,
+            format!("Library quota exceeded ({} tracks)", max_tracks),
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,336 @@
+//! Stand-ins for yt-dlp and ffmpeg, for tests. A download writes a made-up source
+//! file instead of fetching anything, and converting it writes segments that only
+//! depend on the source and the audio format, so the same download always makes
+//! the same track.
+//!
+//! Segments are MPEG-TS packets of filler rather than audio: players can't play
+//! them, but everything the server does with a track's files works.
+
+use crate::downloader::{DownloadError, DownloadFailure};
+use crate::media_tools::{Fetch, MediaTools, NewTrack, ToolFuture, ToolResult};
+use crate::storage::{playlist_duration, CrossfadeHints, HlsSession, TempoKey};
+use crate::transcode::{
+    artwork_hash, converted_session, thumbnail_file, TranscodeOptions, KEY_FILE, THUMBNAIL_SIZES,
+    VIDEO_PLAYLIST,
+};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::sync::watch;
+
+/// First line of a fake source file; the second is its length in seconds.
+const SOURCE_HEADER: &str = "music-lib fake source";
+
+/// Length of a source whose URL doesn't ask for another with `?duration=`.
+const DEFAULT_DURATION: f64 = 30.0;
+
+/// Sources whose URL contains this fail to download, as removed videos do.
+const UNAVAILABLE: &str = "unavailable";
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+/// MPEG-TS packets written per second of a rendition, to keep test tracks small.
+const PACKETS_PER_SECOND: f64 = 2.0;
+
+/// Segment length of the video rendition, as ffmpeg is asked for.
+const VIDEO_SEGMENT_DURATION: u32 = 6;
+
+/// yt-dlp and ffmpeg as far as the server can tell, without running either.
+pub struct FakeTools;
+
+/// The length of a fake source, or of a track's playlist being converted again.
+async fn source_duration(file_path: &Path) -> ToolResult<f64> {
+    let content = tokio::fs::read_to_string(file_path).await?;
+    if file_path.extension().is_some_and(|ext| ext == "m3u8") {
+        return Ok(playlist_duration(&content));
+    }
+    let mut lines = content.lines();
+    if lines.next() != Some(SOURCE_HEADER) {
+        return Err(format!("FFmpeg error: {} is not a fake source", file_path.display()).into());
+    }
+    Ok(lines
+        .next()
+        .and_then(|line| line.parse().ok())
+        .unwrap_or(DEFAULT_DURATION))
+}
+
+/// How much of a source of `duration` `options` converts.
+fn clipped(duration: f64, options: &TranscodeOptions) -> f64 {
+    match options.clip {
+        Some((start, end)) => (end.min(duration) - start.clamp(0.0, duration)).max(0.0),
+        None => duration,
+    }
+}
+
+/// Identifies what is converted, so each rendition of each clip gets its own bytes.
+async fn seed(file_path: &Path, options: &TranscodeOptions, rendition: &str) -> ToolResult<String> {
+    let source = tokio::fs::read(file_path).await?;
+    let mut hasher = Sha256::new();
+    hasher.update(&source);
+    hasher.update(format!(
+        "{}:{:?}:{:?}:{}",
+        rendition, options.clip, options.audio.codec, options.audio.bitrate
+    ));
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// `duration` seconds of filler packets, the same for the same seed and index.
+fn segment_data(seed: &str, index: usize, duration: f64) -> Vec<u8> {
+    let block = Sha256::digest(format!("{}:{}", seed, index));
+    let packets = (duration * PACKETS_PER_SECOND).ceil().max(1.0) as usize;
+    let mut data = Vec::with_capacity(packets * TS_PACKET_SIZE);
+    for _ in 0..packets {
+        data.push(TS_SYNC_BYTE);
+        data.extend(block.iter().cycle().take(TS_PACKET_SIZE - 1));
+    }
+    data
+}
+
+/// A rendition as ffmpeg's HLS muxer writes it: `playlist` listing segments named
+/// after `pattern` ("{}" being the number), or byte ranges of `single` in
+/// single-file mode, encrypted with the track's key when `options` asks for it.
+struct Rendition<'a> {
+    playlist: &'a str,
+    pattern: &'a str,
+    single: &'a str,
+    segment_duration: u32,
+}
+
+impl Rendition<'_> {
+    async fn write(
+        &self,
+        segments_dir: &Path,
+        seed: &str,
+        length: f64,
+        options: &TranscodeOptions,
+    ) -> ToolResult<PathBuf> {
+        tokio::fs::create_dir_all(segments_dir).await?;
+        let target = self.segment_duration as f64;
+        let count = (length / target).ceil().max(1.0) as usize;
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:{}\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n",
+            if options.single_file { 4 } else { 3 },
+            self.segment_duration
+        );
+        if options.encrypt {
+            let key_path = segments_dir.join(KEY_FILE);
+            if !key_path.exists() {
+                tokio::fs::write(&key_path, &Sha256::digest(seed)[..16]).await?;
+            }
+            playlist.push_str(&format!("#EXT-X-KEY:METHOD=AES-128,URI=\"{}\"\n", KEY_FILE));
+        }
+
+        let mut single = Vec::new();
+        for index in 0..count {
+            let duration = (length - index as f64 * target).clamp(0.0, target);
+            let data = segment_data(seed, index, duration);
+            playlist.push_str(&format!("#EXTINF:{:.6},\n", duration));
+            if options.single_file {
+                playlist.push_str(&format!(
+                    "#EXT-X-BYTERANGE:{}@{}\n{}\n",
+                    data.len(),
+                    single.len(),
+                    self.single
+                ));
+                single.extend(data);
+            } else {
+                let name = self.pattern.replace("{}", &format!("{:03}", index));
+                tokio::fs::write(segments_dir.join(&name), data).await?;
+                playlist.push_str(&format!("{}\n", name));
+            }
+        }
+        if options.single_file {
+            tokio::fs::write(segments_dir.join(self.single), single).await?;
+        }
+        playlist.push_str("#EXT-X-ENDLIST\n");
+
+        let playlist_path = segments_dir.join(self.playlist);
+        tokio::fs::write(&playlist_path, playlist).await?;
+        Ok(playlist_path)
+    }
+}
+
+impl FakeTools {
+    async fn fetch(fetch: Fetch<'_>) -> ToolResult<PathBuf> {
+        let url = fetch.request.url.as_str();
+        if url.contains(UNAVAILABLE) {
+            return Err(DownloadError::new(
+                DownloadFailure::SourceUnavailable,
+                "yt-dlp error: ERROR: [fake] Video unavailable. This video has been removed",
+            )
+            .into());
+        }
+        let duration = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| {
+                url.query_pairs()
+                    .find(|(name, _)| name == "duration")
+                    .and_then(|(_, value)| value.parse::<f64>().ok())
+            })
+            .filter(|duration| *duration > 0.0)
+            .unwrap_or(DEFAULT_DURATION);
+
+        let dir = fetch.download_dir;
+        let info = serde_json::json!({
+            "id": hex::encode(&Sha256::digest(url)[..6]),
+            "extractor": "fake",
+            "webpage_url": url,
+            "duration": duration,
+        });
+        tokio::fs::write(dir.join("audio.info.json"), info.to_string()).await?;
+        let mut thumbnail = vec![0xff, 0xd8, 0xff];
+        thumbnail.extend(Sha256::digest(url));
+        tokio::fs::write(dir.join("audio.jpg"), thumbnail).await?;
+
+        let audio = dir.join("audio.mp3");
+        tokio::fs::write(
+            &audio,
+            format!("{}\n{}\n{}\n", SOURCE_HEADER, duration, url),
+        )
+        .await?;
+        Ok(audio)
+    }
+
+    async fn segment(
+        file_path: &Path,
+        track: NewTrack<'_>,
+        options: &TranscodeOptions,
+        progress: Option<&watch::Sender<f64>>,
+    ) -> ToolResult<HlsSession> {
+        let length = clipped(source_duration(file_path).await?, options);
+        let segments_dir = track.cache_dir.join(track.session_id);
+        let rendition = Rendition {
+            playlist: "playlist.m3u8",
+            pattern: "{}.ts",
+            single: "audio.ts",
+            segment_duration: options.audio.segment_duration,
+        };
+        let seed = seed(file_path, options, "audio").await?;
+        rendition
+            .write(&segments_dir, &seed, length, options)
+            .await?;
+        if let Some(progress) = progress {
+            progress.send_replace(100.0);
+        }
+        converted_session(
+            segments_dir,
+            track.session_id,
+            track.title,
+            track.origin_url,
+            options,
+        )
+        .await
+    }
+
+    async fn video(
+        file_path: &Path,
+        segments_dir: &Path,
+        options: &TranscodeOptions,
+        progress: Option<&watch::Sender<f64>>,
+    ) -> ToolResult<PathBuf> {
+        let length = clipped(source_duration(file_path).await?, options);
+        let rendition = Rendition {
+            playlist: VIDEO_PLAYLIST,
+            pattern: "video_{}.ts",
+            single: "video.ts",
+            segment_duration: VIDEO_SEGMENT_DURATION,
+        };
+        let seed = seed(file_path, options, "video").await?;
+        let playlist = rendition
+            .write(segments_dir, &seed, length, options)
+            .await?;
+        if let Some(progress) = progress {
+            progress.send_replace(100.0);
+        }
+        Ok(playlist)
+    }
+
+    async fn thumbnails(image: &Path, segments_dir: &Path) -> ToolResult<String> {
+        let image = tokio::fs::read(image).await?;
+        if image.is_empty() {
+            return Err("FFmpeg error: the image is empty".into());
+        }
+        for (size, width) in THUMBNAIL_SIZES {
+            let mut thumbnail = vec![0xff, 0xd8, 0xff];
+            thumbnail.extend(Sha256::digest([&image[..], &width.to_le_bytes()].concat()));
+            tokio::fs::write(segments_dir.join(thumbnail_file(size)), thumbnail).await?;
+        }
+        artwork_hash(segments_dir)
+            .await
+            .ok_or_else(|| "Thumbnails were not written".into())
+    }
+}
+
+impl MediaTools for FakeTools {
+    fn fetch<'a>(&'a self, fetch: Fetch<'a>) -> ToolFuture<'a, ToolResult<PathBuf>> {
+        Box::pin(FakeTools::fetch(fetch))
+    }
+
+    fn probe_duration<'a>(
+        &'a self,
+        file_path: &'a Path,
+        _options: &'a TranscodeOptions,
+    ) -> ToolFuture<'a, Option<f64>> {
+        Box::pin(async move { source_duration(file_path).await.ok() })
+    }
+
+    fn segment<'a>(
+        &'a self,
+        file_path: &'a Path,
+        track: NewTrack<'a>,
+        options: &'a TranscodeOptions,
+        progress: Option<&'a watch::Sender<f64>>,
+    ) -> ToolFuture<'a, ToolResult<HlsSession>> {
+        Box::pin(FakeTools::segment(file_path, track, options, progress))
+    }
+
+    fn video<'a>(
+        &'a self,
+        file_path: &'a Path,
+        segments_dir: &'a Path,
+        options: &'a TranscodeOptions,
+        progress: Option<&'a watch::Sender<f64>>,
+    ) -> ToolFuture<'a, ToolResult<PathBuf>> {
+        Box::pin(FakeTools::video(file_path, segments_dir, options, progress))
+    }
+
+    fn thumbnails<'a>(
+        &'a self,
+        image: &'a Path,
+        segments_dir: &'a Path,
+        _options: &'a TranscodeOptions,
+    ) -> ToolFuture<'a, ToolResult<String>> {
+        Box::pin(FakeTools::thumbnails(image, segments_dir))
+    }
+
+    fn crossfade<'a>(
+        &'a self,
+        file_path: &'a Path,
+        options: &'a TranscodeOptions,
+    ) -> ToolFuture<'a, ToolResult<CrossfadeHints>> {
+        Box::pin(async move {
+            let length = clipped(source_duration(file_path).await?, options);
+            Ok(CrossfadeHints {
+                fade_in_end: 0.0,
+                fade_out_start: length,
+                duration: length,
+            })
+        })
+    }
+
+    fn tempo_key<'a>(
+        &'a self,
+        file_path: &'a Path,
+        _options: &'a TranscodeOptions,
+        _duration: Option<f64>,
+    ) -> ToolFuture<'a, ToolResult<TempoKey>> {
+        Box::pin(async move {
+            source_duration(file_path).await?;
+            Ok(TempoKey {
+                bpm: 120.0,
+                key: "A minor".to_string(),
+                camelot: "8A".to_string(),
+            })
+        })
+    }
+}
@@ -0,0 +1,502 @@
+//! Talking to other music-lib instances: mirroring (--sync-from), read replicas (--upstream)
+//! and overlay libraries (--overlay-path).
+
+use crate::library::assign_slug;
+use crate::storage::{
+    generate_url_hash, is_safe_path_component, load_overlay_library, playlist_duration,
+    playlist_segments, unix_timestamp, AudioCodec, CacheDirs, Chapter, CrossfadeHints, HlsCache,
+    HlsSession, IdentificationStatus, TempoKey, Visibility, DEFAULT_BITRATE,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::fs::create_dir_all;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// A track as listed by another instance's /api/tracks.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RemoteTrack {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    #[serde(default)]
+    pub(crate) artist: Option<String>,
+    #[serde(default)]
+    pub(crate) album: Option<String>,
+    #[serde(default)]
+    pub(crate) origin_url: String,
+    /// Unset by primaries from before short links
+    #[serde(default)]
+    pub(crate) slug: String,
+    pub(crate) session_id: String,
+    pub(crate) total_segments: u32,
+    pub(crate) segment_duration: f32,
+    #[serde(default)]
+    pub(crate) crossfade: Option<CrossfadeHints>,
+    #[serde(default)]
+    pub(crate) bpm: Option<f64>,
+    #[serde(default)]
+    pub(crate) key: Option<String>,
+    #[serde(default)]
+    pub(crate) camelot: Option<String>,
+    /// Missing on primaries from before it was recorded
+    #[serde(default)]
+    pub(crate) date_added: Option<u64>,
+    #[serde(default)]
+    pub(crate) encrypted: bool,
+    #[serde(default)]
+    pub(crate) codec: AudioCodec,
+    /// Missing on primaries from before it was configurable
+    #[serde(default)]
+    pub(crate) bitrate: Option<u32>,
+    #[serde(default)]
+    pub(crate) identification: Option<IdentificationStatus>,
+}
+
+/// Sent by mirrors so their playlist fetches don't count as listens.
+pub(crate) const SYNC_HEADER: &str = "x-music-lib-sync";
+
+/// Cached playlists are fetched again once they are this old, as the upstream may
+/// have refreshed or re-transcoded the track; segments never change.
+const UPSTREAM_PLAYLIST_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Read replica state: files fetched from the upstream server are kept under `dir`.
+pub(crate) struct Upstream {
+    pub(crate) base_url: String,
+    pub(crate) client: reqwest::Client,
+    pub(crate) dir: PathBuf,
+    /// Least recently played sessions are evicted beyond this size
+    pub(crate) max_bytes: Option<u64>,
+    /// When each session was last served since startup
+    last_used: std::sync::Mutex<HashMap<String, SystemTime>>,
+    /// Files being fetched, so listeners starting the same track wait for one fetch
+    fetching: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+impl Upstream {
+    pub(crate) fn new(base_url: &str, dir: PathBuf, max_bytes: Option<u64>) -> Self {
+        Upstream {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+            dir,
+            max_bytes,
+            last_used: std::sync::Mutex::new(HashMap::new()),
+            fetching: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Returns a playlist or segment of an upstream session, fetching it on a cache miss.
+pub(crate) async fn fetch_upstream_file(
+    upstream: &Upstream,
+    session_id: &str,
+    name: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    if !is_safe_path_component(session_id) || !is_safe_path_component(name) {
+        return Err("Invalid upstream path".into());
+    }
+    if let Ok(mut last_used) = upstream.last_used.lock() {
+        last_used.insert(session_id.to_string(), SystemTime::now());
+    }
+
+    let session_dir = upstream.dir.join(session_id);
+    let path = session_dir.join(name);
+    let playlist = name.ends_with(".m3u8") || name.ends_with(".vtt");
+    if let Some(data) = cached_file(&path, playlist).await {
+        return Ok(data);
+    }
+
+    let lock = {
+        let mut fetching = upstream.fetching.lock().await;
+        Arc::clone(fetching.entry(path.clone()).or_default())
+    };
+    let result = {
+        let _fetching = lock.lock().await;
+        // Fetched while this request waited
+        match cached_file(&path, playlist).await {
+            Some(data) => Ok(data),
+            None => fetch_into_cache(upstream, session_id, name, &path).await,
+        }
+    };
+    upstream.fetching.lock().await.remove(&path);
+
+    match result {
+        Ok(data) => Ok(data),
+        // A stale playlist beats none while the upstream is unreachable
+        Err(e) => match tokio::fs::read(&path).await {
+            Ok(data) if playlist => Ok(data),
+            _ => Err(e),
+        },
+    }
+}
+
+/// The cached copy of a file, unless it is a playlist due to be fetched again.
+async fn cached_file(path: &Path, playlist: bool) -> Option<Vec<u8>> {
+    if playlist {
+        let modified = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+        if modified
+            .elapsed()
+            .map_or(true, |age| age > UPSTREAM_PLAYLIST_TTL)
+        {
+            return None;
+        }
+    }
+    tokio::fs::read(path).await.ok()
+}
+
+async fn fetch_into_cache(
+    upstream: &Upstream,
+    session_id: &str,
+    name: &str,
+    path: &Path,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let data = upstream
+        .client
+        .get(format!(
+            "{}/api/hls/{}/{}",
+            upstream.base_url, session_id, name
+        ))
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    // Write under a temporary name so concurrent readers never see partial files
+    let session_dir = upstream.dir.join(session_id);
+    create_dir_all(&session_dir).await?;
+    let tmp_path = session_dir.join(format!(".{}.{}", name, Uuid::new_v4()));
+    tokio::fs::write(&tmp_path, &data).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+
+    Ok(data.to_vec())
+}
+
+/// Deletes the least recently played upstream sessions until the cache fits in
+/// `--upstream-cache-mb`. Sessions not played since startup go by when their
+/// files were last written.
+pub(crate) async fn evict_upstream(upstream: &Upstream) -> Result<(), String> {
+    let Some(max_bytes) = upstream.max_bytes else {
+        return Ok(());
+    };
+    let mut entries = match tokio::fs::read_dir(&upstream.dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Failed to list the upstream cache: {}", e)),
+    };
+
+    let mut sessions = Vec::new();
+    let mut total = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Ok(mut files) = tokio::fs::read_dir(entry.path()).await else {
+            continue;
+        };
+        let mut size = 0;
+        let mut written = SystemTime::UNIX_EPOCH;
+        while let Ok(Some(file)) = files.next_entry().await {
+            if let Ok(metadata) = file.metadata().await {
+                size += metadata.len();
+                written = written.max(metadata.modified().unwrap_or(written));
+            }
+        }
+        let session_id = entry.file_name().to_string_lossy().into_owned();
+        total += size;
+        sessions.push((session_id, written, size));
+    }
+    if total <= max_bytes {
+        return Ok(());
+    }
+
+    {
+        let last_used = upstream
+            .last_used
+            .lock()
+            .map_err(|_| "Upstream cache state is poisoned".to_string())?;
+        for (session_id, used, _) in sessions.iter_mut() {
+            if let Some(time) = last_used.get(session_id.as_str()) {
+                *used = (*used).max(*time);
+            }
+        }
+    }
+    sessions.sort_by_key(|(_, used, _)| *used);
+
+    let mut evicted = 0;
+    for (session_id, _, size) in sessions {
+        if total <= max_bytes {
+            break;
+        }
+        if tokio::fs::remove_dir_all(upstream.dir.join(&session_id))
+            .await
+            .is_ok()
+        {
+            total -= size;
+            evicted += 1;
+            if let Ok(mut last_used) = upstream.last_used.lock() {
+                last_used.remove(&session_id);
+            }
+        }
+    }
+    if evicted > 0 {
+        println!(
+            "🧹 Evicted {} sessions from the upstream cache ({} MiB left)",
+            evicted,
+            total / (1024 * 1024)
+        );
+    }
+    Ok(())
+}
+
+/// Tracks listed by the upstream server that aren't in the local library.
+pub(crate) async fn upstream_tracks(
+    upstream: &Upstream,
+    local_ids: &[String],
+) -> Vec<serde_json::Value> {
+    let response = upstream
+        .client
+        .get(format!("{}/api/tracks", upstream.base_url))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+
+    let tracks: Vec<serde_json::Value> = match response {
+        Ok(response) => match response.json().await {
+            Ok(tracks) => tracks,
+            Err(e) => {
+                eprintln!("Warning: Invalid track list from upstream: {}", e);
+                return Vec::new();
+            }
+        },
+        Err(e) => {
+            eprintln!("Warning: Failed to list upstream tracks: {}", e);
+            return Vec::new();
+        }
+    };
+
+    tracks
+        .into_iter()
+        .filter(|track| {
+            track["id"]
+                .as_str()
+                .is_some_and(|id| !local_ids.iter().any(|local| local == id))
+        })
+        .collect()
+}
+
+/// The tracks of the overlay libraries in `dirs`; the first library listing a track wins.
+pub(crate) async fn load_overlays(dirs: &[PathBuf]) -> HashMap<String, HlsSession> {
+    let mut tracks = HashMap::new();
+    for dir in dirs {
+        for (id, session) in load_overlay_library(dir).await {
+            tracks.entry(id).or_insert(session);
+        }
+    }
+    tracks
+}
+
+/// Replaces the overlay tracks in `cache` with `overlay`, leaving out those this
+/// library has a track of its own for. Returns how many tracks were added and removed.
+pub(crate) fn apply_overlay(
+    cache: &mut HashMap<String, HlsSession>,
+    mut overlay: HashMap<String, HlsSession>,
+) -> (usize, usize) {
+    let before = cache.len();
+    cache.retain(|id, session| !session.read_only || overlay.contains_key(id));
+    let removed = before - cache.len();
+
+    overlay.retain(|id, _| cache.get(id).is_none_or(|session| session.read_only));
+    let added = overlay.keys().filter(|id| !cache.contains_key(*id)).count();
+    cache.extend(overlay);
+    (added, removed)
+}
+
+/// Re-reads the overlay libraries, picking up the tracks their instances added and
+/// dropping the ones they deleted.
+pub(crate) async fn rescan_overlays(dirs: &[PathBuf], hls_cache: &HlsCache) {
+    let overlay = load_overlays(dirs).await;
+    let (added, removed) = apply_overlay(&mut *hls_cache.write().await, overlay);
+    if added > 0 || removed > 0 {
+        println!(
+            "✓ Overlay libraries changed: {} tracks added, {} removed",
+            added, removed
+        );
+    }
+}
+
+/// Pulls the tracks this instance doesn't have yet from a primary server.
+pub(crate) async fn sync(
+    client: &reqwest::Client,
+    primary: &str,
+    cache_dirs: &CacheDirs,
+    hls_cache: &HlsCache,
+) -> Result<(), String> {
+    let primary = primary.trim_end_matches('/');
+    match sync_from_primary(client, primary, cache_dirs, hls_cache).await {
+        Ok(0) => Ok(()),
+        Ok(count) => {
+            println!("✓ Synced {} new tracks from {}", count, primary);
+            Ok(())
+        }
+        Err(e) => Err(format!("Sync from {} failed: {}", primary, e)),
+    }
+}
+
+pub(crate) async fn sync_from_primary(
+    client: &reqwest::Client,
+    primary: &str,
+    cache_dirs: &CacheDirs,
+    hls_cache: &HlsCache,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let tracks: Vec<RemoteTrack> = client
+        .get(format!("{}/api/tracks", primary))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut synced = 0;
+    // Keys stay with the primary, so encrypted tracks would be unplayable here
+    for track in tracks.into_iter().filter(|track| !track.encrypted) {
+        let exists = {
+            let cache = hls_cache.read().await;
+            cache.contains_key(&track.id)
+                || (!track.origin_url.is_empty()
+                    && cache.values().any(|s| s.origin_url == track.origin_url))
+        };
+        if exists {
+            continue;
+        }
+
+        let track_dir = cache_dirs.for_new_track();
+        match mirror_track(client, primary, track_dir, &track).await {
+            Ok(mut session) => {
+                {
+                    let mut cache = hls_cache.write().await;
+                    // The primary's slug, unless a track here has it
+                    if cache.values().any(|other| other.slug == session.slug) {
+                        session.slug.clear();
+                    }
+                    assign_slug(&cache, &mut session);
+                    cache.insert(track.id.clone(), session);
+                }
+                hls_cache.changed();
+                synced += 1;
+            }
+            Err(e) => {
+                eprintln!("Warning: Failed to sync '{}': {}", track.title, e);
+                let _ = tokio::fs::remove_dir_all(track_dir.join(&track.session_id)).await;
+            }
+        }
+    }
+
+    Ok(synced)
+}
+
+/// Copies a remote track's playlist and segments into the local cache.
+pub(crate) async fn mirror_track(
+    client: &reqwest::Client,
+    primary: &str,
+    cache_dir: &Path,
+    track: &RemoteTrack,
+) -> Result<HlsSession, Box<dyn std::error::Error + Send + Sync>> {
+    if !is_safe_path_component(&track.session_id) {
+        return Err(format!("Invalid session id: {}", track.session_id).into());
+    }
+
+    let segments_dir = cache_dir.join(&track.session_id);
+    create_dir_all(&segments_dir).await?;
+
+    let base_url = format!("{}/api/hls/{}", primary, track.session_id);
+    let playlist = client
+        .get(format!("{}/playlist.m3u8", base_url))
+        .header(SYNC_HEADER, "1")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    // Single-file tracks list the same file once per byte range
+    let mut segments: Vec<&str> = playlist_segments(&playlist)
+        .iter()
+        .map(|segment| segment.uri)
+        .collect();
+    segments.dedup();
+    for segment in segments {
+        if !is_safe_path_component(segment) {
+            return Err(format!("Unexpected segment URI: {}", segment).into());
+        }
+
+        let data = client
+            .get(format!("{}/{}", base_url, segment))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        tokio::fs::write(segments_dir.join(segment), &data).await?;
+    }
+
+    let playlist_path = segments_dir.join("playlist.m3u8");
+    tokio::fs::write(&playlist_path, &playlist).await?;
+
+    // Primaries from before chapter support answer 404
+    let chapters = match client
+        .get(format!("{}/api/tracks/{}/chapters", primary, track.id))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+    {
+        Ok(response) => response.json::<Vec<Chapter>>().await.unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+
+    Ok(HlsSession {
+        id: track.session_id.clone(),
+        slug: track.slug.clone(),
+        title: track.title.clone(),
+        artist: track.artist.clone(),
+        album: track.album.clone(),
+        origin_url: track.origin_url.clone(),
+        // Imports on the primary have no origin URL; their id is all there is
+        origin_hash: if track.origin_url.is_empty() {
+            track.id.clone()
+        } else {
+            generate_url_hash(&track.origin_url)
+        },
+        segments_dir,
+        playlist_path,
+        total_segments: track.total_segments,
+        segment_duration: track.segment_duration,
+        duration: playlist_duration(&playlist),
+        listen_count: 0,
+        unique_listeners: 0,
+        last_listen: None,
+        crossfade: track.crossfade,
+        chapters,
+        has_video: false,
+        has_thumbnail: false,
+        artwork_hash: None,
+        custom_artwork: false,
+        encrypted: false,
+        codec: track.codec,
+        bitrate: track.bitrate.unwrap_or(DEFAULT_BITRATE),
+        tempo_key: match (track.bpm, &track.key, &track.camelot) {
+            (Some(bpm), Some(key), Some(camelot)) => Some(TempoKey {
+                bpm,
+                key: key.clone(),
+                camelot: camelot.clone(),
+            }),
+            _ => None,
+        },
+        identification: None,
+        notes: Vec::new(),
+        date_added: track.date_added.unwrap_or_else(unix_timestamp),
+        pinned: false,
+        visibility: Visibility::Public,
+        read_only: false,
+    })
+}
@@ -0,0 +1,369 @@
+//! What subscriptions follow: the episodes of a podcast's RSS feed, the feed URLs
+//! of an OPML subscription list, and the uploads of a channel or playlist as yt-dlp
+//! lists them. Only the handful of fields subscriptions need are read.
+
+use crate::process::{ProcessError, ProcessLimits};
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// How long yt-dlp may take to list a channel; large ones take a page per 100 uploads.
+const LISTING_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A podcast's RSS feed, or a channel's uploads.
+#[derive(Debug, Clone)]
+pub struct Feed {
+    pub title: String,
+    pub author: Option<String>,
+    /// Oldest first
+    pub episodes: Vec<FeedEpisode>,
+}
+
+/// An item of a feed with an audio enclosure, or an upload of a channel.
+#[derive(Debug, Clone)]
+pub struct FeedEpisode {
+    /// The item's guid, or its enclosure URL when it has none; the video id for uploads
+    pub guid: String,
+    pub title: String,
+    /// Enclosure URL, or the upload's page
+    pub url: String,
+    /// `pubDate` as a Unix time
+    pub published: Option<u64>,
+}
+
+/// A feed listed in an OPML file.
+#[derive(Debug, Clone)]
+pub struct OpmlFeed {
+    pub url: String,
+    pub title: Option<String>,
+}
+
+/// Reads an RSS feed; `None` when the document isn't one. Items without an audio
+/// enclosure, such as blog posts or video episodes, are left out.
+pub fn parse_feed(xml: &str) -> Option<Feed> {
+    let channel = element(xml, "channel")?;
+    // The channel's own elements come before its first item
+    let header = &channel[..channel.find("<item").unwrap_or(channel.len())];
+    let header = match (header.find("<image"), header.find("</image>")) {
+        (Some(start), Some(end)) if start < end => {
+            format!("{}{}", &header[..start], &header[end..])
+        }
+        _ => header.to_string(),
+    };
+    let title = element(&header, "title").map(text).unwrap_or_default();
+    let author = element(&header, "itunes:author")
+        .map(text)
+        .filter(|author| !author.is_empty());
+
+    let mut episodes: Vec<FeedEpisode> = elements(channel, "item")
+        .filter_map(|item| {
+            let enclosure = start_tags(item, "enclosure")
+                .find(|tag| attribute(tag, "type").is_none_or(|kind| kind.starts_with("audio/")))?;
+            let url = attribute(enclosure, "url").filter(|url| !url.is_empty())?;
+            let guid = element(item, "guid")
+                .map(text)
+                .filter(|guid| !guid.is_empty())
+                .unwrap_or_else(|| url.clone());
+            Some(FeedEpisode {
+                guid,
+                title: element(item, "title").map(text).unwrap_or_default(),
+                url,
+                published: element(item, "pubDate").and_then(|date| parse_date(&text(date))),
+            })
+        })
+        .collect();
+    // Feeds list their newest episode first, but not all of them do
+    episodes.reverse();
+    if episodes.iter().all(|episode| episode.published.is_some()) {
+        episodes.sort_by_key(|episode| episode.published);
+    }
+
+    Some(Feed {
+        title,
+        author,
+        episodes,
+    })
+}
+
+/// The feeds of an OPML subscription list, as podcast apps export them.
+pub fn parse_opml(xml: &str) -> Vec<OpmlFeed> {
+    start_tags(xml, "outline")
+        .filter_map(|tag| {
+            let url = attribute(tag, "xmlUrl").filter(|url| !url.is_empty())?;
+            let title = attribute(tag, "title")
+                .or_else(|| attribute(tag, "text"))
+                .filter(|title| !title.is_empty());
+            Some(OpmlFeed { url, title })
+        })
+        .collect()
+}
+
+/// Lists a YouTube channel's or playlist's uploads with `yt-dlp --flat-playlist`,
+/// which reads only the listing pages, not every video.
+pub async fn list_channel(ytdlp: &Path, limits: &ProcessLimits, url: &str) -> Result<Feed, String> {
+    let listing = flat_listing(ytdlp, limits, url, LISTING_TIMEOUT).await?;
+    parse_listing(&listing, !url.contains("list="))
+        .ok_or_else(|| "The URL is not a channel or playlist".to_string())
+}
+
+/// yt-dlp's JSON for a playlist-like `target` (a channel, a playlist, or a search
+/// such as `ytsearch10:...`), without resolving each entry.
+pub(crate) async fn flat_listing(
+    ytdlp: &Path,
+    limits: &ProcessLimits,
+    target: &str,
+    timeout: Duration,
+) -> Result<serde_json::Value, String> {
+    let output = limits
+        .at_most(Some(timeout))
+        .output(
+            Command::new(ytdlp)
+                .args([
+                    "--flat-playlist",
+                    "--dump-single-json",
+                    "--no-warnings",
+                    "--quiet",
+                ])
+                .arg(target),
+        )
+        .await;
+    let output = match output {
+        Ok(output) => output,
+        Err(ProcessError::Io(e)) => return Err(format!("Failed to run yt-dlp: {}", e)),
+        Err(ProcessError::TimedOut(timeout)) => {
+            return Err(format!("yt-dlp took longer than {}s", timeout.as_secs()))
+        }
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr
+            .lines()
+            .rev()
+            .find_map(|line| line.strip_prefix("ERROR:"))
+            .map(str::trim)
+            .unwrap_or("yt-dlp failed")
+            .to_string());
+    }
+
+    serde_json::from_slice(&output.stdout).map_err(|e| format!("Unreadable yt-dlp output: {}", e))
+}
+
+/// Reads yt-dlp's JSON for a channel or playlist. Channels list their newest upload
+/// first, playlists are kept in their own order.
+fn parse_listing(listing: &serde_json::Value, newest_first: bool) -> Option<Feed> {
+    let entries = listing["entries"].as_array()?;
+    let field = |name: &str| {
+        listing[name]
+            .as_str()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+    // A channel's title names the tab, e.g. "Some Channel - Videos"
+    let title = if newest_first {
+        field("channel").or_else(|| field("uploader"))
+    } else {
+        None
+    }
+    .or_else(|| field("title"))?;
+
+    let mut episodes: Vec<FeedEpisode> = entries
+        .iter()
+        // Channel pages without a tab list the tabs themselves
+        .filter(|entry| entry["_type"].as_str() != Some("playlist"))
+        .filter_map(|entry| {
+            let guid = entry["id"].as_str()?.to_string();
+            let url = entry["url"]
+                .as_str()
+                .or_else(|| entry["webpage_url"].as_str())?
+                .to_string();
+            Some(FeedEpisode {
+                guid,
+                title: entry["title"].as_str().unwrap_or_default().to_string(),
+                url,
+                published: entry["timestamp"].as_u64(),
+            })
+        })
+        .collect();
+    if newest_first {
+        episodes.reverse();
+    }
+
+    Some(Feed {
+        title,
+        author: field("uploader").or_else(|| field("channel")),
+        episodes,
+    })
+}
+
+/// Start tags named `name`, attributes included.
+fn start_tags<'a>(xml: &'a str, name: &str) -> impl Iterator<Item = &'a str> + 'a {
+    let open = format!("<{}", name);
+    let mut rest = xml;
+    std::iter::from_fn(move || loop {
+        let start = rest.find(&open)?;
+        let after = &rest[start + open.len()..];
+        let end = after.find('>')?;
+        let tag = &after[..end];
+        rest = &after[end..];
+        // `<item` shouldn't match `<itemCount`
+        if tag.is_empty() || tag.starts_with(|c: char| c.is_whitespace() || c == '/') {
+            return Some(tag);
+        }
+    })
+}
+
+/// Contents of every `name` element, for elements that don't nest.
+fn elements<'a>(xml: &'a str, name: &str) -> impl Iterator<Item = &'a str> + 'a {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut rest = xml;
+    std::iter::from_fn(move || loop {
+        let start = rest.find(&open)?;
+        let after = &rest[start + open.len()..];
+        let end = after.find('>')?;
+        let tag = &after[..end];
+        if !(tag.is_empty() || tag.starts_with(|c: char| c.is_whitespace() || c == '/')) {
+            rest = &after[end..];
+            continue;
+        }
+        if tag.ends_with('/') {
+            rest = &after[end..];
+            return Some("");
+        }
+        let content = &after[end + 1..];
+        let Some(length) = content.find(&close) else {
+            rest = "";
+            return Some(content);
+        };
+        rest = &content[length..];
+        return Some(&content[..length]);
+    })
+}
+
+/// Contents of the first `name` element.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    elements(xml, name).next()
+}
+
+/// Value of an attribute in a start tag, with entities decoded.
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    loop {
+        let equals = rest.find('=')?;
+        let key = rest[..equals].trim();
+        let key = key.rsplit(char::is_whitespace).next().unwrap_or(key);
+        let value = rest[equals + 1..].trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let length = value[1..].find(quote)?;
+        if key.eq_ignore_ascii_case(name) {
+            return Some(decode_entities(&value[1..1 + length]));
+        }
+        rest = &value[1 + length + 1..];
+    }
+}
+
+/// Element text with CDATA sections unwrapped and entities decoded.
+fn text(raw: &str) -> String {
+    let mut decoded = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find("<![CDATA[") {
+        decoded.push_str(&decode_entities(&rest[..start]));
+        let data = &rest[start + 9..];
+        let end = data.find("]]>").unwrap_or(data.len());
+        decoded.push_str(&data[..end]);
+        rest = data.get(end + 3..).unwrap_or_default();
+    }
+    decoded.push_str(&decode_entities(rest));
+    decoded.trim().to_string()
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';').filter(|end| *end <= 10) else {
+            decoded.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..end];
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match character {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Unix time of an RFC 822 date as feeds write them, e.g.
+/// "Wed, 02 Oct 2024 10:00:00 +0000"; the weekday and seconds are optional.
+fn parse_date(date: &str) -> Option<u64> {
+    let date = date.split_once(',').map_or(date, |(_, rest)| rest);
+    let mut parts = date.split_whitespace();
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?.get(..3)?.to_ascii_lowercase();
+    let month = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ]
+    .iter()
+    .position(|name| *name == month)? as i64
+        + 1;
+    let year: i64 = match parts.next()?.parse().ok()? {
+        year @ 0..=49 => 2000 + year,
+        year @ 50..=99 => 1900 + year,
+        year => year,
+    };
+    let mut time = parts.next()?.split(':').map(str::parse::<i64>);
+    let hours = time.next()?.ok()?;
+    let minutes = time.next()?.ok()?;
+    let seconds = time.next().unwrap_or(Ok(0)).ok()?;
+    let offset = match parts.next() {
+        Some(zone) if zone.starts_with(['+', '-']) && zone.len() == 5 => {
+            let sign = if zone.starts_with('-') { -1 } else { 1 };
+            let hours: i64 = zone[1..3].parse().ok()?;
+            let minutes: i64 = zone[3..5].parse().ok()?;
+            sign * (hours * 60 + minutes) * 60
+        }
+        // Named zones are rare and mostly GMT; the day is what matters for ordering
+        _ => 0,
+    };
+
+    // Days since the epoch, the inverse of `storage::utc_date`
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y.rem_euclid(400);
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let timestamp = days * 86_400 + hours * 3600 + minutes * 60 + seconds - offset;
+    u64::try_from(timestamp).ok()
+}
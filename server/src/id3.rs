@@ -0,0 +1,384 @@
+//! Timed ID3 metadata in MPEG-TS segments, as described in Apple's "Timed Metadata
+//! for HTTP Live Streaming", so native players (iOS lock screen, tvOS) show the
+//! track's title, artist and artwork without a custom client.
+//!
+//! ffmpeg can't produce a timed ID3 stream from the command line, so the tag is
+//! added to the finished segments: a private PES stream on its own PID, announced
+//! in the PMT with the metadata descriptors the spec asks for.
+
+use crate::storage::HlsSession;
+use crate::transcode::thumbnail_file;
+use std::path::Path;
+
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+/// PID of the metadata stream; ffmpeg numbers its streams from 0x100.
+const METADATA_PID: u16 = 0x1F0;
+const METADATA_STREAM_TYPE: u8 = 0x15;
+/// Private stream 1, which carries ID3 in the spec
+const METADATA_STREAM_ID: u8 = 0xBD;
+/// Artwork beyond this doesn't fit a single PES packet next to the text frames.
+const MAX_TAG_SIZE: usize = 65_000;
+
+/// What is written into the ID3 tags of a track's segments.
+pub(crate) struct TrackTags<'a> {
+    pub(crate) title: &'a str,
+    pub(crate) artist: Option<&'a str>,
+    pub(crate) album: Option<&'a str>,
+    /// JPEG cover, only sent with the first segment to keep the others small
+    pub(crate) artwork: Option<Vec<u8>>,
+}
+
+/// Tags a track's segments with its title, artist, album and medium thumbnail.
+pub(crate) async fn tag_track(
+    session: &HlsSession,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let artwork = if session.has_thumbnail {
+        tokio::fs::read(session.segments_dir.join(thumbnail_file("medium")))
+            .await
+            .ok()
+    } else {
+        None
+    };
+    let tags = TrackTags {
+        title: &session.title,
+        artist: session.artist.as_deref(),
+        album: session.album.as_deref(),
+        artwork,
+    };
+    tag_segments(&session.playlist_path, &tags).await
+}
+
+/// Adds the tags to every `.ts` segment listed in the playlist, in order, so the
+/// metadata stream's continuity counter runs on across segments.
+pub(crate) async fn tag_segments(
+    playlist_path: &Path,
+    tags: &TrackTags<'_>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let segments_dir = playlist_path.parent().ok_or("Playlist has no directory")?;
+    let playlist = tokio::fs::read_to_string(playlist_path).await?;
+    // Inserted packets would shift every byte range after them
+    if playlist.contains("#EXT-X-BYTERANGE") {
+        return Err("Single-file tracks can't carry timed metadata".into());
+    }
+    let text_tag = id3_tag(tags, false);
+    let full_tag = match id3_tag(tags, true) {
+        tag if tag.len() <= MAX_TAG_SIZE => tag,
+        _ => text_tag.clone(),
+    };
+
+    let mut continuity = 0u8;
+    let segments = playlist
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.ends_with(".ts") && !line.starts_with('#'));
+    for (index, segment) in segments.enumerate() {
+        let path = segments_dir.join(segment);
+        let data = tokio::fs::read(&path).await?;
+        let tag = if index == 0 { &full_tag } else { &text_tag };
+        let tagged = insert_metadata(&data, tag, &mut continuity)
+            .map_err(|e| format!("{}: {}", segment, e))?;
+
+        // Written aside and renamed so a crash never leaves a half written segment
+        let temp = path.with_extension("ts.tmp");
+        tokio::fs::write(&temp, tagged).await?;
+        tokio::fs::rename(&temp, &path).await?;
+    }
+
+    Ok(())
+}
+
+/// An ID3v2.4 tag with the text frames and, when asked for, the artwork.
+fn id3_tag(tags: &TrackTags, with_artwork: bool) -> Vec<u8> {
+    let mut frames = Vec::new();
+    let mut text_frame = |id: &[u8; 4], text: &str| {
+        let mut body = vec![0x03]; // UTF-8
+        body.extend_from_slice(text.as_bytes());
+        push_frame(&mut frames, id, &body);
+    };
+    text_frame(b"TIT2", tags.title);
+    if let Some(artist) = tags.artist {
+        text_frame(b"TPE1", artist);
+    }
+    if let Some(album) = tags.album {
+        text_frame(b"TALB", album);
+    }
+    if let (true, Some(artwork)) = (with_artwork, &tags.artwork) {
+        let mut body = vec![0x03];
+        body.extend_from_slice(b"image/jpeg\0");
+        body.push(0x03); // front cover
+        body.push(0x00); // empty description
+        body.extend_from_slice(artwork);
+        push_frame(&mut frames, b"APIC", &body);
+    }
+
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend_from_slice(&synchsafe(frames.len()));
+    tag.extend_from_slice(&frames);
+    tag
+}
+
+fn push_frame(frames: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    frames.extend_from_slice(id);
+    frames.extend_from_slice(&synchsafe(body.len()));
+    frames.extend_from_slice(&[0x00, 0x00]);
+    frames.extend_from_slice(body);
+}
+
+/// ID3v2.4 sizes keep the top bit of every byte clear.
+fn synchsafe(size: usize) -> [u8; 4] {
+    [
+        ((size >> 21) & 0x7F) as u8,
+        ((size >> 14) & 0x7F) as u8,
+        ((size >> 7) & 0x7F) as u8,
+        (size & 0x7F) as u8,
+    ]
+}
+
+/// A transport stream packet's PID, payload start flag and payload.
+struct Packet<'a> {
+    pid: u16,
+    unit_start: bool,
+    payload: &'a [u8],
+}
+
+fn parse_packet(packet: &[u8]) -> Option<Packet<'_>> {
+    if packet.len() != PACKET_SIZE || packet[0] != SYNC_BYTE {
+        return None;
+    }
+    let pid = u16::from(packet[1] & 0x1F) << 8 | u16::from(packet[2]);
+    let unit_start = packet[1] & 0x40 != 0;
+    let offset = match (packet[3] >> 4) & 0x03 {
+        0x01 => 4,
+        0x03 => 5 + usize::from(packet[4]),
+        _ => PACKET_SIZE,
+    };
+    Some(Packet {
+        pid,
+        unit_start,
+        payload: packet.get(offset..).unwrap_or_default(),
+    })
+}
+
+/// The PSI section a packet starts, skipping the pointer field.
+fn section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = usize::from(*payload.first()?);
+    let section = payload.get(1 + pointer..)?;
+    let length = usize::from(u16::from(*section.get(1)? & 0x0F) << 8 | u16::from(*section.get(2)?));
+    section.get(..3 + length)
+}
+
+/// Returns `segment` with the PMT extended by the metadata stream and the tag sent
+/// right after the first PMT, timed at the first audio frame.
+fn insert_metadata(segment: &[u8], tag: &[u8], continuity: &mut u8) -> Result<Vec<u8>, String> {
+    if !segment.len().is_multiple_of(PACKET_SIZE) {
+        return Err("not a transport stream".to_string());
+    }
+    let packets: Vec<&[u8]> = segment.chunks(PACKET_SIZE).collect();
+    let parsed: Vec<Packet> = packets
+        .iter()
+        .map(|packet| parse_packet(packet).ok_or("lost transport stream sync"))
+        .collect::<Result<_, _>>()?;
+
+    let pmt_pid = parsed
+        .iter()
+        .filter(|packet| packet.pid == 0 && packet.unit_start)
+        .find_map(|packet| pmt_pid(section(packet.payload)?))
+        .ok_or("no program association table")?;
+    let pmt = parsed
+        .iter()
+        .filter(|packet| packet.pid == pmt_pid && packet.unit_start)
+        .find_map(|packet| section(packet.payload))
+        .ok_or("no program map table")?;
+    let (program_number, stream_pids) = pmt_streams(pmt).ok_or("malformed program map table")?;
+    if stream_pids.contains(&METADATA_PID) {
+        return Err("segment already has a metadata stream".to_string());
+    }
+    let new_pmt = extend_pmt(pmt, program_number);
+
+    let pts = parsed
+        .iter()
+        .filter(|packet| packet.unit_start && stream_pids.contains(&packet.pid))
+        .find_map(|packet| pes_pts(packet.payload))
+        .ok_or("no timestamped audio")?;
+    let pes = metadata_pes(tag, pts);
+
+    let mut output = Vec::with_capacity(segment.len() + pes.len() + PACKET_SIZE * 2);
+    let mut inserted = false;
+    for (packet, parsed) in packets.iter().zip(&parsed) {
+        if parsed.pid != pmt_pid {
+            output.extend_from_slice(packet);
+            continue;
+        }
+        // Every repetition of the PMT gets the new section, keeping its counter
+        output.extend_from_slice(&psi_packet(pmt_pid, packet[3] & 0x0F, &new_pmt)?);
+        if !inserted {
+            packetize(&mut output, METADATA_PID, &pes, continuity);
+            inserted = true;
+        }
+    }
+    Ok(output)
+}
+
+/// PMT PID of the first program in a PAT section.
+fn pmt_pid(pat: &[u8]) -> Option<u16> {
+    // Entries follow the 8 byte header; the last 4 bytes are the CRC
+    pat.get(8..pat.len().checked_sub(4)?)?
+        .chunks_exact(4)
+        .find(|entry| entry[0] != 0 || entry[1] != 0)
+        .map(|entry| u16::from(entry[2] & 0x1F) << 8 | u16::from(entry[3]))
+}
+
+/// Program number and elementary stream PIDs of a PMT section.
+fn pmt_streams(pmt: &[u8]) -> Option<(u16, Vec<u16>)> {
+    let program_number = u16::from(*pmt.get(3)?) << 8 | u16::from(*pmt.get(4)?);
+    let program_info_length =
+        usize::from(u16::from(pmt.get(10)? & 0x0F) << 8 | u16::from(*pmt.get(11)?));
+    let mut rest = pmt.get(12 + program_info_length..pmt.len().checked_sub(4)?)?;
+    let mut pids = Vec::new();
+    while rest.len() >= 5 {
+        pids.push(u16::from(rest[1] & 0x1F) << 8 | u16::from(rest[2]));
+        let info_length = usize::from(u16::from(rest[3] & 0x0F) << 8 | u16::from(rest[4]));
+        rest = rest.get(5 + info_length..)?;
+    }
+    Some((program_number, pids))
+}
+
+/// The PMT with a metadata_pointer_descriptor in the program info and the ID3
+/// stream (with its metadata_descriptor) appended.
+fn extend_pmt(pmt: &[u8], program_number: u16) -> Vec<u8> {
+    let program_info_length = usize::from(u16::from(pmt[10] & 0x0F) << 8 | u16::from(pmt[11]));
+    let pointer_descriptor = [
+        [0x25, 0x0F, 0xFF, 0xFF].as_slice(),
+        b"ID3 ",
+        &[0xFF],
+        b"ID3 ",
+        &[0x00, 0x1F],
+        &program_number.to_be_bytes(),
+    ]
+    .concat();
+    let metadata_descriptor = [
+        [0x26, 0x0D, 0xFF, 0xFF].as_slice(),
+        b"ID3 ",
+        &[0xFF],
+        b"ID3 ",
+        &[0x00, 0x0F],
+    ]
+    .concat();
+
+    let mut section = pmt[..12].to_vec();
+    let new_info_length = program_info_length + pointer_descriptor.len();
+    section[10] = 0xF0 | (new_info_length >> 8) as u8;
+    section[11] = new_info_length as u8;
+    section.extend_from_slice(&pmt[12..12 + program_info_length]);
+    section.extend_from_slice(&pointer_descriptor);
+    section.extend_from_slice(&pmt[12 + program_info_length..pmt.len() - 4]);
+    section.push(METADATA_STREAM_TYPE);
+    section.push(0xE0 | (METADATA_PID >> 8) as u8);
+    section.push(METADATA_PID as u8);
+    section.push(0xF0);
+    section.push(metadata_descriptor.len() as u8);
+    section.extend_from_slice(&metadata_descriptor);
+
+    // section_length counts everything after itself, CRC included
+    let length = section.len() - 3 + 4;
+    section[1] = (section[1] & 0xF0) | (length >> 8) as u8;
+    section[2] = length as u8;
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+/// A packet carrying a whole PSI section, padded with 0xFF.
+fn psi_packet(pid: u16, continuity: u8, section: &[u8]) -> Result<[u8; PACKET_SIZE], String> {
+    if section.len() > PACKET_SIZE - 5 {
+        return Err("program map table doesn't fit a packet".to_string());
+    }
+    let mut packet = [0xFF; PACKET_SIZE];
+    packet[0] = SYNC_BYTE;
+    packet[1] = 0x40 | (pid >> 8) as u8;
+    packet[2] = pid as u8;
+    packet[3] = 0x10 | continuity;
+    packet[4] = 0x00; // pointer field
+    packet[5..5 + section.len()].copy_from_slice(section);
+    Ok(packet)
+}
+
+/// Presentation timestamp of the first timestamped PES packet in `segment`, i.e. the
+/// 90 kHz time the segment starts at; `None` for encrypted or truncated data.
+pub(crate) fn first_pts(segment: &[u8]) -> Option<u64> {
+    segment
+        .chunks_exact(PACKET_SIZE)
+        .map_while(parse_packet)
+        .filter(|packet| packet.unit_start)
+        .find_map(|packet| pes_pts(packet.payload))
+}
+
+/// Presentation timestamp of a PES packet starting in `payload`.
+fn pes_pts(payload: &[u8]) -> Option<u64> {
+    if payload.get(..3)? != [0x00, 0x00, 0x01] || payload.get(7)? & 0x80 == 0 {
+        return None;
+    }
+    let p = payload.get(9..14)?;
+    Some(
+        u64::from(p[0] >> 1 & 0x07) << 30
+            | u64::from(p[1]) << 22
+            | u64::from(p[2] >> 1) << 15
+            | u64::from(p[3]) << 7
+            | u64::from(p[4] >> 1),
+    )
+}
+
+fn metadata_pes(tag: &[u8], pts: u64) -> Vec<u8> {
+    let mut pes = vec![0x00, 0x00, 0x01, METADATA_STREAM_ID];
+    pes.extend_from_slice(&((tag.len() + 8) as u16).to_be_bytes());
+    // Data aligned, PTS only
+    pes.extend_from_slice(&[0x84, 0x80, 0x05]);
+    pes.extend_from_slice(&[
+        0x21 | (pts >> 29 & 0x0E) as u8,
+        (pts >> 22) as u8,
+        0x01 | (pts >> 14 & 0xFE) as u8,
+        (pts >> 7) as u8,
+        0x01 | (pts << 1 & 0xFE) as u8,
+    ]);
+    pes.extend_from_slice(tag);
+    pes
+}
+
+/// Splits a PES packet into transport packets, stuffing the last one through its
+/// adaptation field.
+fn packetize(output: &mut Vec<u8>, pid: u16, pes: &[u8], continuity: &mut u8) {
+    for (index, chunk) in pes.chunks(PACKET_SIZE - 4).enumerate() {
+        let start = if index == 0 { 0x40 } else { 0x00 };
+        output.extend_from_slice(&[SYNC_BYTE, start | (pid >> 8) as u8, pid as u8]);
+        let stuffing = PACKET_SIZE - 4 - chunk.len();
+        if stuffing == 0 {
+            output.push(0x10 | *continuity);
+        } else {
+            output.push(0x30 | *continuity);
+            output.push((stuffing - 1) as u8);
+            if stuffing > 1 {
+                output.push(0x00);
+                output.extend(std::iter::repeat_n(0xFF, stuffing - 2));
+            }
+        }
+        output.extend_from_slice(chunk);
+        *continuity = (*continuity + 1) & 0x0F;
+    }
+}
+
+/// CRC-32/MPEG-2, the checksum of PSI sections.
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= u32::from(byte) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                crc << 1 ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
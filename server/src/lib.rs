@@ -0,0 +1,47 @@
+//! music-lib: a self-hosted HLS music server.
+//!
+//! The `music-server` binary is a thin wrapper around [`run`] and the offline
+//! commands in [`maintenance`]; the modules below expose the storage format, download
+//! pipeline, transcoding, audio analysis, AcoustID identification and cue sheet
+//! helpers for embedding the server or building tools on top of the cache directory.
+
+pub mod acoustid;
+pub mod analysis;
+pub mod api;
+pub mod config;
+pub mod cue;
+pub mod downloader;
+pub mod library;
+pub mod maintenance;
+pub mod media_tools;
+pub mod notifications;
+pub mod process;
+pub mod storage;
+pub mod transcode;
+pub mod webhooks;
+
+mod backup;
+mod connections;
+mod content_hash;
+#[cfg(feature = "fake-tools")]
+mod fake_tools;
+mod federation;
+mod feeds;
+mod id3;
+mod lyrics;
+mod merge;
+mod party;
+mod playback;
+mod playlist_files;
+mod radio;
+mod removals;
+mod scheduler;
+mod search;
+mod segment_cache;
+mod sources;
+mod systemd;
+mod throttle;
+mod tools;
+
+pub use api::run;
+pub use config::Config;
@@ -0,0 +1,419 @@
+//! Track listings and the views built on top of them (artists, albums, collections).
+
+use crate::storage::CrossfadeHints;
+use crate::storage::{
+    AudioCodec, Collection, HlsSession, IdentificationStatus, SourceStatus, TrackNote, Visibility,
+};
+use crate::transcode::VIDEO_PLAYLIST;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Longest slug in characters, before a number is added to tell it apart.
+const MAX_SLUG_LENGTH: usize = 60;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackInfo {
+    pub id: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub origin_url: String,
+    pub slug: String,
+    /// Link that keeps leading to the track, e.g. `/t/daft-punk-around-the-world`
+    pub short_url: String,
+    pub url: String,
+    /// Master playlist with bandwidth and codecs, for native players
+    pub master_url: String,
+    pub session_id: String,
+    pub total_segments: u32,
+    pub segment_duration: f32,
+    /// Length in seconds
+    pub duration: f64,
+    pub listen_count: u64,
+    /// Distinct listeners per day, summed over all days
+    pub unique_listeners: u64,
+    pub crossfade: Option<CrossfadeHints>,
+    pub resume_position: Option<f64>,
+    /// Mean of all devices' star ratings
+    pub average_rating: Option<f64>,
+    pub rating_count: usize,
+    /// Stars given by the device in `X-Device-Id`
+    pub user_rating: Option<u8>,
+    /// Video HLS playlist, for tracks downloaded with their music video
+    pub video_url: Option<String>,
+    /// Artwork taken from the source, when it had any, or uploaded
+    pub thumbnails: Option<Thumbnails>,
+    /// Whether the thumbnails were made from uploaded artwork
+    pub custom_artwork: bool,
+    pub bpm: Option<f64>,
+    pub key: Option<String>,
+    pub camelot: Option<String>,
+    /// Set when the title came from an AcoustID match
+    pub identification: Option<IdentificationStatus>,
+    pub notes: Vec<TrackNote>,
+    /// Unix time the track was added to the library
+    pub date_added: u64,
+    /// Players need a key token to fetch the segment key
+    pub encrypted: bool,
+    pub codec: AudioCodec,
+    /// Audio bitrate in kbit/s
+    pub bitrate: u32,
+    /// Outcome of the last check that the origin URL still resolves
+    pub source_status: Option<SourceStatus>,
+    /// Never evicted or purged automatically
+    pub pinned: bool,
+    pub visibility: Visibility,
+    /// From a read-only overlay library; it can't be deleted, re-transcoded or edited
+    pub read_only: bool,
+}
+
+/// Thumbnail URLs by size; see `THUMBNAIL_SIZES` for their widths.
+#[derive(Debug, Clone, Serialize)]
+pub struct Thumbnails {
+    pub small: String,
+    pub medium: String,
+    pub large: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArtistInfo {
+    pub name: String,
+    pub track_count: usize,
+    pub album_count: usize,
+    pub listen_count: u64,
+    /// Artwork of the first of the tracks that has any
+    pub thumbnails: Option<Thumbnails>,
+    pub tracks: Vec<TrackInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AlbumInfo {
+    pub title: String,
+    pub artist: Option<String>,
+    pub track_count: usize,
+    pub listen_count: u64,
+    /// Artwork of the first of the tracks that has any
+    pub thumbnails: Option<Thumbnails>,
+    pub tracks: Vec<TrackInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CollectionNode {
+    pub name: String,
+    pub path: String,
+    pub id: Option<String>,
+    pub track_ids: Vec<String>,
+    pub children: Vec<CollectionNode>,
+}
+
+pub fn track_info(file_hash: &str, session: &HlsSession) -> TrackInfo {
+    TrackInfo {
+        id: file_hash.to_string(),
+        title: session.title.clone(),
+        artist: session.artist.clone(),
+        album: session.album.clone(),
+        origin_url: session.origin_url.clone(),
+        slug: session.slug.clone(),
+        short_url: format!("/t/{}", session.slug),
+        url: format!("/api/hls/{}/playlist.m3u8", session.id),
+        master_url: format!("/api/hls/{}/master.m3u8", session.id),
+        session_id: session.id.clone(),
+        total_segments: session.total_segments,
+        segment_duration: session.segment_duration,
+        duration: (track_duration(session) * 1000.0).round() / 1000.0,
+        listen_count: session.listen_count,
+        unique_listeners: session.unique_listeners,
+        crossfade: session.crossfade,
+        resume_position: None,
+        average_rating: None,
+        rating_count: 0,
+        user_rating: None,
+        video_url: session
+            .has_video
+            .then(|| format!("/api/hls/{}/{}", session.id, VIDEO_PLAYLIST)),
+        thumbnails: session.has_thumbnail.then(|| Thumbnails {
+            small: thumbnail_url(session, "small"),
+            medium: thumbnail_url(session, "medium"),
+            large: thumbnail_url(session, "large"),
+        }),
+        custom_artwork: session.custom_artwork,
+        bpm: session.tempo_key.as_ref().map(|t| t.bpm),
+        key: session.tempo_key.as_ref().map(|t| t.key.clone()),
+        camelot: session.tempo_key.as_ref().map(|t| t.camelot.clone()),
+        identification: session.identification.as_ref().map(|i| i.status),
+        notes: session.notes.clone(),
+        date_added: session.date_added,
+        encrypted: session.encrypted,
+        codec: session.codec,
+        bitrate: session.bitrate,
+        source_status: None,
+        pinned: session.pinned,
+        visibility: session.visibility,
+        read_only: session.read_only,
+    }
+}
+
+/// URL of the track's thumbnail in `size`, naming the artwork's content hash when
+/// it is known so the URL changes with the image.
+pub(crate) fn thumbnail_url(session: &HlsSession, size: &str) -> String {
+    match &session.artwork_hash {
+        Some(hash) => format!("/api/hls/{}/thumbnail/{}.{}.jpg", session.id, size, hash),
+        None => format!("/api/hls/{}/thumbnail/{}", session.id, size),
+    }
+}
+
+/// A name for links made from a track's artist and title, e.g.
+/// "daft-punk-around-the-world": lowercase ASCII letters and digits between dashes,
+/// with accents dropped.
+pub fn slugify(artist: Option<&str>, title: &str) -> String {
+    let name = match artist {
+        Some(artist) if !artist.trim().is_empty() => format!("{} {}", artist, title),
+        _ => title.to_string(),
+    };
+    let mut slug = String::new();
+    for c in name.chars().flat_map(char::to_lowercase) {
+        let folded = match c {
+            'a'..='z' | '0'..='9' => Some(c.encode_utf8(&mut [0; 4]).to_string()),
+            _ => fold_letter(c).map(str::to_string),
+        };
+        match folded {
+            Some(letters) => slug.push_str(&letters),
+            None if !slug.is_empty() && !slug.ends_with('-') => slug.push('-'),
+            None => {}
+        }
+    }
+
+    let mut slug = slug.trim_end_matches('-').to_string();
+    if slug.len() > MAX_SLUG_LENGTH {
+        // Cut at a word boundary when there is one
+        let cut = &slug[..MAX_SLUG_LENGTH];
+        slug = cut.rfind('-').map_or(cut, |dash| &cut[..dash]).to_string();
+    }
+    if slug.is_empty() {
+        "track".to_string()
+    } else {
+        slug
+    }
+}
+
+/// ASCII spelling of a lowercase letter with a diacritic, and of "&".
+fn fold_letter(c: char) -> Option<&'static str> {
+    Some(match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'č' => "c",
+        'ď' | 'đ' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ę' | 'ě' => "e",
+        'ì' | 'í' | 'î' | 'ï' | 'ī' => "i",
+        'ł' => "l",
+        'ñ' | 'ń' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ő' | 'ō' => "o",
+        'œ' => "oe",
+        'ř' => "r",
+        'ś' | 'š' | 'ş' => "s",
+        'ß' => "ss",
+        'ť' | 'ţ' => "t",
+        'ù' | 'ú' | 'û' | 'ü' | 'ů' | 'ű' | 'ū' => "u",
+        'ý' | 'ÿ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        '&' => "and",
+        _ => return None,
+    })
+}
+
+/// `base`, or `base-2`, `base-3`, ... if it is taken.
+fn unique_slug(base: String, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(&base) {
+        return base;
+    }
+    (2..)
+        .map(|number| format!("{}-{}", base, number))
+        .find(|slug| !taken(slug))
+        .expect("some number is free")
+}
+
+/// Gives `session` a slug no track in `library` has, unless it already has one.
+pub fn assign_slug(library: &HashMap<String, HlsSession>, session: &mut HlsSession) {
+    if !session.slug.is_empty() {
+        return;
+    }
+    let base = slugify(session.artist.as_deref(), &session.title);
+    session.slug = unique_slug(base, |slug| {
+        library.values().any(|other| other.slug == slug)
+    });
+}
+
+/// Gives every track without a slug one, oldest first. Returns how many got one.
+pub fn assign_slugs(library: &mut HashMap<String, HlsSession>) -> usize {
+    let mut taken: HashSet<String> = library
+        .values()
+        .filter(|session| !session.slug.is_empty())
+        .map(|session| session.slug.clone())
+        .collect();
+    let mut missing: Vec<(u64, String)> = library
+        .iter()
+        .filter(|(_, session)| session.slug.is_empty())
+        .map(|(id, session)| (session.date_added, id.clone()))
+        .collect();
+    missing.sort();
+
+    for (_, id) in &missing {
+        let Some(session) = library.get_mut(id) else {
+            continue;
+        };
+        let base = slugify(session.artist.as_deref(), &session.title);
+        let slug = unique_slug(base, |slug| taken.contains(slug));
+        taken.insert(slug.clone());
+        session.slug = slug;
+    }
+    missing.len()
+}
+
+/// Track length in seconds; estimated from the segment count only for sessions
+/// whose playlist couldn't be read.
+pub fn track_duration(session: &HlsSession) -> f64 {
+    if session.duration > 0.0 {
+        return session.duration;
+    }
+    match session.crossfade {
+        Some(hints) => hints.duration,
+        None => session.total_segments as f64 * session.segment_duration as f64,
+    }
+}
+
+/// Groups tracks by artist. Tracks without an artist are left out.
+pub fn group_by_artist(cache: &HashMap<String, HlsSession>) -> Vec<ArtistInfo> {
+    let mut artists: HashMap<&str, Vec<(&String, &HlsSession)>> = HashMap::new();
+    for (hash, session) in cache {
+        if let Some(artist) = session.artist.as_deref() {
+            artists.entry(artist).or_default().push((hash, session));
+        }
+    }
+
+    let mut result: Vec<ArtistInfo> = artists
+        .into_iter()
+        .map(|(name, sessions)| {
+            let mut albums: Vec<&str> = sessions
+                .iter()
+                .filter_map(|(_, s)| s.album.as_deref())
+                .collect();
+            albums.sort_unstable();
+            albums.dedup();
+
+            let mut tracks: Vec<TrackInfo> =
+                sessions.iter().map(|(h, s)| track_info(h, s)).collect();
+            tracks.sort_by(|a, b| a.title.cmp(&b.title));
+
+            ArtistInfo {
+                name: name.to_string(),
+                track_count: tracks.len(),
+                album_count: albums.len(),
+                listen_count: tracks.iter().map(|t| t.listen_count).sum(),
+                thumbnails: first_artwork(&tracks),
+                tracks,
+            }
+        })
+        .collect();
+
+    result.sort_by_key(|a| a.name.to_lowercase());
+    result
+}
+
+/// Artwork for a group of tracks: that of the first one that has any.
+fn first_artwork(tracks: &[TrackInfo]) -> Option<Thumbnails> {
+    tracks.iter().find_map(|track| track.thumbnails.clone())
+}
+
+pub type AlbumKey<'a> = (&'a str, Option<&'a str>);
+
+/// Groups tracks by (album, artist). Tracks without an album are left out.
+pub fn group_by_album(cache: &HashMap<String, HlsSession>) -> Vec<AlbumInfo> {
+    let mut albums: HashMap<AlbumKey, Vec<(&String, &HlsSession)>> = HashMap::new();
+    for (hash, session) in cache {
+        if let Some(album) = session.album.as_deref() {
+            albums
+                .entry((album, session.artist.as_deref()))
+                .or_default()
+                .push((hash, session));
+        }
+    }
+
+    let mut result: Vec<AlbumInfo> = albums
+        .into_iter()
+        .map(|((title, artist), sessions)| {
+            let mut tracks: Vec<TrackInfo> =
+                sessions.iter().map(|(h, s)| track_info(h, s)).collect();
+            tracks.sort_by(|a, b| a.title.cmp(&b.title));
+
+            AlbumInfo {
+                title: title.to_string(),
+                artist: artist.map(str::to_string),
+                track_count: tracks.len(),
+                listen_count: tracks.iter().map(|t| t.listen_count).sum(),
+                thumbnails: first_artwork(&tracks),
+                tracks,
+            }
+        })
+        .collect();
+
+    result.sort_by_key(|a| a.title.to_lowercase());
+    result
+}
+
+/// Normalizes a collection path like " DJ mixes / Techno/" into "DJ mixes/Techno".
+pub fn normalize_collection_path(path: &str) -> Option<String> {
+    let parts: Vec<&str> = path
+        .split('/')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .collect();
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+/// Builds the collection tree. Parent folders that were never created explicitly
+/// still show up as nodes without an id.
+pub fn build_collection_tree(collections: &HashMap<String, Collection>) -> Vec<CollectionNode> {
+    let mut sorted: Vec<&Collection> = collections.values().collect();
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut roots: Vec<CollectionNode> = Vec::new();
+    for collection in sorted {
+        let mut nodes = &mut roots;
+        let mut path = String::new();
+        let segments: Vec<&str> = collection.path.split('/').collect();
+
+        for (i, segment) in segments.iter().enumerate() {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(segment);
+
+            let index = match nodes.iter().position(|n| n.name == *segment) {
+                Some(index) => index,
+                None => {
+                    nodes.push(CollectionNode {
+                        name: segment.to_string(),
+                        path: path.clone(),
+                        id: None,
+                        track_ids: Vec::new(),
+                        children: Vec::new(),
+                    });
+                    nodes.len() - 1
+                }
+            };
+
+            if i == segments.len() - 1 {
+                nodes[index].id = Some(collection.id.clone());
+                nodes[index].track_ids = collection.track_ids.clone();
+            }
+            nodes = &mut nodes[index].children;
+        }
+    }
+
+    roots
+}
@@ -0,0 +1,130 @@
+//! Time-synced lyrics: LRC files kept next to a track's segments, turned into a
+//! WebVTT subtitle rendition so native HLS players show them line by line.
+
+use std::path::{Path, PathBuf};
+
+/// The track's lyrics in its segments directory.
+pub(crate) const LYRICS_FILE: &str = "lyrics.lrc";
+
+/// The last line is shown this long when the track's length is unknown.
+const LAST_LINE_SECONDS: f64 = 5.0;
+
+/// A line of lyrics and when it starts, in seconds into the track.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LyricLine {
+    pub(crate) start: f64,
+    pub(crate) text: String,
+}
+
+/// The timed lines of an LRC file, in order. A line may carry several timestamps
+/// (a repeated chorus); word timestamps of enhanced LRC are dropped, and the
+/// `[offset:]` tag is applied.
+pub(crate) fn parse_lrc(lrc: &str) -> Vec<LyricLine> {
+    let mut offset = 0.0;
+    let mut lines = Vec::new();
+    for line in lrc.lines() {
+        let mut rest = line.trim();
+        let mut starts = Vec::new();
+        while let Some(tag) = rest.strip_prefix('[') {
+            let Some(end) = tag.find(']') else {
+                break;
+            };
+            let (tag, after) = (&tag[..end], &tag[end + 1..]);
+            if let Some(start) = parse_timestamp(tag) {
+                starts.push(start);
+            } else if let Some(value) = tag.strip_prefix("offset:") {
+                // Milliseconds; a positive offset shows the lyrics sooner
+                if let Ok(ms) = value.trim().parse::<f64>() {
+                    offset = ms / 1000.0;
+                }
+            }
+            rest = after.trim_start();
+        }
+
+        let text = strip_word_timestamps(rest);
+        lines.extend(starts.into_iter().map(|start| LyricLine {
+            start,
+            text: text.clone(),
+        }));
+    }
+    for line in &mut lines {
+        line.start = (line.start - offset).max(0.0);
+    }
+    lines.sort_by(|a, b| a.start.total_cmp(&b.start));
+    lines
+}
+
+/// `mm:ss`, `mm:ss.xx` or `mm:ss:xx` as seconds.
+fn parse_timestamp(tag: &str) -> Option<f64> {
+    let (minutes, seconds) = tag.split_once(':')?;
+    let minutes: u64 = minutes.trim().parse().ok()?;
+    // Some editors write hundredths after a colon
+    let seconds: f64 = seconds.trim().replacen(':', ".", 1).parse().ok()?;
+    Some(minutes as f64 * 60.0 + seconds)
+}
+
+fn strip_word_timestamps(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) if parse_timestamp(&rest[start + 1..start + end]).is_some() => {
+                stripped.push_str(&rest[..start]);
+                rest = &rest[start + end + 1..];
+            }
+            _ => {
+                stripped.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    stripped.push_str(rest);
+    stripped.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The lines as a WebVTT document, each shown until the next one starts; empty
+/// lines only end the one before. `start_pts` is the 90 kHz timestamp the track's
+/// first segment starts at, which the cue times are relative to.
+pub(crate) fn to_webvtt(lines: &[LyricLine], duration: f64, start_pts: u64) -> String {
+    let mut vtt = format!(
+        "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000\n",
+        start_pts
+    );
+    for (index, line) in lines.iter().enumerate() {
+        if line.text.is_empty() {
+            continue;
+        }
+        let end = match lines.get(index + 1) {
+            Some(next) => next.start,
+            None if duration > line.start => duration,
+            None => line.start + LAST_LINE_SECONDS,
+        };
+        if end <= line.start {
+            continue;
+        }
+        vtt.push_str(&format!(
+            "\n{} --> {}\n{}\n",
+            vtt_time(line.start),
+            vtt_time(end),
+            line.text
+        ));
+    }
+    vtt
+}
+
+pub(crate) fn vtt_time(seconds: f64) -> String {
+    let ms = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// An LRC file named like `file`, e.g. `song.lrc` next to `song.flac`.
+pub(crate) fn find_lrc(file: &Path) -> Option<PathBuf> {
+    let path = file.with_extension("lrc");
+    path.is_file().then_some(path)
+}
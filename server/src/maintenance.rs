@@ -0,0 +1,857 @@
+//! Offline maintenance of a cache directory: importing local files, exporting tracks,
+//! verifying segments, collecting garbage and restoring backups, without a running server,
+//! and updating yt-dlp.
+
+use crate::acoustid::AcoustId;
+use crate::analysis::analyze_tempo_key;
+use crate::backup::Snapshot;
+use crate::config::{ExportArgs, GcArgs, ImportArgs, RestoreArgs, UpdateYtdlpArgs, VerifyArgs};
+use crate::cue::{find_cue, load_cue};
+use crate::downloader::is_audio_file;
+use crate::library::assign_slug;
+use crate::lyrics::{find_lrc, LYRICS_FILE};
+use crate::process::ProcessLimits;
+use crate::storage::{
+    generate_url_hash, is_safe_path_component, load_collections, load_hls_cache,
+    load_hls_cache_index, load_queues, load_ratings, load_source_checks, new_track_id,
+    parse_hls_cache, playlist_segments, save_collections, save_hls_cache, save_queues,
+    save_ratings, save_source_checks, track_with_origin, unix_timestamp, AudioCodec, HlsSession,
+    SECONDS_PER_DAY,
+};
+use crate::tools;
+use crate::transcode::{
+    analyze_crossfade, create_hls_segments, create_thumbnails, thumbnail_file, AudioFormat,
+    TranscodeOptions, KEY_FILE, THUMBNAIL_SIZES, VIDEO_PLAYLIST,
+};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::process::Command;
+use uuid::Uuid;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Cover images looked for next to an imported file, in order of preference.
+const ARTWORK_FILES: [&str; 4] = ["cover.jpg", "folder.jpg", "front.jpg", "cover.png"];
+
+/// Directory of the upstream proxy cache, which isn't a track.
+const UPSTREAM_DIR: &str = "upstream";
+
+const HLS_CACHE_FILE: &str = "hls_cache.json";
+
+/// One track to cut from an imported file.
+struct ImportPart {
+    /// Hash of the file's location, with the cue track's range
+    origin_hash: String,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    clip: Option<(f64, f64)>,
+}
+
+/// Converts local audio files into library tracks. A file with a cue sheet next to it
+/// becomes one track per cue track; importing a file again skips the tracks it
+/// already produced.
+pub async fn import(args: ImportArgs) -> Result<(), Error> {
+    let dirs = args.cache.dirs();
+    let cache_dir = dirs.primary();
+    for dir in dirs.all() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+
+    let mut files = Vec::new();
+    for path in &args.paths {
+        collect_audio_files(path, &mut files)?;
+    }
+    if files.is_empty() {
+        return Err("No audio files found".into());
+    }
+    if args.title.is_some() && files.len() > 1 {
+        return Err("--title can only be given when importing a single file".into());
+    }
+
+    let options = TranscodeOptions {
+        ffmpeg_path: args.ffmpeg_path.clone(),
+        single_file: args.single_file_hls,
+        encrypt: args.encrypt_segments,
+        audio: AudioFormat {
+            codec: args.audio_codec,
+            bitrate: args.audio_bitrate,
+            segment_duration: args.segment_duration,
+        },
+        ..TranscodeOptions::default()
+    };
+    let acoustid = args.acoustid_key.clone().map(|api_key| AcoustId {
+        api_key,
+        min_score: args.acoustid_min_score,
+        client: reqwest::Client::new(),
+    });
+
+    let mut cache = load_hls_cache(&dirs).await?;
+    let (mut imported, mut skipped, mut failed) = (0, 0, 0);
+    for file in &files {
+        let parts = match import_parts(file, &args, &options).await {
+            Ok(parts) => parts,
+            Err(e) => {
+                eprintln!("✗ {}: {}", file.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        let artwork = ARTWORK_FILES
+            .iter()
+            .filter_map(|name| Some(file.parent()?.join(name)))
+            .find(|path| path.is_file());
+
+        for part in parts {
+            if let Some(track_id) = track_with_origin(&cache, &part.origin_hash) {
+                println!("- Skipping '{}', already imported", cache[&track_id].title);
+                skipped += 1;
+                continue;
+            }
+            let track_id = new_track_id();
+            let session = import_part(
+                file,
+                part,
+                artwork.as_deref(),
+                &options,
+                acoustid.as_ref(),
+                dirs.for_new_track(),
+            )
+            .await;
+            match session {
+                Ok(mut session) => {
+                    println!("✓ Imported '{}' as {}", session.title, track_id);
+                    assign_slug(&cache, &mut session);
+                    cache.insert(track_id, session);
+                    // Saved after every track, so an interrupted import keeps its progress
+                    save_hls_cache(cache_dir, &cache).await?;
+                    imported += 1;
+                }
+                Err(e) => {
+                    eprintln!("✗ {}: {}", file.display(), e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!(
+        "✓ Imported {} tracks, {} already in the library, {} failed",
+        imported, skipped, failed
+    );
+    if failed > 0 {
+        return Err(format!("{} imports failed", failed).into());
+    }
+    Ok(())
+}
+
+/// Adds `path` if it is an audio file, or every audio file below it if it is a directory.
+fn collect_audio_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    if path.is_file() {
+        if !is_audio_file(path) {
+            return Err(format!("{} is not a supported audio file", path.display()).into());
+        }
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect_audio_files(&entry, files)?;
+        } else if is_audio_file(&entry) {
+            files.push(entry);
+        }
+    }
+    Ok(())
+}
+
+/// The tracks in `file`: one per track of its cue sheet, or the whole file.
+async fn import_parts(
+    file: &Path,
+    args: &ImportArgs,
+    options: &TranscodeOptions,
+) -> Result<Vec<ImportPart>, Error> {
+    // Files are recognised by their location, like downloads by their URL
+    let source = format!("file://{}", std::fs::canonicalize(file)?.display());
+
+    let cue = if args.ignore_cue {
+        None
+    } else {
+        find_cue(file)
+    };
+    let Some(cue_path) = cue else {
+        return Ok(vec![ImportPart {
+            origin_hash: generate_url_hash(&source),
+            title: args.title.clone(),
+            artist: args.artist.clone(),
+            album: args.album.clone(),
+            clip: None,
+        }]);
+    };
+
+    let sheet = load_cue(&cue_path)
+        .await
+        .map_err(|e| format!("Invalid cue sheet {}: {}", cue_path.display(), e))?;
+    let name = file.file_name().unwrap_or_default();
+    let cue_file = match sheet.files.as_slice() {
+        [only] => only,
+        files => files
+            .iter()
+            .find(|cue_file| Path::new(&cue_file.name).file_name() == Some(name))
+            .ok_or_else(|| format!("{} doesn't list this file", cue_path.display()))?,
+    };
+    // The last track runs until the end of the file
+    let duration = analyze_crossfade(file, options).await?.duration;
+
+    Ok(cue_file
+        .tracks
+        .iter()
+        .zip(cue_file.chapters(duration))
+        .map(|(track, chapter)| ImportPart {
+            origin_hash: generate_url_hash(&format!(
+                "{}#t={},{}",
+                source, chapter.start, chapter.end
+            )),
+            title: Some(chapter.title),
+            artist: sheet
+                .performer_of(track)
+                .map(str::to_string)
+                .or_else(|| args.artist.clone()),
+            album: sheet.title.clone().or_else(|| args.album.clone()),
+            clip: Some((chapter.start, chapter.end)),
+        })
+        .collect())
+}
+
+/// Converts one part of `file` into a new track, analysed like a download.
+async fn import_part(
+    file: &Path,
+    part: ImportPart,
+    artwork: Option<&Path>,
+    options: &TranscodeOptions,
+    acoustid: Option<&AcoustId>,
+    cache_dir: &Path,
+) -> Result<HlsSession, Error> {
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    let mut title = part.title.clone().unwrap_or_else(|| stem.to_string());
+    let mut artist = part.artist;
+    let mut album = part.album;
+
+    let mut identification = None;
+    if let (Some(acoustid), None) = (acoustid, &part.title) {
+        match acoustid.identify(file, &ProcessLimits::default()).await {
+            Ok(Some(mut found)) => {
+                found.previous_title = std::mem::replace(&mut title, found.title.clone());
+                found.previous_artist = artist.clone();
+                found.previous_album = album.clone();
+                artist = artist.or_else(|| found.artist.clone());
+                album = album.or_else(|| found.album.clone());
+                identification = Some(found);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Warning: AcoustID identification failed: {}", e),
+        }
+    }
+
+    let session_id = Uuid::new_v4().to_string();
+    let transcode = TranscodeOptions {
+        clip: part.clip,
+        ..options.clone()
+    };
+    // Imports have no origin URL, so they are never refreshed or source-checked
+    let mut session =
+        match create_hls_segments(file, cache_dir, &session_id, &title, "", &transcode, None).await
+        {
+            Ok(session) => session,
+            Err(e) => {
+                let _ = tokio::fs::remove_dir_all(cache_dir.join(&session_id)).await;
+                return Err(e);
+            }
+        };
+    session.origin_hash = part.origin_hash;
+    session.artist = artist;
+    session.album = album;
+    session.identification = identification;
+
+    match analyze_crossfade(file, &transcode).await {
+        Ok(hints) => session.crossfade = Some(hints),
+        Err(e) => eprintln!("Warning: Crossfade analysis failed: {}", e),
+    }
+
+    let duration = session.crossfade.map(|hints| hints.duration);
+    match analyze_tempo_key(file, &transcode, duration).await {
+        Ok(tempo_key) => session.tempo_key = Some(tempo_key),
+        Err(e) => eprintln!("Warning: Tempo and key analysis failed: {}", e),
+    }
+
+    if let Some(artwork) = artwork {
+        match create_thumbnails(artwork, &session.segments_dir, &transcode).await {
+            Ok(artwork_hash) => {
+                session.has_thumbnail = true;
+                session.artwork_hash = Some(artwork_hash);
+            }
+            Err(e) => eprintln!("Warning: Thumbnail conversion failed: {}", e),
+        }
+    }
+
+    // Lyrics are timed against the whole file, so cue sheet tracks don't get them
+    if let (Some(lrc), None) = (find_lrc(file), part.clip) {
+        if let Err(e) = tokio::fs::copy(&lrc, session.segments_dir.join(LYRICS_FILE)).await {
+            eprintln!("Warning: Failed to copy lyrics {}: {}", lrc.display(), e);
+        }
+    }
+
+    Ok(session)
+}
+
+/// Writes tracks out as audio files named after their artist and title, with the
+/// segments joined without re-encoding.
+pub async fn export(args: ExportArgs) -> Result<(), Error> {
+    let cache = load_hls_cache(&args.cache.dirs()).await?;
+    let mut tracks: Vec<(&String, &HlsSession)> = if args.track_ids.is_empty() {
+        cache.iter().collect()
+    } else {
+        args.track_ids
+            .iter()
+            .map(|id| {
+                cache
+                    .get_key_value(id)
+                    .ok_or_else(|| format!("No track with id {}", id))
+            })
+            .collect::<Result<_, _>>()?
+    };
+    tracks.sort_by(|(_, a), (_, b)| {
+        (&a.artist, &a.album, &a.title).cmp(&(&b.artist, &b.album, &b.title))
+    });
+    tokio::fs::create_dir_all(&args.output).await?;
+
+    let (mut exported, mut skipped, mut failed) = (0, 0, 0);
+    let mut names = HashSet::new();
+    for (id, session) in tracks {
+        let path = args.output.join(export_file_name(session, &mut names));
+        if path.exists() && !args.overwrite {
+            println!("- Skipping '{}', {} exists", session.title, path.display());
+            skipped += 1;
+            continue;
+        }
+        match export_track(&args.ffmpeg_path, session, &path).await {
+            Ok(()) => {
+                println!("✓ Exported {} to {}", id, path.display());
+                exported += 1;
+            }
+            Err(e) => {
+                eprintln!("✗ {} '{}': {}", id, session.title, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!(
+        "✓ Exported {} tracks, {} skipped, {} failed",
+        exported, skipped, failed
+    );
+    if failed > 0 {
+        return Err(format!("{} exports failed", failed).into());
+    }
+    Ok(())
+}
+
+/// "Artist - Title.ext" without characters file systems reject, numbered when two
+/// tracks would share a name.
+fn export_file_name(session: &HlsSession, names: &mut HashSet<String>) -> String {
+    let base = match &session.artist {
+        Some(artist) => format!("{} - {}", artist, session.title),
+        None => session.title.clone(),
+    };
+    let base: String = base
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let base = base.trim().trim_start_matches('.');
+    let extension = match session.codec {
+        AudioCodec::Aac => "m4a",
+        AudioCodec::Mp3 => "mp3",
+    };
+
+    let mut name = format!("{}.{}", base, extension);
+    let mut number = 2;
+    while !names.insert(name.to_lowercase()) {
+        name = format!("{} ({}).{}", base, number, extension);
+        number += 1;
+    }
+    name
+}
+
+async fn export_track(ffmpeg: &Path, session: &HlsSession, path: &Path) -> Result<(), Error> {
+    let mut command = Command::new(ffmpeg);
+    command
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(&session.playlist_path)
+        .args(["-map", "0:a", "-c", "copy"]);
+    if session.codec == AudioCodec::Aac {
+        command.args(["-bsf:a", "aac_adtstoasc"]);
+    }
+    command
+        .arg("-metadata")
+        .arg(format!("title={}", session.title));
+    if let Some(artist) = &session.artist {
+        command.arg("-metadata").arg(format!("artist={}", artist));
+    }
+    if let Some(album) = &session.album {
+        command.arg("-metadata").arg(format!("album={}", album));
+    }
+
+    let output = command.arg(path).output().await?;
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(path).await;
+        let error = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("FFmpeg error: {}", error.trim()).into());
+    }
+
+    let lyrics = session.segments_dir.join(LYRICS_FILE);
+    if lyrics.is_file() {
+        tokio::fs::copy(&lyrics, path.with_extension("lrc")).await?;
+    }
+    Ok(())
+}
+
+/// Something wrong with a track's files.
+enum Problem {
+    /// The audio can't be played as it is
+    Unplayable(String),
+    /// The playlist holds a different number of segments than recorded
+    SegmentCount(u32),
+    MissingArtwork,
+    MissingVideo,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Unplayable(reason) => f.write_str(reason),
+            Problem::SegmentCount(count) => {
+                write!(
+                    f,
+                    "playlist lists {} segments, library has another count",
+                    count
+                )
+            }
+            Problem::MissingArtwork => f.write_str("thumbnails missing"),
+            Problem::MissingVideo => f.write_str("video rendition missing"),
+        }
+    }
+}
+
+/// Checks every track's playlist, segment files, key, artwork and video rendition.
+/// With `fix`, unplayable tracks that aren't pinned are dropped from the library (their
+/// directories are left for `gc`) and the other problems are corrected in the library's
+/// records.
+pub async fn verify(args: VerifyArgs) -> Result<(), Error> {
+    let dirs = args.cache.dirs();
+    let cache_dir = dirs.primary();
+    let index = load_hls_cache_index(cache_dir).await?;
+    let mut cache = load_hls_cache(&dirs).await?;
+
+    let mut problems = 0;
+    let mut changed = false;
+    // load_hls_cache already left out tracks whose directory or playlist is gone
+    for track in index.iter().filter(|track| !cache.contains_key(&track.id)) {
+        println!(
+            "✗ {} '{}': {} or its playlist is missing",
+            track.id,
+            track.title,
+            track.segments_dir.display()
+        );
+        problems += 1;
+        changed = true;
+    }
+
+    let mut ids: Vec<String> = cache.keys().cloned().collect();
+    ids.sort();
+    for id in ids {
+        let session = &cache[&id];
+        let found = check_track(session).await;
+        if found.is_empty() {
+            continue;
+        }
+        for problem in &found {
+            println!("✗ {} '{}': {}", id, session.title, problem);
+        }
+        problems += found.len();
+        if !args.fix {
+            continue;
+        }
+
+        changed = true;
+        if found.iter().any(|p| matches!(p, Problem::Unplayable(_))) {
+            if session.pinned {
+                println!("- Keeping pinned track {} in the library", id);
+            } else {
+                cache.remove(&id);
+            }
+            continue;
+        }
+        let session = cache.get_mut(&id).unwrap();
+        for problem in found {
+            match problem {
+                Problem::SegmentCount(count) => session.total_segments = count,
+                Problem::MissingArtwork => {
+                    session.has_thumbnail = false;
+                    session.artwork_hash = None;
+                    session.custom_artwork = false;
+                }
+                Problem::MissingVideo => session.has_video = false,
+                Problem::Unplayable(_) => {}
+            }
+        }
+    }
+
+    if args.fix && changed {
+        save_hls_cache(cache_dir, &cache).await?;
+        println!(
+            "✓ Fixed {} problems; run gc to delete the removed tracks' files",
+            problems
+        );
+    } else {
+        println!("✓ Checked {} tracks: {} problems", index.len(), problems);
+    }
+    if problems > 0 && !args.fix {
+        return Err(format!(
+            "{} problems found, run verify --fix to repair them",
+            problems
+        )
+        .into());
+    }
+    Ok(())
+}
+
+async fn check_track(session: &HlsSession) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let playlist = match tokio::fs::read_to_string(&session.playlist_path).await {
+        Ok(playlist) => playlist,
+        Err(e) => {
+            problems.push(Problem::Unplayable(format!("playlist unreadable: {}", e)));
+            return problems;
+        }
+    };
+
+    let segments = playlist_segments(&playlist);
+    if segments.is_empty() {
+        problems.push(Problem::Unplayable(
+            "playlist lists no segments".to_string(),
+        ));
+    }
+    let mut missing = Vec::new();
+    for segment in &segments {
+        let needed = segment
+            .byte_range
+            .map_or(1, |(length, offset)| length + offset);
+        let size = tokio::fs::metadata(session.segments_dir.join(segment.uri))
+            .await
+            .map_or(0, |metadata| metadata.len());
+        if size < needed && !missing.contains(&segment.uri) {
+            missing.push(segment.uri);
+        }
+    }
+    if let Some(first) = missing.first() {
+        problems.push(Problem::Unplayable(format!(
+            "{} segment files missing or truncated, first {}",
+            missing.len(),
+            first
+        )));
+    }
+    if session.encrypted && !session.segments_dir.join(KEY_FILE).is_file() {
+        problems.push(Problem::Unplayable("encryption key missing".to_string()));
+    }
+
+    if !segments.is_empty() && segments.len() as u32 != session.total_segments {
+        problems.push(Problem::SegmentCount(segments.len() as u32));
+    }
+    let has_thumbnails = THUMBNAIL_SIZES
+        .iter()
+        .all(|(size, _)| session.segments_dir.join(thumbnail_file(size)).is_file());
+    if session.has_thumbnail && !has_thumbnails {
+        problems.push(Problem::MissingArtwork);
+    }
+    if session.has_video && !session.segments_dir.join(VIDEO_PLAYLIST).is_file() {
+        problems.push(Problem::MissingVideo);
+    }
+    problems
+}
+
+/// Deletes directories in the cache that no track refers to, such as the leftovers of
+/// interrupted downloads and conversions, and drops deleted tracks from collections,
+/// play queues, ratings and source checks.
+pub async fn gc(args: GcArgs) -> Result<(), Error> {
+    let dirs = args.cache.dirs();
+    let cache_dir = dirs.primary();
+    // Read as written, so a track whose files are briefly unreadable isn't collected
+    let index = load_hls_cache_index(cache_dir).await?;
+    let referenced: HashSet<&std::ffi::OsStr> = index
+        .iter()
+        .filter_map(|track| track.segments_dir.file_name())
+        .collect();
+    let track_ids: HashSet<&str> = index.iter().map(|track| track.id.as_str()).collect();
+    let min_age = Duration::from_secs(args.min_age * 60);
+
+    let (mut deleted, mut freed, mut recent) = (0, 0, 0);
+    let mut entries = Vec::new();
+    for dir in dirs.all() {
+        entries.extend(std::fs::read_dir(dir)?.flatten());
+    }
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name();
+        let upstream = path == cache_dir.join(UPSTREAM_DIR);
+        if !path.is_dir() || upstream || referenced.contains(name.as_os_str()) {
+            continue;
+        }
+        let age = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .unwrap_or_default();
+        if age < min_age {
+            recent += 1;
+            continue;
+        }
+
+        let size = dir_size(&path);
+        if args.dry_run {
+            println!("- Would delete {} ({} KiB)", path.display(), size / 1024);
+        } else {
+            if let Err(e) = tokio::fs::remove_dir_all(&path).await {
+                eprintln!("Warning: Failed to delete {}: {}", path.display(), e);
+                continue;
+            }
+            println!("- Deleted {} ({} KiB)", path.display(), size / 1024);
+        }
+        deleted += 1;
+        freed += size;
+    }
+
+    let references = prune_references(cache_dir, &track_ids, args.dry_run).await?;
+    println!(
+        "✓ {} {} directories ({} MiB) and {} references to deleted tracks; {} recent \
+         directories kept",
+        if args.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        },
+        deleted,
+        freed / (1024 * 1024),
+        references,
+        recent
+    );
+    Ok(())
+}
+
+fn dir_size(path: &Path) -> u64 {
+    std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Removes ids of tracks no longer in the library from the files that refer to tracks.
+/// Returns how many references were (or, with `dry_run`, would be) removed.
+async fn prune_references(
+    cache_dir: &Path,
+    track_ids: &HashSet<&str>,
+    dry_run: bool,
+) -> Result<usize, Error> {
+    let mut removed = 0;
+
+    let mut collections = load_collections(cache_dir).await?;
+    let before = removed;
+    for collection in collections.values_mut() {
+        let count = collection.track_ids.len();
+        collection
+            .track_ids
+            .retain(|id| track_ids.contains(id.as_str()));
+        removed += count - collection.track_ids.len();
+    }
+    if removed > before && !dry_run {
+        save_collections(cache_dir, &collections).await?;
+    }
+
+    let mut queues = load_queues(cache_dir).await?;
+    let before = removed;
+    for queue in queues.values_mut() {
+        let count = queue.len();
+        queue.retain(|id| track_ids.contains(id.as_str()));
+        removed += count - queue.len();
+    }
+    if removed > before && !dry_run {
+        save_queues(cache_dir, &queues).await?;
+    }
+
+    let mut ratings = load_ratings(cache_dir).await?;
+    let count = ratings.len();
+    ratings.retain(|id, _| track_ids.contains(id.as_str()));
+    if ratings.len() < count {
+        removed += count - ratings.len();
+        if !dry_run {
+            save_ratings(cache_dir, &ratings).await?;
+        }
+    }
+
+    let mut checks = load_source_checks(cache_dir).await?;
+    let count = checks.len();
+    checks.retain(|id, _| track_ids.contains(id.as_str()));
+    if checks.len() < count {
+        removed += count - checks.len();
+        if !dry_run {
+            save_source_checks(cache_dir, &checks).await?;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Replaces the library metadata with a backup's. Tracks whose directory is gone but
+/// which were converted again since the backup keep their current files; the others
+/// are left out. Files the backup doesn't hold (e.g. the history, unless it was
+/// included) are left as they are.
+pub async fn restore(args: RestoreArgs) -> Result<(), Error> {
+    let dirs = args.cache.dirs();
+    let cache_dir = dirs.primary();
+    let (path, snapshot) = Snapshot::read(&args.backup).await?;
+    println!(
+        "Restoring {}, taken {} days ago",
+        path.display(),
+        unix_timestamp().saturating_sub(snapshot.created_at) / SECONDS_PER_DAY
+    );
+    let content = snapshot
+        .files
+        .get(HLS_CACHE_FILE)
+        .ok_or("The backup holds no hls_cache.json")?;
+    let parsed = parse_hls_cache(content, &dirs)
+        .await
+        .map_err(|e| format!("The backup's hls_cache.json is invalid: {}", e))?;
+    let current = load_hls_cache(&dirs).await?;
+
+    let mut restored = parsed.tracks;
+    let (mut reconverted, mut lost, mut new) = (0, 0, 0);
+    let mut missing: Vec<(String, HlsSession)> = parsed.missing.into_iter().collect();
+    missing.sort_by(|(_, a), (_, b)| a.title.cmp(&b.title));
+    for (id, backed_up) in missing {
+        let Some(now) = current.get(&id) else {
+            println!(
+                "✗ '{}': {} is gone, leaving the track out",
+                backed_up.title,
+                backed_up.segments_dir.display()
+            );
+            lost += 1;
+            continue;
+        };
+        println!(
+            "✓ '{}': converted again since the backup, keeping its current files",
+            backed_up.title
+        );
+        restored.insert(
+            id,
+            HlsSession {
+                title: backed_up.title,
+                artist: backed_up.artist,
+                album: backed_up.album,
+                listen_count: backed_up.listen_count,
+                unique_listeners: backed_up.unique_listeners,
+                identification: backed_up.identification,
+                notes: backed_up.notes,
+                date_added: backed_up.date_added,
+                pinned: backed_up.pinned,
+                visibility: backed_up.visibility,
+                ..now.clone()
+            },
+        );
+        reconverted += 1;
+    }
+
+    let mut added: Vec<(&String, &HlsSession)> = current
+        .iter()
+        .filter(|(id, _)| !restored.contains_key(*id))
+        .collect();
+    added.sort_by(|(_, a), (_, b)| a.title.cmp(&b.title));
+    for (id, session) in added {
+        if args.keep_new {
+            println!("+ '{}': added after the backup, keeping it", session.title);
+            restored.insert(id.clone(), session.clone());
+        } else {
+            println!(
+                "- '{}': added after the backup; gc deletes its files",
+                session.title
+            );
+        }
+        new += 1;
+    }
+
+    let files: Vec<(&String, &String)> = snapshot
+        .files
+        .iter()
+        .filter(|(name, _)| *name != HLS_CACHE_FILE && is_safe_path_component(name))
+        .collect();
+    if !args.dry_run {
+        save_hls_cache(cache_dir, &restored).await?;
+        for (name, content) in &files {
+            tokio::fs::write(cache_dir.join(name), content).await?;
+        }
+    }
+
+    let verb = if args.dry_run {
+        "Would restore"
+    } else {
+        "Restored"
+    };
+    println!(
+        "✓ {} {} tracks: {} on their current files, {} left out as their files are gone, \
+         {} added since the backup",
+        verb,
+        restored.len(),
+        reconverted,
+        lost,
+        new
+    );
+    if !files.is_empty() {
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        println!("✓ {} {}", verb, names.join(", "));
+    }
+    Ok(())
+}
+
+/// Updates yt-dlp, which a running server picks up with its next download.
+pub async fn update_ytdlp(args: UpdateYtdlpArgs) -> Result<(), Error> {
+    let update = tools::update_ytdlp(&args.ytdlp_path, &ProcessLimits::default()).await?;
+    if !update.output.is_empty() {
+        println!("{}", update.output);
+    }
+    let version = update.version.as_deref().unwrap_or("unknown");
+    match update.updated {
+        true => println!(
+            "✓ Updated yt-dlp from {} to {}",
+            update.previous_version.as_deref().unwrap_or("unknown"),
+            version
+        ),
+        false => println!("✓ yt-dlp {} is up to date", version),
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! The external programs a track is made with: yt-dlp fetching the source, and
+//! ffmpeg converting it into HLS, analysing it and scaling its artwork.
+//!
+//! Ingesting, retranscoding and artwork uploads reach them through [`MediaTools`],
+//! so the server can run with the fake tools of the `fake-tools` feature, which
+//! make deterministic tracks without running anything.
+
+use crate::analysis::analyze_tempo_key;
+use crate::downloader::{fetch_with_ytdlp, DownloadQueue, DownloadRequest, IngestOptions};
+use crate::storage::{CrossfadeHints, HlsSession, TempoKey};
+use crate::transcode::{
+    analyze_crossfade, create_hls_segments, create_thumbnails, create_video_hls, probe_duration,
+    TranscodeOptions,
+};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::sync::watch;
+
+pub type ToolResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// What the tools return, boxed so [`MediaTools`] can be used as a trait object.
+pub type ToolFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A source to fetch for a download job, whose status gets the transfer progress.
+pub struct Fetch<'a> {
+    pub request: &'a DownloadRequest,
+    /// Where the audio, its info JSON and its thumbnail are written
+    pub download_dir: &'a Path,
+    pub download_queue: &'a DownloadQueue,
+    pub download_id: &'a str,
+    pub options: &'a IngestOptions,
+}
+
+/// A track to be converted into HLS segments under `cache_dir/session_id`.
+pub struct NewTrack<'a> {
+    pub cache_dir: &'a Path,
+    pub session_id: &'a str,
+    pub title: &'a str,
+    pub origin_url: &'a str,
+}
+
+/// Fetches sources and converts them into tracks. `progress`, when given, receives
+/// the percentage of the input converted so far.
+pub trait MediaTools: Send + Sync {
+    /// Downloads a source that isn't a direct link, and returns the audio file.
+    fn fetch<'a>(&'a self, fetch: Fetch<'a>) -> ToolFuture<'a, ToolResult<PathBuf>>;
+
+    /// The length of an audio file in seconds, if it can be told.
+    fn probe_duration<'a>(
+        &'a self,
+        file_path: &'a Path,
+        options: &'a TranscodeOptions,
+    ) -> ToolFuture<'a, Option<f64>>;
+
+    /// Converts `file_path` (audio, or a track's playlist) into HLS segments.
+    fn segment<'a>(
+        &'a self,
+        file_path: &'a Path,
+        track: NewTrack<'a>,
+        options: &'a TranscodeOptions,
+        progress: Option<&'a watch::Sender<f64>>,
+    ) -> ToolFuture<'a, ToolResult<HlsSession>>;
+
+    /// Adds a video rendition next to a track's audio segments, and returns its
+    /// playlist.
+    fn video<'a>(
+        &'a self,
+        file_path: &'a Path,
+        segments_dir: &'a Path,
+        options: &'a TranscodeOptions,
+        progress: Option<&'a watch::Sender<f64>>,
+    ) -> ToolFuture<'a, ToolResult<PathBuf>>;
+
+    /// Scales `image` into thumbnails of every size in `segments_dir`, and returns
+    /// their content hash.
+    fn thumbnails<'a>(
+        &'a self,
+        image: &'a Path,
+        segments_dir: &'a Path,
+        options: &'a TranscodeOptions,
+    ) -> ToolFuture<'a, ToolResult<String>>;
+
+    /// Where the audio can be faded into the next track.
+    fn crossfade<'a>(
+        &'a self,
+        file_path: &'a Path,
+        options: &'a TranscodeOptions,
+    ) -> ToolFuture<'a, ToolResult<CrossfadeHints>>;
+
+    /// Tempo and key; `duration` centers the part of the audio analysed.
+    fn tempo_key<'a>(
+        &'a self,
+        file_path: &'a Path,
+        options: &'a TranscodeOptions,
+        duration: Option<f64>,
+    ) -> ToolFuture<'a, ToolResult<TempoKey>>;
+}
+
+/// yt-dlp and ffmpeg, at the paths the server was started with.
+pub struct SystemTools;
+
+impl MediaTools for SystemTools {
+    fn fetch<'a>(&'a self, fetch: Fetch<'a>) -> ToolFuture<'a, ToolResult<PathBuf>> {
+        Box::pin(fetch_with_ytdlp(fetch))
+    }
+
+    fn probe_duration<'a>(
+        &'a self,
+        file_path: &'a Path,
+        options: &'a TranscodeOptions,
+    ) -> ToolFuture<'a, Option<f64>> {
+        Box::pin(probe_duration(file_path, options))
+    }
+
+    fn segment<'a>(
+        &'a self,
+        file_path: &'a Path,
+        track: NewTrack<'a>,
+        options: &'a TranscodeOptions,
+        progress: Option<&'a watch::Sender<f64>>,
+    ) -> ToolFuture<'a, ToolResult<HlsSession>> {
+        Box::pin(create_hls_segments(
+            file_path,
+            track.cache_dir,
+            track.session_id,
+            track.title,
+            track.origin_url,
+            options,
+            progress,
+        ))
+    }
+
+    fn video<'a>(
+        &'a self,
+        file_path: &'a Path,
+        segments_dir: &'a Path,
+        options: &'a TranscodeOptions,
+        progress: Option<&'a watch::Sender<f64>>,
+    ) -> ToolFuture<'a, ToolResult<PathBuf>> {
+        Box::pin(create_video_hls(file_path, segments_dir, options, progress))
+    }
+
+    fn thumbnails<'a>(
+        &'a self,
+        image: &'a Path,
+        segments_dir: &'a Path,
+        options: &'a TranscodeOptions,
+    ) -> ToolFuture<'a, ToolResult<String>> {
+        Box::pin(create_thumbnails(image, segments_dir, options))
+    }
+
+    fn crossfade<'a>(
+        &'a self,
+        file_path: &'a Path,
+        options: &'a TranscodeOptions,
+    ) -> ToolFuture<'a, ToolResult<CrossfadeHints>> {
+        Box::pin(analyze_crossfade(file_path, options))
+    }
+
+    fn tempo_key<'a>(
+        &'a self,
+        file_path: &'a Path,
+        options: &'a TranscodeOptions,
+        duration: Option<f64>,
+    ) -> ToolFuture<'a, ToolResult<TempoKey>> {
+        Box::pin(analyze_tempo_key(file_path, options, duration))
+    }
+}
@@ -0,0 +1,463 @@
+//! Merging another instance's library into this one, from an archive of its cache
+//! directory or over HTTP, for consolidating servers. Tracks this library already
+//! has are skipped, and reported when the two copies' tags differ.
+
+use crate::federation::{mirror_track, RemoteTrack};
+use crate::library::assign_slug;
+use crate::storage::{
+    generate_url_hash, is_safe_path_component, new_track_id, parse_hls_cache, CacheDirs, HlsCache,
+    HlsSession, Identification, IdentificationStatus,
+};
+use flate2::read::GzDecoder;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Directories archives are unpacked into start with this, next to the tracks.
+const UNPACK_PREFIX: &str = ".merge-";
+
+/// How a track of the other library was recognised as one this library has.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchedBy {
+    OriginUrl,
+    /// The same MusicBrainz recording, as identified by the audio fingerprint
+    Fingerprint,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MergedTrack {
+    /// Id of the track here; the other library's, unless a track here had it
+    pub id: String,
+    /// Id of the track in the other library
+    pub from_id: String,
+    pub title: String,
+}
+
+/// A track both libraries have, tagged differently. This library's tags are kept.
+#[derive(Debug, Serialize)]
+pub struct MergeConflict {
+    pub track_id: String,
+    pub from_id: String,
+    pub matched_by: MatchedBy,
+    /// Per differing tag: this library's value, then the other's
+    pub differences: HashMap<&'static str, [Option<String>; 2]>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MergeFailure {
+    pub from_id: String,
+    pub title: String,
+    pub error: String,
+}
+
+/// What a merge did, or would do with `dry_run`.
+#[derive(Debug, Default, Serialize)]
+pub struct MergeReport {
+    pub dry_run: bool,
+    pub imported: Vec<MergedTrack>,
+    /// Tracks this library already has with the same tags
+    pub duplicates: usize,
+    pub conflicts: Vec<MergeConflict>,
+    pub failed: Vec<MergeFailure>,
+}
+
+/// What a track of the other library is known by.
+struct Candidate<'a> {
+    origin_url: &'a str,
+    origin_hash: String,
+    identification: Option<&'a Identification>,
+    title: &'a str,
+    artist: &'a Option<String>,
+    album: &'a Option<String>,
+}
+
+/// The track of this library that is the other library's `candidate`, if any.
+fn find_existing(
+    cache: &HashMap<String, HlsSession>,
+    candidate: &Candidate,
+) -> Option<(String, MatchedBy)> {
+    let recording = candidate
+        .identification
+        .filter(|identification| identification.status != IdentificationStatus::Rejected)
+        .map(|identification| identification.recording_id.as_str());
+    cache
+        .iter()
+        .filter(|(_, session)| !session.read_only)
+        .find_map(|(id, session)| {
+            if (!candidate.origin_url.is_empty() && session.origin_url == candidate.origin_url)
+                || (!candidate.origin_hash.is_empty()
+                    && session.origin_hash == candidate.origin_hash)
+            {
+                return Some((id.clone(), MatchedBy::OriginUrl));
+            }
+            let theirs = recording?;
+            session
+                .identification
+                .as_ref()
+                .filter(|ours| {
+                    ours.status != IdentificationStatus::Rejected && ours.recording_id == theirs
+                })
+                .map(|_| (id.clone(), MatchedBy::Fingerprint))
+        })
+}
+
+/// Notes the track as a duplicate, or as a conflict when the tags differ.
+fn record_existing(
+    report: &mut MergeReport,
+    session: &HlsSession,
+    track_id: String,
+    from_id: &str,
+    matched_by: MatchedBy,
+    candidate: &Candidate,
+) {
+    let mut differences = HashMap::new();
+    if session.title != candidate.title {
+        differences.insert(
+            "title",
+            [
+                Some(session.title.clone()),
+                Some(candidate.title.to_string()),
+            ],
+        );
+    }
+    if session.artist != *candidate.artist {
+        differences.insert("artist", [session.artist.clone(), candidate.artist.clone()]);
+    }
+    if session.album != *candidate.album {
+        differences.insert("album", [session.album.clone(), candidate.album.clone()]);
+    }
+    if differences.is_empty() {
+        report.duplicates += 1;
+    } else {
+        report.conflicts.push(MergeConflict {
+            track_id,
+            from_id: from_id.to_string(),
+            matched_by,
+            differences,
+        });
+    }
+}
+
+/// Adds a merged track under the other library's id, or a new one when a track
+/// here has it. Returns the id.
+fn insert_merged(
+    cache: &mut HashMap<String, HlsSession>,
+    from_id: &str,
+    mut session: HlsSession,
+) -> String {
+    let id = if cache.contains_key(from_id) || !is_safe_path_component(from_id) {
+        new_track_id()
+    } else {
+        from_id.to_string()
+    };
+    // The other library's slug, unless a track here has it
+    if cache.values().any(|other| other.slug == session.slug) {
+        session.slug.clear();
+    }
+    assign_slug(cache, &mut session);
+    cache.insert(id.clone(), session);
+    id
+}
+
+/// Merges the library in a tar archive (optionally gzipped) of another instance's
+/// cache directory. The archive is unpacked next to the tracks, so adding them only
+/// moves their directories.
+pub async fn merge_archive(
+    archive: &Path,
+    cache_dirs: &CacheDirs,
+    hls_cache: &HlsCache,
+    dry_run: bool,
+) -> Result<MergeReport, Error> {
+    let target = cache_dirs.for_new_track().to_path_buf();
+    let unpack_dir = target.join(format!("{}{}", UNPACK_PREFIX, uuid::Uuid::new_v4()));
+    let result = merge_unpacked(archive, &unpack_dir, &target, hls_cache, dry_run).await;
+    let _ = tokio::fs::remove_dir_all(&unpack_dir).await;
+    result
+}
+
+async fn merge_unpacked(
+    archive: &Path,
+    unpack_dir: &Path,
+    target: &Path,
+    hls_cache: &HlsCache,
+    dry_run: bool,
+) -> Result<MergeReport, Error> {
+    {
+        let archive = archive.to_path_buf();
+        let unpack_dir = unpack_dir.to_path_buf();
+        tokio::task::spawn_blocking(move || unpack(&archive, &unpack_dir)).await??;
+    }
+    let root = library_root(unpack_dir)
+        .await
+        .ok_or("The archive has no hls_cache.json")?;
+    let content = tokio::fs::read_to_string(root.join("hls_cache.json")).await?;
+    // The recorded paths are the other instance's, so its tracks are found by name
+    let parsed = parse_hls_cache(&content, &CacheDirs::new(root.clone(), Vec::new())).await?;
+
+    let mut report = MergeReport {
+        dry_run,
+        ..Default::default()
+    };
+    // A path recorded by the other instance may exist here too, e.g. when both ran
+    // on this machine; only what came out of the archive is merged
+    let mut tracks = Vec::new();
+    for (from_id, mut session) in parsed.tracks.into_iter().chain(parsed.missing) {
+        let found = session.segments_dir.file_name().map(|name| root.join(name));
+        match (found, session.playlist_path.file_name()) {
+            (Some(dir), Some(playlist)) if dir.join(playlist).is_file() => {
+                session.playlist_path = dir.join(playlist);
+                session.segments_dir = dir;
+                tracks.push((from_id, session));
+            }
+            _ => report.failed.push(MergeFailure {
+                from_id,
+                title: session.title,
+                error: "Not in the archive".to_string(),
+            }),
+        }
+    }
+
+    tracks.sort_by_key(|(_, session)| session.date_added);
+    for (from_id, mut session) in tracks {
+        let candidate = Candidate {
+            origin_url: &session.origin_url,
+            origin_hash: session.origin_hash.clone(),
+            identification: session.identification.as_ref(),
+            title: &session.title,
+            artist: &session.artist,
+            album: &session.album,
+        };
+        {
+            let cache = hls_cache.read().await;
+            if let Some((track_id, matched_by)) = find_existing(&cache, &candidate) {
+                let ours = &cache[&track_id];
+                record_existing(
+                    &mut report,
+                    ours,
+                    track_id,
+                    &from_id,
+                    matched_by,
+                    &candidate,
+                );
+                continue;
+            }
+        }
+        if dry_run {
+            report.imported.push(MergedTrack {
+                id: from_id.clone(),
+                from_id,
+                title: session.title,
+            });
+            continue;
+        }
+
+        // The directory is named by the session id, which must be free here
+        let session_id = match target.join(&session.id).exists() {
+            true => uuid::Uuid::new_v4().to_string(),
+            false => session.id.clone(),
+        };
+        let segments_dir = target.join(&session_id);
+        if let Err(e) = tokio::fs::rename(&session.segments_dir, &segments_dir).await {
+            report.failed.push(MergeFailure {
+                from_id,
+                title: session.title,
+                error: format!("Failed to move the track's files: {}", e),
+            });
+            continue;
+        }
+        session.playlist_path = segments_dir.join(
+            session
+                .playlist_path
+                .file_name()
+                .unwrap_or("playlist.m3u8".as_ref()),
+        );
+        session.id = session_id;
+        session.segments_dir = segments_dir;
+        session.read_only = false;
+
+        let title = session.title.clone();
+        let id = insert_merged(&mut *hls_cache.write().await, &from_id, session);
+        report.imported.push(MergedTrack { id, from_id, title });
+    }
+    if !report.imported.is_empty() && !dry_run {
+        hls_cache.changed();
+    }
+    Ok(report)
+}
+
+fn unpack(archive: &Path, dir: &Path) -> Result<(), Error> {
+    let mut file = std::fs::File::open(archive)?;
+    let mut magic = [0u8; 2];
+    let gzipped = file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let file = std::fs::File::open(archive)?;
+    let reader: Box<dyn Read> = match gzipped {
+        true => Box::new(GzDecoder::new(file)),
+        false => Box::new(file),
+    };
+    std::fs::create_dir_all(dir)?;
+    let mut archive = tar::Archive::new(reader);
+    let entries = archive
+        .entries()
+        .map_err(|e| format!("Invalid archive: {}", e))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| format!("Invalid archive: {}", e))?;
+        // Links could point anywhere once moved into the library; entries reaching
+        // outside `dir` are skipped by unpack_in
+        if entry.header().entry_type().is_file() || entry.header().entry_type().is_dir() {
+            entry.unpack_in(dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// The unpacked cache directory: the archive's root, or its only directory when
+/// it was made from the directory's parent.
+async fn library_root(dir: &Path) -> Option<PathBuf> {
+    if dir.join("hls_cache.json").is_file() {
+        return Some(dir.to_path_buf());
+    }
+    let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+    let mut found = None;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.path().join("hls_cache.json").is_file() {
+            if found.is_some() {
+                return None;
+            }
+            found = Some(entry.path());
+        }
+    }
+    found
+}
+
+async fn fetch_identification(
+    client: &reqwest::Client,
+    base_url: &str,
+    track_id: &str,
+) -> Option<Identification> {
+    client
+        .get(format!(
+            "{}/api/tracks/{}/identification",
+            base_url, track_id
+        ))
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?
+        .json()
+        .await
+        .ok()
+}
+
+/// Merges the library of the instance at `base_url`, copying the tracks this
+/// library doesn't have like a mirror would.
+pub async fn merge_remote(
+    base_url: &str,
+    cache_dirs: &CacheDirs,
+    hls_cache: &HlsCache,
+    dry_run: bool,
+) -> Result<MergeReport, Error> {
+    let client = reqwest::Client::new();
+    let base_url = base_url.trim_end_matches('/');
+    let tracks: Vec<RemoteTrack> = client
+        .get(format!("{}/api/tracks", base_url))
+        .timeout(Duration::from_secs(60))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut report = MergeReport {
+        dry_run,
+        ..Default::default()
+    };
+    for track in tracks {
+        // The track list only has the identification's status
+        let identification = match track.identification {
+            Some(status) if status != IdentificationStatus::Rejected => {
+                fetch_identification(&client, base_url, &track.id).await
+            }
+            _ => None,
+        };
+        let candidate = Candidate {
+            origin_url: &track.origin_url,
+            origin_hash: match track.origin_url.is_empty() {
+                true => String::new(),
+                false => generate_url_hash(&track.origin_url),
+            },
+            identification: identification.as_ref(),
+            title: &track.title,
+            artist: &track.artist,
+            album: &track.album,
+        };
+        {
+            let cache = hls_cache.read().await;
+            if let Some((track_id, matched_by)) = find_existing(&cache, &candidate) {
+                let ours = &cache[&track_id];
+                record_existing(
+                    &mut report,
+                    ours,
+                    track_id,
+                    &track.id,
+                    matched_by,
+                    &candidate,
+                );
+                continue;
+            }
+        }
+        // Keys stay with the other instance
+        if track.encrypted {
+            report.failed.push(MergeFailure {
+                from_id: track.id,
+                title: track.title,
+                error: "Encrypted; its key can't be copied over HTTP".to_string(),
+            });
+            continue;
+        }
+        if dry_run {
+            report.imported.push(MergedTrack {
+                id: track.id.clone(),
+                from_id: track.id,
+                title: track.title,
+            });
+            continue;
+        }
+
+        let track_dir = cache_dirs.for_new_track();
+        if track_dir.join(&track.session_id).exists() {
+            report.failed.push(MergeFailure {
+                from_id: track.id,
+                title: track.title,
+                error: format!("Session id {} is used by a track here", track.session_id),
+            });
+            continue;
+        }
+        match mirror_track(&client, base_url, track_dir, &track).await {
+            Ok(mut session) => {
+                session.identification = identification;
+                let id = insert_merged(&mut *hls_cache.write().await, &track.id, session);
+                hls_cache.changed();
+                report.imported.push(MergedTrack {
+                    id,
+                    from_id: track.id,
+                    title: track.title,
+                });
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_dir_all(track_dir.join(&track.session_id)).await;
+                report.failed.push(MergeFailure {
+                    from_id: track.id,
+                    title: track.title,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+    Ok(report)
+}
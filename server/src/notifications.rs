@@ -0,0 +1,169 @@
+//! Push notifications to a phone through ntfy or Gotify, for the webhook events
+//! picked with --notify-event.
+
+use crate::config::{Config, NotifyEvent};
+use clap::ValueEnum;
+use std::time::Duration;
+
+enum Sink {
+    /// A topic URL such as https://ntfy.sh/my-music-lib
+    Ntfy { url: String, token: Option<String> },
+    /// A Gotify server and an application token
+    Gotify { url: String, token: String },
+}
+
+impl Sink {
+    fn name(&self) -> &'static str {
+        match self {
+            Sink::Ntfy { .. } => "ntfy",
+            Sink::Gotify { .. } => "Gotify",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Notifications {
+    sinks: Vec<Sink>,
+    events: Vec<NotifyEvent>,
+    client: reqwest::Client,
+}
+
+impl Notifications {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let mut sinks = Vec::new();
+        if let Some(url) = &config.ntfy_url {
+            sinks.push(Sink::Ntfy {
+                url: url.clone(),
+                token: config.ntfy_token.clone(),
+            });
+        }
+        if let Some(url) = &config.gotify_url {
+            let Some(token) = config.gotify_token.clone() else {
+                return Err("--gotify-url needs --gotify-token".to_string());
+            };
+            sinks.push(Sink::Gotify {
+                url: url.trim_end_matches('/').to_string(),
+                token,
+            });
+        }
+        Ok(Notifications {
+            sinks,
+            events: config.notify_events.clone(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Which events go where, e.g. "download_failed, batch_finished to ntfy and
+    /// Gotify"; `None` when there is nowhere to push to.
+    pub fn describe(&self) -> Option<String> {
+        if self.sinks.is_empty() {
+            return None;
+        }
+        let events: Vec<String> = self
+            .events
+            .iter()
+            .filter_map(|event| event.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        let sinks: Vec<&str> = self.sinks.iter().map(Sink::name).collect();
+        Some(format!("{} to {}", events.join(", "), sinks.join(" and ")))
+    }
+
+    /// Pushes the event to every sink in the background, if it is one of the chosen
+    /// events.
+    pub fn send(&self, event: &str, data: &serde_json::Value) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let Ok(event) = NotifyEvent::from_str(event, false) else {
+            return;
+        };
+        if !self.events.contains(&event) {
+            return;
+        }
+
+        let (title, message, urgent) = compose(event, data);
+        for sink in &self.sinks {
+            let request = match sink {
+                Sink::Ntfy { url, token } => {
+                    let mut request = self
+                        .client
+                        .post(url)
+                        .header("Title", title.clone())
+                        .header("Priority", if urgent { "high" } else { "default" })
+                        .header("Tags", if urgent { "warning" } else { "musical_note" })
+                        .body(message.clone());
+                    if let Some(token) = token {
+                        request = request.bearer_auth(token);
+                    }
+                    request
+                }
+                Sink::Gotify { url, token } => self
+                    .client
+                    .post(format!("{}/message", url))
+                    .header("X-Gotify-Key", token)
+                    .json(&serde_json::json!({
+                        "title": title,
+                        "message": message,
+                        "priority": if urgent { 8 } else { 5 },
+                    })),
+            };
+
+            let name = sink.name();
+            tokio::spawn(async move {
+                let result = request
+                    .timeout(Duration::from_secs(10))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(e) = result {
+                    eprintln!("Warning: {} notification failed: {}", name, e);
+                }
+            });
+        }
+    }
+}
+
+/// Title, message and whether the event needs attention.
+fn compose(event: NotifyEvent, data: &serde_json::Value) -> (String, String, bool) {
+    let field = |name: &str| data[name].as_str().unwrap_or_default().to_string();
+    let track = || match data["artist"].as_str() {
+        Some(artist) => format!("{} - {}", artist, field("title")),
+        None => field("title"),
+    };
+    match event {
+        NotifyEvent::TrackAdded => ("Track added".to_string(), track(), false),
+        NotifyEvent::TrackRefreshed => ("Track refreshed".to_string(), track(), false),
+        NotifyEvent::TrackDeleted => ("Track deleted".to_string(), field("title"), false),
+        NotifyEvent::SourceUnavailable => (
+            "Source taken down".to_string(),
+            format!("{}: {}", field("title"), field("error")),
+            true,
+        ),
+        NotifyEvent::DownloadFailed => (
+            "Download failed".to_string(),
+            format!("{}\n{}", field("url"), field("error")),
+            true,
+        ),
+        NotifyEvent::QuotaExceeded => (
+            "Library full".to_string(),
+            format!(
+                "Refused {}: the library holds its {} tracks",
+                field("url"),
+                data["max_tracks"]
+            ),
+            true,
+        ),
+        NotifyEvent::BatchFinished => {
+            let failed = data["failed"].as_u64().unwrap_or_default();
+            (
+                "Batch finished".to_string(),
+                format!(
+                    "{} of {} downloads ready, {} failed",
+                    data["ready"], data["total"], failed
+                ),
+                failed > 0,
+            )
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Listening party rooms with host-controlled, synchronized playback.
+
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+
+/// Rooms open at once; each holds a broadcast channel, so anyone could otherwise
+/// open them until the server runs out of memory.
+pub(crate) const MAX_ROOMS: usize = 100;
+
+/// How long a room nobody is in stays open after it was created or last changed.
+const IDLE_ROOM_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PartyState {
+    pub(crate) queue: Vec<String>,
+    pub(crate) track_id: Option<String>,
+    pub(crate) position: f64,
+    pub(crate) playing: bool,
+}
+
+pub(crate) struct PartyRoom {
+    pub(crate) host_token: String,
+    pub(crate) state: PartyState,
+    pub(crate) updated_at: Instant,
+    pub(crate) events: broadcast::Sender<String>,
+    pub(crate) listeners: usize,
+}
+
+impl PartyRoom {
+    /// Whether `token` is the room's host token. Both sides are hashed first so the
+    /// comparison takes the same time however much of the token matches.
+    pub(crate) fn is_host(&self, token: Option<&str>) -> bool {
+        token.is_some_and(|token| {
+            Sha256::digest(self.host_token.as_bytes()) == Sha256::digest(token.as_bytes())
+        })
+    }
+
+    /// Position extrapolated to now, so late joiners start in sync.
+    pub(crate) fn current_position(&self) -> f64 {
+        if self.state.playing {
+            self.state.position + self.updated_at.elapsed().as_secs_f64()
+        } else {
+            self.state.position
+        }
+    }
+
+    pub(crate) fn snapshot(&self, event: &str) -> String {
+        serde_json::json!({
+            "type": "state",
+            "event": event,
+            "queue": self.state.queue,
+            "track_id": self.state.track_id,
+            "position": self.current_position(),
+            "playing": self.state.playing,
+            "listeners": self.listeners,
+        })
+        .to_string()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum PartyCommand {
+    Play,
+    Pause,
+    Seek { position: f64 },
+    Track { track_id: String },
+    Queue { track_ids: Vec<String> },
+    Next,
+}
+
+pub(crate) type PartyRooms = Arc<RwLock<HashMap<String, PartyRoom>>>;
+
+/// Closes the rooms nobody joined, or was left in, within `IDLE_ROOM_TTL`.
+pub(crate) fn expire_idle_rooms(rooms: &mut HashMap<String, PartyRoom>) {
+    rooms.retain(|_, room| room.listeners > 0 || room.updated_at.elapsed() < IDLE_ROOM_TTL);
+}
+
+pub(crate) fn apply_party_command(room: &mut PartyRoom, command: PartyCommand) -> &'static str {
+    room.state.position = room.current_position();
+    room.updated_at = Instant::now();
+
+    match command {
+        PartyCommand::Play => {
+            room.state.playing = true;
+            "play"
+        }
+        PartyCommand::Pause => {
+            room.state.playing = false;
+            "pause"
+        }
+        PartyCommand::Seek { position } => {
+            room.state.position = position.max(0.0);
+            "seek"
+        }
+        PartyCommand::Track { track_id } => {
+            room.state.track_id = Some(track_id);
+            room.state.position = 0.0;
+            "track"
+        }
+        PartyCommand::Queue { track_ids } => {
+            room.state.queue = track_ids;
+            "queue"
+        }
+        PartyCommand::Next => {
+            let next = room
+                .state
+                .track_id
+                .as_ref()
+                .and_then(|current| room.state.queue.iter().position(|id| id == current))
+                .map_or(0, |index| index + 1);
+            room.state.track_id = room.state.queue.get(next).cloned();
+            room.state.position = 0.0;
+            room.state.playing = room.state.track_id.is_some();
+            "track"
+        }
+    }
+}
+
+pub(crate) async fn handle_party_socket(
+    ws: WebSocket,
+    rooms: PartyRooms,
+    room_id: String,
+    is_host: bool,
+) {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+
+    let (mut events, initial) = {
+        let mut rooms = rooms.write().await;
+        let Some(room) = rooms.get_mut(&room_id) else {
+            return;
+        };
+        room.listeners += 1;
+        let initial = room.snapshot("join");
+        let _ = room.events.send(initial.clone());
+        (room.events.subscribe(), initial)
+    };
+
+    if ws_tx.send(Message::text(initial)).await.is_ok() {
+        loop {
+            tokio::select! {
+                incoming = ws_rx.next() => {
+                    let text = match incoming {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    };
+
+                    if !is_host {
+                        let error = serde_json::json!({
+                            "type": "error",
+                            "message": "Only the host can control playback"
+                        });
+                        if ws_tx.send(Message::text(error.to_string())).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+
+                    match serde_json::from_str::<PartyCommand>(text.as_str()) {
+                        Ok(command) => {
+                            let mut rooms = rooms.write().await;
+                            if let Some(room) = rooms.get_mut(&room_id) {
+                                let event = apply_party_command(room, command);
+                                let _ = room.events.send(room.snapshot(event));
+                            }
+                        }
+                        Err(e) => {
+                            let error = serde_json::json!({
+                                "type": "error",
+                                "message": format!("Invalid command: {}", e)
+                            });
+                            if ws_tx.send(Message::text(error.to_string())).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                event = events.recv() => {
+                    match event {
+                        Ok(event) => {
+                            if ws_tx.send(Message::text(event)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            }
+        }
+    }
+
+    // Drop the room once the last listener has left
+    let mut rooms = rooms.write().await;
+    if let Some(room) = rooms.get_mut(&room_id) {
+        room.listeners = room.listeners.saturating_sub(1);
+        if room.listeners == 0 {
+            rooms.remove(&room_id);
+        } else {
+            let _ = room.events.send(room.snapshot("leave"));
+        }
+    }
+}
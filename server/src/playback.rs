@@ -0,0 +1,88 @@
+//! Per-device playback state: queues, now-playing reports and resume positions.
+
+use crate::library::{track_info, TrackInfo};
+use crate::storage::{save_positions, HlsSession, ResumePositions};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+pub(crate) struct NowPlaying {
+    pub(crate) track_id: String,
+    pub(crate) position: f64,
+    pub(crate) playing: bool,
+    pub(crate) updated_at: Instant,
+}
+
+pub(crate) type NowPlayingMap = Arc<RwLock<HashMap<String, NowPlaying>>>;
+
+/// Playback reports older than this are no longer considered active.
+pub(crate) const NOW_PLAYING_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Position reports this close to the end mark the track as finished.
+pub(crate) const RESUME_END_MARGIN: f64 = 10.0;
+
+pub(crate) enum QueueOp {
+    Append(String),
+    InsertNext(String),
+    Remove(usize),
+    Move { from: usize, to: usize },
+    Clear,
+}
+
+/// Queues are kept per device; clients that don't send `X-Device-Id` share one.
+pub(crate) fn device_key(device_id: Option<String>) -> String {
+    device_id
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| "default".to_string())
+}
+
+/// A device's queue. `tracks` only lists those `visible` lets the requester see;
+/// `track_ids` keeps every entry, so positions in the queue stay valid.
+pub(crate) fn queue_response(
+    device: &str,
+    track_ids: &[String],
+    cache: &HashMap<String, HlsSession>,
+    visible: impl Fn(&HlsSession) -> bool,
+) -> serde_json::Value {
+    let tracks: Vec<TrackInfo> = track_ids
+        .iter()
+        .filter_map(|id| {
+            cache
+                .get(id)
+                .filter(|session| visible(session))
+                .map(|session| track_info(id, session))
+        })
+        .collect();
+
+    serde_json::json!({
+        "device": device,
+        "track_ids": track_ids,
+        "tracks": tracks,
+    })
+}
+
+/// Records where a device stopped in a track. Positions near the end clear
+/// the entry so finished tracks start from the beginning next time.
+pub(crate) async fn record_position(
+    positions: &ResumePositions,
+    cache_dir: &Path,
+    device: &str,
+    track_id: &str,
+    position: f64,
+    duration: f64,
+) {
+    let mut positions = positions.write().await;
+    let device_positions = positions.entry(device.to_string()).or_default();
+    if position <= 0.0 || position >= duration - RESUME_END_MARGIN {
+        device_positions.remove(track_id);
+    } else {
+        device_positions.insert(track_id.to_string(), position);
+    }
+
+    if let Err(e) = save_positions(cache_dir, &positions).await {
+        eprintln!("Warning: Failed to save resume positions: {}", e);
+    }
+}
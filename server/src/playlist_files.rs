@@ -0,0 +1,174 @@
+//! Playlist files for and from other players: M3U (plain or extended), PLS and XSPF.
+
+use std::collections::BTreeMap;
+
+/// One entry of a playlist file: a local path, a `file://` URL or a remote URL.
+#[derive(Debug, Clone, Default)]
+pub struct PlaylistEntry {
+    pub location: String,
+    /// Display title from `#EXTINF` or `TitleN`, often "Artist - Title"
+    pub title: Option<String>,
+    /// Length in seconds, when the playlist gives one
+    pub duration: Option<f64>,
+    /// Artist; only written, M3U and PLS keep it in the title
+    pub creator: Option<String>,
+    pub album: Option<String>,
+    /// Artwork URL
+    pub image: Option<String>,
+}
+
+/// Reads an M3U or PLS playlist; PLS files are told apart by their `[playlist]` header.
+pub fn parse_playlist(content: &str) -> Vec<PlaylistEntry> {
+    let content = content.trim_start_matches('\u{feff}');
+    let is_pls = content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .is_some_and(|line| line.eq_ignore_ascii_case("[playlist]"));
+    if is_pls {
+        parse_pls(content)
+    } else {
+        parse_m3u(content)
+    }
+}
+
+fn parse_m3u(content: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let (mut title, mut duration) = (None, None);
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+        // "#EXTINF:<seconds>[ attributes],<title>" describes the next entry
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            let (head, name) = info.split_once(',').unwrap_or((info, ""));
+            duration = parse_length(head.split_whitespace().next().unwrap_or_default());
+            title = Some(name.trim().to_string()).filter(|name| !name.is_empty());
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        entries.push(PlaylistEntry {
+            location: line.to_string(),
+            title: title.take(),
+            duration: duration.take(),
+            ..PlaylistEntry::default()
+        });
+    }
+    entries
+}
+
+fn parse_pls(content: &str) -> Vec<PlaylistEntry> {
+    // Keys are numbered ("File1", "Title1", ...) and need not come in order
+    let mut numbered: BTreeMap<u32, PlaylistEntry> = BTreeMap::new();
+    for line in content.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let Some(digits) = key.find(|c: char| c.is_ascii_digit()) else {
+            continue;
+        };
+        let Ok(number) = key[digits..].parse::<u32>() else {
+            continue;
+        };
+        let entry = numbered.entry(number).or_default();
+        let value = value.trim();
+        match &key[..digits] {
+            "file" => entry.location = value.to_string(),
+            "title" => entry.title = Some(value.to_string()).filter(|title| !title.is_empty()),
+            "length" => entry.duration = parse_length(value),
+            _ => {}
+        }
+    }
+    // A title or length without a file is no entry
+    numbered
+        .into_values()
+        .filter(|entry| !entry.location.is_empty())
+        .collect()
+}
+
+/// Both formats write -1 for an unknown length.
+fn parse_length(value: &str) -> Option<f64> {
+    value.parse::<f64>().ok().filter(|seconds| *seconds > 0.0)
+}
+
+/// Writes an extended M3U playlist, with entries titled "Artist - Title". `#EXTINF`
+/// lengths are whole seconds, -1 when unknown.
+pub fn write_m3u(entries: &[PlaylistEntry]) -> String {
+    let mut playlist = String::from("#EXTM3U\n");
+    for entry in entries {
+        let title = entry.title.as_deref().unwrap_or_default();
+        let title = match &entry.creator {
+            Some(creator) => format!("{} - {}", creator, title),
+            None => title.to_string(),
+        }
+        .replace(['\r', '\n'], " ");
+        let length = entry
+            .duration
+            .map_or(-1, |duration| duration.round() as i64);
+        playlist.push_str(&format!(
+            "#EXTINF:{},{}\n{}\n",
+            length, title, entry.location
+        ));
+    }
+    playlist
+}
+
+/// Writes an XSPF playlist named `title`; durations are in milliseconds there.
+pub fn write_xspf(title: &str, entries: &[PlaylistEntry]) -> String {
+    let mut playlist = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    playlist.push_str(&format!("  <title>{}</title>\n", escape_xml(title)));
+    playlist.push_str("  <trackList>\n");
+    for entry in entries {
+        playlist.push_str("    <track>\n");
+        let mut element = |name: &str, value: &str| {
+            playlist.push_str(&format!(
+                "      <{name}>{}</{name}>\n",
+                escape_xml(value),
+                name = name
+            ));
+        };
+        element("location", &entry.location);
+        if let Some(title) = &entry.title {
+            element("title", title);
+        }
+        if let Some(creator) = &entry.creator {
+            element("creator", creator);
+        }
+        if let Some(album) = &entry.album {
+            element("album", album);
+        }
+        if let Some(duration) = entry.duration {
+            element(
+                "duration",
+                &((duration * 1000.0).round() as u64).to_string(),
+            );
+        }
+        if let Some(image) = &entry.image {
+            element("image", image);
+        }
+        playlist.push_str("    </track>\n");
+    }
+    playlist.push_str("  </trackList>\n</playlist>\n");
+    playlist
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
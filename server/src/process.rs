@@ -0,0 +1,248 @@
+//! Supervision of the programs the server runs (ffmpeg, yt-dlp, fpcalc and hooks).
+//! Each starts in a process group of its own, so a timeout or a dropped request kills
+//! it together with whatever it started, such as the ffmpeg yt-dlp converts with, and
+//! under the configured resource limits.
+
+use crate::config::Config;
+use std::ffi::CString;
+use std::future::Future;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, Command};
+
+/// File of a cgroup v2 directory that moves the process writing to it into the group.
+pub(crate) const CGROUP_PROCS: &str = "cgroup.procs";
+
+/// Sets a resource limit of the calling process, returning the error from the
+/// enclosing function. A macro, as glibc and musl type the resource differently.
+macro_rules! set_rlimit {
+    ($resource:expr, $limit:expr) => {{
+        let limit = libc::rlimit {
+            rlim_cur: $limit,
+            rlim_max: $limit,
+        };
+        if libc::setrlimit($resource, &limit) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }};
+}
+
+/// What the programs the server runs may use.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessLimits {
+    /// Wall-clock time a process may run before its group is killed
+    pub timeout: Option<Duration>,
+    /// Address space of each process in bytes, which is well above the memory it
+    /// actually uses
+    pub memory: Option<u64>,
+    /// CPU time of each process in seconds
+    pub cpu_time: Option<u64>,
+    /// cgroup v2 directory every process is started in, whose controllers
+    /// (`memory.max`, `cpu.max`, `pids.max`) limit them together
+    pub cgroup: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    Io(io::Error),
+    /// Killed after running for this long
+    TimedOut(Duration),
+}
+
+impl std::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessError::Io(e) => write!(f, "{}", e),
+            ProcessError::TimedOut(timeout) => {
+                write!(f, "timed out after {} seconds", timeout.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+impl From<io::Error> for ProcessError {
+    fn from(e: io::Error) -> Self {
+        ProcessError::Io(e)
+    }
+}
+
+impl ProcessLimits {
+    pub fn from_config(config: &Config) -> Self {
+        ProcessLimits {
+            timeout: Some(config.process_timeout)
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            memory: config.process_memory,
+            cpu_time: config.process_cpu_time,
+            cgroup: config.process_cgroup.clone(),
+        }
+    }
+
+    /// These limits with the timeout lowered to `timeout`, if that's shorter.
+    pub fn at_most(&self, timeout: Option<Duration>) -> ProcessLimits {
+        let timeout = match (self.timeout, timeout) {
+            (Some(limit), Some(timeout)) => Some(limit.min(timeout)),
+            (limit, timeout) => limit.or(timeout),
+        };
+        ProcessLimits {
+            timeout,
+            ..self.clone()
+        }
+    }
+
+    /// These limits without the timeout, for processes meant to run as long as
+    /// they're needed.
+    pub fn without_timeout(&self) -> ProcessLimits {
+        ProcessLimits {
+            timeout: None,
+            ..self.clone()
+        }
+    }
+
+    /// Starts `command` in a new process group, under the resource limits.
+    pub fn spawn(&self, command: &mut Command) -> io::Result<Supervised> {
+        let (memory, cpu_time) = (self.memory, self.cpu_time);
+        let cgroup_procs = match &self.cgroup {
+            Some(cgroup) => Some(
+                CString::new(cgroup.join(CGROUP_PROCS).as_os_str().as_bytes())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            ),
+            None => None,
+        };
+
+        command.process_group(0).kill_on_drop(true);
+        if memory.is_some() || cpu_time.is_some() || cgroup_procs.is_some() {
+            // Only async-signal-safe calls between fork and exec, so everything is
+            // prepared above
+            unsafe {
+                command.pre_exec(move || {
+                    if let Some(limit) = memory {
+                        set_rlimit!(libc::RLIMIT_AS, limit);
+                    }
+                    if let Some(limit) = cpu_time {
+                        set_rlimit!(libc::RLIMIT_CPU, limit);
+                    }
+                    if let Some(path) = &cgroup_procs {
+                        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                        if fd < 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                        libc::close(fd);
+                        if written != 1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        let child = command.spawn()?;
+        Ok(Supervised {
+            group: child.id().map(|pid| pid as libc::pid_t),
+            child,
+            timeout: self.timeout,
+        })
+    }
+
+    /// Runs `command` to completion and collects its output, like `Command::output`.
+    pub async fn output(&self, command: &mut Command) -> Result<Output, ProcessError> {
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = self.spawn(command)?;
+        let mut stdout = child.stdout.take();
+        let mut stderr = child.stderr.take();
+        let timeout = child.timeout();
+        within(timeout, async {
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            let read_out = async {
+                match stdout.as_mut() {
+                    Some(stdout) => stdout.read_to_end(&mut out).await.map(drop),
+                    None => Ok(()),
+                }
+            };
+            let read_err = async {
+                match stderr.as_mut() {
+                    Some(stderr) => stderr.read_to_end(&mut err).await.map(drop),
+                    None => Ok(()),
+                }
+            };
+            let (status, _, _) = tokio::try_join!(child.wait(), read_out, read_err)?;
+            Ok(Output {
+                status,
+                stdout: out,
+                stderr: err,
+            })
+        })
+        .await?
+    }
+}
+
+/// Runs `work`, which reads from and waits for a supervised process, for at most
+/// `timeout`. The process group is killed when the `Supervised` is dropped.
+pub async fn within<T>(
+    timeout: Option<Duration>,
+    work: impl Future<Output = T>,
+) -> Result<T, ProcessError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, work)
+            .await
+            .map_err(|_| ProcessError::TimedOut(timeout)),
+        None => Ok(work.await),
+    }
+}
+
+/// A process started by `ProcessLimits::spawn`. Dropping it kills the process and
+/// everything it started.
+pub struct Supervised {
+    child: Child,
+    group: Option<libc::pid_t>,
+    timeout: Option<Duration>,
+}
+
+impl Supervised {
+    /// How long the process may run, for `within`.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Kills the process group now.
+    pub fn kill_group(&mut self) {
+        if let Some(group) = self.group.take() {
+            unsafe {
+                libc::kill(-group, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+impl Deref for Supervised {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for Supervised {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Drop for Supervised {
+    fn drop(&mut self) {
+        // Also after a clean exit: what the process left running in its group goes too
+        self.kill_group();
+    }
+}
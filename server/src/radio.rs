@@ -0,0 +1,160 @@
+//! Continuous MP3 radio stream of the library.
+
+use crate::config::RadioOrder;
+use crate::process::ProcessLimits;
+use crate::storage::{HlsCache, HlsSession, Visibility};
+use axum::body::{Body, Bytes};
+use axum::http::Response;
+use rand::seq::SliceRandom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::sync::{broadcast, RwLock};
+
+pub(crate) struct Radio {
+    pub(crate) chunks: broadcast::Sender<Bytes>,
+    pub(crate) on_air: RwLock<Option<String>>,
+    /// The ffmpeg binary tracks are re-encoded with
+    pub(crate) ffmpeg_path: PathBuf,
+    /// Resource limits ffmpeg runs under; it streams in real time, so without a
+    /// timeout
+    pub(crate) limits: ProcessLimits,
+}
+
+/// Plays the library into the radio broadcast channel, one ffmpeg process per track.
+/// Idles while nobody is tuned in. Anyone may listen, so with `public_only` private
+/// tracks are skipped.
+pub(crate) async fn run_radio(
+    hls_cache: HlsCache,
+    radio: Arc<Radio>,
+    order: RadioOrder,
+    public_only: bool,
+) {
+    let on_air = |session: &HlsSession| !public_only || session.visibility == Visibility::Public;
+    loop {
+        if radio.chunks.receiver_count() == 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+
+        let mut playlist: Vec<(String, String)> = {
+            let cache = hls_cache.read().await;
+            cache
+                .iter()
+                .filter(|(_, session)| on_air(session))
+                .map(|(hash, session)| (hash.clone(), session.title.clone()))
+                .collect()
+        };
+        if playlist.is_empty() {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        match order {
+            RadioOrder::Shuffle => playlist.shuffle(&mut rand::thread_rng()),
+            RadioOrder::Sequential => playlist.sort_by(|a, b| a.1.cmp(&b.1)),
+        }
+
+        for (file_hash, _) in playlist {
+            if radio.chunks.receiver_count() == 0 {
+                break;
+            }
+
+            let session = {
+                let cache = hls_cache.read().await;
+                cache
+                    .get(&file_hash)
+                    .filter(|session| on_air(session))
+                    .cloned()
+            };
+            let Some(session) = session else {
+                continue;
+            };
+
+            *radio.on_air.write().await = Some(file_hash);
+            if let Err(e) = stream_radio_track(&session, &radio).await {
+                eprintln!("Warning: Radio failed to play '{}': {}", session.title, e);
+            }
+        }
+
+        *radio.on_air.write().await = None;
+    }
+}
+
+pub(crate) async fn stream_radio_track(
+    session: &HlsSession,
+    radio: &Radio,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Skip leading/trailing silence when the track has been analyzed
+    let mut trim_args = Vec::new();
+    if let Some(hints) = session.crossfade {
+        trim_args.extend([
+            "-ss".to_string(),
+            hints.fade_in_end.to_string(),
+            "-to".to_string(),
+            hints.fade_out_start.to_string(),
+        ]);
+    }
+
+    let mut command = Command::new(&radio.ffmpeg_path);
+    command
+        .arg("-re")
+        .args(&trim_args)
+        .args([
+            "-i",
+            session.playlist_path.to_str().unwrap(),
+            "-vn",
+            "-c:a",
+            "libmp3lame",
+            "-b:a",
+            "128k",
+            "-f",
+            "mp3",
+            "pipe:1",
+        ])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null());
+    let mut child = radio.limits.spawn(&mut command)?;
+
+    let mut stdout = child.stdout.take().ok_or("ffmpeg stdout unavailable")?;
+    let mut buf = vec![0u8; 8192];
+    loop {
+        let n = stdout.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        // Sending only fails once every listener has disconnected
+        if radio
+            .chunks
+            .send(Bytes::copy_from_slice(&buf[..n]))
+            .is_err()
+        {
+            child.kill().await?;
+            break;
+        }
+    }
+
+    child.wait().await?;
+    Ok(())
+}
+
+pub(crate) fn radio_response(radio: &Radio) -> Response<Body> {
+    let stream = futures_util::stream::unfold(radio.chunks.subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(chunk) => return Some((Ok::<_, std::io::Error>(chunk), rx)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Response::builder()
+        .header("Content-Type", "audio/mpeg")
+        .header("Cache-Control", "no-cache, no-store")
+        .header("icy-name", "music-lib radio")
+        .body(Body::from_stream(stream))
+        .unwrap()
+}
@@ -0,0 +1,120 @@
+//! Tracks deleted while someone was streaming them: their segments stay until the
+//! listeners are done, and their playlists answer that the track was removed. The
+//! old segments of a refreshed track are kept the same way.
+
+use crate::connections::Connections;
+use crate::segment_cache::SegmentCache;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often parked segment directories are checked for listeners.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Removed sessions are recognized for this long, so players reloading a playlist
+/// are told the track is gone instead of getting a 404.
+const REMEMBER: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct Removed {
+    removed_at: Instant,
+    /// Segments kept for the listeners at the time of the delete
+    segments_dir: Option<PathBuf>,
+    /// Swapped for new segments rather than deleted: the track lives on under
+    /// another session
+    replaced: bool,
+}
+
+pub(crate) struct Removals {
+    /// By HLS session id
+    sessions: Mutex<HashMap<String, Removed>>,
+}
+
+impl Removals {
+    pub(crate) fn new() -> Self {
+        Removals {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remembers a deleted track's session; `segments_dir`, when given, is deleted
+    /// by `run_removals` once nobody streams the session anymore.
+    pub(crate) fn add(&self, session_id: &str, segments_dir: Option<PathBuf>) {
+        self.sessions.lock().unwrap().insert(
+            session_id.to_string(),
+            Removed {
+                removed_at: Instant::now(),
+                segments_dir,
+                replaced: false,
+            },
+        );
+    }
+
+    /// Keeps the segments a track had before they were replaced, under its old
+    /// session, until nobody streams them anymore.
+    pub(crate) fn replace(&self, session_id: &str, segments_dir: PathBuf) {
+        self.sessions.lock().unwrap().insert(
+            session_id.to_string(),
+            Removed {
+                removed_at: Instant::now(),
+                segments_dir: Some(segments_dir),
+                replaced: true,
+            },
+        );
+    }
+
+    /// Whether the session belonged to a track that was deleted.
+    pub(crate) fn contains(&self, session_id: &str) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .is_some_and(|removed| !removed.replaced)
+    }
+
+    /// Where the segments of a removed session still being streamed are.
+    pub(crate) fn segments_dir(&self, session_id: &str) -> Option<PathBuf> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .and_then(|removed| removed.segments_dir.clone())
+    }
+
+    /// Takes out the kept directories of sessions without listeners, and forgets
+    /// sessions removed long ago.
+    fn take_idle(&self, connections: &Connections) -> Vec<PathBuf> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut idle = Vec::new();
+        for (session_id, removed) in sessions.iter_mut() {
+            if removed.segments_dir.is_some() && connections.listeners(session_id) == 0 {
+                idle.extend(removed.segments_dir.take());
+            }
+        }
+        sessions.retain(|_, removed| {
+            removed.segments_dir.is_some() || removed.removed_at.elapsed() < REMEMBER
+        });
+        idle
+    }
+}
+
+/// Deletes the segments of removed tracks once their last listener has stopped.
+pub(crate) async fn run_removals(
+    removals: Arc<Removals>,
+    connections: Arc<Connections>,
+    segment_cache: Arc<SegmentCache>,
+) {
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        for segments_dir in removals.take_idle(&connections) {
+            segment_cache.remove_dir(&segments_dir);
+            match tokio::fs::remove_dir_all(&segments_dir).await {
+                Ok(()) => println!(
+                    "🗑️ Deleted {} now that nobody is streaming it",
+                    segments_dir.display()
+                ),
+                Err(e) => eprintln!("Warning: Failed to delete segments dir: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! The periodic jobs (subscription checks, backups, expiring the download history,
+//! ...): each runs on its own interval, reports how its last run went, and can be
+//! run by hand from /api/admin/tasks.
+
+use crate::storage::unix_timestamp;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// A task and how its runs went, as /api/admin/tasks lists it.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TaskStatus {
+    pub(crate) name: &'static str,
+    pub(crate) description: &'static str,
+    pub(crate) interval_seconds: u64,
+    pub(crate) running: bool,
+    pub(crate) runs: u64,
+    pub(crate) failures: u64,
+    pub(crate) last_started_at: Option<u64>,
+    pub(crate) last_finished_at: Option<u64>,
+    pub(crate) last_duration_ms: Option<u64>,
+    /// Why the last run failed; cleared by a successful one
+    pub(crate) last_error: Option<String>,
+    /// Unset while the task runs
+    pub(crate) next_run_at: Option<u64>,
+}
+
+struct Task {
+    status: Mutex<TaskStatus>,
+    /// Wakes the task before its time, for runs started by hand
+    trigger: mpsc::Sender<()>,
+}
+
+/// Why a task couldn't be started by hand.
+pub(crate) enum TriggerError {
+    NotFound,
+    Running,
+}
+
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    tasks: Mutex<Vec<Arc<Task>>>,
+}
+
+impl Scheduler {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `job` after `first_run`, then again `interval` after each run has finished.
+    /// A failed run is logged and kept as the task's `last_error`.
+    pub(crate) fn add<F, Fut>(
+        &self,
+        name: &'static str,
+        description: &'static str,
+        interval: Duration,
+        first_run: Duration,
+        job: F,
+    ) where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let (trigger, mut triggered) = mpsc::channel(1);
+        let task = Arc::new(Task {
+            status: Mutex::new(TaskStatus {
+                name,
+                description,
+                interval_seconds: interval.as_secs(),
+                running: false,
+                runs: 0,
+                failures: 0,
+                last_started_at: None,
+                last_finished_at: None,
+                last_duration_ms: None,
+                last_error: None,
+                next_run_at: None,
+            }),
+            trigger,
+        });
+        self.tasks.lock().unwrap().push(Arc::clone(&task));
+
+        tokio::spawn(async move {
+            let mut delay = first_run;
+            loop {
+                task.status.lock().unwrap().next_run_at = Some(unix_timestamp() + delay.as_secs());
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = triggered.recv() => {}
+                }
+
+                {
+                    let mut status = task.status.lock().unwrap();
+                    // A trigger that came in as the time was up is this run
+                    while triggered.try_recv().is_ok() {}
+                    status.running = true;
+                    status.next_run_at = None;
+                    status.last_started_at = Some(unix_timestamp());
+                }
+                let started = Instant::now();
+                let result = job().await;
+
+                let mut status = task.status.lock().unwrap();
+                status.running = false;
+                status.runs += 1;
+                status.last_finished_at = Some(unix_timestamp());
+                status.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+                if let Err(e) = &result {
+                    eprintln!("Warning: Task {} failed: {}", name, e);
+                    status.failures += 1;
+                }
+                status.last_error = result.err();
+                delay = interval;
+            }
+        });
+    }
+
+    /// Every task, in the order they were added.
+    pub(crate) fn list(&self) -> Vec<TaskStatus> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|task| task.status.lock().unwrap().clone())
+            .collect()
+    }
+
+    /// Runs a task now instead of at its next scheduled time, which then counts
+    /// from the end of this run.
+    pub(crate) fn trigger(&self, name: &str) -> Result<(), TriggerError> {
+        let tasks = self.tasks.lock().unwrap();
+        let task = tasks
+            .iter()
+            .find(|task| task.status.lock().unwrap().name == name)
+            .ok_or(TriggerError::NotFound)?;
+        let status = task.status.lock().unwrap();
+        if status.running {
+            return Err(TriggerError::Running);
+        }
+        // Full when already triggered, and that run is still to start
+        let _ = task.trigger.try_send(());
+        Ok(())
+    }
+}
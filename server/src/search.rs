@@ -0,0 +1,210 @@
+//! Picking a track from a search query: yt-dlp searches YouTube, and the results
+//! are ranked by how likely each is the studio recording the query asks for.
+
+use crate::feeds::flat_listing;
+use crate::process::ProcessLimits;
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long a YouTube search may take.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Words marking another version than the studio recording, unless the query asks
+/// for it.
+const VERSION_WORDS: &[&str] = &[
+    "live",
+    "cover",
+    "karaoke",
+    "instrumental",
+    "remix",
+    "acoustic",
+    "reaction",
+    "nightcore",
+    "slowed",
+    "sped up",
+    "8d",
+    "tutorial",
+    "hour",
+    "hours",
+];
+
+/// A video found by a search.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    pub channel: Option<String>,
+    /// Seconds; unknown for livestreams
+    pub duration: Option<f64>,
+    /// The channel has YouTube's verified badge
+    pub verified: bool,
+}
+
+impl SearchResult {
+    /// Artist and title of the track: the channel and title of a "- Topic" upload,
+    /// or the two halves of an "Artist - Title (Official Video)" title.
+    pub fn track_names(&self) -> (Option<String>, String) {
+        if let Some(artist) = self
+            .channel
+            .as_deref()
+            .and_then(|channel| channel.strip_suffix(" - Topic"))
+        {
+            return (Some(artist.to_string()), self.title.clone());
+        }
+        match self.title.split_once(" - ") {
+            Some((artist, title)) => {
+                let title = ["(", "["]
+                    .iter()
+                    .filter_map(|open| title.find(open))
+                    .min()
+                    .filter(|start| *start > 0)
+                    .map_or(title, |start| &title[..start]);
+                (Some(artist.trim().to_string()), title.trim().to_string())
+            }
+            None => (None, self.title.clone()),
+        }
+    }
+}
+
+/// What makes a search result acceptable, and which results are preferred.
+#[derive(Debug, Clone)]
+pub struct Heuristics {
+    /// Shorter results (intros, shorts) are skipped
+    pub min_duration: f64,
+    /// Longer results (full albums, mixes) are skipped
+    pub max_duration: f64,
+    /// Prefer uploads of the artist's channel, its auto-generated "- Topic" channel,
+    /// VEVO and verified channels
+    pub prefer_official: bool,
+}
+
+/// A search result with its score, or why it was passed over.
+#[derive(Debug, Clone, Serialize)]
+pub struct Candidate {
+    #[serde(flatten)]
+    pub result: SearchResult,
+    pub score: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected: Option<String>,
+}
+
+/// Searches YouTube for `query`, returning up to `count` results in YouTube's order.
+pub async fn search(
+    ytdlp: &Path,
+    limits: &ProcessLimits,
+    query: &str,
+    count: usize,
+) -> Result<Vec<SearchResult>, String> {
+    let target = format!("ytsearch{}:{}", count, query);
+    let listing = flat_listing(ytdlp, limits, &target, SEARCH_TIMEOUT).await?;
+    let entries = listing["entries"].as_array().cloned().unwrap_or_default();
+    Ok(entries
+        .iter()
+        .filter_map(|entry| {
+            let url = entry["url"]
+                .as_str()
+                .or_else(|| entry["webpage_url"].as_str())?
+                .to_string();
+            let live = matches!(
+                entry["live_status"].as_str(),
+                Some("is_live" | "is_upcoming")
+            );
+            Some(SearchResult {
+                title: entry["title"].as_str().unwrap_or_default().to_string(),
+                url,
+                channel: entry["channel"]
+                    .as_str()
+                    .or_else(|| entry["uploader"].as_str())
+                    .map(str::to_string),
+                duration: entry["duration"].as_f64().filter(|_| !live),
+                verified: entry["channel_is_verified"].as_bool().unwrap_or(false),
+            })
+        })
+        .collect())
+}
+
+/// Scores each result for `query`, best first; rejected results come last.
+pub fn rank(results: Vec<SearchResult>, query: &str, heuristics: &Heuristics) -> Vec<Candidate> {
+    let query_words = words(query);
+    // "Artist - Title" names the artist, whose own channel is the official one
+    let artist = query
+        .split_once(" - ")
+        .map(|(artist, _)| words(artist).join(" "))
+        .filter(|artist| !artist.is_empty());
+
+    let mut candidates: Vec<Candidate> = results
+        .into_iter()
+        .enumerate()
+        .map(|(position, result)| {
+            let rejected = match result.duration {
+                None => Some("Length unknown, possibly a livestream".to_string()),
+                Some(duration) if duration < heuristics.min_duration => {
+                    Some(format!("Shorter than {}s", heuristics.min_duration))
+                }
+                Some(duration) if duration > heuristics.max_duration => {
+                    Some(format!("Longer than {}s", heuristics.max_duration))
+                }
+                Some(_) => None,
+            };
+
+            let title = format!(" {} ", words(&result.title).join(" "));
+            let channel = result.channel.as_deref().unwrap_or_default();
+            let channel_words = format!(" {} ", words(channel).join(" "));
+
+            // YouTube's own order counts, but less than what the result is
+            let mut score = -(position as i32) * 2;
+            let matched = query_words
+                .iter()
+                .filter(|word| {
+                    title.contains(&format!(" {} ", word))
+                        || channel_words.contains(&format!(" {} ", word))
+                })
+                .count();
+            if !query_words.is_empty() {
+                score += (40 * matched / query_words.len()) as i32;
+            }
+            let query_text = format!(" {} ", query_words.join(" "));
+            for word in VERSION_WORDS {
+                let word = format!(" {} ", word);
+                if title.contains(&word) && !query_text.contains(&word) {
+                    score -= 25;
+                }
+            }
+            if heuristics.prefer_official {
+                if channel.ends_with(" - Topic") {
+                    score += 30;
+                } else if channel.to_lowercase().ends_with("vevo") {
+                    score += 20;
+                }
+                if result.verified {
+                    score += 10;
+                }
+                if let Some(artist) = &artist {
+                    if channel_words.trim() == artist
+                        || channel_words.trim() == format!("{} topic", artist)
+                    {
+                        score += 20;
+                    }
+                }
+            }
+
+            Candidate {
+                result,
+                score,
+                rejected,
+            }
+        })
+        .collect();
+    candidates.sort_by_key(|candidate| (candidate.rejected.is_some(), -candidate.score));
+    candidates
+}
+
+/// Lowercase words of `text`, punctuation dropped.
+fn words(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
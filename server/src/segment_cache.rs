@@ -0,0 +1,201 @@
+//! Bounded in-memory LRU cache for HLS segment files.
+
+use crate::storage::{playlist_segments, HlsSession};
+use axum::body::Bytes;
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub(crate) struct SegmentCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    prefetched: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Segment data and the tick of its last use, keyed by file path
+    entries: HashMap<PathBuf, (Bytes, u64)>,
+    /// Last-use tick -> path, oldest first
+    recency: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SegmentCacheStats {
+    pub(crate) capacity_bytes: usize,
+    pub(crate) used_bytes: usize,
+    pub(crate) entries: usize,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) hit_rate: f64,
+    /// Segments read ahead of a listener's requests
+    pub(crate) prefetched: u64,
+}
+
+impl SegmentCache {
+    /// A cache holding at most `capacity` bytes of segment data; 0 disables caching.
+    pub(crate) fn new(capacity: usize) -> Self {
+        SegmentCache {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            prefetched: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Whether a segment is cached, without counting as a use.
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        self.state.lock().unwrap().entries.contains_key(path)
+    }
+
+    pub(crate) fn get(&self, path: &Path) -> Option<Bytes> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+
+        let Some((data, last_used)) = state.entries.get_mut(path) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let previous = std::mem::replace(last_used, tick);
+        let data = data.clone();
+        state.recency.remove(&previous);
+        state.recency.insert(tick, path.to_path_buf());
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(data)
+    }
+
+    /// Whether a segment of `len` bytes can be cached at all.
+    pub(crate) fn accepts(&self, len: u64) -> bool {
+        self.capacity > 0 && len <= self.capacity as u64
+    }
+
+    /// Stores a segment, evicting the least recently used ones to stay within capacity.
+    pub(crate) fn insert(&self, path: PathBuf, data: Bytes) {
+        if !self.accepts(data.len() as u64) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+
+        if let Some((old, last_used)) = state.entries.remove(&path) {
+            state.size -= old.len();
+            state.recency.remove(&last_used);
+        }
+
+        while state.size + data.len() > self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = state.entries.remove(&oldest) {
+                state.size -= evicted.len();
+            }
+        }
+
+        state.size += data.len();
+        state.recency.insert(tick, path.clone());
+        state.entries.insert(path, (data, tick));
+    }
+
+    /// Stores a segment read ahead of the request for it.
+    pub(crate) fn insert_prefetched(&self, path: PathBuf, data: Bytes) {
+        if self.accepts(data.len() as u64) {
+            self.prefetched.fetch_add(1, Ordering::Relaxed);
+            self.insert(path, data);
+        }
+    }
+
+    /// Drops every cached segment below `dir`, e.g. when a track is deleted.
+    pub(crate) fn remove_dir(&self, dir: &Path) {
+        let mut state = self.state.lock().unwrap();
+        let removed: Vec<PathBuf> = state
+            .entries
+            .keys()
+            .filter(|path| path.starts_with(dir))
+            .cloned()
+            .collect();
+
+        for path in removed {
+            if let Some((data, last_used)) = state.entries.remove(&path) {
+                state.size -= data.len();
+                state.recency.remove(&last_used);
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> SegmentCacheStats {
+        let state = self.state.lock().unwrap();
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+
+        SegmentCacheStats {
+            capacity_bytes: self.capacity,
+            used_bytes: state.size,
+            entries: state.entries.len(),
+            hits,
+            misses,
+            hit_rate: if lookups == 0 {
+                0.0
+            } else {
+                hits as f64 / lookups as f64
+            },
+            prefetched: self.prefetched.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How many tracks are read at once when warming the cache up.
+const WARM_UP_CONCURRENCY: usize = 8;
+
+/// Reads the first segment of each track into `cache`, so the tracks most likely
+/// to be played start without touching the disk. Stops once the cache is full
+/// rather than evicting the segments read before. Returns how many were read.
+pub(crate) async fn warm_up(cache: &SegmentCache, tracks: Vec<HlsSession>) -> usize {
+    let mut first_segments = Box::pin(
+        futures_util::stream::iter(tracks)
+            .map(|session| async move {
+                let playlist = tokio::fs::read_to_string(&session.playlist_path)
+                    .await
+                    .ok()?;
+                let path = session
+                    .segments_dir
+                    .join(playlist_segments(&playlist).first()?.uri);
+                let data = tokio::fs::read(&path).await.ok()?;
+                Some((path, Bytes::from(data)))
+            })
+            .buffered(WARM_UP_CONCURRENCY),
+    );
+
+    let mut warmed = 0;
+    while let Some(segment) = first_segments.next().await {
+        let Some((path, data)) = segment else {
+            continue;
+        };
+        if cache.stats().used_bytes + data.len() > cache.capacity {
+            break;
+        }
+        cache.insert(path, data);
+        warmed += 1;
+    }
+    warmed
+}
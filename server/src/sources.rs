@@ -0,0 +1,200 @@
+//! Checks that tracks' origin URLs still resolve, so tracks whose upload has been
+//! taken down are known before their segments are the only copy left.
+
+use crate::downloader::parse_clip_url;
+use crate::process::{ProcessError, ProcessLimits};
+use crate::storage::{
+    save_source_checks, unix_timestamp, HlsCache, SourceCheck, SourceChecks, SourceStatus,
+};
+use crate::webhooks::Webhooks;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::process::Command;
+
+/// How long yt-dlp may take to resolve one URL before the check counts as failed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Pause between URLs, so a large library doesn't get the server rate limited.
+const PROBE_PAUSE: Duration = Duration::from_secs(2);
+
+/// Parts of yt-dlp errors that say nothing about the source itself.
+const TRANSIENT_ERRORS: [&str; 6] = [
+    "Unable to download",
+    "timed out",
+    "Temporary failure",
+    "Connection",
+    "HTTP Error 429",
+    "HTTP Error 5",
+];
+
+pub(crate) struct SourceChecker {
+    pub(crate) checks: SourceChecks,
+    running: AtomicBool,
+    /// The yt-dlp binary
+    ytdlp_path: PathBuf,
+    limits: ProcessLimits,
+}
+
+impl SourceChecker {
+    pub(crate) fn new(checks: SourceChecks, ytdlp_path: PathBuf, limits: ProcessLimits) -> Self {
+        SourceChecker {
+            checks,
+            running: AtomicBool::new(false),
+            ytdlp_path,
+            limits: limits.at_most(Some(PROBE_TIMEOUT)),
+        }
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Whether yt-dlp's error means the source is gone rather than unreachable.
+pub(crate) fn classify(error: &str) -> SourceStatus {
+    if error.contains("HTTP Error 404") || error.contains("HTTP Error 410") {
+        return SourceStatus::Unavailable;
+    }
+    if TRANSIENT_ERRORS.iter().any(|marker| error.contains(marker)) {
+        SourceStatus::Unknown
+    } else {
+        SourceStatus::Unavailable
+    }
+}
+
+/// Asks yt-dlp to resolve `url` without downloading anything.
+async fn probe(ytdlp: &Path, limits: &ProcessLimits, url: &str) -> (SourceStatus, Option<String>) {
+    let output = limits
+        .output(
+            Command::new(ytdlp)
+                .args(["--simulate", "--no-playlist", "--no-warnings", "--quiet"])
+                .arg(url),
+        )
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => (SourceStatus::Available, None),
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let error = stderr
+                .lines()
+                .rev()
+                .find_map(|line| line.strip_prefix("ERROR:"))
+                .map(str::trim)
+                .unwrap_or("yt-dlp failed")
+                .to_string();
+            (classify(&error), Some(error))
+        }
+        Err(ProcessError::Io(e)) => (
+            SourceStatus::Unknown,
+            Some(format!("Failed to run yt-dlp: {}", e)),
+        ),
+        Err(ProcessError::TimedOut(_)) => (
+            SourceStatus::Unknown,
+            Some("Timed out resolving the URL".to_string()),
+        ),
+    }
+}
+
+/// Checks the origin URL of every downloaded track once. Returns `false` without
+/// checking anything when a run is already in progress.
+pub(crate) async fn check_sources(
+    checker: &SourceChecker,
+    hls_cache: &HlsCache,
+    cache_dir: &Path,
+    webhooks: &Webhooks,
+) -> bool {
+    if checker.running.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    // Chapter tracks of one upload share its URL; resolve it once for all of them
+    let mut by_url: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    {
+        let cache = hls_cache.read().await;
+        for (id, session) in cache.iter() {
+            let (url, _) = parse_clip_url(&session.origin_url);
+            if url.starts_with("http://") || url.starts_with("https://") {
+                by_url
+                    .entry(url.to_string())
+                    .or_default()
+                    .push((id.clone(), session.title.clone()));
+            }
+        }
+    }
+
+    let (mut checked, mut unavailable, mut unknown) = (0, 0, 0);
+    for (index, (url, tracks)) in by_url.iter().enumerate() {
+        if index > 0 {
+            tokio::time::sleep(PROBE_PAUSE).await;
+        }
+        let (status, error) = probe(&checker.ytdlp_path, &checker.limits, url).await;
+        let now = unix_timestamp();
+
+        let mut checks = checker.checks.write().await;
+        for (id, title) in tracks {
+            let previous = checks.get(id);
+            let was_unavailable = previous.is_some_and(|c| c.status == SourceStatus::Unavailable);
+            let unavailable_since = match status {
+                SourceStatus::Available => None,
+                SourceStatus::Unavailable => {
+                    previous.and_then(|c| c.unavailable_since).or(Some(now))
+                }
+                SourceStatus::Unknown => previous.and_then(|c| c.unavailable_since),
+            };
+
+            if status == SourceStatus::Unavailable && !was_unavailable {
+                eprintln!(
+                    "Warning: Source of '{}' is no longer available: {}",
+                    title,
+                    error.as_deref().unwrap_or_default()
+                );
+                webhooks.emit(
+                    "source_unavailable",
+                    serde_json::json!({
+                        "id": id,
+                        "title": title,
+                        "origin_url": url,
+                        "error": error,
+                    }),
+                );
+            }
+
+            checks.insert(
+                id.clone(),
+                SourceCheck {
+                    status,
+                    checked_at: now,
+                    unavailable_since,
+                    error: error.clone(),
+                },
+            );
+            checked += 1;
+            match status {
+                SourceStatus::Available => {}
+                SourceStatus::Unavailable => unavailable += 1,
+                SourceStatus::Unknown => unknown += 1,
+            }
+        }
+        hls_cache.touch();
+    }
+
+    let mut checks = checker.checks.write().await;
+    {
+        let cache = hls_cache.read().await;
+        checks.retain(|id, _| cache.contains_key(id));
+    }
+    if let Err(e) = save_source_checks(cache_dir, &checks).await {
+        eprintln!("Warning: Failed to save source checks: {}", e);
+    }
+    drop(checks);
+
+    checker.running.store(false, Ordering::SeqCst);
+    println!(
+        "✓ Checked {} track sources: {} unavailable, {} could not be checked",
+        checked, unavailable, unknown
+    );
+    true
+}
@@ -0,0 +1,1556 @@
+//! Persistent library state: track sessions and the JSON files they are stored in.
+
+use crate::downloader::{DownloadStatus, Priority};
+use crate::library::assign_slugs;
+use crate::transcode::artwork_hash;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, Notify, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct HlsSession {
+    pub id: String,
+    /// Readable name for links (`/t/{slug}`), unique in the library; it stays when
+    /// the title changes or the track is refreshed
+    pub slug: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub origin_url: String,
+    /// Hash of the origin URL, or of an imported file's location: how the track is
+    /// recognised when its source is added again. Tracks added before ids were
+    /// assigned have it as their id.
+    pub origin_hash: String,
+    pub segments_dir: PathBuf,
+    pub playlist_path: PathBuf,
+    pub total_segments: u32,
+    pub segment_duration: f32,
+    /// Length in seconds, summed from the playlist's segment durations
+    pub duration: f64,
+    pub listen_count: u64,
+    /// Distinct listeners per day, summed over all days
+    pub unique_listeners: u64,
+    pub last_listen: Option<Instant>,
+    pub crossfade: Option<CrossfadeHints>,
+    pub chapters: Vec<Chapter>,
+    /// Whether a video rendition was kept next to the audio segments
+    pub has_video: bool,
+    /// Whether thumbnails in every size were made from the source's artwork
+    pub has_thumbnail: bool,
+    /// Content hash of the thumbnails, named in their URLs
+    pub artwork_hash: Option<String>,
+    /// Whether the thumbnails were made from uploaded artwork, which refreshes keep
+    pub custom_artwork: bool,
+    /// Whether segments are AES-128 encrypted with a key only the key endpoint hands out
+    pub encrypted: bool,
+    pub codec: AudioCodec,
+    /// Audio bitrate in kbit/s
+    pub bitrate: u32,
+    pub tempo_key: Option<TempoKey>,
+    pub identification: Option<Identification>,
+    pub notes: Vec<TrackNote>,
+    /// Unix time the track was added to the library
+    pub date_added: u64,
+    /// Kept when tracks are evicted or purged automatically
+    pub pinned: bool,
+    /// Whether guests see the track when the server has an owner token
+    pub visibility: Visibility,
+    /// From an overlay library (`--overlay-path`): its files and metadata belong to
+    /// another instance, so it is never changed or saved here
+    pub read_only: bool,
+}
+
+/// A free-text note on a track, e.g. "drop at 24:30".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackNote {
+    pub id: String,
+    pub text: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// An AcoustID match applied to a track that was added without a title.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identification {
+    /// MusicBrainz recording ID
+    pub recording_id: String,
+    pub score: f64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub status: IdentificationStatus,
+    /// Tags the track had before the match was applied, restored if it is rejected
+    pub previous_title: String,
+    pub previous_artist: Option<String>,
+    pub previous_album: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentificationStatus {
+    Pending,
+    Confirmed,
+    Rejected,
+}
+
+/// Audio codec of a track's HLS segments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    #[default]
+    Aac,
+    Mp3,
+}
+
+impl AudioCodec {
+    pub fn name(self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "AAC",
+            AudioCodec::Mp3 => "MP3",
+        }
+    }
+
+    /// RFC 6381 codec string, as listed in master playlists.
+    pub fn codecs(self) -> &'static str {
+        match self {
+            AudioCodec::Aac => "mp4a.40.2",
+            AudioCodec::Mp3 => "mp4a.40.34",
+        }
+    }
+}
+
+/// Who sees a track. With `--owner-token` set, private tracks are only listed and
+/// served to requests presenting it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Private,
+}
+
+/// Audio bitrate in kbit/s of tracks converted before it was configurable.
+pub const DEFAULT_BITRATE: u32 = 128;
+
+fn default_bitrate() -> u32 {
+    DEFAULT_BITRATE
+}
+
+/// What a track is converted again from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetranscodeSource {
+    /// Download the track again from its origin URL
+    Download,
+    /// Decode the track's current segments; lossy, but works without an origin URL
+    Segments,
+}
+
+/// A run converting every track in the library to one audio format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Migration {
+    pub codec: AudioCodec,
+    pub bitrate: u32,
+    pub segment_duration: u32,
+    /// Unset: each track's origin URL when it has one, its segments otherwise
+    pub source: Option<RetranscodeSource>,
+    /// Tracks converted at the same time
+    pub concurrency: usize,
+    pub status: MigrationStatus,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub converted: usize,
+    /// Tracks that failed are not retried when the migration is resumed
+    pub failed: Vec<MigrationFailure>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationStatus {
+    Running,
+    /// Stopped by request; can be resumed
+    Cancelled,
+    Finished,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationFailure {
+    pub track_id: String,
+    pub title: String,
+    pub error: String,
+}
+
+/// Conversion of a track's segments to another audio format, queued for a
+/// transcode slot like the conversion step of a download.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TranscodeJob {
+    pub id: String,
+    pub track_id: String,
+    pub title: String,
+    pub codec: AudioCodec,
+    pub bitrate: u32,
+    pub segment_duration: u32,
+    pub priority: Priority,
+    pub status: TranscodeStatus,
+    /// Completion of the conversion, 0-100
+    pub percent: Option<f64>,
+    /// Session the new segments are written to, once the job has started
+    pub session_id: Option<String>,
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscodeStatus {
+    /// Waiting for a transcode slot
+    Pending,
+    Running,
+    Ready,
+    Failed,
+}
+
+impl TranscodeStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(self, TranscodeStatus::Ready | TranscodeStatus::Failed)
+    }
+}
+
+/// Detected tempo and musical key of a track.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TempoKey {
+    pub bpm: f64,
+    /// e.g. "A minor"
+    pub key: String,
+    /// Camelot wheel code used for harmonic mixing, e.g. "8A"
+    pub camelot: String,
+}
+
+/// A titled section of a track, e.g. one song of a mix, in seconds from the start.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+    pub title: String,
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Where audible content starts and ends, in seconds from the start of the track.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CrossfadeHints {
+    pub fade_in_end: f64,
+    pub fade_out_start: f64,
+    pub duration: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HlsCacheEntry {
+    file_hash: String,
+    session_id: String,
+    #[serde(default)]
+    slug: String,
+    title: String,
+    #[serde(default)]
+    artist: Option<String>,
+    #[serde(default)]
+    album: Option<String>,
+    #[serde(default)]
+    origin_url: String,
+    #[serde(default)]
+    origin_hash: String,
+    segments_dir: String,
+    playlist_path: String,
+    total_segments: u32,
+    segment_duration: f32,
+    #[serde(default)]
+    duration: Option<f64>,
+    #[serde(default)]
+    listen_count: u64,
+    #[serde(default)]
+    unique_listeners: u64,
+    #[serde(default)]
+    crossfade: Option<CrossfadeHints>,
+    #[serde(default)]
+    chapters: Vec<Chapter>,
+    #[serde(default)]
+    has_video: bool,
+    #[serde(default)]
+    has_thumbnail: bool,
+    #[serde(default)]
+    artwork_hash: Option<String>,
+    #[serde(default)]
+    custom_artwork: bool,
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    codec: AudioCodec,
+    #[serde(default = "default_bitrate")]
+    bitrate: u32,
+    #[serde(default)]
+    tempo_key: Option<TempoKey>,
+    #[serde(default)]
+    identification: Option<Identification>,
+    #[serde(default)]
+    notes: Vec<TrackNote>,
+    #[serde(default)]
+    date_added: Option<u64>,
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    visibility: Visibility,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HlsCacheData {
+    #[serde(default)]
+    version: u32,
+    entries: Vec<HlsCacheEntry>,
+}
+
+/// Version of the hls_cache.json layout this build writes. Renaming, moving or changing
+/// the meaning of a field takes a new version and a step in `HLS_CACHE_MIGRATIONS`; a
+/// new field with a `serde(default)` needs neither.
+const HLS_CACHE_VERSION: u32 = 1;
+
+/// Upgrades of an hls_cache.json document, the first from version 0 to 1 and so on.
+const HLS_CACHE_MIGRATIONS: [fn(&mut serde_json::Value); HLS_CACHE_VERSION as usize] = [
+    // Files from before the version was recorded: every field added until then has
+    // a default
+    |_| {},
+];
+
+/// Decodes an hls_cache.json document of any version up to `HLS_CACHE_VERSION`,
+/// migrating older ones. Also returns the version the document was written with.
+fn decode_hls_cache(
+    content: &str,
+) -> Result<(HlsCacheData, u32), Box<dyn std::error::Error + Send + Sync>> {
+    let mut document: serde_json::Value = serde_json::from_str(content)?;
+    let version = match document.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or("version is not a number")?,
+    };
+    if version > HLS_CACHE_VERSION {
+        return Err(format!(
+            "written by a newer version of the server (schema version {}, this one reads up to {})",
+            version, HLS_CACHE_VERSION
+        )
+        .into());
+    }
+    for migrate in &HLS_CACHE_MIGRATIONS[version as usize..] {
+        migrate(&mut document);
+    }
+    Ok((serde_json::from_value(document)?, version))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collection {
+    pub id: String,
+    pub path: String,
+    pub track_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CollectionsData {
+    collections: Vec<Collection>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub registered_at: u64,
+}
+
+/// The library: every track by id, behind an async lock, and the directory whose
+/// hls_cache.json it is saved to. Changes are written by `run_saver` in batches.
+pub struct TrackStore {
+    tracks: RwLock<HashMap<String, HlsSession>>,
+    cache_dir: PathBuf,
+    /// Keeps saves in order, so an older state never overwrites a newer one
+    saving: Mutex<()>,
+    /// Changes not written yet
+    pending: AtomicUsize,
+    changed: Notify,
+    /// Counts changes to what the track listing shows, for its ETag. Starts at the
+    /// startup time in milliseconds, so it keeps growing across restarts.
+    revision: AtomicU64,
+    save_delay: Duration,
+    save_batch: usize,
+}
+
+pub type HlsCache = Arc<TrackStore>;
+
+impl TrackStore {
+    /// A store writing changes at most `save_delay` after they were made, or as soon
+    /// as `save_batch` of them are waiting.
+    pub fn new(
+        cache_dir: PathBuf,
+        tracks: HashMap<String, HlsSession>,
+        save_delay: Duration,
+        save_batch: usize,
+    ) -> Self {
+        TrackStore {
+            tracks: RwLock::new(tracks),
+            cache_dir,
+            saving: Mutex::new(()),
+            pending: AtomicUsize::new(0),
+            changed: Notify::new(),
+            revision: AtomicU64::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u64),
+            ),
+            save_delay,
+            save_batch,
+        }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, HashMap<String, HlsSession>> {
+        self.tracks.read().await
+    }
+
+    /// Write access, which counts as a change of the revision: overlays and play
+    /// counts change tracks without them being saved.
+    pub async fn write(&self) -> RwLockWriteGuard<'_, HashMap<String, HlsSession>> {
+        let tracks = self.tracks.write().await;
+        self.touch();
+        tracks
+    }
+
+    /// The library's current revision. Read it while holding a guard from `read`, so
+    /// it matches the tracks seen.
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Ordering::SeqCst)
+    }
+
+    /// Counts a change to something listed with the tracks but kept elsewhere, such as
+    /// ratings. Call it while holding that data's write lock.
+    pub fn touch(&self) {
+        self.revision.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Marks the library as changed, to be written by `run_saver`.
+    pub fn changed(&self) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        self.changed.notify_one();
+    }
+
+    /// Writes the library to hls_cache.json now if it has unsaved changes, logging
+    /// failures. Must not be called while holding a guard from `read` or `write`.
+    pub async fn flush(&self) {
+        let _saving = self.saving.lock().await;
+        let pending = self.pending.swap(0, Ordering::Relaxed);
+        if pending == 0 {
+            return;
+        }
+        let json_content = {
+            let tracks = self.tracks.read().await;
+            encode_hls_cache(&tracks)
+        };
+        let result = match json_content {
+            Ok(json_content) => {
+                write_atomically(&self.cache_dir.join("hls_cache.json"), json_content).await
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            eprintln!("Warning: Failed to save HLS cache: {}", e);
+            // Tried again with the next change
+            self.pending.fetch_add(pending, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writes the library whenever it changed, after waiting `--save-delay` for further
+/// changes to write along, or until `--save-batch` of them are waiting.
+pub async fn run_saver(store: HlsCache) {
+    loop {
+        store.changed.notified().await;
+        let deadline = tokio::time::Instant::now() + store.save_delay;
+        while store.pending.load(Ordering::Relaxed) < store.save_batch {
+            if tokio::time::timeout_at(deadline, store.changed.notified())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        store.flush().await;
+    }
+}
+
+/// Replaces `path` with `contents` through a temporary file next to it, so the file
+/// is either the old or the new version even if the process dies while writing.
+async fn write_atomically(path: &Path, contents: String) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = tokio::fs::File::create(&temp_path).await?;
+    file.write_all(contents.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&temp_path, path).await
+}
+
+/// The cache directories: the primary one holds the library's files, and every one of
+/// them holds track directories.
+#[derive(Debug, Clone)]
+pub struct CacheDirs {
+    dirs: Vec<PathBuf>,
+}
+
+impl CacheDirs {
+    pub fn new(primary: PathBuf, extra: Vec<PathBuf>) -> Self {
+        let mut dirs = vec![primary];
+        for dir in extra {
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+        CacheDirs { dirs }
+    }
+
+    pub fn primary(&self) -> &Path {
+        &self.dirs[0]
+    }
+
+    pub fn all(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    /// Where a new track's directory goes: the cache directory with the most free
+    /// space, or the primary one when that can't be told.
+    pub fn for_new_track(&self) -> &Path {
+        if self.dirs.len() == 1 {
+            return self.primary();
+        }
+        self.dirs
+            .iter()
+            .filter_map(|dir| Some((free_space(dir)?, dir)))
+            .max_by_key(|&(free, _)| free)
+            .map_or(self.primary(), |(_, dir)| dir)
+    }
+
+    /// The first cache directory containing `relative`.
+    pub fn find(&self, relative: &Path) -> Option<PathBuf> {
+        self.dirs
+            .iter()
+            .map(|dir| dir.join(relative))
+            .find(|path| path.exists())
+    }
+}
+
+/// Bytes available to unprivileged users on the file system holding `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: `path` is a valid C string and `stats` is only read after statvfs filled it
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stats = unsafe { stats.assume_init() };
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+pub type Collections = Arc<RwLock<HashMap<String, Collection>>>;
+
+pub type PlayQueues = Arc<RwLock<HashMap<String, Vec<String>>>>;
+
+pub type ResumePositions = Arc<RwLock<HashMap<String, HashMap<String, f64>>>>;
+
+pub type Devices = Arc<RwLock<HashMap<String, Device>>>;
+
+/// Access to segment keys handed to one device until `expires_at`. Only a hash of
+/// the token is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyGrant {
+    pub id: String,
+    pub token_hash: String,
+    pub device_id: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+/// Key grants by token hash.
+pub type KeyGrants = Arc<RwLock<HashMap<String, KeyGrant>>>;
+
+/// A link that lets anyone holding its token play one track, until it expires or
+/// its plays run out. Only a hash of the token is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub id: String,
+    pub token_hash: String,
+    pub track_id: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub max_plays: Option<u64>,
+    /// Times the track's playlist was fetched through the link
+    pub plays: u64,
+    pub last_played_at: Option<u64>,
+}
+
+impl ShareLink {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Whether the link may start another play.
+    pub fn has_plays_left(&self) -> bool {
+        self.max_plays
+            .is_none_or(|max_plays| self.plays < max_plays)
+    }
+}
+
+/// Share links by token hash.
+pub type ShareLinks = Arc<RwLock<HashMap<String, ShareLink>>>;
+
+/// Star ratings (1-5) by track, then by device.
+pub type Ratings = Arc<RwLock<HashMap<String, HashMap<String, u8>>>>;
+
+/// Whether a track's origin URL still resolved the last time it was checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceStatus {
+    Available,
+    /// Taken down, made private or otherwise gone from the source site
+    Unavailable,
+    /// The check itself failed, e.g. the site couldn't be reached
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceCheck {
+    pub status: SourceStatus,
+    pub checked_at: u64,
+    /// First check that found the source gone, kept while it stays unavailable
+    pub unavailable_since: Option<u64>,
+    /// yt-dlp's error message, unless the source is available
+    pub error: Option<String>,
+}
+
+/// Source checks by track.
+pub type SourceChecks = Arc<RwLock<HashMap<String, SourceCheck>>>;
+
+/// What a subscription follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriptionKind {
+    /// An RSS feed whose items carry audio enclosures
+    Podcast,
+    /// A YouTube channel or playlist, listed with yt-dlp
+    Channel,
+}
+
+/// A feed checked on a schedule, whose new items are downloaded into a collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub kind: SubscriptionKind,
+    pub url: String,
+    pub title: String,
+    pub author: Option<String>,
+    /// Collection the downloaded items are added to
+    pub collection_id: String,
+    pub created_at: u64,
+    pub checked_at: Option<u64>,
+    /// Why the last check failed
+    pub error: Option<String>,
+    /// Items already downloaded or skipped, by guid
+    #[serde(default)]
+    pub seen: Vec<String>,
+}
+
+/// Subscriptions by id.
+pub type Subscriptions = Arc<RwLock<HashMap<String, Subscription>>>;
+
+/// A counted listen, one line of history.jsonl.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayEvent {
+    pub track_id: String,
+    pub played_at: u64,
+    /// Track length in seconds, kept so listening time survives the track's deletion
+    pub duration: f64,
+    /// Hash of the client ID (or IP) that played it, for counting unique listeners
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listener: Option<String>,
+}
+
+impl PlayEvent {
+    /// Days since the Unix epoch (UTC) the play happened on.
+    pub fn day(&self) -> u64 {
+        self.played_at / SECONDS_PER_DAY
+    }
+}
+
+pub const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+pub type History = Arc<RwLock<Vec<PlayEvent>>>;
+
+/// Id for a new track. It is random rather than derived from where the track came
+/// from, so the same song added from another URL or file can keep its identity.
+pub fn new_track_id() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// The id of the track whose origin hashes to `origin_hash`.
+pub fn track_with_origin(cache: &HashMap<String, HlsSession>, origin_hash: &str) -> Option<String> {
+    cache
+        .iter()
+        .find(|(_, session)| session.origin_hash == origin_hash)
+        .map(|(id, _)| id.clone())
+}
+
+/// Hash of a track's origin URL or file location, see `HlsSession::origin_hash`.
+pub fn generate_url_hash(url: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `YYYY-MM-DD` for a count of days since the Unix epoch (proleptic Gregorian).
+pub fn utc_date(days: u64) -> String {
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub fn is_safe_path_component(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['/', '\\']) && name != "." && name != ".."
+}
+
+/// The `#EXTINF` segment durations of a media playlist, in seconds.
+pub fn segment_durations(playlist: &str) -> impl Iterator<Item = f64> + '_ {
+    playlist
+        .lines()
+        .filter_map(|line| line.strip_prefix("#EXTINF:"))
+        .filter_map(|info| info.split(',').next()?.trim().parse::<f64>().ok())
+}
+
+/// A media segment as listed in a playlist.
+pub struct PlaylistSegment<'a> {
+    pub uri: &'a str,
+    pub duration: f64,
+    /// Length and offset within `uri`, for single-file tracks
+    pub byte_range: Option<(u64, u64)>,
+}
+
+/// The segments of a media playlist, in order. Byte ranges without an offset
+/// continue where the previous one ended, as in the HLS spec.
+pub fn playlist_segments(playlist: &str) -> Vec<PlaylistSegment<'_>> {
+    let mut segments = Vec::new();
+    let (mut duration, mut byte_range) = (0.0, None);
+    let mut next_offset = 0;
+    for line in playlist.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            duration = info
+                .split(',')
+                .next()
+                .and_then(|d| d.trim().parse().ok())
+                .unwrap_or(0.0);
+        } else if let Some(range) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            let (length, offset) = match range.split_once('@') {
+                Some((length, offset)) => (length.parse().ok(), offset.parse().ok()),
+                None => (range.parse().ok(), Some(next_offset)),
+            };
+            byte_range = length.zip(offset);
+            if let Some((length, offset)) = byte_range {
+                next_offset = offset + length;
+            }
+        } else if !line.is_empty() && !line.starts_with('#') {
+            segments.push(PlaylistSegment {
+                uri: line,
+                duration,
+                byte_range: byte_range.take(),
+            });
+            duration = 0.0;
+        }
+    }
+    segments
+}
+
+/// Sum of the `#EXTINF` segment durations of a media playlist, in seconds.
+pub fn playlist_duration(playlist: &str) -> f64 {
+    segment_durations(playlist).sum()
+}
+
+/// Reads hls_cache.json from the primary cache directory. A track whose directory is
+/// gone but turns up under the same name in another of `dirs` (e.g. a disk mounted
+/// somewhere else) is loaded from there.
+///
+/// A file of an older schema version is migrated, after copying it to
+/// `hls_cache.v<version>.json`. A file that can't be read, including one written by a
+/// newer version, is an error rather than an empty library that would overwrite it.
+pub async fn load_hls_cache(
+    dirs: &CacheDirs,
+) -> Result<HashMap<String, HlsSession>, Box<dyn std::error::Error + Send + Sync>> {
+    let cache_file = dirs.primary().join("hls_cache.json");
+    if !cache_file.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = tokio::fs::read_to_string(&cache_file)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", cache_file.display(), e))?;
+    let started = Instant::now();
+    let parsed = parse_hls_cache(&content, dirs)
+        .await
+        .map_err(|e| format!("Failed to parse {}: {}", cache_file.display(), e))?;
+    if parsed.version < HLS_CACHE_VERSION {
+        let kept = dirs
+            .primary()
+            .join(format!("hls_cache.v{}.json", parsed.version));
+        if !kept.exists() {
+            tokio::fs::copy(&cache_file, &kept)
+                .await
+                .map_err(|e| format!("Failed to keep a copy at {}: {}", kept.display(), e))?;
+        }
+        println!(
+            "✓ Upgraded hls_cache.json from schema version {} to {}, the old file is kept as {}",
+            parsed.version,
+            HLS_CACHE_VERSION,
+            kept.display()
+        );
+    }
+    println!(
+        "✓ Loaded {} HLS cache entries from disk in {:.1}s",
+        parsed.tracks.len(),
+        started.elapsed().as_secs_f64()
+    );
+    if parsed.moved > 0 {
+        println!("✓ Found {} tracks in another cache directory", parsed.moved);
+    }
+    if !parsed.missing.is_empty() {
+        report_missing(&parsed);
+    }
+    let mut tracks = parsed.tracks;
+    let slugged = assign_slugs(&mut tracks);
+    if slugged > 0 {
+        save_hls_cache(dirs.primary(), &tracks)
+            .await
+            .map_err(|e| format!("Failed to save {}: {}", cache_file.display(), e))?;
+        println!("✓ Gave {} tracks a short link name", slugged);
+    }
+    Ok(tracks)
+}
+
+/// Lists the tracks left out of the library because their files are gone, which
+/// are dropped from hls_cache.json the next time it is saved.
+fn report_missing(parsed: &ParsedCache) {
+    let gone = |which: MissingFiles| {
+        parsed
+            .missing_files
+            .values()
+            .filter(|missing| **missing == which)
+            .count()
+    };
+    eprintln!(
+        "Warning: Skipped {} tracks whose files are missing ({} directories and {} playlists gone); they are left out of hls_cache.json from its next save",
+        parsed.missing.len(),
+        gone(MissingFiles::Directory),
+        gone(MissingFiles::Playlist)
+    );
+    let mut missing: Vec<(&String, &HlsSession)> = parsed.missing.iter().collect();
+    missing.sort_by(|(_, a), (_, b)| a.title.cmp(&b.title));
+    for (id, session) in missing.iter().take(MISSING_LISTED) {
+        let (what, path) = match parsed.missing_files.get(*id) {
+            Some(MissingFiles::Playlist) => ("playlist", &session.playlist_path),
+            _ => ("directory", &session.segments_dir),
+        };
+        eprintln!(
+            "  ✗ {} '{}': {} {} is missing",
+            id,
+            session.title,
+            what,
+            path.display()
+        );
+    }
+    if missing.len() > MISSING_LISTED {
+        eprintln!("  … and {} more", missing.len() - MISSING_LISTED);
+    }
+}
+
+/// Reads the library of another instance from its cache directory `dir`, e.g. a
+/// read-only network share. Its tracks are marked `read_only`.
+pub async fn load_overlay_library(dir: &Path) -> HashMap<String, HlsSession> {
+    // The recorded paths are the other instance's, so its tracks are found by name
+    let dirs = CacheDirs::new(dir.to_path_buf(), Vec::new());
+    let cache_file = dir.join("hls_cache.json");
+    if !cache_file.exists() {
+        return HashMap::new();
+    }
+    let parsed = match tokio::fs::read_to_string(&cache_file).await {
+        Ok(content) => match parse_hls_cache(&content, &dirs).await {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("Warning: Failed to parse {}: {}", cache_file.display(), e);
+                return HashMap::new();
+            }
+        },
+        Err(e) => {
+            eprintln!("Warning: Failed to read {}: {}", cache_file.display(), e);
+            return HashMap::new();
+        }
+    };
+    let mut cache_map = parsed.tracks;
+    for session in cache_map.values_mut() {
+        session.read_only = true;
+    }
+    // Only kept in memory; the other instance names its tracks itself
+    assign_slugs(&mut cache_map);
+    cache_map
+}
+
+/// The tracks of an hls_cache.json document, checked against the files on disk.
+pub struct ParsedCache {
+    /// Tracks whose files exist
+    pub tracks: HashMap<String, HlsSession>,
+    /// How many of them were found in another cache directory than recorded
+    pub moved: usize,
+    /// Tracks whose directory or playlist is gone, with the paths as recorded
+    pub missing: HashMap<String, HlsSession>,
+    /// What is gone of each missing track
+    pub missing_files: HashMap<String, MissingFiles>,
+    /// The schema version the document was written with
+    pub version: u32,
+}
+
+/// Which of a track's files are gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingFiles {
+    Directory,
+    Playlist,
+}
+
+/// How many tracks are checked against the disk at once when the library is read.
+const PARSE_CONCURRENCY: usize = 32;
+
+/// Skipped tracks listed by name at startup; the rest are only counted.
+const MISSING_LISTED: usize = 20;
+
+/// Reads the entries of an hls_cache.json document, finding moved tracks in `dirs`
+/// like `load_hls_cache`. Documents of older schema versions are migrated.
+pub async fn parse_hls_cache(
+    content: &str,
+    dirs: &CacheDirs,
+) -> Result<ParsedCache, Box<dyn std::error::Error + Send + Sync>> {
+    let (cache_data, version) = decode_hls_cache(content)?;
+    // A library on a network share takes a round trip per file, so tracks are
+    // checked side by side
+    let checked: Vec<(String, HlsSession, Result<bool, MissingFiles>)> =
+        futures_util::stream::iter(cache_data.entries)
+            .map(|entry| check_entry(entry, dirs))
+            .buffer_unordered(PARSE_CONCURRENCY)
+            .collect()
+            .await;
+
+    let mut parsed = ParsedCache {
+        tracks: HashMap::new(),
+        moved: 0,
+        missing: HashMap::new(),
+        missing_files: HashMap::new(),
+        version,
+    };
+    for (id, session, found) in checked {
+        match found {
+            Ok(moved) => {
+                parsed.moved += moved as usize;
+                parsed.tracks.insert(id, session);
+            }
+            Err(missing) => {
+                parsed.missing_files.insert(id.clone(), missing);
+                parsed.missing.insert(id, session);
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// The track of an hls_cache.json entry, and whether it was found in another cache
+/// directory than recorded, or which of its files are gone.
+async fn check_entry(
+    entry: HlsCacheEntry,
+    dirs: &CacheDirs,
+) -> (String, HlsSession, Result<bool, MissingFiles>) {
+    let mut segments_dir = PathBuf::from(&entry.segments_dir);
+    let mut playlist_path = PathBuf::from(&entry.playlist_path);
+    let mut moved = false;
+    if !exists(&segments_dir).await {
+        let found = match segments_dir.file_name() {
+            Some(name) => dirs.find(Path::new(name)),
+            None => None,
+        };
+        if let (Some(dir), Some(playlist)) = (found, playlist_path.file_name()) {
+            playlist_path = dir.join(playlist);
+            segments_dir = dir;
+            moved = true;
+        }
+    }
+    let found = if !exists(&segments_dir).await {
+        Err(MissingFiles::Directory)
+    } else if !exists(&playlist_path).await {
+        Err(MissingFiles::Playlist)
+    } else {
+        Ok(moved)
+    };
+
+    // Thumbnails from before their URLs named a hash are hashed once
+    let artwork_hash = match entry.artwork_hash {
+        None if entry.has_thumbnail => artwork_hash(&segments_dir).await,
+        artwork_hash => artwork_hash,
+    };
+    // Entries from before date_added was recorded fall back to when their playlist
+    // was written
+    let date_added = match entry.date_added {
+        Some(date_added) => date_added,
+        None => file_modified(&playlist_path).await,
+    };
+    let duration = match entry.duration {
+        Some(duration) => duration,
+        None => tokio::fs::read_to_string(&playlist_path)
+            .await
+            .map(|playlist| playlist_duration(&playlist))
+            .unwrap_or_default(),
+    };
+    let session = HlsSession {
+        id: entry.session_id,
+        slug: entry.slug,
+        title: entry.title,
+        artist: entry.artist,
+        album: entry.album,
+        origin_url: entry.origin_url,
+        // Ids used to be the origin hash
+        origin_hash: if entry.origin_hash.is_empty() {
+            entry.file_hash.clone()
+        } else {
+            entry.origin_hash
+        },
+        segments_dir,
+        playlist_path,
+        total_segments: entry.total_segments,
+        segment_duration: entry.segment_duration,
+        duration,
+        listen_count: entry.listen_count,
+        unique_listeners: entry.unique_listeners,
+        last_listen: None,
+        crossfade: entry.crossfade,
+        chapters: entry.chapters,
+        has_video: entry.has_video,
+        has_thumbnail: entry.has_thumbnail,
+        artwork_hash,
+        custom_artwork: entry.custom_artwork,
+        encrypted: entry.encrypted,
+        codec: entry.codec,
+        bitrate: entry.bitrate,
+        tempo_key: entry.tempo_key,
+        identification: entry.identification,
+        notes: entry.notes,
+        date_added,
+        pinned: entry.pinned,
+        visibility: entry.visibility,
+        read_only: false,
+    };
+    (entry.file_hash, session, found)
+}
+
+async fn exists(path: &Path) -> bool {
+    tokio::fs::try_exists(path).await.unwrap_or(false)
+}
+
+/// An entry of hls_cache.json as written, whether or not its files still exist.
+#[derive(Debug, Clone)]
+pub struct IndexedTrack {
+    pub id: String,
+    pub title: String,
+    pub segments_dir: PathBuf,
+}
+
+/// Every entry of hls_cache.json, including the ones `load_hls_cache` skips because
+/// their directory or playlist is gone. Unlike `load_hls_cache`, a missing or
+/// unreadable file is an error.
+pub async fn load_hls_cache_index(
+    cache_dir: &Path,
+) -> Result<Vec<IndexedTrack>, Box<dyn std::error::Error + Send + Sync>> {
+    let cache_file = cache_dir.join("hls_cache.json");
+    let content = tokio::fs::read_to_string(&cache_file)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", cache_file.display(), e))?;
+    let (cache_data, _) = decode_hls_cache(&content)
+        .map_err(|e| format!("Failed to parse {}: {}", cache_file.display(), e))?;
+    Ok(cache_data
+        .entries
+        .into_iter()
+        .map(|entry| IndexedTrack {
+            id: entry.file_hash,
+            title: entry.title,
+            segments_dir: PathBuf::from(entry.segments_dir),
+        })
+        .collect())
+}
+
+/// Unix time a file was last modified, or now if that can't be read.
+async fn file_modified(path: &Path) -> u64 {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(unix_timestamp, |age| age.as_secs())
+}
+
+pub async fn save_hls_cache(
+    cache_dir: &Path,
+    cache: &HashMap<String, HlsSession>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let json_content = encode_hls_cache(cache)?;
+    write_atomically(&cache_dir.join("hls_cache.json"), json_content).await?;
+    Ok(())
+}
+
+/// The hls_cache.json document for `cache`.
+fn encode_hls_cache(cache: &HashMap<String, HlsSession>) -> Result<String, serde_json::Error> {
+    let mut entries = Vec::new();
+
+    // Overlay tracks stay in their own library
+    for (file_hash, session) in cache.iter().filter(|(_, session)| !session.read_only) {
+        let entry = HlsCacheEntry {
+            file_hash: file_hash.clone(),
+            session_id: session.id.clone(),
+            slug: session.slug.clone(),
+            title: session.title.clone(),
+            artist: session.artist.clone(),
+            album: session.album.clone(),
+            origin_url: session.origin_url.clone(),
+            origin_hash: session.origin_hash.clone(),
+            segments_dir: session.segments_dir.to_string_lossy().to_string(),
+            playlist_path: session.playlist_path.to_string_lossy().to_string(),
+            total_segments: session.total_segments,
+            segment_duration: session.segment_duration,
+            duration: Some(session.duration),
+            listen_count: session.listen_count,
+            unique_listeners: session.unique_listeners,
+            crossfade: session.crossfade,
+            chapters: session.chapters.clone(),
+            has_video: session.has_video,
+            has_thumbnail: session.has_thumbnail,
+            artwork_hash: session.artwork_hash.clone(),
+            custom_artwork: session.custom_artwork,
+            encrypted: session.encrypted,
+            codec: session.codec,
+            bitrate: session.bitrate,
+            tempo_key: session.tempo_key.clone(),
+            identification: session.identification.clone(),
+            notes: session.notes.clone(),
+            date_added: Some(session.date_added),
+            pinned: session.pinned,
+            visibility: session.visibility,
+        };
+        entries.push(entry);
+    }
+
+    let cache_data = HlsCacheData {
+        version: HLS_CACHE_VERSION,
+        entries,
+    };
+    serde_json::to_string_pretty(&cache_data)
+}
+
+pub async fn load_collections(
+    cache_dir: &Path,
+) -> Result<HashMap<String, Collection>, Box<dyn std::error::Error + Send + Sync>> {
+    let collections_file = cache_dir.join("collections.json");
+    if !collections_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&collections_file).await?;
+    let data: CollectionsData = serde_json::from_str(&content)?;
+    Ok(data
+        .collections
+        .into_iter()
+        .map(|c| (c.id.clone(), c))
+        .collect())
+}
+
+pub async fn save_collections(
+    cache_dir: &Path,
+    collections: &HashMap<String, Collection>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut collections: Vec<Collection> = collections.values().cloned().collect();
+    collections.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let json_content = serde_json::to_string_pretty(&CollectionsData { collections })?;
+    tokio::fs::write(cache_dir.join("collections.json"), json_content).await?;
+
+    Ok(())
+}
+
+pub async fn load_queues(
+    cache_dir: &Path,
+) -> Result<HashMap<String, Vec<String>>, Box<dyn std::error::Error + Send + Sync>> {
+    let queues_file = cache_dir.join("queues.json");
+    if !queues_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&queues_file).await?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub async fn save_queues(
+    cache_dir: &Path,
+    queues: &HashMap<String, Vec<String>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let json_content = serde_json::to_string_pretty(queues)?;
+    tokio::fs::write(cache_dir.join("queues.json"), json_content).await?;
+
+    Ok(())
+}
+
+pub async fn load_devices(
+    cache_dir: &Path,
+) -> Result<HashMap<String, Device>, Box<dyn std::error::Error + Send + Sync>> {
+    let devices_file = cache_dir.join("devices.json");
+    if !devices_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&devices_file).await?;
+    let devices: Vec<Device> = serde_json::from_str(&content)?;
+    Ok(devices.into_iter().map(|d| (d.id.clone(), d)).collect())
+}
+
+pub async fn save_devices(
+    cache_dir: &Path,
+    devices: &HashMap<String, Device>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut devices: Vec<&Device> = devices.values().collect();
+    devices.sort_by_key(|d| d.registered_at);
+
+    let json_content = serde_json::to_string_pretty(&devices)?;
+    tokio::fs::write(cache_dir.join("devices.json"), json_content).await?;
+
+    Ok(())
+}
+
+/// Key grants that haven't expired yet.
+pub async fn load_key_grants(
+    cache_dir: &Path,
+) -> Result<HashMap<String, KeyGrant>, Box<dyn std::error::Error + Send + Sync>> {
+    let grants_file = cache_dir.join("key_grants.json");
+    if !grants_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&grants_file).await?;
+    let grants: Vec<KeyGrant> = serde_json::from_str(&content)?;
+    let now = unix_timestamp();
+    Ok(grants
+        .into_iter()
+        .filter(|g| g.expires_at > now)
+        .map(|g| (g.token_hash.clone(), g))
+        .collect())
+}
+
+pub async fn save_key_grants(
+    cache_dir: &Path,
+    grants: &HashMap<String, KeyGrant>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut grants: Vec<&KeyGrant> = grants.values().collect();
+    grants.sort_by_key(|g| g.created_at);
+
+    let json_content = serde_json::to_string_pretty(&grants)?;
+    tokio::fs::write(cache_dir.join("key_grants.json"), json_content).await?;
+
+    Ok(())
+}
+
+/// Share links that haven't expired yet.
+pub async fn load_share_links(
+    cache_dir: &Path,
+) -> Result<HashMap<String, ShareLink>, Box<dyn std::error::Error + Send + Sync>> {
+    let links_file = cache_dir.join("share_links.json");
+    if !links_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&links_file).await?;
+    let links: Vec<ShareLink> = serde_json::from_str(&content)?;
+    let now = unix_timestamp();
+    Ok(links
+        .into_iter()
+        .filter(|link| !link.is_expired(now))
+        .map(|link| (link.token_hash.clone(), link))
+        .collect())
+}
+
+pub async fn save_share_links(
+    cache_dir: &Path,
+    links: &HashMap<String, ShareLink>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut links: Vec<&ShareLink> = links.values().collect();
+    links.sort_by_key(|link| link.created_at);
+
+    let json_content = serde_json::to_string_pretty(&links)?;
+    tokio::fs::write(cache_dir.join("share_links.json"), json_content).await?;
+
+    Ok(())
+}
+
+pub async fn load_positions(
+    cache_dir: &Path,
+) -> Result<HashMap<String, HashMap<String, f64>>, Box<dyn std::error::Error + Send + Sync>> {
+    let positions_file = cache_dir.join("positions.json");
+    if !positions_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&positions_file).await?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub async fn save_positions(
+    cache_dir: &Path,
+    positions: &HashMap<String, HashMap<String, f64>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let json_content = serde_json::to_string_pretty(positions)?;
+    tokio::fs::write(cache_dir.join("positions.json"), json_content).await?;
+
+    Ok(())
+}
+
+pub async fn load_ratings(
+    cache_dir: &Path,
+) -> Result<HashMap<String, HashMap<String, u8>>, Box<dyn std::error::Error + Send + Sync>> {
+    let ratings_file = cache_dir.join("ratings.json");
+    if !ratings_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&ratings_file).await?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub async fn save_ratings(
+    cache_dir: &Path,
+    ratings: &HashMap<String, HashMap<String, u8>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let json_content = serde_json::to_string_pretty(ratings)?;
+    tokio::fs::write(cache_dir.join("ratings.json"), json_content).await?;
+
+    Ok(())
+}
+
+pub async fn load_migration(
+    cache_dir: &Path,
+) -> Result<Option<Migration>, Box<dyn std::error::Error + Send + Sync>> {
+    let migration_file = cache_dir.join("migration.json");
+    if !migration_file.exists() {
+        return Ok(None);
+    }
+
+    let content = tokio::fs::read_to_string(&migration_file).await?;
+    Ok(Some(serde_json::from_str(&content)?))
+}
+
+pub async fn save_migration(
+    cache_dir: &Path,
+    migration: &Migration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let json_content = serde_json::to_string_pretty(migration)?;
+    tokio::fs::write(cache_dir.join("migration.json"), json_content).await?;
+
+    Ok(())
+}
+
+/// Transcode jobs by id, unfinished ones included so they run again after a restart.
+pub async fn load_transcodes(
+    cache_dir: &Path,
+) -> Result<HashMap<String, TranscodeJob>, Box<dyn std::error::Error + Send + Sync>> {
+    let transcodes_file = cache_dir.join("transcodes.json");
+    if !transcodes_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&transcodes_file).await?;
+    let jobs: Vec<TranscodeJob> = serde_json::from_str(&content)?;
+    Ok(jobs.into_iter().map(|job| (job.id.clone(), job)).collect())
+}
+
+pub async fn save_transcodes(
+    cache_dir: &Path,
+    jobs: &HashMap<String, TranscodeJob>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut jobs: Vec<&TranscodeJob> = jobs.values().collect();
+    jobs.sort_by_key(|job| job.created_at);
+    let json_content = serde_json::to_string_pretty(&jobs)?;
+    write_atomically(&cache_dir.join("transcodes.json"), json_content).await?;
+
+    Ok(())
+}
+
+/// Finished download jobs, by id.
+pub async fn load_downloads(
+    cache_dir: &Path,
+) -> Result<HashMap<String, DownloadStatus>, Box<dyn std::error::Error + Send + Sync>> {
+    let downloads_file = cache_dir.join("downloads.json");
+    if !downloads_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&downloads_file).await?;
+    let jobs: Vec<DownloadStatus> = serde_json::from_str(&content)?;
+    Ok(jobs.into_iter().map(|job| (job.id.clone(), job)).collect())
+}
+
+/// Writes the finished jobs of `jobs`; running ones would be stale after a restart.
+pub async fn save_downloads(
+    cache_dir: &Path,
+    jobs: &HashMap<String, DownloadStatus>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut finished: Vec<&DownloadStatus> = jobs
+        .values()
+        .filter(|job| job.finished_at.is_some())
+        .collect();
+    finished.sort_by_key(|job| job.created_at);
+    let json_content = serde_json::to_string_pretty(&finished)?;
+    tokio::fs::write(cache_dir.join("downloads.json"), json_content).await?;
+
+    Ok(())
+}
+
+pub async fn load_source_checks(
+    cache_dir: &Path,
+) -> Result<HashMap<String, SourceCheck>, Box<dyn std::error::Error + Send + Sync>> {
+    let checks_file = cache_dir.join("source_checks.json");
+    if !checks_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&checks_file).await?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub async fn save_source_checks(
+    cache_dir: &Path,
+    checks: &HashMap<String, SourceCheck>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let json_content = serde_json::to_string_pretty(checks)?;
+    tokio::fs::write(cache_dir.join("source_checks.json"), json_content).await?;
+
+    Ok(())
+}
+
+pub async fn load_subscriptions(
+    cache_dir: &Path,
+) -> Result<HashMap<String, Subscription>, Box<dyn std::error::Error + Send + Sync>> {
+    let subscriptions_file = cache_dir.join("subscriptions.json");
+    if !subscriptions_file.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let content = tokio::fs::read_to_string(&subscriptions_file).await?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub async fn save_subscriptions(
+    cache_dir: &Path,
+    subscriptions: &HashMap<String, Subscription>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let json_content = serde_json::to_string_pretty(subscriptions)?;
+    tokio::fs::write(cache_dir.join("subscriptions.json"), json_content).await?;
+
+    Ok(())
+}
+
+pub async fn load_history(
+    cache_dir: &Path,
+) -> Result<Vec<PlayEvent>, Box<dyn std::error::Error + Send + Sync>> {
+    let history_file = cache_dir.join("history.jsonl");
+    if !history_file.exists() {
+        return Ok(Vec::new());
+    }
+
+    // A line cut short by a crash mid-append shouldn't lose the rest of the history
+    let content = tokio::fs::read_to_string(&history_file).await?;
+    let mut skipped = 0;
+    let events: Vec<PlayEvent> = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str(line).map_err(|_| skipped += 1).ok())
+        .collect();
+    if skipped > 0 {
+        eprintln!(
+            "Warning: Skipped {} unreadable lines in history.jsonl",
+            skipped
+        );
+    }
+    Ok(events)
+}
+
+/// Appends a listen to history.jsonl.
+pub async fn append_history(
+    cache_dir: &Path,
+    event: &PlayEvent,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut line = serde_json::to_string(event)?;
+    line.push('\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(cache_dir.join("history.jsonl"))
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Rewrites history.jsonl with `events`, e.g. after stripping listener IDs.
+pub async fn save_history(
+    cache_dir: &Path,
+    events: &[PlayEvent],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut content = String::new();
+    for event in events {
+        content.push_str(&serde_json::to_string(event)?);
+        content.push('\n');
+    }
+    tokio::fs::write(cache_dir.join("history.jsonl"), content).await?;
+
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! systemd integration: listening on a socket passed by socket activation, and
+//! reporting readiness and watchdog pings over `$NOTIFY_SOCKET`. Both are no-ops when
+//! the server isn't started by systemd.
+
+use std::time::Duration;
+
+/// The first file descriptor systemd passes (`SD_LISTEN_FDS_START`).
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// How many sockets `LISTEN_PID` and `LISTEN_FDS` say systemd passed to this process.
+#[cfg(unix)]
+fn passed_fds() -> usize {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    if !for_us {
+        return 0;
+    }
+    std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The TCP socket systemd passed with socket activation, if any. It stays open while
+/// the service restarts, so connections made in between wait instead of being refused.
+#[cfg(unix)]
+pub(crate) fn activated_listener() -> std::io::Result<Option<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let count = passed_fds();
+    if count == 0 {
+        return Ok(None);
+    }
+    if count > 1 {
+        eprintln!(
+            "Warning: systemd passed {} sockets; only the first is used",
+            count
+        );
+    }
+    // Safety: systemd hands descriptors from 3 up to this process, which owns them
+    // from now on
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn activated_listener() -> std::io::Result<Option<std::net::TcpListener>> {
+    Ok(None)
+}
+
+/// Sends `state` (e.g. `READY=1`) to the service manager; does nothing outside systemd.
+#[cfg(unix)]
+pub(crate) fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(e) = sent {
+        eprintln!("Warning: Failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn notify(_state: &str) {}
+
+/// How often systemd expects a watchdog ping, when `WatchdogSec=` is set for the service.
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    let for_us = match std::env::var("WATCHDOG_PID") {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        Err(_) => true,
+    };
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (for_us && usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Pings the watchdog at half its timeout for as long as the runtime is responsive.
+pub(crate) async fn run_watchdog(timeout: Duration) {
+    let mut ticks = tokio::time::interval(timeout / 2);
+    loop {
+        ticks.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
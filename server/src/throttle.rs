@@ -0,0 +1,129 @@
+//! Egress rate limits for HLS segments: one bucket shared by every listener and
+//! one per client IP.
+
+use axum::body::{Body, Bytes};
+use futures_util::{stream, StreamExt};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bodies are released in pieces of at most this size, so a throttled client
+/// receives a steady trickle instead of bursts.
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Client buckets idle for this long are forgotten once the map grows.
+const CLIENT_IDLE: Duration = Duration::from_secs(60);
+const MAX_IDLE_CLIENTS: usize = 1024;
+
+/// A token bucket allowing a one second burst. Takers may overdraw it; they then
+/// wait until the debt is paid back, which queues concurrent takers fairly.
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        TokenBucket {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// How long to wait before sending `bytes`.
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.rate) - bytes as f64;
+        state.updated = now;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+
+    fn last_used(&self) -> Instant {
+        self.state.lock().unwrap().updated
+    }
+}
+
+pub(crate) struct Throttle {
+    global: Option<Arc<TokenBucket>>,
+    per_client: Option<u64>,
+    clients: Mutex<HashMap<IpAddr, Arc<TokenBucket>>>,
+}
+
+impl Throttle {
+    /// Rates are in bytes per second; `None` leaves that limit off.
+    pub(crate) fn new(global: Option<u64>, per_client: Option<u64>) -> Self {
+        Throttle {
+            global: global.map(|rate| Arc::new(TokenBucket::new(rate))),
+            per_client,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wraps a segment body so it is sent no faster than the limits allow. Without a
+    /// known client address only the global limit applies.
+    pub(crate) fn limit(&self, client: Option<IpAddr>, body: Body) -> Body {
+        let mut buckets: Vec<Arc<TokenBucket>> = self.global.iter().cloned().collect();
+        if let (Some(rate), Some(client)) = (self.per_client, client) {
+            buckets.push(self.client_bucket(client, rate));
+        }
+        if buckets.is_empty() {
+            return body;
+        }
+
+        let chunks = body.into_data_stream().flat_map(|chunk| {
+            let pieces: Vec<Result<Bytes, axum::Error>> = match chunk {
+                Ok(bytes) => (0..bytes.len())
+                    .step_by(CHUNK_SIZE)
+                    .map(|start| Ok(bytes.slice(start..(start + CHUNK_SIZE).min(bytes.len()))))
+                    .collect(),
+                Err(e) => vec![Err(e)],
+            };
+            stream::iter(pieces)
+        });
+        let throttled = chunks.then(move |chunk| {
+            let wait = match &chunk {
+                Ok(bytes) => buckets
+                    .iter()
+                    .map(|bucket| bucket.take(bytes.len()))
+                    .max()
+                    .unwrap_or_default(),
+                Err(_) => Duration::ZERO,
+            };
+            async move {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+                chunk
+            }
+        });
+        Body::from_stream(throttled)
+    }
+
+    fn client_bucket(&self, client: IpAddr, rate: u64) -> Arc<TokenBucket> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_IDLE_CLIENTS && !clients.contains_key(&client) {
+            let now = Instant::now();
+            clients.retain(|_, bucket| now.duration_since(bucket.last_used()) < CLIENT_IDLE);
+        }
+        Arc::clone(
+            clients
+                .entry(client)
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate))),
+        )
+    }
+}
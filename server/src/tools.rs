@@ -0,0 +1,108 @@
+//! Versions of the external programs the server runs, checked at startup.
+
+use crate::process::{ProcessError, ProcessLimits};
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Oldest FFmpeg release the server runs with, as major and minor version.
+pub(crate) const MIN_FFMPEG: (u32, u32) = (4, 0);
+
+/// Oldest yt-dlp release downloads work with: the first with `--js-runtimes`, which
+/// every download passes.
+pub(crate) const MIN_YTDLP: (u32, u32, u32) = (2025, 11, 12);
+
+/// How long `yt-dlp -U` may take, downloading the new release included.
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// yt-dlp's version before and after `yt-dlp -U`.
+#[derive(Debug, Serialize)]
+pub(crate) struct YtdlpUpdate {
+    pub previous_version: Option<String>,
+    pub version: Option<String>,
+    pub updated: bool,
+    /// What yt-dlp printed, e.g. the release notes link
+    pub output: String,
+}
+
+/// First line `program` prints for `flag`, or `None` if it can't be run.
+pub(crate) async fn version_line(program: &Path, flag: &str) -> Option<String> {
+    let output = Command::new(program).arg(flag).output().await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Some(stdout.lines().next().unwrap_or_default().trim().to_string())
+}
+
+/// Major and minor version from the first line of `ffmpeg -version`, e.g.
+/// "ffmpeg version 6.1.1-3ubuntu5 Copyright ..." or "ffmpeg version n7.0". Builds
+/// from git ("N-113000-g...") have none.
+pub(crate) fn ffmpeg_version(line: &str) -> Option<(u32, u32)> {
+    let version = line.strip_prefix("ffmpeg version ")?;
+    let version = version.strip_prefix('n').unwrap_or(version);
+    let mut numbers = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(str::parse::<u32>);
+    let major = numbers.next()?.ok()?;
+    let minor = numbers.next().and_then(Result::ok).unwrap_or(0);
+    Some((major, minor))
+}
+
+/// The release date yt-dlp names its versions by, e.g. "2025.11.12"; nightly builds
+/// add the time ("2025.11.12.232811").
+pub(crate) fn ytdlp_version(line: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = line.trim().split('.').map(str::parse::<u32>);
+    let year = parts.next()?.ok()?;
+    let month = parts.next()?.ok()?;
+    let day = parts.next()?.ok()?;
+    Some((year, month, day))
+}
+
+/// Updates yt-dlp to its latest release with `yt-dlp -U`, which replaces the binary in
+/// place. Installs from pip or a package manager refuse, and the error says how to
+/// update them instead.
+pub(crate) async fn update_ytdlp(
+    ytdlp: &Path,
+    limits: &ProcessLimits,
+) -> Result<YtdlpUpdate, String> {
+    let previous_version = version_line(ytdlp, "--version").await;
+    let output = limits
+        .at_most(Some(UPDATE_TIMEOUT))
+        .output(Command::new(ytdlp).arg("-U"))
+        .await;
+    let output = match output {
+        Ok(output) => output,
+        Err(ProcessError::Io(e)) => {
+            return Err(format!("Failed to run {}: {}", ytdlp.display(), e))
+        }
+        Err(ProcessError::TimedOut(timeout)) => {
+            return Err(format!("yt-dlp -U took longer than {}s", timeout.as_secs()))
+        }
+    };
+    let printed = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    )
+    .trim()
+    .to_string();
+    if !output.status.success() {
+        return Err(printed
+            .lines()
+            .rev()
+            .find_map(|line| line.strip_prefix("ERROR:"))
+            .map(str::trim)
+            .unwrap_or("yt-dlp -U failed")
+            .to_string());
+    }
+
+    let version = version_line(ytdlp, "--version").await;
+    Ok(YtdlpUpdate {
+        updated: version.is_some() && version != previous_version,
+        previous_version,
+        version,
+        output: printed,
+    })
+}
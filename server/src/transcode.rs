@@ -0,0 +1,654 @@
+//! ffmpeg based HLS conversion and audio analysis.
+
+use crate::config::IoClass;
+use crate::content_hash::content_hash;
+use crate::downloader::Priority;
+use crate::process::{within, ProcessLimits};
+use crate::storage::{
+    generate_url_hash, playlist_duration, unix_timestamp, AudioCodec, CrossfadeHints, HlsSession,
+    Visibility, DEFAULT_BITRATE,
+};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::fs::create_dir_all;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{oneshot, watch};
+
+/// How ffmpeg is started for transcode jobs.
+#[derive(Debug, Clone)]
+pub struct TranscodeOptions {
+    /// The ffmpeg binary
+    pub ffmpeg_path: PathBuf,
+    /// Extra arguments placed before `-i`, e.g. `-hwaccel auto`
+    pub input_args: Vec<String>,
+    /// CPU niceness to run ffmpeg with (through `nice`)
+    pub nice: Option<i32>,
+    /// IO scheduling class to run ffmpeg with (through `ionice`)
+    pub ionice: Option<IoClass>,
+    /// Timeout and resource limits ffmpeg runs under
+    pub limits: ProcessLimits,
+    /// Only transcode this part of the input, as start and end in seconds
+    pub clip: Option<(f64, f64)>,
+    /// Write one .ts file per rendition and a byte-range playlist
+    pub single_file: bool,
+    /// AES-128 encrypt segments with the track's key
+    pub encrypt: bool,
+    pub audio: AudioFormat,
+}
+
+/// Codec, bitrate and segment length of a track's audio rendition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub codec: AudioCodec,
+    /// kbit/s
+    pub bitrate: u32,
+    /// Target segment length in seconds
+    pub segment_duration: u32,
+}
+
+impl Default for AudioFormat {
+    fn default() -> Self {
+        AudioFormat {
+            codec: AudioCodec::Aac,
+            bitrate: DEFAULT_BITRATE,
+            segment_duration: 10,
+        }
+    }
+}
+
+impl AudioFormat {
+    /// The format `session` was converted with.
+    pub fn of(session: &HlsSession) -> Self {
+        AudioFormat {
+            codec: session.codec,
+            bitrate: session.bitrate,
+            segment_duration: session.segment_duration.round().max(1.0) as u32,
+        }
+    }
+
+    fn encoder(&self) -> &'static str {
+        match self.codec {
+            AudioCodec::Aac => "aac",
+            AudioCodec::Mp3 => "libmp3lame",
+        }
+    }
+}
+
+impl Default for TranscodeOptions {
+    fn default() -> Self {
+        TranscodeOptions {
+            ffmpeg_path: PathBuf::from("ffmpeg"),
+            input_args: Vec::new(),
+            nice: None,
+            ionice: None,
+            limits: ProcessLimits::default(),
+            clip: None,
+            single_file: false,
+            encrypt: false,
+            audio: AudioFormat::default(),
+        }
+    }
+}
+
+impl TranscodeOptions {
+    /// An ffmpeg command for `file_path`, wrapped in `ionice`/`nice` as configured,
+    /// with the input arguments already added.
+    pub(crate) fn ffmpeg(&self, file_path: &Path) -> Command {
+        let mut wrapper: Vec<String> = Vec::new();
+        if let Some(class) = self.ionice {
+            wrapper.extend(["ionice".into(), "-c".into(), class.number().to_string()]);
+        }
+        if let Some(nice) = self.nice {
+            wrapper.extend(["nice".into(), "-n".into(), nice.to_string()]);
+        }
+
+        let mut command = match wrapper.split_first() {
+            Some((program, args)) => {
+                let mut command = Command::new(program);
+                command.args(args).arg(&self.ffmpeg_path);
+                command
+            }
+            None => Command::new(&self.ffmpeg_path),
+        };
+        command.args(&self.input_args);
+        if let Some((start, end)) = self.clip {
+            command
+                .arg("-ss")
+                .arg(start.to_string())
+                .arg("-to")
+                .arg(end.to_string());
+        }
+        command.arg("-i").arg(file_path);
+        command
+    }
+
+    /// HLS muxer arguments writing numbered segments after `pattern` (e.g. `%03d.ts`),
+    /// or everything into `single` in single-file mode.
+    fn segment_args(&self, segments_dir: &Path, pattern: &str, single: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.single_file {
+            args.extend(["-hls_flags".to_string(), "single_file".to_string()]);
+        }
+        let name = if self.single_file { single } else { pattern };
+        args.extend([
+            "-hls_segment_filename".to_string(),
+            segments_dir.join(name).display().to_string(),
+        ]);
+        args
+    }
+
+    /// HLS muxer arguments encrypting segments with the key in `segments_dir`, which
+    /// is created on first use so the audio and video renditions share it.
+    async fn encryption_args(
+        &self,
+        segments_dir: &Path,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        if !self.encrypt {
+            return Ok(Vec::new());
+        }
+
+        let key_path = segments_dir.join(KEY_FILE);
+        if !key_path.exists() {
+            tokio::fs::write(&key_path, rand::random::<[u8; 16]>()).await?;
+        }
+        // Key URI as written into the playlist, then where ffmpeg reads the key from
+        let info_path = segments_dir.join(KEY_INFO_FILE);
+        tokio::fs::write(
+            &info_path,
+            format!("{}\n{}\n", KEY_FILE, key_path.display()),
+        )
+        .await?;
+        Ok(vec![
+            "-hls_key_info_file".to_string(),
+            info_path.display().to_string(),
+        ])
+    }
+
+    /// Length of the clipped part; ffmpeg still reports the whole input's duration.
+    fn clip_duration(&self) -> Option<f64> {
+        self.clip.map(|(start, end)| (end - start).max(0.0))
+    }
+}
+
+/// A fixed number of ffmpeg slots, handed out by download priority and then in
+/// arrival order.
+#[derive(Clone)]
+pub struct TranscodeSlots {
+    queue: Arc<Mutex<SlotQueue>>,
+}
+
+struct SlotQueue {
+    free: usize,
+    waiting: BinaryHeap<SlotWaiter>,
+    next_seq: u64,
+}
+
+struct SlotWaiter {
+    priority: Priority,
+    seq: u64,
+    slot_tx: oneshot::Sender<TranscodeSlot>,
+}
+
+impl Ord for SlotWaiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Max-heap: higher priority first, then whoever has waited longest
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for SlotWaiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SlotWaiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SlotWaiter {}
+
+/// A held transcode slot; dropping it passes the slot to the next waiter.
+pub struct TranscodeSlot {
+    queue: Arc<Mutex<SlotQueue>>,
+}
+
+impl TranscodeSlots {
+    pub fn new(slots: usize) -> Self {
+        TranscodeSlots {
+            queue: Arc::new(Mutex::new(SlotQueue {
+                free: slots,
+                waiting: BinaryHeap::new(),
+                next_seq: 0,
+            })),
+        }
+    }
+
+    /// Waits for a free slot. Waiters with a higher priority are served first.
+    pub async fn acquire(
+        &self,
+        priority: Priority,
+    ) -> Result<TranscodeSlot, Box<dyn std::error::Error + Send + Sync>> {
+        let slot_rx = {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.free > 0 && queue.waiting.is_empty() {
+                queue.free -= 1;
+                return Ok(TranscodeSlot {
+                    queue: Arc::clone(&self.queue),
+                });
+            }
+            let (slot_tx, slot_rx) = oneshot::channel();
+            let seq = queue.next_seq;
+            queue.next_seq += 1;
+            queue.waiting.push(SlotWaiter {
+                priority,
+                seq,
+                slot_tx,
+            });
+            slot_rx
+        };
+        Ok(slot_rx.await?)
+    }
+}
+
+impl Drop for TranscodeSlot {
+    fn drop(&mut self) {
+        let mut slot = TranscodeSlot {
+            queue: Arc::clone(&self.queue),
+        };
+        loop {
+            let waiter = {
+                let mut queue = self.queue.lock().unwrap();
+                match queue.waiting.pop() {
+                    Some(waiter) => waiter,
+                    None => {
+                        queue.free += 1;
+                        // The slot went back to the pool, it must not be released twice
+                        std::mem::forget(slot);
+                        return;
+                    }
+                }
+            };
+            // A waiter that gave up hands the slot back; a slot it never received is
+            // released again when the channel drops it
+            match waiter.slot_tx.send(slot) {
+                Ok(()) => return,
+                Err(returned) => slot = returned,
+            }
+        }
+    }
+}
+
+/// Converts `file_path` into HLS segments under `cache_dir/session_id`. When `progress`
+/// is given it receives the percentage of the input converted so far.
+pub async fn create_hls_segments(
+    file_path: &Path,
+    cache_dir: &Path,
+    session_id: &str,
+    title: &str,
+    origin_url: &str,
+    options: &TranscodeOptions,
+    progress: Option<&watch::Sender<f64>>,
+) -> Result<HlsSession, Box<dyn std::error::Error + Send + Sync>> {
+    let segments_dir = cache_dir.join(session_id);
+    create_dir_all(&segments_dir).await?;
+
+    let playlist_path = segments_dir.join("playlist.m3u8");
+    let audio = options.audio;
+
+    let mut command = options.ffmpeg(file_path);
+    command.args([
+        "-vn",
+        "-c:a",
+        audio.encoder(),
+        "-b:a",
+        &format!("{}k", audio.bitrate),
+        "-hls_time",
+        &audio.segment_duration.to_string(),
+        "-hls_list_size",
+        "0",
+    ]);
+    command.args(options.segment_args(&segments_dir, "%03d.ts", "audio.ts"));
+    command.args(options.encryption_args(&segments_dir).await?);
+    run_with_progress(
+        command,
+        &playlist_path,
+        options.clip_duration(),
+        &options.limits,
+        progress,
+    )
+    .await?;
+    let _ = tokio::fs::remove_file(segments_dir.join(KEY_INFO_FILE)).await;
+
+    converted_session(segments_dir, session_id, title, origin_url, options).await
+}
+
+/// The session of a track just converted into `segments_dir` with `options`, read
+/// from its playlist.
+pub(crate) async fn converted_session(
+    segments_dir: PathBuf,
+    session_id: &str,
+    title: &str,
+    origin_url: &str,
+    options: &TranscodeOptions,
+) -> Result<HlsSession, Box<dyn std::error::Error + Send + Sync>> {
+    let playlist_path = segments_dir.join("playlist.m3u8");
+    let audio = options.audio;
+    let playlist_content = tokio::fs::read_to_string(&playlist_path).await?;
+    let total_segments = playlist_content
+        .lines()
+        .filter(|line| line.ends_with(".ts"))
+        .count() as u32;
+
+    Ok(HlsSession {
+        id: session_id.to_string(),
+        slug: String::new(),
+        title: title.to_string(),
+        artist: None,
+        album: None,
+        origin_url: origin_url.to_string(),
+        origin_hash: if origin_url.is_empty() {
+            String::new()
+        } else {
+            generate_url_hash(origin_url)
+        },
+        segments_dir,
+        playlist_path,
+        total_segments,
+        segment_duration: audio.segment_duration as f32,
+        duration: playlist_duration(&playlist_content),
+        listen_count: 0,
+        unique_listeners: 0,
+        last_listen: None,
+        crossfade: None,
+        chapters: Vec::new(),
+        has_video: false,
+        has_thumbnail: false,
+        artwork_hash: None,
+        custom_artwork: false,
+        encrypted: options.encrypt,
+        codec: audio.codec,
+        bitrate: audio.bitrate,
+        tempo_key: None,
+        identification: None,
+        notes: Vec::new(),
+        date_added: unix_timestamp(),
+        pinned: false,
+        visibility: Visibility::Public,
+        read_only: false,
+    })
+}
+
+/// File name of a track's AES-128 key inside its segments directory, and the URI the
+/// playlist points players to; only the authenticated key endpoint serves it.
+pub const KEY_FILE: &str = "key";
+const KEY_INFO_FILE: &str = "key.info";
+
+/// File name of the optional video rendition inside a track's segments directory.
+pub const VIDEO_PLAYLIST: &str = "video.m3u8";
+
+/// Converts `file_path` into a 720p H.264 HLS rendition next to the track's audio
+/// segments, as `video.m3u8` with `video_NNN.ts` segments.
+pub async fn create_video_hls(
+    file_path: &Path,
+    segments_dir: &Path,
+    options: &TranscodeOptions,
+    progress: Option<&watch::Sender<f64>>,
+) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let playlist_path = segments_dir.join(VIDEO_PLAYLIST);
+
+    let mut command = options.ffmpeg(file_path);
+    command.args([
+        "-map",
+        "0:v:0",
+        "-map",
+        "0:a:0",
+        "-vf",
+        "scale=-2:'min(720,ih)'",
+        "-c:v",
+        "libx264",
+        "-preset",
+        "veryfast",
+        "-crf",
+        "23",
+        "-c:a",
+        "aac",
+        "-b:a",
+        "128k",
+        "-hls_time",
+        "6",
+        "-hls_list_size",
+        "0",
+    ]);
+    command.args(options.segment_args(segments_dir, "video_%03d.ts", "video.ts"));
+    command.args(options.encryption_args(segments_dir).await?);
+    run_with_progress(
+        command,
+        &playlist_path,
+        options.clip_duration(),
+        &options.limits,
+        progress,
+    )
+    .await?;
+    let _ = tokio::fs::remove_file(segments_dir.join(KEY_INFO_FILE)).await;
+
+    Ok(playlist_path)
+}
+
+/// Thumbnail variants kept per track, by name and maximum width in pixels.
+pub const THUMBNAIL_SIZES: [(&str, u32); 3] = [("small", 120), ("medium", 360), ("large", 720)];
+
+/// File name of a thumbnail variant inside a track's segments directory.
+pub fn thumbnail_file(size: &str) -> String {
+    format!("thumb_{}.jpg", size)
+}
+
+/// Scales the source thumbnail `image` into JPEG variants of every size in
+/// `THUMBNAIL_SIZES` next to the track's segments. Smaller images aren't upscaled.
+/// Returns the thumbnails' content hash.
+pub async fn create_thumbnails(
+    image: &Path,
+    segments_dir: &Path,
+    options: &TranscodeOptions,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    // Input arguments and clips are meant for the audio, not the image
+    let options = TranscodeOptions {
+        input_args: Vec::new(),
+        clip: None,
+        ..options.clone()
+    };
+    for (size, width) in THUMBNAIL_SIZES {
+        let mut command = options.ffmpeg(image);
+        command
+            .args([
+                "-hide_banner",
+                "-y",
+                "-vf",
+                &format!("scale='min({},iw)':-2", width),
+                "-frames:v",
+                "1",
+                "-q:v",
+                "3",
+            ])
+            .arg(segments_dir.join(thumbnail_file(size)));
+        let output = options.limits.output(&mut command).await?;
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("FFmpeg error: {}", error).into());
+        }
+    }
+
+    artwork_hash(segments_dir)
+        .await
+        .ok_or_else(|| "Thumbnails were not written".into())
+}
+
+/// One content hash over the thumbnails in every size, or `None` if any is missing.
+pub(crate) async fn artwork_hash(segments_dir: &Path) -> Option<String> {
+    let mut data = Vec::new();
+    for (size, _) in THUMBNAIL_SIZES {
+        data.extend(
+            tokio::fs::read(segments_dir.join(thumbnail_file(size)))
+                .await
+                .ok()?,
+        );
+    }
+    Some(content_hash(&data))
+}
+
+/// Runs an ffmpeg command writing to `output` to completion. When `progress` is given
+/// it receives the percentage of the input (or of `clip_duration`) converted so far.
+async fn run_with_progress(
+    mut command: Command,
+    output: &Path,
+    clip_duration: Option<f64>,
+    limits: &ProcessLimits,
+    progress: Option<&watch::Sender<f64>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut child = limits.spawn(
+        command
+            .args(["-progress", "pipe:1", "-nostats"])
+            .arg(output)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+
+    // Progress comes as key=value lines on stdout; the input duration needed to turn
+    // it into a percentage is only printed on stderr, so both are read side by side
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+    let (mut stdout_open, mut stderr_open) = (true, true);
+    let mut duration: Option<f64> = clip_duration;
+    let mut log = String::new();
+
+    let timeout = child.timeout();
+    let status = within(timeout, async {
+        while stdout_open || stderr_open {
+            tokio::select! {
+                line = stdout.next_line(), if stdout_open => match line? {
+                    Some(line) => {
+                        let out_time = line.strip_prefix("out_time=").and_then(parse_ffmpeg_time);
+                        if let (Some(out_time), Some(duration), Some(progress)) =
+                            (out_time, duration, progress)
+                        {
+                            progress.send_replace((out_time / duration * 100.0).clamp(0.0, 100.0));
+                        }
+                    }
+                    None => stdout_open = false,
+                },
+                line = stderr.next_line(), if stderr_open => match line? {
+                    Some(line) => {
+                        duration = duration.or_else(|| parse_duration_line(&line));
+                        log.push_str(&line);
+                        log.push('\n');
+                    }
+                    None => stderr_open = false,
+                },
+            }
+        }
+        child.wait().await
+    })
+    .await
+    .map_err(|e| format!("FFmpeg {}", e))??;
+
+    if !status.success() {
+        return Err(format!("FFmpeg error: {}", log).into());
+    }
+
+    Ok(())
+}
+
+/// Parses an ffmpeg "HH:MM:SS.xx" timestamp into seconds.
+pub fn parse_ffmpeg_time(value: &str) -> Option<f64> {
+    let mut parts = value.trim().split(':');
+    let hours: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// Reads the input length from ffmpeg's "  Duration: 00:03:25.12, start: ..." line.
+fn parse_duration_line(line: &str) -> Option<f64> {
+    let rest = line.trim().strip_prefix("Duration:")?;
+    parse_ffmpeg_time(rest.split(',').next()?).filter(|duration| *duration > 0.0)
+}
+
+/// The length of `file_path` in seconds, from ffmpeg's description of the input.
+pub async fn probe_duration(file_path: &Path, options: &TranscodeOptions) -> Option<f64> {
+    let output = options
+        .limits
+        .output(options.ffmpeg(file_path).arg("-hide_banner"))
+        .await
+        .ok()?;
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .find_map(parse_duration_line)
+}
+
+/// Finds leading and trailing silence with ffmpeg's silencedetect filter so players
+/// (and the radio stream) know where a track can be faded into the next one.
+pub async fn analyze_crossfade(
+    file_path: &Path,
+    options: &TranscodeOptions,
+) -> Result<CrossfadeHints, Box<dyn std::error::Error + Send + Sync>> {
+    let output = options
+        .limits
+        .output(options.ffmpeg(file_path).args([
+            "-hide_banner",
+            "-af",
+            "silencedetect=noise=-45dB:d=0.3",
+            "-f",
+            "null",
+            "-",
+        ]))
+        .await?;
+
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("FFmpeg error: {}", error).into());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let duration = options
+        .clip_duration()
+        .or_else(|| stderr.lines().find_map(parse_duration_line))
+        .ok_or("Could not determine track duration")?;
+
+    // Collect (start, end) pairs of silent ranges
+    let mut silences: Vec<(f64, f64)> = Vec::new();
+    for line in stderr.lines() {
+        if let Some((_, value)) = line.split_once("silence_start: ") {
+            if let Ok(start) = value.trim().parse::<f64>() {
+                silences.push((start, duration));
+            }
+        } else if let Some((_, value)) = line.split_once("silence_end: ") {
+            let end = value.split('|').next().unwrap_or("").trim().parse::<f64>();
+            if let (Ok(end), Some(last)) = (end, silences.last_mut()) {
+                last.1 = end;
+            }
+        }
+    }
+
+    let fade_in_end = match silences.first() {
+        Some((start, end)) if *start <= 0.1 => *end,
+        _ => 0.0,
+    };
+    let fade_out_start = match silences.last() {
+        Some((start, end)) if *end >= duration - 0.1 && *start > fade_in_end => *start,
+        _ => duration,
+    };
+
+    Ok(CrossfadeHints {
+        fade_in_end,
+        fade_out_start,
+        duration,
+    })
+}
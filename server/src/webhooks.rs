@@ -0,0 +1,60 @@
+//! Signed webhook delivery for library and download events.
+
+use crate::notifications::Notifications;
+use crate::storage::unix_timestamp;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+
+pub struct Webhooks {
+    pub urls: Vec<String>,
+    pub secret: Option<String>,
+    pub client: reqwest::Client,
+    /// Pushes the chosen events to ntfy or Gotify as well
+    pub notifications: Notifications,
+}
+
+impl Webhooks {
+    /// Posts the event to every configured URL in the background.
+    pub fn emit(&self, event: &str, data: serde_json::Value) {
+        self.notifications.send(event, &data);
+        if self.urls.is_empty() {
+            return;
+        }
+
+        let body = serde_json::json!({
+            "event": event,
+            "timestamp": unix_timestamp(),
+            "data": data,
+        })
+        .to_string();
+
+        let signature = self.secret.as_ref().map(|secret| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC accepts keys of any length");
+            mac.update(body.as_bytes());
+            format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+        });
+
+        for url in &self.urls {
+            let mut request = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .header("X-Music-Lib-Event", event)
+                .timeout(Duration::from_secs(10))
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header("X-Music-Lib-Signature", signature);
+            }
+
+            let url = url.clone();
+            tokio::spawn(async move {
+                match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Warning: Webhook {} failed: {}", url, e),
+                }
+            });
+        }
+    }
+}
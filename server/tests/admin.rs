@@ -0,0 +1,96 @@
+mod common;
+
+use common::TestServer;
+use reqwest::header::AUTHORIZATION;
+use reqwest::StatusCode;
+use serde_json::json;
+
+const OWNER_TOKEN: &str = "owner-secret";
+
+#[tokio::test]
+async fn only_the_owner_corrects_listen_counts() {
+    let server = TestServer::start_with(&["--owner-token", OWNER_TOKEN]).await;
+    let url = "https://music.example/watch?v=looped";
+    server.download(url).await;
+    let track = server.track(url).await;
+    let path = format!("/api/tracks/{}/listen_count", track["id"].as_str().unwrap());
+    let listen_count = json!({ "listen_count": 12 });
+
+    let guest = server.client.patch(server.url(&path)).json(&listen_count);
+    let guest = guest.send().await.expect("request");
+    assert_eq!(guest.status(), StatusCode::UNAUTHORIZED);
+
+    let owner = server
+        .client
+        .patch(server.url(&path))
+        .header(AUTHORIZATION, format!("Bearer {}", OWNER_TOKEN))
+        .json(&listen_count);
+    assert_eq!(
+        owner.send().await.expect("request").status(),
+        StatusCode::OK
+    );
+    assert_eq!(server.track(url).await["listen_count"], 12);
+}
+
+#[tokio::test]
+async fn without_an_owner_token_nobody_corrects_listen_counts() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=unguarded";
+    server.download(url).await;
+    let track = server.track(url).await;
+    let path = format!("/api/tracks/{}/listen_count", track["id"].as_str().unwrap());
+
+    let response = server
+        .client
+        .patch(server.url(&path))
+        .json(&json!({ "listen_count": 12 }))
+        .send()
+        .await
+        .expect("request");
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn admin_routes_need_the_owner_token() {
+    let server = TestServer::start_with(&["--owner-token", OWNER_TOKEN]).await;
+    let routes = [
+        "/api/admin/connections",
+        "/api/admin/backups",
+        "/api/admin/tasks",
+        "/api/admin/migration",
+    ];
+    for path in routes {
+        let guest = server.get(path).await;
+        assert_eq!(guest.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        let owner = server
+            .client
+            .get(server.url(path))
+            .header(AUTHORIZATION, format!("Bearer {}", OWNER_TOKEN))
+            .send()
+            .await
+            .expect("request");
+        // Past the gate; backups without a target answer 404
+        assert_ne!(owner.status(), StatusCode::UNAUTHORIZED, "{}", path);
+    }
+
+    let changes = [
+        "/api/admin/backups",
+        "/api/admin/migration",
+        "/api/admin/merge",
+        "/api/admin/ytdlp/update",
+        "/api/admin/tasks/backup/run",
+    ];
+    for path in changes {
+        let guest = server.post(path, json!({})).await;
+        assert_eq!(guest.status(), StatusCode::UNAUTHORIZED, "{}", path);
+    }
+    let token = format!("/api/admin/connections?token={}", OWNER_TOKEN);
+    assert_eq!(server.get(&token).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn without_an_owner_token_admin_routes_are_closed() {
+    let server = TestServer::start().await;
+    let response = server.get("/api/admin/connections").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::TestServer;
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn track_list_is_cached_until_the_library_changes() {
+    let server = TestServer::start().await;
+    server
+        .download("https://music.example/watch?v=etag-one")
+        .await;
+
+    let response = server.get("/api/tracks").await;
+    let etag = response.headers()[ETAG].clone();
+    let unchanged = server
+        .client
+        .get(server.url("/api/tracks"))
+        .header(IF_NONE_MATCH, etag.clone())
+        .send()
+        .await
+        .expect("request");
+    assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+
+    server
+        .download("https://music.example/watch?v=etag-two")
+        .await;
+    let changed = server
+        .client
+        .get(server.url("/api/tracks"))
+        .header(IF_NONE_MATCH, etag)
+        .send()
+        .await
+        .expect("request");
+    assert_eq!(changed.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn segments_are_served_the_same_every_time() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=repeat&duration=15";
+    server.download(url).await;
+    let track = server.track(url).await;
+
+    for segment in server.segments(&track).await {
+        let first = server.hls_file(&track, &segment).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let first = first.bytes().await.expect("segment");
+        let second = server.hls_file(&track, &segment).await.bytes().await;
+        assert_eq!(first, second.expect("segment"));
+    }
+}
+
+#[tokio::test]
+async fn the_library_survives_a_restart() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=kept&duration=20";
+    server.download(url).await;
+    let before = server.track(url).await;
+    let segments = server.segments(&before).await;
+
+    let server = server.restart(false).await;
+    let after = server.track(url).await;
+    assert_eq!(after["id"], before["id"]);
+    assert_eq!(after["session_id"], before["session_id"]);
+    assert_eq!(server.segments(&after).await, segments);
+    for segment in &segments {
+        let response = server.hls_file(&after, segment).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
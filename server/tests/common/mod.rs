@@ -0,0 +1,210 @@
+//! Runs the server binary with the fake tools on a library in a temporary
+//! directory, and talks to it over HTTP.
+
+#![allow(dead_code)]
+
+use reqwest::{Client, Response, StatusCode};
+use serde_json::Value;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// How long the server may take to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How long the server may take to save the library and exit.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TestServer {
+    child: Child,
+    port: u16,
+    /// Options the server was started with besides the library and port
+    args: Vec<String>,
+    /// The library, removed once the server is dropped
+    library: TempDir,
+    pub client: Client,
+}
+
+impl TestServer {
+    /// A server on an empty library.
+    pub async fn start() -> TestServer {
+        TestServer::start_with(&[]).await
+    }
+
+    /// A server on an empty library, started with the options `args`.
+    pub async fn start_with(args: &[&str]) -> TestServer {
+        let library = tempfile::tempdir().expect("temporary library");
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        TestServer::on(library, args, false).await
+    }
+
+    /// A server on `library`, refusing changes when `readonly`.
+    async fn on(library: TempDir, args: Vec<String>, readonly: bool) -> TestServer {
+        let port = free_port();
+        let mut command = Command::new(env!("CARGO_BIN_EXE_music-server"));
+        command
+            .arg("--fake-tools")
+            .args(["--port", &port.to_string()])
+            .arg("--cache-path")
+            .arg(library.path())
+            .args(["--save-delay", "0"])
+            .args(&args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if readonly {
+            command.arg("--readonly");
+        }
+        let child = command.spawn().expect("server starts");
+        let server = TestServer {
+            child,
+            port,
+            args,
+            library,
+            client: Client::new(),
+        };
+        server.wait_until_ready().await;
+        server
+    }
+
+    async fn wait_until_ready(&self) {
+        let started = Instant::now();
+        while started.elapsed() < STARTUP_TIMEOUT {
+            if let Ok(response) = self.client.get(self.url("/api/mode")).send().await {
+                if response.status().is_success() {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server didn't start listening on port {}", self.port);
+    }
+
+    /// Stops the server the way an init system does, and starts it again on the
+    /// same library.
+    pub async fn restart(self, readonly: bool) -> TestServer {
+        let args = self.args.clone();
+        let library = self.stop();
+        TestServer::on(library, args, readonly).await
+    }
+
+    /// Stops the server with SIGTERM, so it saves the library before exiting.
+    fn stop(mut self) -> TempDir {
+        terminate(&mut self.child);
+        let library = tempfile::tempdir().expect("temporary library");
+        std::mem::replace(&mut self.library, library)
+    }
+
+    pub fn library(&self) -> &Path {
+        self.library.path()
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    pub async fn get(&self, path: &str) -> Response {
+        self.client
+            .get(self.url(path))
+            .send()
+            .await
+            .expect("request")
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> Response {
+        self.client
+            .post(self.url(path))
+            .json(&body)
+            .send()
+            .await
+            .expect("request")
+    }
+
+    pub async fn delete(&self, path: &str) -> Response {
+        self.client
+            .delete(self.url(path))
+            .send()
+            .await
+            .expect("request")
+    }
+
+    /// Downloads `url`, which answers once the track is ready, and returns the
+    /// response.
+    pub async fn download(&self, url: &str) -> Value {
+        let response = self
+            .post("/api/download", serde_json::json!({ "url": url }))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "downloading {}", url);
+        response.json().await.expect("download response")
+    }
+
+    /// Every track of the library.
+    pub async fn tracks(&self) -> Vec<Value> {
+        let response = self.get("/api/tracks").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        response.json().await.expect("track list")
+    }
+
+    /// The track made from the source at `url`.
+    pub async fn track(&self, url: &str) -> Value {
+        self.tracks()
+            .await
+            .into_iter()
+            .find(|track| track["origin_url"] == url)
+            .unwrap_or_else(|| panic!("no track of {}", url))
+    }
+
+    /// A file served under a track's HLS directory.
+    pub async fn hls_file(&self, track: &Value, file: &str) -> Response {
+        let session_id = track["session_id"].as_str().expect("session id");
+        self.get(&format!("/api/hls/{}/{}", session_id, file)).await
+    }
+
+    /// The segment URIs of a track's playlist.
+    pub async fn segments(&self, track: &Value) -> Vec<String> {
+        let response = self.hls_file(track, "playlist.m3u8").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let playlist = response.text().await.expect("playlist");
+        playlist
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let stopping = Instant::now();
+    while stopping.elapsed() < SHUTDOWN_TIMEOUT {
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    panic!("server didn't exit after SIGTERM");
+}
+
+/// A port nothing is listening on right now.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|address| address.port())
+        .expect("free port")
+}
+
+/// The error code of an error response.
+pub async fn error_code(response: Response) -> String {
+    let body: Value = response.json().await.expect("error body");
+    body["code"].as_str().unwrap_or_default().to_string()
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use common::{error_code, TestServer};
+use reqwest::StatusCode;
+use serde_json::json;
+
+#[tokio::test]
+async fn retranscoding_changes_the_segments() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=convert&duration=25";
+    server.download(url).await;
+    let before = server.track(url).await;
+    let old_segments = server.segments(&before).await;
+    let first = server.hls_file(&before, &old_segments[0]).await;
+    assert_eq!(first.status(), StatusCode::OK);
+
+    let path = format!("/api/tracks/{}/retranscode", before["id"].as_str().unwrap());
+    let response = server
+        .post(
+            &path,
+            json!({ "segment_duration": 5, "bitrate": 192, "source": "segments" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let after: serde_json::Value = response.json().await.expect("track");
+
+    assert_eq!(after["id"], before["id"]);
+    assert_ne!(after["session_id"], before["session_id"]);
+    assert_eq!(after["bitrate"], 192);
+    assert_eq!(after["segment_duration"], 5.0);
+    assert_eq!(after["total_segments"], 5);
+    assert_eq!(after["duration"], 25.0);
+    assert_eq!(server.segments(&after).await.len(), 5);
+
+    // The old playlist is gone once the new segments are swapped in, but whoever was
+    // playing it gets to finish
+    let old = server.hls_file(&before, "playlist.m3u8").await;
+    assert_ne!(old.status(), StatusCode::OK);
+    for segment in &old_segments[1..] {
+        let response = server.hls_file(&before, segment).await;
+        assert_eq!(response.status(), StatusCode::OK, "segment {}", segment);
+    }
+}
+
+#[tokio::test]
+async fn retranscoding_from_the_origin_downloads_again() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=refetch&duration=8";
+    server.download(url).await;
+    let before = server.track(url).await;
+
+    let path = format!("/api/tracks/{}/retranscode", before["id"].as_str().unwrap());
+    let response = server
+        .post(
+            &path,
+            json!({ "segment_duration": 2, "source": "download" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let after = server.track(url).await;
+    assert_eq!(after["id"], before["id"]);
+    assert_eq!(after["total_segments"], 4);
+    assert_eq!(server.tracks().await.len(), 1);
+}
+
+#[tokio::test]
+async fn retranscoding_checks_the_format() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=badformat";
+    server.download(url).await;
+    let track = server.track(url).await;
+
+    let path = format!("/api/tracks/{}/retranscode", track["id"].as_str().unwrap());
+    let response = server.post(&path, json!({ "bitrate": 1000 })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code(response).await, "bad_request");
+    assert_eq!(server.track(url).await["session_id"], track["session_id"]);
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use common::TestServer;
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn deleting_a_track_removes_its_files() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=doomed";
+    server.download(url).await;
+    let track = server.track(url).await;
+    let session_id = track["session_id"].as_str().unwrap();
+    let segments_dir = server.library().join(session_id);
+    assert!(segments_dir.join("playlist.m3u8").exists());
+
+    let path = format!("/api/tracks/{}", track["id"].as_str().unwrap());
+    assert_eq!(server.delete(&path).await.status(), StatusCode::OK);
+
+    assert!(server.tracks().await.is_empty());
+    assert!(!segments_dir.exists());
+    let playlist = server.hls_file(&track, "playlist.m3u8").await;
+    assert!(playlist.status().is_client_error());
+    assert_eq!(server.delete(&path).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn a_deleted_track_can_be_downloaded_again() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=again";
+    server.download(url).await;
+    let track = server.track(url).await;
+    let path = format!("/api/tracks/{}", track["id"].as_str().unwrap());
+    assert_eq!(server.delete(&path).await.status(), StatusCode::OK);
+
+    server.download(url).await;
+    let again = server.track(url).await;
+    assert_ne!(again["session_id"], track["session_id"]);
+}
+
+#[tokio::test]
+async fn deletions_survive_a_restart() {
+    let server = TestServer::start().await;
+    server.download("https://music.example/watch?v=stays").await;
+    let url = "https://music.example/watch?v=goes";
+    server.download(url).await;
+    let track = server.track(url).await;
+    let path = format!("/api/tracks/{}", track["id"].as_str().unwrap());
+    assert_eq!(server.delete(&path).await.status(), StatusCode::OK);
+
+    let server = server.restart(false).await;
+    let tracks = server.tracks().await;
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(
+        tracks[0]["origin_url"],
+        "https://music.example/watch?v=stays"
+    );
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use common::{error_code, TestServer};
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn download_makes_a_playable_track() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=first&duration=25";
+
+    let response = server.download(url).await;
+    assert_eq!(response["total_segments"], 3);
+    assert_eq!(response["segment_duration"], 10.0);
+
+    let track = server.track(url).await;
+    assert_eq!(track["duration"], 25.0);
+    assert_eq!(track["bpm"], 120.0);
+    assert!(track["thumbnails"]["small"].is_string());
+
+    let segments = server.segments(&track).await;
+    assert_eq!(segments.len(), 3);
+    for segment in &segments {
+        let response = server.hls_file(&track, segment).await;
+        assert_eq!(response.status(), StatusCode::OK, "segment {}", segment);
+        let data = response.bytes().await.expect("segment");
+        assert!(!data.is_empty());
+        assert_eq!(
+            data.len() % 188,
+            0,
+            "segment {} is whole TS packets",
+            segment
+        );
+    }
+}
+
+#[tokio::test]
+async fn the_same_source_makes_the_same_segments() {
+    let url = "https://music.example/watch?v=same&duration=12";
+    let mut contents = Vec::new();
+    for _ in 0..2 {
+        let server = TestServer::start().await;
+        server.download(url).await;
+        let track = server.track(url).await;
+        let mut data = Vec::new();
+        for segment in server.segments(&track).await {
+            let response = server.hls_file(&track, &segment).await;
+            data.push(response.bytes().await.expect("segment"));
+        }
+        contents.push(data);
+    }
+    assert_eq!(contents[0], contents[1]);
+}
+
+#[tokio::test]
+async fn downloading_a_track_again_is_a_conflict() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=twice";
+    server.download(url).await;
+
+    let response = server
+        .post("/api/download", serde_json::json!({ "url": url }))
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(error_code(response).await, "duplicate_track");
+    assert_eq!(server.tracks().await.len(), 1);
+}
+
+#[tokio::test]
+async fn unavailable_sources_fail_with_a_code() {
+    let server = TestServer::start().await;
+    let response = server
+        .post(
+            "/api/download",
+            serde_json::json!({ "url": "https://music.example/unavailable" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body: serde_json::Value = response.json().await.expect("error body");
+    assert_eq!(body["code"], "source_unavailable");
+
+    let download_id = body["details"]["download_id"].as_str().expect("job id");
+    let status: serde_json::Value = server
+        .get(&format!("/api/download/{}", download_id))
+        .await
+        .json()
+        .await
+        .expect("job status");
+    assert_eq!(status["status"], "error");
+    assert!(server.tracks().await.is_empty());
+}
+
+#[tokio::test]
+async fn a_refresh_keeps_the_old_segments_for_their_listeners() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=refreshed&duration=25";
+    server.download(url).await;
+    let track = server.track(url).await;
+    let segments = server.segments(&track).await;
+    let first = server.hls_file(&track, &segments[0]).await;
+    assert_eq!(first.status(), StatusCode::OK);
+
+    let path = format!("/api/tracks/{}/refresh", track["id"].as_str().unwrap());
+    let response = server.post(&path, serde_json::json!({})).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let refreshed = server.track(url).await;
+    assert_ne!(refreshed["session_id"], track["session_id"]);
+
+    // Someone is still playing the old session, so it plays to the end
+    let old_dir = server.library().join(track["session_id"].as_str().unwrap());
+    assert!(old_dir.exists());
+    for segment in &segments[1..] {
+        let response = server.hls_file(&track, segment).await;
+        assert_eq!(response.status(), StatusCode::OK, "segment {}", segment);
+    }
+}
+
+#[tokio::test]
+async fn direct_links_cant_redirect_to_blocked_domains() {
+    // Redirects every request to a domain the server blocks
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("listener");
+    let address = listener.local_addr().expect("address");
+    tokio::spawn(async move {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let _ = stream
+                .write_all(
+                    b"HTTP/1.1 302 Found\r\nLocation: http://blocked.example/song.mp3\r\n\
+                      Content-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await;
+        }
+    });
+
+    let server = TestServer::start_with(&["--block-domain", "blocked.example"]).await;
+    let url = format!("http://{}/song.mp3", address);
+    let response = server
+        .post("/api/download", serde_json::json!({ "url": url }))
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, "url_not_allowed");
+    assert!(server.tracks().await.is_empty());
+}
@@ -0,0 +1,27 @@
+mod common;
+
+use common::TestServer;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn artists_and_albums_carry_their_tracks_artwork() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=cover";
+    let response = server
+        .post(
+            "/api/download",
+            json!({ "url": url, "artist": "Some Artist", "album": "Some Album" }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let track = server.track(url).await;
+
+    for path in ["/api/artists", "/api/albums"] {
+        let response = server.get(path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let list: Value = response.json().await.expect("list");
+        assert_eq!(list[0]["thumbnails"], track["thumbnails"], "{}", path);
+        assert!(list[0]["thumbnails"]["small"].is_string(), "{}", path);
+    }
+}
@@ -0,0 +1,23 @@
+mod common;
+
+use common::{error_code, TestServer};
+use reqwest::StatusCode;
+use serde_json::json;
+
+/// As many as the server keeps open at once
+const MAX_ROOMS: usize = 100;
+
+#[tokio::test]
+async fn only_so_many_parties_are_open_at_once() {
+    let server = TestServer::start().await;
+    for _ in 0..MAX_ROOMS {
+        let response = server.post("/api/party", json!({})).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let room: serde_json::Value = response.json().await.expect("room");
+        assert!(room["host_token"].is_string());
+    }
+
+    let response = server.post("/api/party", json!({})).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(error_code(response).await, "service_unavailable");
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::TestServer;
+use reqwest::StatusCode;
+use serde_json::Value;
+
+/// Imports an M3U playlist of `urls` as the collection `path` through `endpoint`.
+async fn import(server: &TestServer, endpoint: &str, path: &str, urls: &[&str]) -> Value {
+    let playlist = format!("#EXTM3U\n{}\n", urls.join("\n"));
+    let response = server
+        .client
+        .post(server.url(&format!("{}?path={}", endpoint, path)))
+        .body(playlist)
+        .send()
+        .await
+        .expect("request");
+    assert_eq!(response.status(), StatusCode::CREATED, "{}", endpoint);
+    response.json().await.expect("imported collection")
+}
+
+#[tokio::test]
+async fn playlists_import_as_collections_under_either_path() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=imported";
+    server.download(url).await;
+    let track = server.track(url).await;
+
+    for (endpoint, path) in [
+        ("/api/collections/import", "Imported"),
+        ("/api/playlists/import", "Also imported"),
+    ] {
+        let imported = import(&server, endpoint, path, &[url]).await;
+        assert_eq!(imported["matched"], 1);
+        assert_eq!(imported["collection"]["track_ids"][0], track["id"]);
+    }
+}
+
+#[tokio::test]
+async fn playlists_export_under_either_path() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=exported";
+    server.download(url).await;
+    let track = server.track(url).await;
+    let imported = import(&server, "/api/playlists/import", "Exported", &[url]).await;
+    let id = imported["collection"]["id"]
+        .as_str()
+        .expect("collection id");
+
+    for format in ["m3u8", "xspf"] {
+        let collection = server
+            .get(&format!("/api/collections/{}/export.{}", id, format))
+            .await;
+        assert_eq!(collection.status(), StatusCode::OK);
+        let collection = collection.text().await.expect("export");
+        assert!(collection.contains(track["session_id"].as_str().unwrap()));
+
+        let playlist = server
+            .get(&format!("/api/playlists/{}/export.{}", id, format))
+            .await;
+        assert_eq!(playlist.status(), StatusCode::OK);
+        assert_eq!(playlist.text().await.expect("export"), collection);
+    }
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::{error_code, TestServer};
+use reqwest::StatusCode;
+use serde_json::json;
+
+#[tokio::test]
+async fn readonly_mode_serves_the_library_and_refuses_changes() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=frozen&duration=12";
+    server.download(url).await;
+    let server = server.restart(true).await;
+
+    let mode: serde_json::Value = server.get("/api/mode").await.json().await.unwrap();
+    assert_eq!(mode["readonly"], true);
+
+    let track = server.track(url).await;
+    for segment in server.segments(&track).await {
+        let response = server.hls_file(&track, &segment).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let id = track["id"].as_str().unwrap();
+    let refused = [
+        server
+            .post(
+                "/api/download",
+                json!({ "url": "https://music.example/new" }),
+            )
+            .await,
+        server.delete(&format!("/api/tracks/{}", id)).await,
+        server
+            .post(
+                &format!("/api/tracks/{}/retranscode", id),
+                json!({ "segment_duration": 5 }),
+            )
+            .await,
+        server.post("/api/party", json!({})).await,
+    ];
+    for response in refused {
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(response).await, "readonly_mode");
+    }
+
+    let tracks = server.tracks().await;
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0]["session_id"], track["session_id"]);
+}
+
+#[tokio::test]
+async fn a_readonly_server_keeps_the_library_as_it_was() {
+    let server = TestServer::start().await;
+    let url = "https://music.example/watch?v=unchanged";
+    server.download(url).await;
+    let track = server.track(url).await;
+
+    let server = server.restart(true).await;
+    let path = format!("/api/tracks/{}", track["id"].as_str().unwrap());
+    assert_eq!(server.delete(&path).await.status(), StatusCode::FORBIDDEN);
+
+    let server = server.restart(false).await;
+    assert_eq!(server.track(url).await["session_id"], track["session_id"]);
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use common::TestServer;
+use reqwest::header::AUTHORIZATION;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+const OWNER_TOKEN: &str = "owner-secret";
+const KEY_TOKEN: &str = "key-secret";
+
+const PUBLIC_URL: &str = "https://music.example/watch?v=public";
+const PRIVATE_URL: &str = "https://music.example/watch?v=private";
+
+/// The URI of the first line of `playlist` that `is_uri` picks.
+fn find_uri(playlist: &str, is_uri: impl Fn(&str) -> bool) -> String {
+    playlist
+        .lines()
+        .find(|line| is_uri(line))
+        .unwrap_or_else(|| panic!("no such URI in\n{}", playlist))
+        .to_string()
+}
+
+async fn share(server: &TestServer, track_id: &str) -> String {
+    let response = server
+        .client
+        .post(server.url(&format!("/api/tracks/{}/share", track_id)))
+        .header(AUTHORIZATION, format!("Bearer {}", OWNER_TOKEN))
+        .json(&json!({ "max_plays": 1 }))
+        .send()
+        .await
+        .expect("request");
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let link: Value = response.json().await.expect("share link");
+    link["token"].as_str().expect("token").to_string()
+}
+
+#[tokio::test]
+async fn share_links_unlock_encrypted_tracks_keys() {
+    let server = TestServer::start_with(&[
+        "--encrypt-segments",
+        "--key-token",
+        KEY_TOKEN,
+        "--owner-token",
+        OWNER_TOKEN,
+    ])
+    .await;
+    server.download(PUBLIC_URL).await;
+    server.download(PRIVATE_URL).await;
+    let public = server.track(PUBLIC_URL).await;
+    let private = server.track(PRIVATE_URL).await;
+    let response = server
+        .client
+        .put(server.url(&format!(
+            "/api/tracks/{}/visibility",
+            private["id"].as_str().unwrap()
+        )))
+        .header(AUTHORIZATION, format!("Bearer {}", OWNER_TOKEN))
+        .json(&json!({ "visibility": "private" }))
+        .send()
+        .await
+        .expect("request");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for (track, other) in [(&public, &private), (&private, &public)] {
+        let url = track["origin_url"].as_str().unwrap();
+        let session_id = track["session_id"].as_str().unwrap();
+        let key = format!("/api/hls/{}/key", session_id);
+        // 404 for a private track, which guests don't know exists
+        let guest = server.get(&key).await;
+        assert!(guest.status().is_client_error(), "{}", url);
+
+        // The link leads to the master playlist, whose URIs pass the token on down to
+        // the key
+        let token = share(&server, track["id"].as_str().unwrap()).await;
+        let response = server.get(&format!("/s/{}", token)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", url);
+        let master = response.text().await.expect("master playlist");
+        let media = find_uri(&master, |line| line.starts_with("playlist.m3u8"));
+        assert_eq!(media, format!("playlist.m3u8?token={}", token));
+        let response = server
+            .get(&format!("/api/hls/{}/{}", session_id, media))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", url);
+        let playlist = response.text().await.expect("media playlist");
+        let key_line = find_uri(&playlist, |line| line.starts_with("#EXT-X-KEY"));
+        assert!(
+            key_line.contains(&format!("URI=\"key?token={}\"", token)),
+            "{}",
+            key_line
+        );
+
+        let response = server.get(&format!("{}?token={}", key, token)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", url);
+        assert_eq!(response.bytes().await.expect("key").len(), 16);
+
+        // A link to another track doesn't open this one's key
+        let other = share(&server, other["id"].as_str().unwrap()).await;
+        let response = server.get(&format!("{}?token={}", key, other)).await;
+        assert!(response.status().is_client_error(), "{}", url);
+    }
+}
@@ -0,0 +1,206 @@
+mod common;
+
+use common::TestServer;
+use reqwest::header::AUTHORIZATION;
+use reqwest::{RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+
+const OWNER_TOKEN: &str = "owner-secret";
+
+const PUBLIC_URL: &str = "https://music.example/watch?v=public";
+const PRIVATE_URL: &str = "https://music.example/watch?v=private";
+
+fn as_owner(request: RequestBuilder) -> RequestBuilder {
+    request.header(AUTHORIZATION, format!("Bearer {}", OWNER_TOKEN))
+}
+
+async fn body(request: RequestBuilder) -> Value {
+    let response = request.send().await.expect("request");
+    assert!(response.status().is_success(), "{}", response.status());
+    response.json().await.expect("JSON body")
+}
+
+async fn text(request: RequestBuilder) -> String {
+    let response = request.send().await.expect("request");
+    assert_eq!(response.status(), StatusCode::OK);
+    response.text().await.expect("body")
+}
+
+/// A library of a public and a private track, both played once, queued and in a
+/// collection. Returns the private track.
+async fn library_with_a_private_track(server: &TestServer) -> (Value, String) {
+    server.download(PUBLIC_URL).await;
+    server.download(PRIVATE_URL).await;
+    let track = server.track(PRIVATE_URL).await;
+    let id = track["id"].as_str().unwrap().to_string();
+    let client = &server.client;
+
+    let path = format!("/api/tracks/{}/visibility", id);
+    body(as_owner(client.put(server.url(&path))).json(&json!({ "visibility": "private" }))).await;
+
+    let collection =
+        body(as_owner(client.post(server.url("/api/collections"))).json(&json!({ "path": "Mix" })))
+            .await;
+    let collection_id = collection["id"]
+        .as_str()
+        .expect("collection id")
+        .to_string();
+    for track in body(as_owner(client.get(server.url("/api/tracks"))))
+        .await
+        .as_array()
+        .unwrap()
+    {
+        let path = format!("/api/collections/{}/tracks", collection_id);
+        let request = as_owner(client.post(server.url(&path)));
+        let response = request
+            .json(&json!({ "track_id": track["id"] }))
+            .send()
+            .await
+            .expect("request");
+        assert!(response.status().is_success());
+
+        // A play for the stats, a queue entry and a now-playing report each
+        let playlist = track["url"].as_str().unwrap();
+        text(as_owner(client.get(server.url(playlist)))).await;
+        let request = as_owner(client.post(server.url("/api/queue"))).header("X-Device-Id", "den");
+        body(request.json(&json!({ "track_id": track["id"] }))).await;
+        let device = format!("device-{}", track["id"].as_str().unwrap());
+        let request = as_owner(client.post(server.url("/api/now-playing")))
+            .header("X-Device-Id", device)
+            .json(&json!({ "track_id": track["id"], "position": 5.0, "playing": true }));
+        body(request).await;
+    }
+    (track, collection_id)
+}
+
+/// The track ids of a list of tracks, wrapped as `field` in each entry when given.
+fn ids(list: &Value, field: Option<&str>) -> Vec<String> {
+    list.as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| match field {
+            Some(field) => entry[field]["id"].as_str().unwrap().to_string(),
+            None => entry["id"].as_str().unwrap().to_string(),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn guests_dont_see_private_tracks_in_listings() {
+    let server = TestServer::start_with(&["--owner-token", OWNER_TOKEN]).await;
+    let client = &server.client;
+    let cursor = body(client.get(server.url("/api/changes"))).await["cursor"].clone();
+    let (private, collection_id) = library_with_a_private_track(&server).await;
+    let id = private["id"].as_str().unwrap().to_string();
+    let session_id = private["session_id"].as_str().unwrap();
+
+    for path in [
+        "/api/export.m3u8".to_string(),
+        "/api/export.xspf".to_string(),
+        format!("/api/collections/{}/export.m3u8", collection_id),
+        format!("/api/collections/{}/export.xspf", collection_id),
+    ] {
+        let guest = text(client.get(server.url(&path))).await;
+        assert!(
+            !guest.contains(session_id),
+            "{} lists the private track",
+            path
+        );
+        let owner = text(as_owner(client.get(server.url(&path)))).await;
+        assert!(
+            owner.contains(session_id),
+            "{} hides it from the owner",
+            path
+        );
+    }
+
+    let top_tracks = server.url("/api/stats/top-tracks");
+    let guest = body(client.get(&top_tracks)).await;
+    assert!(!ids(&guest, None).contains(&id));
+    let owner = body(as_owner(client.get(&top_tracks))).await;
+    assert!(ids(&owner, None).contains(&id));
+
+    let now_playing = server.url("/api/now-playing");
+    let guest = body(client.get(&now_playing)).await;
+    assert!(!ids(&guest, Some("track")).contains(&id));
+    let owner = body(as_owner(client.get(&now_playing))).await;
+    assert!(ids(&owner, Some("track")).contains(&id));
+
+    let queue = client
+        .get(server.url("/api/queue"))
+        .header("X-Device-Id", "den");
+    let queue = body(queue).await;
+    assert_eq!(ids(&queue["tracks"], None).len(), 1);
+    assert!(!ids(&queue["tracks"], None).contains(&id));
+    let queue = as_owner(client.get(server.url("/api/queue"))).header("X-Device-Id", "den");
+    assert!(ids(&body(queue).await["tracks"], None).contains(&id));
+
+    let append = client
+        .post(server.url("/api/queue"))
+        .json(&json!({ "track_id": id }))
+        .send()
+        .await
+        .expect("request");
+    assert_eq!(append.status(), StatusCode::NOT_FOUND);
+
+    let collections = server.url("/api/collections");
+    let guest = body(client.get(&collections)).await;
+    assert_eq!(guest[0]["id"], collection_id.as_str());
+    assert_eq!(guest[0]["track_ids"].as_array().unwrap().len(), 1);
+    assert!(!guest[0]["track_ids"]
+        .as_array()
+        .unwrap()
+        .contains(&json!(id)));
+    let owner = body(as_owner(client.get(&collections))).await;
+    assert!(owner[0]["track_ids"]
+        .as_array()
+        .unwrap()
+        .contains(&json!(id)));
+
+    let changes = server.url(&format!("/api/changes?since={}", cursor));
+    let playlist = |changes: Value| {
+        changes["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|change| change["id"] == collection_id.as_str())
+            .map(|change| change["data"]["track_ids"].clone())
+            .expect("collection change")
+    };
+    let guest = playlist(body(client.get(&changes)).await);
+    assert!(!guest.as_array().unwrap().contains(&json!(id)));
+    let owner = playlist(body(as_owner(client.get(&changes))).await);
+    assert!(owner.as_array().unwrap().contains(&json!(id)));
+
+    let radio = body(client.get(server.url("/api/radio"))).await;
+    assert!(radio["track"].is_null());
+}
+
+#[tokio::test]
+async fn private_tracks_slugs_and_short_links_are_not_found_for_guests() {
+    let server = TestServer::start_with(&["--owner-token", OWNER_TOKEN]).await;
+    let (private, _) = library_with_a_private_track(&server).await;
+    let slug = private["slug"].as_str().expect("slug");
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    for path in [
+        format!("/api/hls/{}/playlist.m3u8", slug),
+        format!("/t/{}", slug),
+    ] {
+        let guest = client.get(server.url(&path)).send().await.expect("request");
+        assert_eq!(guest.status(), StatusCode::NOT_FOUND, "{}", path);
+        let owner = as_owner(client.get(server.url(&path)))
+            .send()
+            .await
+            .expect("request");
+        assert!(owner.status().is_redirection(), "{}", path);
+    }
+
+    let public = server.track(PUBLIC_URL).await;
+    let path = format!("/t/{}", public["slug"].as_str().expect("slug"));
+    let guest = client.get(server.url(&path)).send().await.expect("request");
+    assert!(guest.status().is_redirection());
+}